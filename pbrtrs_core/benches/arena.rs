@@ -0,0 +1,82 @@
+use bumpalo::Bump;
+use cgmath::point3;
+use criterion::{black_box, criterion_group, Criterion};
+use pbrtrs_core::light::PointLight;
+use pbrtrs_core::raytracer::{trace_camera_ray, PathSample};
+use pbrtrs_core::scene::{CameraBuilder, MaterialBuilder, Object, Scene, SceneBuilder, Shape};
+use pbrtrs_core::stats::RayStats;
+use pbrtrs_core::types::{Color, Scalar};
+
+const TILE_SIZE: usize = 32;
+const SAMPLES: usize = 4;
+
+fn scene() -> Scene {
+    SceneBuilder::new()
+        .camera(
+            CameraBuilder::new()
+                .resolution(TILE_SIZE, TILE_SIZE)
+                .bounce_limit(8)
+                .build(),
+        )
+        .add_object(Object::new(
+            Shape::Sphere { radius: 1.0 },
+            point3(0.0, 0.0, 4.0),
+            MaterialBuilder::new().roughness(0.3).clearcoat(0.5).build(),
+        ))
+        .add_object(Object::new(
+            Shape::Plane { uv_scale: 1.0 },
+            point3(0.0, -1.0, 0.0),
+            MaterialBuilder::new().build(),
+        ))
+        .add_light(PointLight::new(
+            point3(2.0, 3.0, 1.0),
+            Color::new(20.0, 20.0, 20.0),
+        ))
+        .build()
+}
+
+fn trace(x: usize, y: usize, scene: &Scene, arena: &Bump, stats: &RayStats) -> PathSample {
+    let ray = scene
+        .camera
+        .generate_ray(x as Scalar + 0.5, y as Scalar + 0.5, 0.0);
+    trace_camera_ray(&ray, scene, arena, stats)
+}
+
+/// A new arena for every pixel that keeps all of its samples' BxDFs
+fn arena_per_pixel(scene: &Scene, stats: &RayStats) {
+    for y in 0..TILE_SIZE {
+        for x in 0..TILE_SIZE {
+            let arena = Bump::new();
+            for _ in 0..SAMPLES {
+                black_box(trace(x, y, scene, &arena, stats));
+            }
+        }
+    }
+}
+
+/// One arena for the whole tile, reset after every sample
+fn reused_arena(scene: &Scene, stats: &RayStats) {
+    let mut arena = Bump::new();
+    for y in 0..TILE_SIZE {
+        for x in 0..TILE_SIZE {
+            for _ in 0..SAMPLES {
+                black_box(trace(x, y, scene, &arena, stats));
+                arena.reset();
+            }
+        }
+    }
+}
+
+pub fn bench_tile_arena(c: &mut Criterion) {
+    let scene = scene();
+    let stats = RayStats::new();
+    let mut group = c.benchmark_group("tile_arena");
+    group.sample_size(20);
+    group.bench_function("arena_per_pixel", |b| {
+        b.iter(|| arena_per_pixel(&scene, &stats))
+    });
+    group.bench_function("reused_arena", |b| b.iter(|| reused_arena(&scene, &stats)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_tile_arena);
@@ -1,7 +1,15 @@
 use criterion::criterion_main;
 
+mod arena;
+mod hdri;
+mod packets;
+mod spheres;
 mod util;
 
 criterion_main! {
-    util::benches
+    util::benches,
+    hdri::benches,
+    arena::benches,
+    spheres::benches,
+    packets::benches
 }
@@ -0,0 +1,28 @@
+use criterion::{criterion_group, BenchmarkId, Criterion};
+use image::{Rgb, Rgb32FImage};
+use pbrtrs_core::light::hdri::{importance_distribution, MAX_DISTRIBUTION_SIZE};
+use std::thread;
+
+pub fn bench_importance_distribution(c: &mut Criterion) {
+    // The size of a 4k environment map, which is averaged down before sampling
+    let image = Rgb32FImage::from_fn(4096, 2048, |x, y| {
+        Rgb([(x % 97) as f32, (y % 61) as f32, ((x + y) % 13) as f32])
+    });
+    let available = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let mut thread_counts = vec![1, available];
+    thread_counts.dedup();
+    let mut group = c.benchmark_group("hdri_importance_distribution");
+    group.sample_size(10);
+    for threads in thread_counts {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| importance_distribution(&image, 1.0, MAX_DISTRIBUTION_SIZE, threads));
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_importance_distribution);
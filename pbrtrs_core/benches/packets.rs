@@ -0,0 +1,98 @@
+use cgmath::point3;
+use criterion::{black_box, criterion_group, Criterion};
+use pbrtrs_core::intersect::RAY_PACKET;
+use pbrtrs_core::mesh::TriangleMesh;
+use pbrtrs_core::scene::{
+    CameraBuilder, MaterialBuilder, Object, Scene, SceneBuilder, Shape, Visibility,
+};
+use pbrtrs_core::types::scalar::consts::PI;
+use pbrtrs_core::types::{Pt3, Ray, Scalar};
+
+const RESOLUTION: usize = 64;
+/// Rings and segments of the tessellated sphere, about 260k triangles
+const RINGS: u32 = 256;
+const SEGMENTS: u32 = 512;
+
+fn sphere_mesh() -> TriangleMesh {
+    let positions = (0..=RINGS)
+        .flat_map(|ring| {
+            let theta = ring as Scalar / RINGS as Scalar * PI;
+            (0..=SEGMENTS).map(move |segment| {
+                let phi = segment as Scalar / SEGMENTS as Scalar * 2.0 * PI;
+                Pt3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                )
+            })
+        })
+        .collect();
+    let triangles = (0..RINGS)
+        .flat_map(|ring| {
+            (0..SEGMENTS).flat_map(move |segment| {
+                let i = ring * (SEGMENTS + 1) + segment;
+                let below = i + SEGMENTS + 1;
+                [[i, below, i + 1], [i + 1, below, below + 1]]
+            })
+        })
+        .collect();
+    TriangleMesh::new(positions, None, None, triangles)
+}
+
+fn scene() -> Scene {
+    SceneBuilder::new()
+        .camera(
+            CameraBuilder::new()
+                .resolution(RESOLUTION, RESOLUTION)
+                .build(),
+        )
+        .add_object(Object::new(
+            Shape::Mesh(sphere_mesh()),
+            // Close enough to fill the image
+            point3(0.0, 0.0, 1.2),
+            MaterialBuilder::new().build(),
+        ))
+        .build()
+}
+
+/// Camera rays through the centers of each 2x2 block of pixels
+fn packets(scene: &Scene) -> Vec<[Ray; RAY_PACKET]> {
+    let mut packets = Vec::new();
+    for y in (0..RESOLUTION).step_by(2) {
+        for x in (0..RESOLUTION).step_by(2) {
+            packets.push(std::array::from_fn(|lane| {
+                let x = (x + lane % 2) as Scalar + 0.5;
+                let y = (y + lane / 2) as Scalar + 0.5;
+                scene.camera.generate_ray(x, y, 0.0)
+            }));
+        }
+    }
+    packets
+}
+
+pub fn bench_ray_packets(c: &mut Criterion) {
+    let scene = scene();
+    let packets = packets(&scene);
+    let mut group = c.benchmark_group("camera_ray_packets");
+    group.sample_size(20);
+    group.bench_function("single_rays", |b| {
+        b.iter(|| {
+            for rays in &packets {
+                for ray in rays {
+                    black_box(scene.intersect(ray, Visibility::CAMERA).is_hit());
+                }
+            }
+        })
+    });
+    group.bench_function("packets", |b| {
+        b.iter(|| {
+            for rays in &packets {
+                let hits = scene.intersect_packet(rays, Visibility::CAMERA);
+                black_box(hits.iter().filter(|hit| hit.is_hit()).count());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_ray_packets);
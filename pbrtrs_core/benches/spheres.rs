@@ -0,0 +1,85 @@
+use cgmath::{point3, vec3, InnerSpace};
+use criterion::{black_box, criterion_group, Criterion};
+use pbrtrs_core::intersect::PossibleIntersection;
+use pbrtrs_core::scene::{
+    CameraBuilder, MaterialBuilder, Object, Scene, SceneBuilder, Shape, Visibility,
+};
+use pbrtrs_core::types::{Ray, Scalar};
+
+const SPHERES: usize = 10_000;
+const RAYS: usize = 64;
+
+fn random(range: Scalar) -> Scalar {
+    (fastrand::f64() as Scalar * 2.0 - 1.0) * range
+}
+
+fn scene() -> Scene {
+    fastrand::seed(1);
+    let mut builder = SceneBuilder::new().camera(CameraBuilder::new().build());
+    for _ in 0..SPHERES {
+        builder = builder.add_object(Object::new(
+            Shape::Sphere {
+                radius: 0.05 + random(0.05).abs(),
+            },
+            point3(random(20.0), random(20.0), random(20.0)),
+            MaterialBuilder::new().build(),
+        ));
+    }
+    builder.build()
+}
+
+fn rays() -> Vec<Ray> {
+    (0..RAYS)
+        .map(|_| {
+            let direction = vec3(random(1.0), random(1.0), random(1.0)).normalize();
+            Ray::new(
+                point3(0.0, 0.0, -30.0),
+                direction + vec3(0.0, 0.0, 1.0),
+                0.0,
+            )
+        })
+        .collect()
+}
+
+/// Every object intersected one at a time, as without packing
+fn scalar_nearest(scene: &Scene, ray: &Ray) -> Option<Scalar> {
+    scene
+        .objects()
+        .iter()
+        .filter_map(|object| {
+            match object.shape.intersect(
+                ray,
+                object.transform_at(ray.time),
+                &object.material,
+                object,
+            ) {
+                PossibleIntersection::Hit(hit) => Some(hit.distance),
+                _ => None,
+            }
+        })
+        .min_by(Scalar::total_cmp)
+}
+
+pub fn bench_sphere_intersect(c: &mut Criterion) {
+    let scene = scene();
+    let rays = rays();
+    let mut group = c.benchmark_group("sphere_intersect");
+    group.sample_size(20);
+    group.bench_function("scalar", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(scalar_nearest(&scene, ray));
+            }
+        })
+    });
+    group.bench_function("packed", |b| {
+        b.iter(|| {
+            for ray in &rays {
+                black_box(scene.intersect(ray, Visibility::CAMERA).is_hit());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_sphere_intersect);
@@ -3,15 +3,14 @@ pub mod distribution;
 pub use distribution::*;
 use std::fmt::{Debug, Formatter};
 
-use crate::bxdf::distribution::Distribution;
 use crate::debugger;
 use crate::intersect::Intersection;
 use crate::material::TransportMode;
-use crate::types::color::BLACK;
+use crate::types::color::{BLACK, WHITE};
 use crate::types::scalar::consts::{FRAC_1_PI, PI};
 use crate::types::{color, scalar, Color, Scalar, Vec3};
 use crate::util::{bitfield_methods, random_cos_sample_hemisphere, reflect, NormalBasisVector};
-use cgmath::{point3, vec3, Array, ElementWise, InnerSpace, Zero};
+use cgmath::{vec3, InnerSpace, Zero};
 use smallvec::SmallVec;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -74,12 +73,45 @@ pub trait BxDF: Debug {
         self.f(wo, *wi)
     }
 
-    fn rho(&self, _wo: Vec3, _samples: &[[Scalar; 2]]) -> Color {
-        unimplemented!()
+    /// Directional-hemispherical reflectance, the fraction of light reflected or transmitted
+    /// towards `wo`.
+    ///
+    /// The default estimates it with one call to [`BxDF::sample_f`] per sample. `sample_f`
+    /// draws its own random numbers, so only the number of samples is used.
+    fn rho(&self, wo: Vec3, samples: &[[Scalar; 2]]) -> Color {
+        if samples.is_empty() {
+            return BLACK;
+        }
+        let rho = samples.iter().fold(BLACK, |rho, _| {
+            let mut wi = Vec3::zero();
+            let mut pdf = 0.0;
+            let mut sampled_kind = self.kind();
+            let f = self.sample_f(wo, &mut wi, &mut pdf, &mut sampled_kind);
+            if pdf > 0.0 {
+                rho + f * wi.abs_cos_theta() / pdf
+            } else {
+                rho
+            }
+        });
+        rho / samples.len() as Scalar
     }
 
-    fn rho2(&self, _samples1: &[[Scalar; 2]], _samples2: &[[Scalar; 2]]) -> Color {
-        unimplemented!()
+    /// Hemispherical-hemispherical reflectance, the fraction of uniform incident light that is
+    /// reflected or transmitted.
+    ///
+    /// The default picks `wo` uniformly over the hemisphere from `samples1` and estimates
+    /// [`BxDF::rho`] for each with one sample, so `samples2` only sets the number of samples.
+    fn rho2(&self, samples1: &[[Scalar; 2]], samples2: &[[Scalar; 2]]) -> Color {
+        let n = samples1.len().min(samples2.len());
+        if n == 0 {
+            return BLACK;
+        }
+        // rho(wo) weighted by |cos(wo)| / PI and divided by the uniform pdf 1 / (2 PI)
+        let rho = samples1[..n].iter().fold(BLACK, |rho, &u| {
+            let wo = uniform_sample_hemisphere(u);
+            rho + self.rho(wo, &[u]) * 2.0 * wo.abs_cos_theta()
+        });
+        rho / n as Scalar
     }
 
     fn pdf(&self, wo: Vec3, wi: Vec3) -> Scalar {
@@ -98,6 +130,13 @@ pub trait BxDF: Debug {
     }
 }
 
+fn uniform_sample_hemisphere([u1, u2]: [Scalar; 2]) -> Vec3 {
+    let z = u1;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u2;
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
 #[derive(Debug)]
 pub struct ScaledBxDF<B: BxDF>(Scalar, B);
 
@@ -121,6 +160,14 @@ impl<B: BxDF> BxDF for ScaledBxDF<B> {
     ) -> Color {
         self.0 * self.1.sample_f(wo, wi, pdf, sampled_kind)
     }
+
+    fn rho(&self, wo: Vec3, samples: &[[Scalar; 2]]) -> Color {
+        self.0 * self.1.rho(wo, samples)
+    }
+
+    fn rho2(&self, samples1: &[[Scalar; 2]], samples2: &[[Scalar; 2]]) -> Color {
+        self.0 * self.1.rho2(samples1, samples2)
+    }
 }
 
 #[derive(Debug)]
@@ -144,6 +191,79 @@ impl BxDF for Lambertian {
     }
 }
 
+/// Oren-Nayar diffuse reflection from rough surfaces, with the standard deviation of the
+/// microfacet angles `sigma` in radians. A `sigma` of 0 is Lambertian.
+#[derive(Debug)]
+pub struct OrenNayar(pub Color, pub Scalar);
+
+impl BxDF for OrenNayar {
+    fn kind(&self) -> BxDFKind {
+        BxDFKind::DIFFUSE.set(BxDFKind::REFLECTION)
+    }
+
+    fn f(&self, wo: Vec3, wi: Vec3) -> Color {
+        let sigma2 = self.1 * self.1;
+        let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+        let sin_theta_i = wi.sin_theta();
+        let sin_theta_o = wo.sin_theta();
+        let max_cos = if sin_theta_i > 1e-4 && sin_theta_o > 1e-4 {
+            let d_cos = wi.cos_phi() * wo.cos_phi() + wi.sin_phi() * wo.sin_phi();
+            d_cos.max(0.0)
+        } else {
+            0.0
+        };
+
+        let (sin_alpha, tan_beta) = if wi.abs_cos_theta() > wo.abs_cos_theta() {
+            (sin_theta_o, sin_theta_i / wi.abs_cos_theta())
+        } else {
+            (sin_theta_i, sin_theta_o / wo.abs_cos_theta())
+        };
+        self.0 * (FRAC_1_PI * (a + b * max_cos * sin_alpha * tan_beta))
+    }
+}
+
+/// Diffuse lobe of the Disney BRDF, blended towards its Hanrahan-Krueger based subsurface
+/// approximation by `subsurface`. Gives the flattened look of subsurface scattering without
+/// tracing below the surface.
+#[derive(Debug)]
+pub struct DisneyDiffuse {
+    pub color: Color,
+    pub roughness: Scalar,
+    pub subsurface: Scalar,
+}
+
+impl BxDF for DisneyDiffuse {
+    fn kind(&self) -> BxDFKind {
+        BxDFKind::DIFFUSE.set(BxDFKind::REFLECTION)
+    }
+
+    fn f(&self, wo: Vec3, wi: Vec3) -> Color {
+        let cos_o = wo.abs_cos_theta();
+        let cos_i = wi.abs_cos_theta();
+        let wh = wi + wo;
+        if cos_o + cos_i == 0.0 || wh == Vec3::zero() {
+            return BLACK;
+        }
+        // Angle between the light and the half vector
+        let cos_d = wi.dot(wh.normalize());
+        let weight_o = (1.0 - cos_o).powi(5);
+        let weight_i = (1.0 - cos_i).powi(5);
+
+        // Retroreflection brightens grazing angles on rough surfaces
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let diffuse = (1.0 + (fd90 - 1.0) * weight_o) * (1.0 + (fd90 - 1.0) * weight_i);
+
+        let fss90 = self.roughness * cos_d * cos_d;
+        let fss = (1.0 + (fss90 - 1.0) * weight_o) * (1.0 + (fss90 - 1.0) * weight_i);
+        let subsurface = 1.25 * (fss * (1.0 / (cos_o + cos_i) - 0.5) + 0.5);
+
+        let blend = diffuse * (1.0 - self.subsurface) + subsurface * self.subsurface;
+        self.color * (FRAC_1_PI * blend)
+    }
+}
+
 #[inline]
 fn fr_schlick(r0: Color, cos_i: Scalar) -> Color {
     // theta_i is the angle between wi and wo
@@ -176,6 +296,90 @@ fn fr_dielectric(mut cos_i: Scalar, mut eta_i: Scalar, mut eta_t: Scalar) -> Sca
     (r_parl.powi(2) + r_perp.powi(2)) / 2.0
 }
 
+/// Fresnel reflectance of a conductor with complex index of refraction `eta + i k` relative to
+/// the outside medium
+#[inline]
+fn fr_conductor(cos_i: Scalar, eta: Scalar, k: Scalar) -> Scalar {
+    let cos_i = cos_i.abs().min(1.0);
+    let cos2 = cos_i * cos_i;
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i * a;
+    let r_s = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+    (r_p + r_s) / 2.0
+}
+
+/// Wavelengths in nanometers of the Fraunhofer d, F and C lines, which define the Abbe number
+const FRAUNHOFER_D: Scalar = 587.56;
+const FRAUNHOFER_F: Scalar = 486.13;
+const FRAUNHOFER_C: Scalar = 656.27;
+
+/// Wavelength in nanometers each color channel is refracted at by dispersive dielectrics
+const CHANNEL_WAVELENGTHS: [Scalar; 3] = [610.0, 550.0, 465.0];
+
+/// IOR at `wavelength` nanometers of a dielectric with IOR `ior` at the d line and Abbe number
+/// `abbe_number`, from Cauchy's equation `A + B / wavelength^2`
+pub fn cauchy_ior(ior: Scalar, abbe_number: Scalar, wavelength: Scalar) -> Scalar {
+    let b = (ior - 1.0) / (abbe_number * (FRAUNHOFER_F.powi(-2) - FRAUNHOFER_C.powi(-2)));
+    ior + b * (wavelength.powi(-2) - FRAUNHOFER_D.powi(-2))
+}
+
+/// Color channel a path is refracted with by dispersive dielectrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wavelength {
+    pub channel: usize,
+    /// Whether an earlier dispersive refraction left the path carrying only `channel`
+    pub monochromatic: bool,
+}
+
+impl Wavelength {
+    /// Picks one of the channels uniformly for a path that still carries all of them
+    pub fn sample() -> Self {
+        Wavelength {
+            channel: ((scalar::rand() * 3.0) as usize).min(2),
+            monochromatic: false,
+        }
+    }
+
+    /// Wavelength of a path that only carries `channel`
+    pub fn monochromatic(channel: usize) -> Self {
+        Wavelength {
+            channel,
+            monochromatic: true,
+        }
+    }
+
+    pub fn nanometers(self) -> Scalar {
+        CHANNEL_WAVELENGTHS[self.channel]
+    }
+
+    /// White in the channel, black in the others
+    pub fn mask(self) -> Color {
+        let mut mask = [0.0; 3];
+        mask[self.channel] = 1.0;
+        Color::from(mask)
+    }
+}
+
+/// Wavelength dependence of a dielectric's IOR
+#[derive(Debug, Clone, Copy)]
+pub struct Dispersion {
+    /// Lower Abbe numbers spread the colors further apart
+    pub abbe_number: Scalar,
+    pub wavelength: Wavelength,
+}
+
 #[allow(unused)]
 fn schlick_r0_from_eta(eta: Scalar) -> Scalar {
     (eta - 1.0).powi(2) / (eta + 1.0).powi(2)
@@ -212,6 +416,45 @@ impl Fresnel for FresnelDielectric {
     }
 }
 
+/// Fresnel equations for metals, with a complex index of refraction per RGB channel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FresnelConductor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+impl FresnelConductor {
+    pub const GOLD: Self = Self::new(color(0.143, 0.374, 1.442), color(3.983, 2.385, 1.603));
+    pub const SILVER: Self = Self::new(color(0.155, 0.117, 0.138), color(4.828, 3.122, 2.147));
+    pub const COPPER: Self = Self::new(color(0.200, 0.924, 1.102), color(3.912, 2.452, 2.142));
+    pub const ALUMINUM: Self = Self::new(color(1.657, 0.880, 0.521), color(9.224, 6.270, 4.837));
+
+    pub const fn new(eta: Color, k: Color) -> Self {
+        Self { eta, k }
+    }
+
+    /// Looks up reference constants by lowercase name
+    pub fn preset(name: &str) -> Option<Self> {
+        match name {
+            "gold" => Some(Self::GOLD),
+            "silver" => Some(Self::SILVER),
+            "copper" => Some(Self::COPPER),
+            "aluminum" | "aluminium" => Some(Self::ALUMINUM),
+            _ => None,
+        }
+    }
+}
+
+impl Fresnel for FresnelConductor {
+    fn f(self, cos_i: Scalar) -> Color {
+        color(
+            fr_conductor(cos_i, self.eta.r, self.k.r),
+            fr_conductor(cos_i, self.eta.g, self.k.g),
+            fr_conductor(cos_i, self.eta.b, self.k.b),
+        )
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct FresnelSchlick(pub Color);
 
@@ -282,9 +525,7 @@ impl<F: Fresnel> BxDF for TransmissionSpecular<F> {
             return BLACK;
         };
 
-        let mut ft = self.color.mul_element_wise(
-            point3(1.0, 1.0, 1.0).sub_element_wise(self.fresnel.f(wi.cos_theta())),
-        );
+        let mut ft = self.color * (WHITE - self.fresnel.f(wi.cos_theta()));
         if self.transport_mode == TransportMode::Radiance {
             ft *= eta_frac.powi(2);
         }
@@ -321,7 +562,11 @@ impl<F: Fresnel> BxDF for ReflectionSpecular<F> {
         *sampled_kind = self.kind();
         *wi = vec3(-wo.x, -wo.y, wo.z);
         *pdf = 1.0;
-        self.fresnel.f(wi.cos_theta()).mul_element_wise(self.color) / wi.abs_cos_theta()
+        self.fresnel.f(wi.cos_theta()) * self.color / wi.abs_cos_theta()
+    }
+
+    fn rho(&self, wo: Vec3, _samples: &[[Scalar; 2]]) -> Color {
+        self.fresnel.f(wo.cos_theta()) * self.color
     }
 
     fn pdf(&self, _wo: Vec3, _wi: Vec3) -> Scalar {
@@ -329,12 +574,17 @@ impl<F: Fresnel> BxDF for ReflectionSpecular<F> {
     }
 }
 
+/// Smooth dielectric, choosing between reflection and refraction by the Fresnel reflectance.
+///
+/// With dispersion `eta_b` is the IOR at the d line and only the path's channel is refracted, the
+/// refracted light of the other channels goes in other directions.
 #[derive(Debug)]
 pub struct FresnelSpecular {
     pub color: Color,
     pub eta_a: Scalar,
     pub eta_b: Scalar,
     pub transport_mode: TransportMode,
+    pub dispersion: Option<Dispersion>,
 }
 
 impl BxDF for FresnelSpecular {
@@ -355,21 +605,42 @@ impl BxDF for FresnelSpecular {
         pdf: &mut Scalar,
         sampled_kind: &mut BxDFKind,
     ) -> Color {
-        let f = fr_dielectric(wo.cos_theta(), self.eta_a, self.eta_b);
+        let eta_b = match self.dispersion {
+            Some(Dispersion {
+                abbe_number,
+                wavelength,
+            }) => cauchy_ior(self.eta_b, abbe_number, wavelength.nanometers()),
+            None => self.eta_b,
+        };
+        // Paths with all channels picked their wavelength uniformly for this event
+        let polychromatic = self.dispersion.filter(|d| !d.wavelength.monochromatic);
+
+        let f = fr_dielectric(wo.cos_theta(), self.eta_a, eta_b);
         if scalar::rand() < f {
             *wi = vec3(-wo.x, -wo.y, wo.z);
             *sampled_kind = BxDFKind::REFLECTION.set(BxDFKind::SPECULAR);
-            *pdf = f;
-            self.color.mul_element_wise(f / wi.abs_cos_theta())
+            if let Some(Dispersion { abbe_number, .. }) = polychromatic {
+                // Every channel is reflected, the chance of reflecting is averaged over the
+                // wavelengths that could have been picked
+                let fresnel = Color::from(CHANNEL_WAVELENGTHS.map(|wavelength| {
+                    let eta_b = cauchy_ior(self.eta_b, abbe_number, wavelength);
+                    fr_dielectric(wo.cos_theta(), self.eta_a, eta_b)
+                }));
+                *pdf = fresnel.average();
+                self.color * fresnel / wi.abs_cos_theta()
+            } else {
+                *pdf = f;
+                self.color * f / wi.abs_cos_theta()
+            }
         } else {
             *pdf = 1.0 - f;
             *sampled_kind = BxDFKind::TRANSMISSION.set(BxDFKind::SPECULAR);
 
             let entering = wo.cos_theta() > 0.0;
             let eta_frac = if entering {
-                self.eta_a / self.eta_b
+                self.eta_a / eta_b
             } else {
-                self.eta_b / self.eta_a
+                eta_b / self.eta_a
             };
 
             *wi = if let Some(wi) = refract(wo, faceforward(vec3(0.0, 0.0, 1.0), wo), eta_frac) {
@@ -378,10 +649,16 @@ impl BxDF for FresnelSpecular {
                 return BLACK;
             };
 
-            let mut ft = self.color.mul_element_wise(1.0 - f);
+            let mut ft = self.color * (1.0 - f);
             if self.transport_mode == TransportMode::Radiance {
                 ft *= eta_frac.powi(2);
             }
+            if let Some(dispersion) = self.dispersion {
+                ft *= dispersion.wavelength.mask();
+            }
+            if polychromatic.is_some() {
+                *pdf /= 3.0;
+            }
             ft / wi.abs_cos_theta()
         }
     }
@@ -426,7 +703,7 @@ impl<D: Distribution, F: Fresnel> BxDF for MicrofacetReflection<D, F> {
                 wh,
                 dfg
             }
-            dfg.mul_element_wise(self.color) / (4.0 * cos_theta_i * cos_theta_o)
+            dfg * self.color / (4.0 * cos_theta_i * cos_theta_o)
         }
     }
 
@@ -486,6 +763,36 @@ impl<'arena> BSDF<'arena> {
         self.bxdfs.push(bxdf);
     }
 
+    /// Points the shading frame to the other side of the surface
+    pub fn flip_normal(&mut self) {
+        self.surface_normal = -self.surface_normal;
+        self.geom_normal = -self.geom_normal;
+        self.surface_cotangent = -self.surface_cotangent;
+    }
+
+    /// Bends the shading normal to `normal`, as given by a normal map. The tangent stays as
+    /// close to the old one as it can.
+    pub fn set_shading_normal(&mut self, normal: Vec3) {
+        let normal = normal.normalize();
+        let tangent = self.surface_tangent - normal * normal.dot(self.surface_tangent);
+        if tangent.magnitude2() <= 1e-12 {
+            return;
+        }
+        self.surface_normal = normal;
+        self.surface_tangent = tangent.normalize();
+        self.surface_cotangent = normal.cross(self.surface_tangent);
+    }
+
+    /// Rotates the shading tangent frame by `angle` radians around the normal, which rotates
+    /// the direction of anisotropic lobes
+    pub fn rotate_tangent(&mut self, angle: Scalar) {
+        let (sin, cos) = angle.sin_cos();
+        let tangent = self.surface_tangent * cos + self.surface_cotangent * sin;
+        let cotangent = self.surface_cotangent * cos - self.surface_tangent * sin;
+        self.surface_tangent = tangent;
+        self.surface_cotangent = cotangent;
+    }
+
     pub fn world_to_normal(&self, v: Vec3) -> Vec3 {
         vec3(
             v.dot(self.surface_cotangent),
@@ -529,7 +836,7 @@ impl<'arena> BSDF<'arena> {
             })
             .for_each(|bxdf| {
                 let f_b = bxdf.f(wo, wi);
-                f.add_assign_element_wise(f_b);
+                f += f_b;
             });
         f
     }
@@ -581,19 +888,18 @@ impl<'arena> BSDF<'arena> {
             if num_matching > 1 {
                 f = BLACK;
                 let reflect = wi_world.dot(self.geom_normal) * wo_world.dot(self.geom_normal) > 0.0;
-                f.add_assign_element_wise(self.f_normal_space(wo, wi, reflect, kind));
+                f += self.f_normal_space(wo, wi, reflect, kind);
             }
         }
         f
     }
 
     pub fn rho(&self, wo: Vec3, samples: &[[Scalar; 2]], kind: BxDFKind) -> Color {
+        let wo = self.world_to_normal(wo);
         self.bxdfs
             .iter()
             .filter(|bxdf| bxdf.kind().matches(kind))
-            .fold(BLACK, |rho, bxdf| {
-                rho.add_element_wise(bxdf.rho(wo, samples))
-            })
+            .fold(BLACK, |rho, bxdf| rho + bxdf.rho(wo, samples))
     }
 
     pub fn rho2(
@@ -605,12 +911,12 @@ impl<'arena> BSDF<'arena> {
         self.bxdfs
             .iter()
             .filter(|bxdf| bxdf.kind().matches(kind))
-            .fold(BLACK, |rho, bxdf| {
-                rho.add_element_wise(bxdf.rho2(samples1, samples2))
-            })
+            .fold(BLACK, |rho, bxdf| rho + bxdf.rho2(samples1, samples2))
     }
 
     pub fn pdf(&self, wo: Vec3, wi: Vec3, kind: BxDFKind) -> Scalar {
+        let wo = self.world_to_normal(wo);
+        let wi = self.world_to_normal(wi);
         let (count, pdf) = self
             .bxdfs
             .iter()
@@ -618,17 +924,23 @@ impl<'arena> BSDF<'arena> {
             .fold((0, 0.0), |(count, pdf), bxdf| {
                 (count + 1, pdf + bxdf.pdf(wo, wi))
             });
-        pdf / count as Scalar
+        if count == 0 {
+            0.0
+        } else {
+            pdf / count as Scalar
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::intersect::Transform;
     use crate::material::EmptyMaterial;
     use crate::scene::Shape;
-    use crate::types::{Euler, Mat4, Pt3, Quaternion, Ray};
-    use cgmath::{assert_abs_diff_eq, point3, EuclideanSpace, Rad, SquareMatrix};
+    use crate::types::color::WHITE;
+    use crate::types::{Pt3, Quaternion, Ray};
+    use cgmath::{assert_abs_diff_eq, point2, EuclideanSpace};
 
     #[test]
     fn bsdf_world_to_normal() {
@@ -639,8 +951,7 @@ mod tests {
                 let si = shape
                     .intersect(
                         &Ray::new(Pt3::from_vec($direction * 10.0), -$direction, 0.0),
-                        Quaternion::zero(),
-                        vec3(0.0, 0.0, 0.0),
+                        Transform::new(Quaternion::zero(), vec3(0.0, 0.0, 0.0)),
                         &EmptyMaterial,
                         &(),
                     )
@@ -687,4 +998,249 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn conductor_fresnel() {
+        // Normal incidence has the closed form ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2)
+        let (eta, k) = (0.2, 3.9);
+        let normal = ((eta - 1.0) * (eta - 1.0) + k * k) / ((eta + 1.0) * (eta + 1.0) + k * k);
+        assert_abs_diff_eq!(fr_conductor(1.0, eta, k), normal, epsilon = 1e-5);
+        assert_abs_diff_eq!(fr_conductor(-1.0, eta, k), normal, epsilon = 1e-5);
+        assert_abs_diff_eq!(fr_conductor(0.0, eta, k), 1.0, epsilon = 1e-5);
+
+        // Without absorption it reduces to a dielectric
+        for cos_i in [0.1, 0.5, 0.9] {
+            assert_abs_diff_eq!(
+                fr_conductor(cos_i, 1.5, 0.0),
+                fr_dielectric(cos_i, 1.0, 1.5),
+                epsilon = 1e-5
+            );
+        }
+
+        // Gold reflects red more than blue
+        let gold = FresnelConductor::GOLD.f(1.0);
+        assert!(gold.r > 0.9 && gold.r > gold.b);
+        assert_eq!(
+            FresnelConductor::preset("gold"),
+            Some(FresnelConductor::GOLD)
+        );
+    }
+
+    #[test]
+    fn dispersion_splits_channels() {
+        assert_abs_diff_eq!(cauchy_ior(1.5, 40.0, FRAUNHOFER_D), 1.5, epsilon = 1e-6);
+        assert_abs_diff_eq!(
+            cauchy_ior(1.5, 40.0, FRAUNHOFER_F) - cauchy_ior(1.5, 40.0, FRAUNHOFER_C),
+            0.5 / 40.0,
+            epsilon = 1e-6
+        );
+
+        let glass = |wavelength| FresnelSpecular {
+            color: WHITE,
+            eta_a: 1.0,
+            eta_b: 1.5,
+            transport_mode: TransportMode::Importance,
+            dispersion: Some(Dispersion {
+                abbe_number: 20.0,
+                wavelength,
+            }),
+        };
+        let sample = |bxdf: &FresnelSpecular, wo| {
+            let (mut wi, mut pdf, mut kind) = (Vec3::zero(), 0.0, BxDFKind::ALL);
+            let f = bxdf.sample_f(wo, &mut wi, &mut pdf, &mut kind);
+            (f * wi.abs_cos_theta() / pdf, wi, kind)
+        };
+
+        // Shorter wavelengths are bent further towards the normal
+        fastrand::seed(5);
+        let wo = vec3(0.7, 0.0, 0.5).normalize();
+        let refracted = [0, 1, 2].map(|channel| loop {
+            let (weight, wi, kind) = sample(&glass(Wavelength::monochromatic(channel)), wo);
+            if kind.has(BxDFKind::TRANSMISSION) {
+                assert_abs_diff_eq!(
+                    weight,
+                    Wavelength::monochromatic(channel).mask(),
+                    epsilon = 1e-6
+                );
+                break wi.sin_theta();
+            }
+        });
+        assert!(refracted[0] > refracted[1] && refracted[1] > refracted[2]);
+
+        // Picking the wavelength per event keeps every channel's reflectance and transmittance
+        const N: usize = 200_000;
+        let (mut reflected, mut transmitted) = (BLACK, BLACK);
+        for _ in 0..N {
+            let (weight, _, kind) = sample(&glass(Wavelength::sample()), wo);
+            if kind.has(BxDFKind::TRANSMISSION) {
+                transmitted += weight;
+            } else {
+                reflected += weight;
+            }
+        }
+        for channel in 0..3 {
+            let eta = cauchy_ior(1.5, 20.0, CHANNEL_WAVELENGTHS[channel]);
+            let fresnel = fr_dielectric(wo.cos_theta(), 1.0, eta);
+            assert_abs_diff_eq!(reflected[channel] / N as Scalar, fresnel, epsilon = 2e-3);
+            assert_abs_diff_eq!(
+                transmitted[channel] / N as Scalar,
+                1.0 - fresnel,
+                epsilon = 1e-2
+            );
+        }
+    }
+
+    #[test]
+    fn oren_nayar_without_roughness_is_lambertian() {
+        let albedo = color(0.8, 0.5, 0.2);
+        let lambertian = Lambertian(albedo);
+        let smooth = OrenNayar(albedo, 0.0);
+        let rough = OrenNayar(albedo, 0.5);
+        let directions = [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.6, 0.0, 0.8),
+            vec3(-0.3, 0.4, 0.5).normalize(),
+            vec3(0.9, 0.1, 0.05).normalize(),
+        ];
+        for wo in directions {
+            for wi in directions {
+                assert_abs_diff_eq!(smooth.f(wo, wi), lambertian.f(wo, wi), epsilon = 1e-6);
+                assert_eq!(smooth.pdf(wo, wi), lambertian.pdf(wo, wi));
+            }
+        }
+
+        // Roughness darkens light arriving from the side and brightens backscattering
+        let wo = vec3(0.8, 0.0, 0.6);
+        let normal = vec3(0.0, 0.0, 1.0);
+        assert!(rough.f(wo, normal).r < lambertian.f(wo, normal).r);
+        assert!(rough.f(wo, wo).r > lambertian.f(wo, wo).r);
+    }
+
+    #[test]
+    fn disney_diffuse_subsurface() {
+        let albedo = color(0.8, 0.5, 0.2);
+        let disney = |roughness, subsurface| DisneyDiffuse {
+            color: albedo,
+            roughness,
+            subsurface,
+        };
+        let directions = [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.6, 0.0, 0.8),
+            vec3(-0.3, 0.4, 0.5).normalize(),
+            vec3(0.9, 0.1, 0.05).normalize(),
+        ];
+        let blend = disney(0.5, 0.5);
+        for wo in directions {
+            for wi in directions {
+                assert_abs_diff_eq!(blend.f(wo, wi), blend.f(wi, wo), epsilon = 1e-6);
+                assert_eq!(blend.pdf(wo, wi), Lambertian(albedo).pdf(wo, wi));
+            }
+        }
+
+        // Seen and lit head on, a smooth surface is Lambertian
+        let normal = vec3(0.0, 0.0, 1.0);
+        let lambertian = Lambertian(albedo).f(normal, normal);
+        assert_abs_diff_eq!(
+            disney(0.0, 0.0).f(normal, normal),
+            lambertian,
+            epsilon = 1e-6
+        );
+        // Subsurface scattering flattens the falloff, darker head on and brighter at grazing
+        // angles
+        let (wo, wi) = (vec3(0.98, 0.0, 0.2), vec3(-0.98, 0.0, 0.2));
+        let (opaque, translucent) = (disney(0.0, 0.0), disney(0.0, 1.0));
+        assert_abs_diff_eq!(
+            translucent.f(normal, normal),
+            lambertian * 0.625,
+            epsilon = 1e-6
+        );
+        assert!(translucent.f(wo, wi).r > opaque.f(wo, wi).r);
+    }
+
+    #[test]
+    fn rho() {
+        fastrand::seed(7);
+        let samples: Vec<[Scalar; 2]> = (0..20_000)
+            .map(|_| [scalar::rand(), scalar::rand()])
+            .collect();
+        let wo = vec3(0.3, 0.0, 0.8).normalize();
+
+        let lambertian = Lambertian(color(0.2, 0.5, 0.8));
+        assert_eq!(lambertian.rho(wo, &samples), lambertian.0);
+        assert_eq!(lambertian.rho2(&samples, &samples), lambertian.0);
+        assert_abs_diff_eq!(
+            lambertian.scale(0.5).rho(wo, &samples),
+            color(0.1, 0.25, 0.4),
+            epsilon = 1e-6
+        );
+
+        let mirror = ReflectionSpecular {
+            color: WHITE,
+            fresnel: FresnelSchlick(color(0.04, 0.04, 0.04)),
+        };
+        let mut wi = Vec3::zero();
+        let mut pdf = 0.0;
+        let mut sampled_kind = BxDFKind::ALL;
+        let f = mirror.sample_f(wo, &mut wi, &mut pdf, &mut sampled_kind);
+        assert_abs_diff_eq!(
+            mirror.rho(wo, &samples),
+            f * wi.abs_cos_theta() / pdf,
+            epsilon = 1e-6
+        );
+
+        // A white Fresnel term only loses energy to masking, which grows with roughness and
+        // is strongest at grazing angles
+        let grazing = vec3(0.98, 0.0, 0.2).normalize();
+        let microfacet_rho = |roughness: Scalar| {
+            MicrofacetReflection {
+                color: WHITE,
+                distribution: TrowbridgeReitzDistribution::new(point2(roughness, roughness)),
+                fresnel: FresnelSchlick(WHITE),
+            }
+            .rho(grazing, &samples)
+        };
+        let smooth = microfacet_rho(0.1);
+        let rough = microfacet_rho(0.8);
+        assert!(smooth.r <= 1.0 + 1e-2, "{smooth:?}");
+        assert!(rough.r < smooth.r, "{rough:?} >= {smooth:?}");
+    }
+
+    #[test]
+    fn microfacet_sampling_matches_uniform_integration() {
+        fastrand::seed(7);
+        for alpha in [0.001, 0.36, 1.0] {
+            let mf = MicrofacetReflection {
+                color: WHITE,
+                distribution: TrowbridgeReitzDistribution::new(point2(alpha, alpha)),
+                fresnel: FresnelSchlick(WHITE),
+            };
+            for wo in [vec3(0.0, 0.0, 1.0), vec3(0.6, 0.0, 0.8)] {
+                const N: usize = 200_000;
+                let mut importance = 0.0;
+                for _ in 0..N {
+                    let (mut wi, mut pdf, mut kind) = (Vec3::zero(), 0.0, BxDFKind::ALL);
+                    let f = mf.sample_f(wo, &mut wi, &mut pdf, &mut kind);
+                    if pdf > 0.0 {
+                        importance += f.r * wi.abs_cos_theta() / pdf;
+                    }
+                }
+                importance /= N as Scalar;
+                assert!(importance <= 1.0 + 1e-3, "alpha {alpha}: {importance}");
+
+                // A near specular lobe is too narrow to integrate uniformly
+                if alpha > 0.01 {
+                    let mut uniform = 0.0;
+                    for _ in 0..N {
+                        let wi = uniform_sample_hemisphere([scalar::rand(), scalar::rand()]);
+                        uniform += mf.f(wo, wi).r * wi.cos_theta() * 2.0 * PI;
+                    }
+                    uniform /= N as Scalar;
+                    assert_abs_diff_eq!(importance, uniform, epsilon = 0.02);
+                } else {
+                    assert_abs_diff_eq!(importance, 1.0, epsilon = 1e-3);
+                }
+            }
+        }
+    }
 }
@@ -75,7 +75,7 @@ fn trowbridge_reitz_sample11(cos_theta: Scalar, u1: Scalar, mut u2: Scalar) -> (
         };
         let z = (u2 * (u2 * (u2 * 0.27385 - 0.73369) + 0.46341))
             / (u2 * (u2 * (u2 * 0.093073 + 0.309420) - 1.0) + 0.597999);
-        let slope_y = s * z * (1.0 + slope_x.powi(2));
+        let slope_y = s * z * (1.0 + slope_x.powi(2)).sqrt();
         assert!(slope_y.is_finite());
         (slope_x, slope_y)
     }
@@ -127,7 +127,8 @@ impl Distribution for TrowbridgeReitzDistribution {
         if abs_tan_theta.is_infinite() {
             0.0
         } else {
-            let alpha = w.cos2_phi() * self.alpha.x.powi(2) * w.sin2_phi() * self.alpha.y.powi(2);
+            let alpha =
+                (w.cos2_phi() * self.alpha.x.powi(2) + w.sin2_phi() * self.alpha.y.powi(2)).sqrt();
             let alpha2_tan2_theta = (alpha * abs_tan_theta).powi(2);
             (-1.0 + (1.0 + alpha2_tan2_theta).sqrt()) / 2.0
         }
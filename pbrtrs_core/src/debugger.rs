@@ -1,23 +1,158 @@
+use crate::types::{Color, Pt3, Scalar, Vec3};
+use std::fmt::{Display, Formatter};
+
+/// Value recorded by `ray_debug!`. Vectors and colors keep their components, so tools can use
+/// them without parsing debug strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugValue {
+    Scalar(Scalar),
+    Vec3(Vec3),
+    Pt3(Pt3),
+    Color(Color),
+    /// `Debug` output of any other type
+    Str(String),
+}
+
+impl DebugValue {
+    /// Name of the variant in the debugger's XML output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DebugValue::Scalar(_) => "scalar",
+            DebugValue::Vec3(_) => "vec3",
+            DebugValue::Pt3(_) => "pt3",
+            DebugValue::Color(_) => "color",
+            DebugValue::Str(_) => "str",
+        }
+    }
+
+    /// Parses a value from its `kind` and its `Display` output
+    pub fn parse(kind: &str, value: &str) -> Option<DebugValue> {
+        let components = || -> Option<[Scalar; 3]> {
+            let mut components = value.split_whitespace().map(|c| c.parse().ok());
+            let xyz = [
+                components.next()??,
+                components.next()??,
+                components.next()??,
+            ];
+            components.next().is_none().then_some(xyz)
+        };
+        Some(match kind {
+            "scalar" => DebugValue::Scalar(value.parse().ok()?),
+            "vec3" => DebugValue::Vec3(components()?.into()),
+            "pt3" => DebugValue::Pt3(components()?.into()),
+            "color" => {
+                let [r, g, b] = components()?;
+                DebugValue::Color(Color::new(r, g, b))
+            }
+            "str" => DebugValue::Str(value.to_owned()),
+            _ => return None,
+        })
+    }
+}
+
+/// Writes values so that [`DebugValue::parse`] reads them back exactly, vectors and colors as
+/// `x y z`
+impl Display for DebugValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DebugValue::Scalar(x) => write!(f, "{x}"),
+            DebugValue::Vec3(v) => write!(f, "{} {} {}", v.x, v.y, v.z),
+            DebugValue::Pt3(p) => write!(f, "{} {} {}", p.x, p.y, p.z),
+            DebugValue::Color(c) => write!(f, "{} {} {}", c.r, c.g, c.b),
+            DebugValue::Str(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Conversion of the values passed to `ray_debug!`. Calling `debug_value` on `&Wrap(&value)`
+/// picks [`Typed`] for the types of [`DebugValue`] and falls back to the `Debug` output through
+/// [`Untyped`] for anything else, as method lookup tries `&Wrap` before `&&Wrap`.
+#[doc(hidden)]
+pub mod record {
+    use super::DebugValue;
+    use crate::types::{Color, Pt3, Scalar, Vec3};
+    use std::fmt::Debug;
+
+    pub struct Wrap<'a, T>(pub &'a T);
+
+    pub trait Typed {
+        fn debug_value(&self) -> DebugValue;
+    }
+
+    impl Typed for Wrap<'_, Scalar> {
+        fn debug_value(&self) -> DebugValue {
+            DebugValue::Scalar(*self.0)
+        }
+    }
+
+    impl Typed for Wrap<'_, Vec3> {
+        fn debug_value(&self) -> DebugValue {
+            DebugValue::Vec3(*self.0)
+        }
+    }
+
+    impl Typed for Wrap<'_, Pt3> {
+        fn debug_value(&self) -> DebugValue {
+            DebugValue::Pt3(*self.0)
+        }
+    }
+
+    impl Typed for Wrap<'_, Color> {
+        fn debug_value(&self) -> DebugValue {
+            DebugValue::Color(*self.0)
+        }
+    }
+
+    pub trait Untyped {
+        fn debug_value(&self) -> DebugValue;
+    }
+
+    impl<T: Debug> Untyped for &Wrap<'_, T> {
+        fn debug_value(&self) -> DebugValue {
+            DebugValue::Str(format!("{:?}", self.0))
+        }
+    }
+}
+
 #[cfg(feature = "enable_debugger")]
 pub mod inner {
+    use super::DebugValue;
     use crate::scene::Scene;
     use crate::types::color::BLACK;
-    use crate::types::{Color, Ray};
+    use crate::types::{Color, Ray, Scalar};
     use std::cell::RefCell;
+    use std::collections::BTreeMap;
     use std::fmt::{Arguments, Write};
-    use std::io::{Result as IoResult, Write as IoWrite};
+    use std::fs::File;
+    use std::io::{BufWriter, Write as IoWrite};
     use std::path::Path;
     use std::sync::Mutex;
+    use xml::writer::{EmitterConfig, Result as XmlResult, XmlEvent};
 
+    static DEBUG_PIXELS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
     static DEBUG_INFO: Mutex<DebugInfo> = Mutex::new(DebugInfo::new());
 
     thread_local! {
-        static ENABLE_DEBUG_PIXEL: RefCell<bool> = RefCell::new(false);
+        /// Pixel being recorded on this thread. Pixels are recorded per thread and only added to
+        /// `DEBUG_INFO` when finished, so pixels rendered at the same time don't interleave.
+        static ACTIVE_PIXEL: RefCell<Option<((usize, usize), PixelInfo)>> =
+            const { RefCell::new(None) };
+    }
+
+    /// Value recorded with `ray_debug!`
+    pub struct DebugEntry {
+        /// Expression that gave the value
+        pub name: &'static str,
+        /// `file:line` of the `ray_debug!`
+        pub location: &'static str,
+        pub value: DebugValue,
     }
 
     pub struct BounceInfo {
         pub ray: Ray,
+        /// Free text written with `ray_print!`
         pub debug_info: String,
+        pub entries: Vec<DebugEntry>,
     }
 
     impl BounceInfo {
@@ -25,6 +160,7 @@ pub mod inner {
             Self {
                 ray,
                 debug_info: String::new(),
+                entries: vec![],
             }
         }
     }
@@ -34,158 +170,222 @@ pub mod inner {
         pub final_color: Color,
     }
 
-    pub struct DebugInfo {
+    pub struct PixelInfo {
         pub samples: Vec<SampleInfo>,
         pub final_color: Color,
     }
 
-    impl DebugInfo {
-        const fn new() -> DebugInfo {
-            DebugInfo {
+    impl PixelInfo {
+        const fn new() -> PixelInfo {
+            PixelInfo {
                 samples: vec![],
                 final_color: BLACK,
             }
         }
+    }
 
-        pub fn save(&self, scene: &Scene, path: impl AsRef<Path>, (x, y): (usize, usize)) {
-            let mut f = std::fs::File::create(path).unwrap();
-
-            writeln!(f, "<xml>").unwrap();
-            writeln!(f, "<camera>").unwrap();
-
-            #[rustfmt::skip]
-            {
-                writeln!(f, "\t<position  value=\"{:?}\" />", scene.camera.position).unwrap();
-                writeln!(f, "\t<direction value=\"{:?}\" />", scene.camera.direction).unwrap();
-                writeln!(f, "\t<sensor_distance value=\"{}\" />", scene.camera.sensor_distance).unwrap();
-                writeln!(f, "\t<exposure_time value=\"{}\" />", scene.camera.exposure_time).unwrap();
-                writeln!(f, "\t<aperture value=\"{}\" />", scene.camera.aperture).unwrap();
-                writeln!(f, "\t<focus_distance value=\"{}\" />", scene.camera.focus_distance).unwrap();
-                writeln!(f, "\t<ldr_scale value=\"{}\" />", scene.camera.ldr_scale).unwrap();
-                writeln!(f, "\t<bounce_limit value=\"{}\" />", scene.camera.bounce_limit).unwrap();
-                writeln!(f, "\t<num_samples value=\"{}\" />", scene.camera.num_samples).unwrap();
-                writeln!(f, "\t<width value=\"{}\" />", scene.camera.width).unwrap();
-                writeln!(f, "\t<height value=\"{}\" />", scene.camera.height).unwrap();
-            };
-
-            writeln!(f, "</camera>").unwrap();
-            writeln!(
-                f,
-                r#"<pixel color="{:?}" x="{x}" y="{y}">"#,
-                self.final_color,
-            )
-            .unwrap();
-            for (sample_number, sample) in self.samples.iter().enumerate() {
-                writeln!(
-                    f,
-                    "\t<sample idx=\"{sample_number}\" color=\"{:?}\">",
-                    sample.final_color
-                )
-                .unwrap();
-
-                for (bounce_number, bounce) in sample.bounces.iter().enumerate() {
-                    bounce.write(&mut f, bounce_number, 2).unwrap();
-                }
+    pub struct DebugInfo {
+        /// Recorded pixels by their coordinates
+        pub pixels: BTreeMap<(usize, usize), PixelInfo>,
+    }
 
-                writeln!(f, "\t</sample>").unwrap();
+    impl DebugInfo {
+        const fn new() -> DebugInfo {
+            DebugInfo {
+                pixels: BTreeMap::new(),
             }
-            writeln!(f, r#"</pixel>"#).unwrap();
-            writeln!(f, "<xml/>").unwrap();
         }
-    }
 
-    impl BounceInfo {
-        fn write(
-            &self,
-            f: &mut impl IoWrite,
-            bounce_number: usize,
-            indent_len: usize,
-        ) -> IoResult<()> {
-            let mut indent = String::from_iter((0..indent_len).map(|_| '\t'));
-            writeln!(
-                f,
-                "{indent}<ray idx=\"{}\" origin=\"{:?}\" direction=\"{:?}\">",
-                bounce_number, self.ray.origin, self.ray.direction
-            )?;
-            indent += "\t";
-            if self.debug_info.len() < 10 && !self.debug_info.contains('\n') {
-                writeln!(f, "{indent}{}", self.debug_info)?;
-            } else {
-                for line in self.debug_info.lines() {
-                    writeln!(f, "{indent}{line}")?;
+        pub fn save(&self, scene: &Scene, path: impl AsRef<Path>) {
+            let file = BufWriter::new(File::create(path).unwrap());
+            self.write(scene, file).unwrap();
+        }
+
+        /// Writes the scene path, the camera and the recorded pixels as XML, vectors and colors
+        /// are written as `"x y z"`
+        pub fn write(&self, scene: &Scene, out: impl IoWrite) -> XmlResult<()> {
+            let mut w = EmitterConfig::new().perform_indent(true).create_writer(out);
+            let camera = &scene.camera;
+
+            w.write(XmlEvent::start_element("xml"))?;
+            if let Some(path) = &scene.path {
+                w.write(XmlEvent::start_element("scene").attr("path", &path.to_string_lossy()))?;
+                w.write(XmlEvent::end_element())?;
+            }
+            w.write(XmlEvent::start_element("camera"))?;
+            for (name, value) in [
+                ("model", format!("{:?}", camera.model)),
+                ("position", xyz(camera.position.into())),
+                ("direction", xyz(camera.direction.into())),
+                ("sensor_distance", camera.sensor_distance.to_string()),
+                ("exposure_time", camera.exposure_time.to_string()),
+                ("aperture", camera.aperture.to_string()),
+                ("focus_distance", camera.focus_distance.to_string()),
+                ("ldr_scale", camera.ldr_scale.to_string()),
+                ("bounce_limit", camera.bounce_limit.to_string()),
+                ("num_samples", camera.num_samples.to_string()),
+                ("width", camera.width.to_string()),
+                ("height", camera.height.to_string()),
+            ] {
+                w.write(XmlEvent::start_element(name).attr("value", &value))?;
+                w.write(XmlEvent::end_element())?;
+            }
+            w.write(XmlEvent::end_element())?;
+
+            for (&(x, y), pixel) in &self.pixels {
+                w.write(
+                    XmlEvent::start_element("pixel")
+                        .attr("color", &rgb(pixel.final_color))
+                        .attr("x", &x.to_string())
+                        .attr("y", &y.to_string()),
+                )?;
+                for (sample_number, sample) in pixel.samples.iter().enumerate() {
+                    w.write(
+                        XmlEvent::start_element("sample")
+                            .attr("idx", &sample_number.to_string())
+                            .attr("color", &rgb(sample.final_color)),
+                    )?;
+                    for (bounce_number, bounce) in sample.bounces.iter().enumerate() {
+                        w.write(
+                            XmlEvent::start_element("ray")
+                                .attr("idx", &bounce_number.to_string())
+                                .attr("origin", &xyz(bounce.ray.origin.into()))
+                                .attr("direction", &xyz(bounce.ray.direction.into())),
+                        )?;
+                        for entry in &bounce.entries {
+                            w.write(
+                                XmlEvent::start_element("value")
+                                    .attr("name", entry.name)
+                                    .attr("location", entry.location)
+                                    .attr("kind", entry.value.kind())
+                                    .attr("value", &entry.value.to_string()),
+                            )?;
+                            w.write(XmlEvent::end_element())?;
+                        }
+                        if !bounce.debug_info.is_empty() {
+                            w.write(XmlEvent::start_element("text"))?;
+                            w.write(XmlEvent::characters(&bounce.debug_info))?;
+                            w.write(XmlEvent::end_element())?;
+                        }
+                        w.write(XmlEvent::end_element())?;
+                    }
+                    w.write(XmlEvent::end_element())?;
                 }
+                w.write(XmlEvent::end_element())?;
             }
-            indent.pop();
-            writeln!(f, "{indent}</ray>")?;
-            Ok(())
+            w.write(XmlEvent::end_element())
         }
     }
 
+    fn xyz([x, y, z]: [Scalar; 3]) -> String {
+        format!("{x} {y} {z}")
+    }
+
+    fn rgb(color: Color) -> String {
+        xyz([color.r, color.g, color.b])
+    }
+
     #[inline]
     pub fn debug_info() -> &'static Mutex<DebugInfo> {
         &DEBUG_INFO
     }
 
+    /// Sets the pixels to record, dropping any recorded before
+    pub fn set_debug_pixels(pixels: &[(usize, usize)]) {
+        *DEBUG_PIXELS.lock().unwrap() = pixels.to_vec();
+        DEBUG_INFO.lock().unwrap().pixels.clear();
+    }
+
+    /// Starts rendering `pixel` on this thread, recording it if it is a debug pixel
+    pub fn begin_pixel(pixel: (usize, usize)) {
+        let debug = DEBUG_PIXELS.lock().unwrap().contains(&pixel);
+        ACTIVE_PIXEL.with(|active| *active.borrow_mut() = debug.then(|| (pixel, PixelInfo::new())));
+    }
+
+    /// Runs `f` on the pixel recorded by this thread, if any
+    #[inline]
+    fn with_active_pixel(f: impl FnOnce(&mut PixelInfo)) {
+        ACTIVE_PIXEL.with(|active| {
+            if let Some((_, pixel)) = active.borrow_mut().as_mut() {
+                f(pixel);
+            }
+        });
+    }
+
     #[inline]
     pub fn begin_ray(ray: Ray) {
-        if is_pixel_debug() {
-            let mut debug = DEBUG_INFO.lock().unwrap();
-            debug
+        with_active_pixel(|pixel| {
+            pixel
                 .samples
                 .last_mut()
                 .expect("not in a sample")
                 .bounces
                 .push(BounceInfo::new(ray));
-        }
+        });
     }
 
     #[inline]
     pub fn begin_sample() {
-        if is_pixel_debug() {
-            let mut debug = DEBUG_INFO.lock().unwrap();
-            debug.samples.push(SampleInfo {
+        with_active_pixel(|pixel| {
+            pixel.samples.push(SampleInfo {
                 bounces: vec![],
                 final_color: BLACK,
             });
-        }
+        });
     }
 
     #[inline]
     pub fn end_sample(color: Color) -> Color {
-        if is_pixel_debug() {
-            let mut debug = DEBUG_INFO.lock().unwrap();
-            debug
+        with_active_pixel(|pixel| {
+            pixel
                 .samples
                 .last_mut()
                 .expect("not in a sample")
                 .final_color = color;
-        }
+        });
         color
     }
 
     #[inline]
     pub fn end_pixel(color: Color) -> Color {
-        if is_pixel_debug() {
-            let mut debug = DEBUG_INFO.lock().unwrap();
-            debug.final_color = color;
+        if let Some((coordinates, mut pixel)) = ACTIVE_PIXEL.with(|active| active.take()) {
+            pixel.final_color = color;
+            DEBUG_INFO.lock().unwrap().pixels.insert(coordinates, pixel);
         }
         color
     }
 
-    #[allow(unused)]
+    /// Runs `f` on the current ray of the pixel recorded by this thread, if any
     #[inline]
-    pub fn ray_write(args: Arguments) {
-        if is_pixel_debug() {
-            let mut debug = DEBUG_INFO.lock().unwrap();
-            let sample = debug
+    fn with_active_bounce(f: impl FnOnce(&mut BounceInfo)) {
+        with_active_pixel(|pixel| {
+            let bounce = pixel
                 .samples
                 .last_mut()
                 .expect("not in a sample")
                 .bounces
                 .last_mut()
                 .expect("not in a ray");
-            sample.debug_info.write_fmt(args).unwrap();
-        }
+            f(bounce);
+        });
+    }
+
+    #[allow(unused)]
+    #[inline]
+    pub fn ray_write(args: Arguments) {
+        with_active_bounce(|bounce| bounce.debug_info.write_fmt(args).unwrap());
+    }
+
+    #[allow(unused)]
+    #[inline]
+    pub fn ray_record(name: &'static str, location: &'static str, value: DebugValue) {
+        with_active_bounce(|bounce| {
+            bounce.entries.push(DebugEntry {
+                name,
+                location,
+                value,
+            })
+        });
     }
 
     #[allow(unused)]
@@ -198,23 +398,104 @@ pub mod inner {
         }
     }
 
+    /// Whether this thread is recording a debug pixel
     #[inline]
-    fn is_pixel_debug() -> bool {
-        ENABLE_DEBUG_PIXEL.with(|f| *f.borrow())
+    pub fn is_pixel_debug() -> bool {
+        ACTIVE_PIXEL.with(|active| active.borrow().is_some())
     }
 
-    #[inline]
-    pub fn set_should_debug_pixel(v: bool) {
-        ENABLE_DEBUG_PIXEL.with(|f| {
-            *f.borrow_mut() = v;
-        });
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use cgmath::{point3, vec3};
+
+        #[test]
+        fn ray_debug_records_typed_values() {
+            // Recorded without `set_debug_pixels`, which is shared with the other tests
+            ACTIVE_PIXEL.with(|active| *active.borrow_mut() = Some(((0, 0), PixelInfo::new())));
+            begin_sample();
+            begin_ray(Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 0.0));
+            let wi = vec3(0.1, -1e-7, 3.3333333);
+            let pdf: Scalar = 0.25;
+            let kind = Some("diffuse");
+            crate::ray_debug!(wi, pdf, -wi, kind);
+            crate::ray_print!("a<b&c\"");
+
+            let (_, pixel) = ACTIVE_PIXEL.with(|active| active.take()).unwrap();
+            let bounce = &pixel.samples[0].bounces[0];
+            let values = bounce
+                .entries
+                .iter()
+                .map(|entry| (entry.name, entry.value.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                values,
+                [
+                    ("wi", DebugValue::Vec3(wi)),
+                    ("pdf", DebugValue::Scalar(0.25)),
+                    ("-wi", DebugValue::Vec3(-wi)),
+                    ("kind", DebugValue::Str("Some(\"diffuse\")".to_owned())),
+                ]
+            );
+            assert!(bounce.entries[0].location.starts_with(file!()));
+            assert_eq!(bounce.debug_info, "a<b&c\"");
+
+            for (_, value) in values {
+                assert_eq!(
+                    DebugValue::parse(value.kind(), &value.to_string()),
+                    Some(value)
+                );
+            }
+        }
+
+        #[test]
+        fn pixels_recorded_concurrently_stay_apart() {
+            set_debug_pixels(&[(1, 2), (3, 4)]);
+            let threads = [(1, 2), (3, 4), (5, 6)].map(|pixel| {
+                std::thread::spawn(move || {
+                    begin_pixel(pixel);
+                    for sample in 0..50 {
+                        begin_sample();
+                        for bounce in 0..3 {
+                            begin_ray(Ray::new(
+                                point3(pixel.0 as _, pixel.1 as _, bounce as _),
+                                vec3(0.0, 0.0, 1.0),
+                                0.0,
+                            ));
+                            ray_write(format_args!("{pixel:?}"));
+                            std::thread::yield_now();
+                        }
+                        end_sample(Color::new(sample as _, 0.0, 0.0));
+                    }
+                    end_pixel(Color::new(pixel.0 as _, 0.0, 0.0));
+                })
+            });
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            let debug = debug_info().lock().unwrap();
+            assert_eq!(
+                debug.pixels.keys().copied().collect::<Vec<_>>(),
+                [(1, 2), (3, 4)]
+            );
+            for (&pixel, info) in &debug.pixels {
+                assert_eq!(info.final_color.r, pixel.0 as Scalar);
+                assert_eq!(info.samples.len(), 50);
+                for sample in &info.samples {
+                    assert_eq!(sample.bounces.len(), 3);
+                    for bounce in &sample.bounces {
+                        assert_eq!(bounce.ray.origin.x, pixel.0 as Scalar);
+                        assert_eq!(bounce.debug_info, format!("{pixel:?}"));
+                    }
+                }
+            }
+        }
     }
 }
 
 #[cfg(feature = "enable_debugger")]
-pub use inner::debug_info;
-#[cfg(feature = "enable_debugger")]
-pub use inner::set_should_debug_pixel;
+pub use inner::{begin_pixel, debug_info, set_debug_pixels};
 
 #[macro_export]
 macro_rules! ray_print {
@@ -227,17 +508,22 @@ macro_rules! ray_print {
 #[allow(unused)]
 pub use ray_print;
 
+/// Records the values of expressions on the current ray, typed as [`DebugValue`]s
 #[macro_export]
 macro_rules! ray_debug {
     ($($arg:expr),*) => {{
         #[cfg(feature = "enable_debugger")]
-        $crate::debugger::inner::ray_write(format_args!(
-            concat!(
-                file!(), ":", line!(), ":\n",
-                $("\t", stringify!($arg), ": {:?}\n"),*
-            ),
-            $($arg),*
-        ));
+        if $crate::debugger::inner::is_pixel_debug() {
+            #[allow(unused_imports)]
+            use $crate::debugger::record::{Typed as _, Untyped as _};
+            $(
+                $crate::debugger::inner::ray_record(
+                    stringify!($arg),
+                    concat!(file!(), ":", line!()),
+                    (&$crate::debugger::record::Wrap(&$arg)).debug_value(),
+                );
+            )*
+        }
     }};
 }
 
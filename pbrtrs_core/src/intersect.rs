@@ -1,18 +1,29 @@
 use crate::light::{AreaLight, Light};
 use crate::material::{EmptyMaterial, Material};
-use crate::scene::{Object, SampledDisneyMaterial, Scene, Shape};
+use crate::scene::{Object, SampledDisneyMaterial, Scene, Shape, Visibility};
+use crate::types::color::{BLACK, WHITE};
 use crate::types::scalar::consts::PI;
-use crate::types::{Pt2, Pt3, Quaternion, Ray, Scalar, Vec3};
-use cgmath::{point2, point3, vec3, EuclideanSpace, InnerSpace, Rotation};
+use crate::types::{Color, Pt2, Pt3, Quaternion, Ray, Scalar, Vec3};
+use crate::util::offset_ray_origin;
+use cgmath::{point2, point3, vec3, EuclideanSpace, InnerSpace, Rotation, Zero};
+use smallvec::SmallVec;
+
+pub mod packet;
 
 pub struct Intersection<'a, M, O> {
     pub distance: Scalar,
     pub normal: Vec3,
     pub tangent: Vec3,
     pub point: Pt3,
+    /// Bound on the absolute floating point error of `point`
+    pub error: Scalar,
+    /// Whether the ray hit the outside of the surface. `normal` always points outwards.
+    pub front_face: bool,
     pub sampled_material: M,
     pub object: &'a O,
     pub uv: Pt2,
+    /// Time of the ray that found the hit, as a fraction of the exposure
+    pub time: Scalar,
 }
 
 impl Intersection<'static, (), ()> {
@@ -22,9 +33,12 @@ impl Intersection<'static, (), ()> {
             normal: vec3(0.0, 0.0, 0.0),
             tangent: vec3(0.0, 0.0, 0.0),
             point: point3(0.0, 0.0, 0.0),
+            error: 0.0,
+            front_face: true,
             sampled_material: (),
             object: &(),
             uv: point2(0.0, 0.0),
+            time: 0.0,
         }
     }
 }
@@ -39,18 +53,24 @@ impl<'a, M, O> Intersection<'a, M, O> {
             normal,
             tangent,
             point,
+            error,
+            front_face,
             sampled_material,
             uv,
             object,
+            time,
         } = self;
         Intersection {
             distance,
             normal,
             tangent,
             point,
+            error,
+            front_face,
             uv,
             sampled_material: f(sampled_material),
             object,
+            time,
         }
     }
 }
@@ -98,121 +118,881 @@ impl<'a, M, O> PossibleIntersection<'a, M, O> {
     }
 }
 
+/// Places a shape in the world, shapes are intersected in their own object space
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    /// Object to world rotation, a zero quaternion stands for no rotation
+    pub rotation: Quaternion,
+    pub translation: Vec3,
+}
+
+impl Transform {
+    pub fn new(rotation: Quaternion, translation: Vec3) -> Self {
+        Self {
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn to_world_point(self, point: Pt3) -> Pt3 {
+        Pt3::from_vec(self.to_world_vector(point.to_vec()) + self.translation)
+    }
+
+    pub fn to_world_vector(self, vector: Vec3) -> Vec3 {
+        self.rotation.rotate_vector(vector)
+    }
+
+    pub fn to_local_point(self, point: Pt3) -> Pt3 {
+        Pt3::from_vec(self.to_local_vector(point.to_vec() - self.translation))
+    }
+
+    pub fn to_local_vector(self, vector: Vec3) -> Vec3 {
+        // The conjugate of a unit quaternion is its inverse, and a zero one stays zero
+        self.rotation.conjugate().rotate_vector(vector)
+    }
+
+    pub fn to_local_ray(self, ray: &Ray) -> Ray {
+        Ray::new(
+            self.to_local_point(ray.origin),
+            self.to_local_vector(ray.direction),
+            ray.time,
+        )
+        .with_t_max(ray.t_max)
+    }
+
+    /// Bound on the error of a point moved to world space, given the bound `error` in object
+    /// space
+    fn to_world_error(self, local: Pt3, world: Pt3, error: Scalar) -> Scalar {
+        let l1 = |p: Pt3| p.x.abs() + p.y.abs() + p.z.abs();
+        // Rotating by the identity is exact
+        let rotation_error = if self.rotation.v.is_zero() {
+            0.0
+        } else {
+            4.0 * l1(local)
+        };
+        error + (rotation_error + l1(world)) * Scalar::EPSILON
+    }
+}
+
+/// Number of rays in a packet for [`Scene::intersect_packet`], e.g. the camera rays through a
+/// 2x2 block of pixels
+pub const RAY_PACKET: usize = 4;
+
+/// Only skips the degenerate root at the ray origin, secondary rays are kept off the surface by
+/// `offset_ray_origin`
+pub(crate) const T_MIN: Scalar = 1e-6;
+
+/// Hit with a shape in its object space
+pub(crate) struct LocalHit {
+    pub distance: Scalar,
+    pub point: Pt3,
+    pub error: Scalar,
+    pub normal: Vec3,
+    pub tangent: Vec3,
+    pub uv: Pt2,
+}
+
+/// Texture coordinate of the point of a sphere with the given outward normal in object space
+pub fn sphere_uv(normal: Vec3) -> Pt2 {
+    let theta = normal.angle(vec3(0.0, 1.0, 0.0)).0;
+    let phi = normal.x.atan2(normal.z);
+
+    point2(theta / PI, (phi + PI) / (2.0 * PI))
+}
+
 impl Shape {
+    pub fn area(&self) -> Scalar {
+        match self {
+            Self::Sphere { radius } => 4.0 * PI * radius * radius,
+            Self::Plane { .. } => Scalar::INFINITY,
+            Self::Mesh(mesh) => mesh.area(),
+        }
+    }
+
+    /// Radius of a sphere around the shape origin that contains the shape
+    pub fn bounding_radius(&self) -> Scalar {
+        match self {
+            Self::Sphere { radius } => *radius,
+            Self::Plane { .. } => Scalar::INFINITY,
+            Self::Mesh(mesh) => mesh.bounding_radius(),
+        }
+    }
+
+    /// Unbounded shapes can't be sampled as lights and are left out of the scene bounds
+    pub fn is_bounded(&self) -> bool {
+        !matches!(self, Self::Plane { .. })
+    }
+
     pub fn intersect<'mat, M: Material, O>(
         &self,
         ray: &Ray,
-        rotate: Quaternion,
-        translate: Vec3,
+        transform: Transform,
         material: &'mat M,
         object: &'mat O,
     ) -> PossibleIntersection<'mat, M::Sampled, O> {
-        const T_MIN: Scalar = 0.001;
+        // Rotations preserve length, so distances along the local ray are world distances
+        let local_ray = transform.to_local_ray(ray);
+        world_hit(
+            self.intersect_local(&local_ray),
+            ray,
+            transform,
+            material,
+            object,
+        )
+    }
+
+    /// Nearest hit of a ray in object space between 0 and `ray.t_max`
+    fn intersect_local(&self, ray: &Ray) -> Option<LocalHit> {
         match self {
             Self::Sphere { radius } => {
-                let sphere_center: Pt3 = Pt3::from_vec(translate);
-                let oc = ray.origin - sphere_center;
+                let oc = ray.origin.to_vec();
 
                 let a = ray.direction.magnitude2(); // can simplify to 1
                 let h = oc.dot(ray.direction);
                 let c = oc.magnitude2() - radius * radius;
                 let discriminant = h * h - a * c;
                 if discriminant < 0.0 {
-                    PossibleIntersection::Miss
+                    return None;
+                }
+                let sqrt_discriminant = discriminant.sqrt();
+                let near = (-h - sqrt_discriminant) / a;
+                // Rays starting inside the sphere, e.g. refracted or shadow rays through
+                // transmissive objects, hit the far side
+                let t = if near >= 0.0 {
+                    near
                 } else {
-                    let t = (-h - discriminant.sqrt()) / a;
-                    if t < 0.0 {
-                        PossibleIntersection::Miss
-                    } else if t < T_MIN {
-                        PossibleIntersection::Ignored
-                    } else {
-                        let point = ray.at(t);
-
-                        let normal = (point - sphere_center).normalize();
-
-                        let tangent = if normal.z.abs() <= 1e-6 && normal.x.abs() <= 1e-6 {
-                            vec3(1.0, 0.0, 0.0)
-                        } else {
-                            vec3(normal.z, 0.0, -normal.x).normalize()
-                        };
-
-                        // Compute UV
-                        let rnormal = rotate.rotate_vector(normal);
-
-                        let theta = rnormal.angle(vec3(0.0, 1.0, 0.0)).0;
-                        let phi = rnormal.x.atan2(rnormal.z);
-
-                        let uv = point2(theta / PI, (phi + PI) / (2.0 * PI));
-
-                        PossibleIntersection::Hit(Intersection {
-                            distance: t,
-                            point,
-                            normal,
-                            tangent,
-                            sampled_material: material.sample(uv),
-                            uv,
-                            object,
-                        })
-                    }
+                    (-h + sqrt_discriminant) / a
+                };
+                if t < 0.0 || t > ray.t_max {
+                    return None;
+                }
+
+                let normal = ray.at(t).to_vec().normalize();
+                // Reprojecting onto the surface bounds the error of the hit point by the size of
+                // the sphere instead of the length of the ray
+                let point = Pt3::from_vec(normal * *radius);
+                let error =
+                    (point.x.abs() + point.y.abs() + point.z.abs() + radius) * Scalar::EPSILON;
+
+                // dp/du points away from the +y pole along a meridian. At the poles the uv
+                // lookup uses phi = 0, the meridian through +z.
+                let sin2_theta = normal.x * normal.x + normal.z * normal.z;
+                let tangent = if sin2_theta <= 1e-12 {
+                    vec3(0.0, 0.0, normal.y.signum())
+                } else {
+                    vec3(normal.y * normal.x, -sin2_theta, normal.y * normal.z).normalize()
+                };
+
+                Some(LocalHit {
+                    distance: t,
+                    point,
+                    error,
+                    normal,
+                    tangent,
+                    uv: sphere_uv(normal),
+                })
+            }
+            Self::Plane { uv_scale } => {
+                // Rays parallel to the plane never hit it
+                if ray.direction.y == 0.0 {
+                    return None;
+                }
+                let t = -ray.origin.y / ray.direction.y;
+                if t < 0.0 || t > ray.t_max {
+                    return None;
                 }
+
+                // Projecting onto the plane makes the hit exact along the normal
+                let hit = ray.at(t);
+                let point = point3(hit.x, 0.0, hit.z);
+                let error = (point.x.abs() + point.z.abs()) * Scalar::EPSILON;
+                Some(LocalHit {
+                    distance: t,
+                    point,
+                    error,
+                    normal: vec3(0.0, 1.0, 0.0),
+                    tangent: vec3(1.0, 0.0, 0.0),
+                    uv: point2(
+                        (point.x * uv_scale).rem_euclid(1.0),
+                        (point.z * uv_scale).rem_euclid(1.0),
+                    ),
+                })
             }
+            Self::Mesh(mesh) => mesh.intersect(ray),
         }
     }
 }
 
+/// Moves the hit of `ray` with a shape in its object space to world space
+fn world_hit<'mat, M: Material, O>(
+    hit: Option<LocalHit>,
+    ray: &Ray,
+    transform: Transform,
+    material: &'mat M,
+    object: &'mat O,
+) -> PossibleIntersection<'mat, M::Sampled, O> {
+    let Some(hit) = hit else {
+        return PossibleIntersection::Miss;
+    };
+    if hit.distance < T_MIN {
+        return PossibleIntersection::Ignored;
+    }
+
+    let point = transform.to_world_point(hit.point);
+    let normal = transform.to_world_vector(hit.normal);
+    let uv = hit.uv;
+    PossibleIntersection::Hit(Intersection {
+        distance: hit.distance,
+        point,
+        error: transform.to_world_error(hit.point, point, hit.error),
+        front_face: ray.direction.dot(normal) < 0.0,
+        normal,
+        tangent: transform.to_world_vector(hit.tangent),
+        sampled_material: material.sample(uv),
+        uv,
+        object,
+        time: ray.time,
+    })
+}
+
 impl Scene {
-    pub fn intersect(&self, ray: &Ray) -> PossibleIntersection<SampledDisneyMaterial, Object> {
-        let mut nearest = PossibleIntersection::Miss;
-        for object in &self.objects {
-            match object.shape.intersect(
-                ray,
-                object.rotation,
-                object.position.to_vec() + object.motion * ray.time,
-                &object.material,
-                object,
-            ) {
-                PossibleIntersection::Hit(intersection) => {
-                    if nearest.is_miss() || intersection.distance < nearest.unwrap_distance() {
-                        nearest = PossibleIntersection::Hit(intersection);
-                    }
-                }
-                PossibleIntersection::Ignored => {
-                    return PossibleIntersection::Ignored;
+    /// Nearest surface hit by `ray`, ignoring objects that aren't visible to `ray_kind`. Objects
+    /// only hit too close to the ray origin are skipped, the result is `Ignored` only if nothing
+    /// else was hit.
+    pub fn intersect(
+        &self,
+        ray: &Ray,
+        ray_kind: Visibility,
+    ) -> PossibleIntersection<'_, SampledDisneyMaterial, Object> {
+        self.intersect_with(ray, ray_kind, |object| object.intersect_self(ray))
+    }
+
+    /// [`Scene::intersect`] for each ray of a packet of coherent rays, e.g. camera rays through
+    /// neighbouring pixels. Meshes are traversed once for the whole packet, everything else is
+    /// intersected ray by ray.
+    pub fn intersect_packet(
+        &self,
+        rays: &[Ray; RAY_PACKET],
+        ray_kind: Visibility,
+    ) -> [PossibleIntersection<'_, SampledDisneyMaterial, Object>; RAY_PACKET] {
+        let mut mesh_hits = self
+            .objects_visible_to(ray_kind)
+            .filter_map(|object| {
+                let Shape::Mesh(mesh) = &object.shape else {
+                    return None;
+                };
+                let transforms = rays.each_ref().map(|ray| object.transform_at(ray.time));
+                let local_rays =
+                    std::array::from_fn(|lane| transforms[lane].to_local_ray(&rays[lane]));
+                Some((object, transforms, mesh.intersect_packet(&local_rays)))
+            })
+            .collect::<SmallVec<[_; 2]>>();
+        std::array::from_fn(|lane| {
+            let ray = &rays[lane];
+            self.intersect_with(ray, ray_kind, |object| {
+                match mesh_hits
+                    .iter_mut()
+                    .find(|(mesh, ..)| std::ptr::eq(*mesh, object))
+                {
+                    // Every object is intersected at most once per ray
+                    Some((_, transforms, hits)) => world_hit(
+                        hits[lane].take(),
+                        ray,
+                        transforms[lane],
+                        &object.material,
+                        object,
+                    ),
+                    None => object.intersect_self(ray),
                 }
-                PossibleIntersection::Miss => {}
-                PossibleIntersection::HitLight(_) => unreachable!(),
-            }
-        }
+            })
+        })
+    }
+
+    /// [`Scene::intersect`] with the objects intersected by `intersect`
+    fn intersect_with<'a>(
+        &'a self,
+        ray: &Ray,
+        ray_kind: Visibility,
+        mut intersect: impl FnMut(&'a Object) -> PossibleIntersection<'a, SampledDisneyMaterial, Object>,
+    ) -> PossibleIntersection<'a, SampledDisneyMaterial, Object> {
+        // Packed spheres rule out all but the nearest of them before any full intersection
+        let (candidates, packet_ignored) = self
+            .packed_objects(ray_kind)
+            .candidates(self.objects(), ray);
+        let (mut nearest, ignored) = nearest_object(candidates, &mut intersect);
+        let mut ignored = ignored || packet_ignored;
         for light in &self.lights {
             if let Light::Area(area) = light {
-                match area.shape.intersect(
-                    ray,
-                    area.rotation,
-                    area.position.to_vec(),
-                    &EmptyMaterial,
-                    area,
-                ) {
+                match area
+                    .shape
+                    .intersect(ray, area.transform(), &EmptyMaterial, area)
+                {
                     PossibleIntersection::Hit(intersection) => {
                         if nearest.is_miss() || intersection.distance < nearest.unwrap_distance() {
                             nearest = PossibleIntersection::HitLight(intersection);
                         }
                     }
-                    PossibleIntersection::Ignored => {
-                        return PossibleIntersection::Ignored;
-                    }
+                    PossibleIntersection::Ignored => ignored = true,
                     PossibleIntersection::Miss => {}
                     PossibleIntersection::HitLight(_) => unreachable!(),
                 }
             }
         }
-        nearest
+        if ignored && nearest.is_miss() {
+            PossibleIntersection::Ignored
+        } else {
+            nearest
+        }
+    }
+
+    /// Whether anything blocks `ray` before `max_distance`. Hits too close to the ray origin to
+    /// be resolved are not treated as occluders.
+    pub fn intersect_shadow(&self, ray: &Ray, max_distance: Scalar) -> bool {
+        let blocks = |hit: PossibleIntersection<_, _>| match hit {
+            PossibleIntersection::Hit(intersection) => intersection.distance < max_distance,
+            _ => false,
+        };
+        self.objects_visible_to(Visibility::SHADOW).any(|object| {
+            blocks(
+                object
+                    .shape
+                    .intersect(ray, object.transform_at(ray.time), &EmptyMaterial, &()),
+            )
+        }) || self.lights.iter().any(|light| match light {
+            Light::Area(area) => {
+                blocks(
+                    area.shape
+                        .intersect(ray, area.transform(), &EmptyMaterial, &()),
+                )
+            }
+            _ => false,
+        })
+    }
+
+    /// Fraction of light carried by `ray` that reaches `max_distance`. Surfaces with
+    /// transmission let light through tinted by their base color, any other surface blocks it.
+    /// Refraction is ignored, so this is only a cheap approximation of caustics.
+    pub fn transmittance_along(&self, ray: &Ray, max_distance: Scalar) -> Color {
+        // Area lights are opaque
+        let area_light_blocks = self.lights.iter().any(|light| match light {
+            Light::Area(area) => matches!(
+                area.shape.intersect(ray, area.transform(), &EmptyMaterial, &()),
+                PossibleIntersection::Hit(hit) if hit.distance < max_distance
+            ),
+            _ => false,
+        });
+        if area_light_blocks {
+            return BLACK;
+        }
+
+        let mut transmittance = WHITE;
+        let mut ray = *ray;
+        let mut remaining = max_distance;
+        for _ in 0..=MAX_TRANSPARENT_SHADOW_INTERFACES {
+            let nearest = self
+                .objects_visible_to(Visibility::SHADOW)
+                .filter_map(|object| {
+                    match object.shape.intersect(
+                        &ray,
+                        object.transform_at(ray.time),
+                        &object.material,
+                        object,
+                    ) {
+                        PossibleIntersection::Hit(hit) if hit.distance < remaining => Some(hit),
+                        _ => None,
+                    }
+                })
+                .min_by(|a, b| a.distance.total_cmp(&b.distance));
+
+            let Some(hit) = nearest else {
+                return transmittance;
+            };
+            let material = &hit.sampled_material;
+            if material.transmission < TRANSPARENT_SHADOW_THRESHOLD {
+                return BLACK;
+            }
+            transmittance *= material.base_color;
+
+            remaining -= hit.distance;
+            let origin = offset_ray_origin(hit.point, hit.error, hit.normal, ray.direction);
+            ray = Ray::new(origin, ray.direction, ray.time);
+        }
+        // Too many interfaces to track, treat the light as blocked
+        BLACK
+    }
+
+    fn objects_visible_to(&self, ray_kind: Visibility) -> impl Iterator<Item = &Object> {
+        self.objects()
+            .iter()
+            .filter(move |object| object.is_visible_to(ray_kind))
+    }
+}
+
+impl Object {
+    fn is_visible_to(&self, ray_kind: Visibility) -> bool {
+        // Shadow catchers are transparent to everything but camera rays
+        self.visibility.has(ray_kind) && (!self.shadow_catcher || ray_kind == Visibility::CAMERA)
+    }
+}
+
+/// Nearest hit among `objects`, and whether any of them was skipped for being hit too close to
+/// the ray origin
+fn nearest_object<'a>(
+    objects: impl Iterator<Item = &'a Object>,
+    mut intersect: impl FnMut(&'a Object) -> PossibleIntersection<'a, SampledDisneyMaterial, Object>,
+) -> (
+    PossibleIntersection<'a, SampledDisneyMaterial, Object>,
+    bool,
+) {
+    let mut nearest = PossibleIntersection::Miss;
+    let mut ignored = false;
+    for object in objects {
+        match intersect(object) {
+            PossibleIntersection::Hit(intersection) => {
+                if nearest.is_miss() || intersection.distance < nearest.unwrap_distance() {
+                    nearest = PossibleIntersection::Hit(intersection);
+                }
+            }
+            PossibleIntersection::Ignored => ignored = true,
+            PossibleIntersection::Miss => {}
+            PossibleIntersection::HitLight(_) => unreachable!(),
+        }
     }
+    (nearest, ignored)
 }
 
+/// Minimum material transmission for a surface to let shadow rays through
+const TRANSPARENT_SHADOW_THRESHOLD: Scalar = 0.5;
+
+/// Number of transmissive surfaces a shadow ray crosses before giving up
+const MAX_TRANSPARENT_SHADOW_INTERFACES: usize = 8;
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cgmath::Zero;
+    use crate::bxdf::{Lambertian, BSDF};
+    use crate::light::{estimate_direct, DirectionLight};
+    use crate::mesh::TriangleMesh;
+    use crate::scene::{
+        rotation_from_degrees, CameraBuilder, MaterialBuilder, SceneBuilder, Texture,
+    };
+    use crate::stats::RayStats;
+    use crate::types::color;
+    use crate::util::random_unit_vec;
+    use cgmath::{assert_abs_diff_eq, Zero};
+
+    #[test]
+    fn intersect_shadow() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 3.0, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+
+        let up = Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0);
+        assert!(scene.intersect_shadow(&up, Scalar::INFINITY));
+        assert!(scene.intersect_shadow(&up, 2.5));
+        // Hits beyond the light don't occlude it
+        assert!(!scene.intersect_shadow(&up, 1.5));
+        assert!(!scene.intersect_shadow(&Ray::new(Pt3::origin(), vec3(0.0, -1.0, 0.0), 0.0), 10.0));
+
+        // Hits too close to the origin to resolve don't occlude
+        let on_surface = Ray::new(point3(0.0, 2.0, 0.0), vec3(1.0, 0.5, 0.0), 0.0);
+        assert!(scene
+            .intersect(&on_surface, Visibility::CAMERA)
+            .is_ignored());
+        assert!(!scene.intersect_shadow(&on_surface, Scalar::INFINITY));
+    }
+
+    fn unwrap_hit<M, O>(hit: PossibleIntersection<M, O>) -> Intersection<M, O> {
+        match hit {
+            PossibleIntersection::Hit(hit) => hit,
+            _ => panic!("expected a hit"),
+        }
+    }
+
+    #[test]
+    fn ignored_hit_does_not_hide_other_objects() {
+        let sphere = |center| {
+            Object::new(
+                Shape::Sphere { radius: 1.0 },
+                center,
+                MaterialBuilder::new().build(),
+            )
+        };
+        // Two unit spheres touching at 30 degrees above +x
+        let second_center = point3((3.0 as Scalar).sqrt(), 1.0, 0.0);
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(sphere(Pt3::origin()))
+            .add_object(sphere(second_center))
+            .build();
+
+        // Leaves the first sphere right next to the contact point, into the second one
+        let ray = Ray::new(point3(1.0, 0.0, 0.0), vec3(0.6, 0.8, 0.0), 0.0);
+        let first = &scene.objects()[0];
+        assert!(first
+            .shape
+            .intersect(&ray, first.transform_at(0.0), &first.material, first)
+            .is_ignored());
+
+        let hit = unwrap_hit(scene.intersect(&ray, Visibility::CAMERA));
+        assert!(std::ptr::eq(hit.object, &scene.objects()[1]));
+        assert_abs_diff_eq!((hit.point - second_center).magnitude(), 1.0, epsilon = 1e-5);
+
+        // Nothing else along the ray, so the self hit is still reported
+        let away = Ray::new(point3(1.0, 0.0, 0.0), vec3(0.6, -0.8, 0.0), 0.0);
+        assert!(scene.intersect(&away, Visibility::CAMERA).is_ignored());
+    }
+
+    #[test]
+    fn tiny_sphere_shadows_its_night_side() {
+        let radius = 0.01;
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Sphere { radius },
+                Pt3::origin(),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+
+        let up = vec3(0.0, 1.0, 0.0);
+        // Just below the terminator the chord to the lit side is far shorter than a unit epsilon
+        for degrees in [0.2, 0.5, 1.0, 5.0, 30.0] {
+            let angle = (degrees as Scalar).to_radians();
+            let normal = vec3(angle.cos(), -angle.sin(), 0.0);
+            let towards = Ray::new(Pt3::from_vec(normal), -normal, 0.0);
+            let hit = unwrap_hit(scene.intersect(&towards, Visibility::CAMERA));
+
+            let origin = offset_ray_origin(hit.point, hit.error, hit.normal, up);
+            let shadow = Ray::new(origin, up, 0.0);
+            assert!(
+                scene.intersect_shadow(&shadow, Scalar::INFINITY),
+                "light leaks {degrees} degrees below the terminator"
+            );
+        }
+    }
+
+    #[test]
+    fn giant_ground_sphere_has_no_shadow_acne() {
+        let radius = 10_000.0;
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Sphere { radius },
+                point3(0.0, -radius, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+        let light = DirectionLight::new(vec3(-0.3, -1.0, -0.2), WHITE).with_power(2.0);
+        let lambertian = Lambertian(WHITE);
+        let stats = RayStats::new();
+
+        for x in -10..=10 {
+            for z in -10..=10 {
+                let camera_ray = Ray::new(
+                    point3(x as Scalar * 7.3, 2.0, z as Scalar * 5.1),
+                    vec3(0.1, -1.0, 0.05),
+                    0.0,
+                );
+                let hit = unwrap_hit(scene.intersect(&camera_ray, Visibility::CAMERA));
+                let mut bsdf = BSDF::new(&hit);
+                bsdf.add(&lambertian);
+                let ld = estimate_direct(&camera_ray, &hit, &light, &bsdf, &scene, &stats, false);
+
+                // Lambertian with albedo 1 reflects E cos / PI
+                let cos = hit.normal.dot(-light.direction).max(0.0);
+                let expected = 2.0 * cos / PI;
+                assert_abs_diff_eq!(ld.r, expected, epsilon = 1e-3 * expected);
+            }
+        }
+    }
+
+    #[test]
+    fn object_visibility() {
+        let sphere = |visibility| {
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_object(
+                    Object::new(
+                        Shape::Sphere { radius: 1.0 },
+                        point3(0.0, 3.0, 0.0),
+                        MaterialBuilder::new().build(),
+                    )
+                    .with_visibility(visibility),
+                )
+                .build()
+        };
+        let up = Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0);
+
+        // Only casts shadows
+        let shadow_only = sphere(Visibility::SHADOW);
+        assert!(shadow_only.intersect(&up, Visibility::CAMERA).is_miss());
+        assert!(shadow_only.intersect(&up, Visibility::DIFFUSE).is_miss());
+        assert!(shadow_only.intersect_shadow(&up, Scalar::INFINITY));
+        assert_eq!(
+            shadow_only.transmittance_along(&up, Scalar::INFINITY),
+            color::BLACK
+        );
+
+        // Seen in reflections but doesn't cast shadows
+        let no_shadow = sphere(Visibility::ALL.unset(Visibility::SHADOW));
+        assert!(no_shadow.intersect(&up, Visibility::CAMERA).is_hit());
+        assert!(no_shadow.intersect(&up, Visibility::SPECULAR).is_hit());
+        assert!(!no_shadow.intersect_shadow(&up, Scalar::INFINITY));
+        assert_eq!(
+            no_shadow.transmittance_along(&up, Scalar::INFINITY),
+            color::WHITE
+        );
+    }
+
+    #[test]
+    fn transmittance_along() {
+        let sphere = |y, transmission| {
+            Object::new(
+                Shape::Sphere { radius: 0.5 },
+                point3(0.0, y, 0.0),
+                MaterialBuilder::new()
+                    .base_color(color(0.5, 0.5, 0.5))
+                    .transmission(transmission)
+                    .build(),
+            )
+        };
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(sphere(2.0, 1.0))
+            .add_object(sphere(4.0, 1.0))
+            .add_object(sphere(6.0, 0.0))
+            .build();
+
+        let up = Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(scene.transmittance_along(&up, 1.0), color::WHITE);
+        assert_abs_diff_eq!(
+            scene.transmittance_along(&up, 3.0),
+            color(0.25, 0.25, 0.25),
+            epsilon = 1e-6
+        );
+        assert_abs_diff_eq!(
+            scene.transmittance_along(&up, 5.0),
+            color(0.0625, 0.0625, 0.0625),
+            epsilon = 1e-6
+        );
+        // The opaque sphere blocks everything
+        assert_eq!(scene.transmittance_along(&up, 7.0), color::BLACK);
+    }
+
+    #[test]
+    fn angular_motion() {
+        let object = |angular_motion| {
+            Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new()
+                    .base_color(Texture::Checker {
+                        a: color::WHITE,
+                        b: color::BLACK,
+                        scale: 8.0,
+                    })
+                    .build(),
+            )
+            .with_angular_motion(angular_motion)
+        };
+        let scene = |angular_motion| {
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_object(object(angular_motion))
+                .build()
+        };
+        let static_scene = scene(Quaternion::zero());
+        let spinning = scene(rotation_from_degrees(vec3(0.0, 90.0, 0.0)));
+
+        let center = |time| Ray::new(Pt3::origin(), vec3(0.1, 0.2, 1.0), time);
+        let uv = |scene: &Scene, ray| scene.intersect(&ray, Visibility::CAMERA).unwrap().uv;
+
+        // At the start of the exposure the object is unrotated
+        assert_eq!(uv(&spinning, center(0.0)), uv(&static_scene, center(0.0)));
+
+        // Later in the exposure the texture has moved
+        let start = uv(&spinning, center(0.0));
+        let end = uv(&spinning, center(1.0));
+        assert!((start.y - end.y).abs() > 0.1);
+        assert_abs_diff_eq!(start.x, end.x, epsilon = 1e-4);
+
+        // The silhouette doesn't change
+        for time in [0.0, 0.5, 1.0] {
+            let inside = Ray::new(Pt3::origin(), vec3(0.3, 0.0, 1.0), time);
+            let outside = Ray::new(Pt3::origin(), vec3(0.4, 0.0, 1.0), time);
+            assert!(spinning.intersect(&inside, Visibility::CAMERA).is_hit());
+            assert!(spinning.intersect(&outside, Visibility::CAMERA).is_miss());
+        }
+    }
+
+    #[test]
+    fn transform_round_trip() {
+        let transform = Transform::new(
+            rotation_from_degrees(vec3(30.0, -60.0, 45.0)),
+            vec3(1.0, -2.0, 3.0),
+        );
+        let point = point3(0.3, 0.5, -0.7);
+        let vector = vec3(-0.2, 0.9, 0.4);
+        assert_abs_diff_eq!(
+            transform.to_local_point(transform.to_world_point(point)),
+            point,
+            epsilon = 1e-5
+        );
+        assert_abs_diff_eq!(
+            transform.to_local_vector(transform.to_world_vector(vector)),
+            vector,
+            epsilon = 1e-5
+        );
+
+        // A zero quaternion is the identity
+        let identity = Transform::new(Quaternion::zero(), Vec3::zero());
+        assert_eq!(identity.to_world_point(point), point);
+        assert_eq!(identity.to_local_vector(vector), vector);
+    }
+
+    #[test]
+    fn rotated_sphere_uv() {
+        let shape = Shape::Sphere { radius: 1.0 };
+        let center = vec3(0.0, 0.0, 5.0);
+        let hit = |rotation, direction: Vec3| {
+            shape
+                .intersect(
+                    &Ray::new(Pt3::from_vec(center - direction * 3.0), direction, 0.0),
+                    Transform::new(rotation, center),
+                    &EmptyMaterial,
+                    &(),
+                )
+                .unwrap_into()
+        };
+
+        // Unrotated spheres keep their texture layout
+        let front = hit(Quaternion::zero(), vec3(0.0, 0.0, -1.0));
+        assert_abs_diff_eq!(front.uv, point2(0.5, 0.5), epsilon = 1e-6);
+        let side = hit(Quaternion::zero(), vec3(-1.0, 0.0, 0.0));
+        assert_abs_diff_eq!(side.uv, point2(0.5, 0.75), epsilon = 1e-6);
+
+        // Rotating the sphere carries the texture, normal and tangent along with it
+        let rotation = rotation_from_degrees(vec3(20.0, 70.0, -35.0));
+        for direction in [
+            vec3(0.0, 0.0, -1.0),
+            vec3(-1.0, 0.0, 0.0),
+            vec3(0.3, -0.8, 0.5).normalize(),
+        ] {
+            let unrotated = hit(Quaternion::zero(), direction);
+            let rotated = hit(rotation, rotation.rotate_vector(direction));
+            assert_abs_diff_eq!(rotated.uv, unrotated.uv, epsilon = 1e-4);
+            assert_abs_diff_eq!(
+                rotated.normal,
+                rotation.rotate_vector(unrotated.normal),
+                epsilon = 1e-5
+            );
+            assert_abs_diff_eq!(
+                rotated.tangent,
+                rotation.rotate_vector(unrotated.tangent),
+                epsilon = 1e-5
+            );
+            assert_abs_diff_eq!(rotated.distance, unrotated.distance, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn sphere_tangent_follows_uv() {
+        let shape = Shape::Sphere { radius: 2.0 };
+        let uv = |normal: Vec3| sphere_uv(normal.normalize());
+        fastrand::seed(5);
+        let mut directions = (0..200).map(|_| random_unit_vec()).collect::<Vec<_>>();
+        directions.extend([vec3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0)]);
+        for direction in directions {
+            let hit = shape
+                .intersect(
+                    &Ray::new(Pt3::from_vec(-direction * 5.0), direction, 0.0),
+                    Transform::new(Quaternion::zero(), Vec3::zero()),
+                    &EmptyMaterial,
+                    &(),
+                )
+                .unwrap_into();
+            let (normal, tangent) = (hit.normal, hit.tangent);
+            assert_abs_diff_eq!(tangent.magnitude(), 1.0, epsilon = 1e-5);
+            assert_abs_diff_eq!(tangent.dot(normal), 0.0, epsilon = 1e-5);
+
+            if normal.x == 0.0 && normal.z == 0.0 {
+                // The uv lookup at a pole uses the meridian through +z
+                assert_eq!(tangent, vec3(0.0, 0.0, normal.y));
+            } else if normal.y.abs() < 0.99 {
+                // Stepping along the tangent moves along u, and along normal x tangent moves
+                // along v, so (tangent, normal x tangent, normal) is right handed like
+                // (dp/du, dp/dv, n)
+                let eps = 1e-3;
+                let start = uv(normal);
+                let du = uv(normal + tangent * eps) - start;
+                assert!(du.x > 0.0, "{normal:?} {tangent:?} {du:?}");
+                assert_abs_diff_eq!(du.y, 0.0, epsilon = 1e-5);
+                let dv = uv(normal + normal.cross(tangent) * eps) - start;
+                assert!(dv.y > 0.0, "{normal:?} {tangent:?} {dv:?}");
+                assert_abs_diff_eq!(dv.x, 0.0, epsilon = 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_tangent_near_poles() {
+        let shape = Shape::Sphere { radius: 1.0 };
+        for rotation in [
+            Quaternion::zero(),
+            rotation_from_degrees(vec3(40.0, 10.0, -25.0)),
+        ] {
+            let transform = Transform::new(rotation, vec3(0.0, 0.0, 4.0));
+            let frame = |theta: Scalar, phi: Scalar| {
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                let local = vec3(sin_theta * phi.sin(), cos_theta, sin_theta * phi.cos());
+                let normal = transform.to_world_vector(local);
+                let origin = transform.to_world_point(Pt3::from_vec(local * 3.0));
+                let hit = shape
+                    .intersect(
+                        &Ray::new(origin, -normal, 0.0),
+                        transform,
+                        &EmptyMaterial,
+                        &(),
+                    )
+                    .unwrap_into();
+                let bsdf = BSDF::new(&hit);
+                (
+                    hit.normal,
+                    hit.tangent,
+                    bsdf.normal_to_world(vec3(1.0, 0.0, 0.0)),
+                )
+            };
+
+            for theta in [1e-3, 2e-3, PI - 2e-3, PI - 1e-3] {
+                let mut previous = frame(theta, 0.0);
+                for step in 1..=360 {
+                    let phi = (step as Scalar).to_radians();
+                    let (normal, tangent, cotangent) = frame(theta, phi);
+                    for v in [tangent, cotangent] {
+                        assert!(v.x.is_finite() && v.y.is_finite() && v.z.is_finite());
+                        assert_abs_diff_eq!(v.magnitude(), 1.0, epsilon = 1e-4);
+                        assert_abs_diff_eq!(v.dot(normal), 0.0, epsilon = 1e-4);
+                    }
+                    // The frame turns with phi around the pole, a degree per step
+                    let turn = |a: Vec3, b: Vec3| a.angle(b).0.to_degrees();
+                    assert!(turn(tangent, previous.1) < 1.5, "theta {theta} phi {phi}");
+                    assert!(turn(cotangent, previous.2) < 1.5, "theta {theta} phi {phi}");
+                    previous = (normal, tangent, cotangent);
+                }
+
+                // Neighbours along a meridian share their frame
+                let (_, tangent, _) = frame(theta, 1.0);
+                let (_, next, _) = frame(theta + 1e-4, 1.0);
+                assert!(tangent.angle(next).0.to_degrees() < 0.5, "theta {theta}");
+            }
+        }
+    }
 
     #[test]
     fn sphere_intersect() {
@@ -227,8 +1007,7 @@ mod tests {
         } = shape
             .intersect(
                 &Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0),
-                Quaternion::zero(),
-                vec3(0.0, 2.0, 0.0),
+                Transform::new(Quaternion::zero(), vec3(0.0, 2.0, 0.0)),
                 &EmptyMaterial,
                 &(),
             )
@@ -249,8 +1028,7 @@ mod tests {
         } = shape
             .intersect(
                 &Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0),
-                Quaternion::zero(),
-                vec3(0.0, 4.0, 0.0),
+                Transform::new(Quaternion::zero(), vec3(0.0, 4.0, 0.0)),
                 &EmptyMaterial,
                 &(),
             )
@@ -269,8 +1047,7 @@ mod tests {
                     vec3(-0.11515933, 0.35110158, -0.9292287),
                     0.0
                 ),
-                Quaternion::zero(),
-                vec3(0.0, -100.0, 0.0),
+                Transform::new(Quaternion::zero(), vec3(0.0, -100.0, 0.0)),
                 &EmptyMaterial,
                 &(),
             )
@@ -280,11 +1057,292 @@ mod tests {
         assert!(shape
             .intersect(
                 &Ray::new(point3(0.0, 1.0, 0.0), vec3(0.0, -1.0, 0.0), 0.0),
-                Quaternion::zero(),
-                vec3(0.0, 0.0, 0.0),
+                Transform::new(Quaternion::zero(), vec3(0.0, 0.0, 0.0)),
                 &EmptyMaterial,
                 &(),
             )
             .is_ignored());
     }
+
+    #[test]
+    fn sphere_respects_t_max() {
+        let shape = Shape::Sphere { radius: 1.0 };
+        let transform = Transform::new(Quaternion::zero(), vec3(0.0, 4.0, 0.0));
+        let distance = |ray: Ray| match shape.intersect(&ray, transform, &EmptyMaterial, &()) {
+            PossibleIntersection::Hit(hit) => Some(hit.distance),
+            _ => None,
+        };
+        let ray = Ray::new(Pt3::origin(), vec3(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(ray.t_max, Scalar::INFINITY);
+        assert_eq!(distance(ray), Some(3.0));
+        assert_eq!(distance(ray.with_t_max(3.0)), Some(3.0));
+        assert_eq!(distance(ray.with_t_max(2.5)), None);
+
+        // From inside only the far side counts
+        let inside = Ray::new(point3(0.0, 4.0, 0.0), vec3(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(distance(inside.with_t_max(1.5)), Some(1.0));
+        assert_eq!(distance(inside.with_t_max(0.5)), None);
+
+        // The bound is a world distance, rotating the object doesn't change it
+        let rotated = Transform::new(
+            rotation_from_degrees(vec3(50.0, 0.0, 20.0)),
+            vec3(0.0, 4.0, 0.0),
+        );
+        let hit = shape.intersect(&ray.with_t_max(2.5), rotated, &EmptyMaterial, &());
+        assert!(hit.is_miss());
+    }
+
+    #[test]
+    fn plane_intersect() {
+        let shape = Shape::Plane { uv_scale: 1.0 };
+        let transform = Transform::new(Quaternion::zero(), vec3(0.0, 1.0, 0.0));
+        let hit = |origin, direction| {
+            shape.intersect(
+                &Ray::new(origin, direction, 0.0),
+                transform,
+                &EmptyMaterial,
+                &(),
+            )
+        };
+
+        // Both sides are hit, the normal stays +y
+        let above = unwrap_hit(hit(point3(2.0, 3.0, -1.0), vec3(0.0, -1.0, 0.0)));
+        assert_eq!(above.distance, 2.0);
+        assert_eq!(above.point, point3(2.0, 1.0, -1.0));
+        assert_eq!(above.normal, vec3(0.0, 1.0, 0.0));
+        assert!(above.front_face);
+        let below = unwrap_hit(hit(point3(2.0, -1.0, -1.0), vec3(0.6, 0.8, 0.0)));
+        assert_abs_diff_eq!(below.distance, 2.5, epsilon = 1e-6);
+        assert_eq!(below.normal, vec3(0.0, 1.0, 0.0));
+        assert!(!below.front_face);
+
+        // Parallel rays miss, even in the plane, as do rays leaving it
+        assert!(hit(point3(0.0, 2.0, 0.0), vec3(1.0, 0.0, 0.0)).is_miss());
+        assert!(hit(point3(0.0, 1.0, 0.0), vec3(0.0, 0.0, 1.0)).is_miss());
+        assert!(hit(point3(0.0, 2.0, 0.0), vec3(0.0, 1.0, 0.0)).is_miss());
+        assert!(shape
+            .intersect(
+                &Ray::new(point3(0.0, 3.0, 0.0), vec3(0.0, -1.0, 0.0), 0.0).with_t_max(1.5),
+                transform,
+                &EmptyMaterial,
+                &()
+            )
+            .is_miss());
+
+        // Rotating the object tilts the plane, here into a wall facing -x
+        let wall = Transform::new(
+            rotation_from_degrees(vec3(0.0, 0.0, 90.0)),
+            vec3(3.0, 0.0, 0.0),
+        );
+        let hit = unwrap_hit(shape.intersect(
+            &Ray::new(point3(0.0, 5.0, 7.0), vec3(1.0, 0.0, 0.0), 0.0),
+            wall,
+            &EmptyMaterial,
+            &(),
+        ));
+        assert_abs_diff_eq!(hit.distance, 3.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(hit.normal, vec3(-1.0, 0.0, 0.0), epsilon = 1e-5);
+        assert!(hit.front_face);
+    }
+
+    #[test]
+    fn plane_uv_tiles() {
+        let uv = |uv_scale, x, z| {
+            unwrap_hit(Shape::Plane { uv_scale }.intersect(
+                &Ray::new(point3(x, 1.0, z), vec3(0.0, -1.0, 0.0), 0.0),
+                Transform::new(Quaternion::zero(), Vec3::zero()),
+                &EmptyMaterial,
+                &(),
+            ))
+            .uv
+        };
+
+        assert_abs_diff_eq!(uv(1.0, 0.25, 0.5), point2(0.25, 0.5), epsilon = 1e-6);
+        // Repeats every unit, including across the origin
+        assert_abs_diff_eq!(uv(1.0, 3.25, -1.5), point2(0.25, 0.5), epsilon = 1e-6);
+        // Doubling the scale halves the tile size
+        assert_abs_diff_eq!(uv(2.0, 0.25, 0.75), point2(0.5, 0.5), epsilon = 1e-6);
+        assert_abs_diff_eq!(uv(2.0, 0.75, 1.25), point2(0.5, 0.5), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn ground_plane_has_no_shadow_acne() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                Pt3::origin(),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+        // The plane doesn't grow the scene bounds
+        assert_eq!(scene.world_radius(), 1.0);
+
+        let light = DirectionLight::new(vec3(-0.3, -1.0, -0.2), WHITE).with_power(2.0);
+        let lambertian = Lambertian(WHITE);
+        let stats = RayStats::new();
+        let expected = 2.0 * light.direction.dot(vec3(0.0, -1.0, 0.0)) / PI;
+        for x in -10..=10 {
+            for z in -10..=10 {
+                let camera_ray = Ray::new(
+                    point3(x as Scalar * 730.0, 2.0, z as Scalar * 510.0),
+                    vec3(0.1, -1.0, 0.05),
+                    0.0,
+                );
+                let hit = unwrap_hit(scene.intersect(&camera_ray, Visibility::CAMERA));
+                let mut bsdf = BSDF::new(&hit);
+                bsdf.add(&lambertian);
+                let ld = estimate_direct(&camera_ray, &hit, &light, &bsdf, &scene, &stats, false);
+                assert_abs_diff_eq!(ld.r, expected, epsilon = 1e-3 * expected);
+            }
+        }
+    }
+
+    #[test]
+    fn packed_spheres_match_scalar_path() {
+        fastrand::seed(17);
+        let random = |range: Scalar| (fastrand::f64() as Scalar * 2.0 - 1.0) * range;
+        let mut builder = SceneBuilder::new().camera(CameraBuilder::new().build());
+        let mut spheres = Vec::new();
+        for i in 0..60 {
+            let center = point3(random(5.0), random(5.0), random(5.0));
+            let radius = 0.2 + random(0.5).abs() * 2.0;
+            spheres.push((center, radius));
+            let mut object = Object::new(
+                Shape::Sphere { radius },
+                center,
+                MaterialBuilder::new().build(),
+            );
+            // Unpacked spheres and visibility rules mixed in with the packed ones
+            match i % 6 {
+                1 => object = object.with_rotation(rotation_from_degrees(vec3(10.0, 20.0, 30.0))),
+                2 => object = object.with_motion(vec3(0.5, 0.0, 0.0)),
+                3 => object = object.with_visibility(Visibility::DIFFUSE),
+                4 => object = object.with_shadow_catcher(true),
+                _ => {}
+            }
+            builder = builder.add_object(object);
+        }
+        // An exact duplicate, the first one must win the tie
+        builder = builder.add_object(Object::new(
+            Shape::Sphere {
+                radius: spheres[0].1,
+            },
+            spheres[0].0,
+            MaterialBuilder::new().build(),
+        ));
+        let scene = builder
+            .add_object(Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                point3(0.0, -4.0, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+
+        let mut rays = (0..2000)
+            .map(|_| {
+                let origin = point3(random(6.0), random(6.0), random(6.0));
+                Ray::new(origin, random_unit_vec(), fastrand::f64() as Scalar)
+            })
+            .collect::<Vec<_>>();
+        // Rays leaving sphere surfaces, whose self hits are ignored
+        rays.extend(spheres.iter().map(|&(center, radius)| {
+            let normal = random_unit_vec();
+            Ray::new(center + normal * radius, random_unit_vec(), 0.0)
+        }));
+
+        for ray_kind in [
+            Visibility::CAMERA,
+            Visibility::SHADOW,
+            Visibility::DIFFUSE,
+            Visibility::SPECULAR,
+            Visibility::CAMERA.set(Visibility::DIFFUSE),
+            Visibility::SHADOW.set(Visibility::SPECULAR),
+            Visibility::ALL,
+        ] {
+            for ray in &rays {
+                let packed = scene.intersect(ray, ray_kind);
+                let (scalar, ignored) =
+                    nearest_object(scene.objects_visible_to(ray_kind), |object| {
+                        object.intersect_self(ray)
+                    });
+                match (packed, scalar) {
+                    (PossibleIntersection::Hit(packed), PossibleIntersection::Hit(scalar)) => {
+                        assert!(std::ptr::eq(packed.object, scalar.object));
+                        assert_eq!(packed.distance.to_bits(), scalar.distance.to_bits());
+                    }
+                    (PossibleIntersection::Ignored, PossibleIntersection::Miss) => assert!(ignored),
+                    (PossibleIntersection::Miss, PossibleIntersection::Miss) => assert!(!ignored),
+                    _ => panic!("packed and scalar intersections differ"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn intersect_packet_matches_intersect() {
+        let quad = || {
+            TriangleMesh::new(
+                vec![
+                    point3(-1.0, 0.0, -1.0),
+                    point3(1.0, 0.0, -1.0),
+                    point3(1.0, 0.0, 1.0),
+                    point3(-1.0, 0.0, 1.0),
+                ],
+                None,
+                None,
+                vec![[0, 2, 1], [0, 3, 2]],
+            )
+        };
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Mesh(quad()),
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_object(
+                Object::new(
+                    Shape::Mesh(quad()),
+                    point3(0.0, 0.5, 4.0),
+                    MaterialBuilder::new().build(),
+                )
+                .with_rotation(rotation_from_degrees(vec3(80.0, 10.0, 0.0)))
+                .with_motion(vec3(0.3, 0.0, 0.0)),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 0.5 },
+                point3(0.5, 0.5, 3.5),
+                MaterialBuilder::new().build(),
+            ))
+            .add_object(Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                point3(0.0, -2.0, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+
+        fastrand::seed(23);
+        let random = || fastrand::f64() as Scalar - 0.5;
+        for _ in 0..500 {
+            // Coherent rays from the origin, like camera rays through neighbouring pixels
+            let direction = vec3(random(), random(), 1.0);
+            let rays = std::array::from_fn(|_| {
+                let jitter = vec3(random(), random(), 0.0) * 0.05;
+                Ray::new(Pt3::origin(), direction + jitter, fastrand::f64() as Scalar)
+            });
+            let hits = scene.intersect_packet(&rays, Visibility::CAMERA);
+            for (ray, hit) in rays.iter().zip(hits) {
+                match (hit, scene.intersect(ray, Visibility::CAMERA)) {
+                    (PossibleIntersection::Hit(hit), PossibleIntersection::Hit(expected)) => {
+                        assert!(std::ptr::eq(hit.object, expected.object));
+                        assert_eq!(hit.distance.to_bits(), expected.distance.to_bits());
+                        assert_eq!(hit.normal, expected.normal);
+                    }
+                    (PossibleIntersection::Miss, PossibleIntersection::Miss) => {}
+                    _ => panic!("packet and single ray intersections differ"),
+                }
+            }
+        }
+    }
 }
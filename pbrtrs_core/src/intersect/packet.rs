@@ -0,0 +1,181 @@
+//! Spheres laid out as a structure of arrays and tested against a ray several at a time. Only
+//! spheres without motion or rotation are packed: for those the object space test of
+//! [`Shape::intersect`] runs the same operations in world space, so both find bit for bit the
+//! same distances.
+
+use super::T_MIN;
+use crate::scene::{Object, Shape, Visibility};
+use crate::types::{Pt3, Ray, Scalar};
+use cgmath::Zero;
+
+/// Spheres tested together in one step of [`SpherePacket::nearest`], the loop over them is
+/// written without branches so it can be vectorized
+const LANES: usize = 4;
+
+/// Centers and radii of spheres, padded to a multiple of [`LANES`] with spheres nothing hits
+#[derive(Debug, Default)]
+pub struct SpherePacket {
+    center_x: Vec<Scalar>,
+    center_y: Vec<Scalar>,
+    center_z: Vec<Scalar>,
+    radius: Vec<Scalar>,
+    len: usize,
+}
+
+/// Result of [`SpherePacket::nearest`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PacketHit {
+    /// Index and distance of the nearest sphere hit at least `T_MIN` from the ray origin, the
+    /// lowest index wins ties
+    pub nearest: Option<(usize, Scalar)>,
+    /// Whether any sphere was hit too close to the ray origin to be resolved
+    pub ignored: bool,
+}
+
+impl SpherePacket {
+    /// Packs spheres given by their center and radius
+    pub fn new(spheres: impl IntoIterator<Item = (Pt3, Scalar)>) -> Self {
+        let mut packet = Self::default();
+        for (center, radius) in spheres {
+            packet.push(center, radius);
+        }
+        packet.len = packet.radius.len();
+        // NaN spheres never pass the discriminant test
+        while packet.radius.len() % LANES != 0 {
+            let nan = Scalar::NAN;
+            packet.push(Pt3::new(nan, nan, nan), nan);
+        }
+        packet
+    }
+
+    fn push(&mut self, center: Pt3, radius: Scalar) {
+        self.center_x.push(center.x);
+        self.center_y.push(center.y);
+        self.center_z.push(center.z);
+        self.radius.push(radius);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Nearest sphere hit by `ray` between 0 and `ray.t_max`, as found by [`Shape::intersect`]
+    pub fn nearest(&self, ray: &Ray) -> PacketHit {
+        let (origin, direction) = (ray.origin, ray.direction);
+        // Same operations in the same order as the sphere case of `Shape::intersect_local`
+        let a = direction.x * direction.x + direction.y * direction.y + direction.z * direction.z;
+
+        let mut best_distance = [Scalar::INFINITY; LANES];
+        let mut best_index = [usize::MAX; LANES];
+        let mut ignored = [false; LANES];
+        let chunks = self
+            .center_x
+            .chunks_exact(LANES)
+            .zip(self.center_y.chunks_exact(LANES))
+            .zip(self.center_z.chunks_exact(LANES))
+            .zip(self.radius.chunks_exact(LANES));
+        for (chunk, (((center_x, center_y), center_z), radius)) in chunks.enumerate() {
+            for lane in 0..LANES {
+                let oc_x = origin.x - center_x[lane];
+                let oc_y = origin.y - center_y[lane];
+                let oc_z = origin.z - center_z[lane];
+                let h = oc_x * direction.x + oc_y * direction.y + oc_z * direction.z;
+                let c = oc_x * oc_x + oc_y * oc_y + oc_z * oc_z - radius[lane] * radius[lane];
+                let discriminant = h * h - a * c;
+                let sqrt_discriminant = discriminant.sqrt();
+                let near = (-h - sqrt_discriminant) / a;
+                let far = (-h + sqrt_discriminant) / a;
+                let t = if near >= 0.0 { near } else { far };
+
+                let in_range = discriminant >= 0.0 && t >= 0.0 && t <= ray.t_max;
+                ignored[lane] |= in_range && t < T_MIN;
+                let closer = in_range && t >= T_MIN && t < best_distance[lane];
+                best_distance[lane] = if closer { t } else { best_distance[lane] };
+                best_index[lane] = if closer {
+                    chunk * LANES + lane
+                } else {
+                    best_index[lane]
+                };
+            }
+        }
+
+        let nearest = (0..LANES)
+            .filter(|&lane| best_index[lane] != usize::MAX)
+            .min_by(|&a, &b| {
+                best_distance[a]
+                    .total_cmp(&best_distance[b])
+                    .then(best_index[a].cmp(&best_index[b]))
+            })
+            .map(|lane| (best_index[lane], best_distance[lane]));
+        PacketHit {
+            nearest,
+            ignored: ignored.contains(&true),
+        }
+    }
+}
+
+/// Scene objects visible to a kind of ray, split into packed spheres and everything else
+#[derive(Debug)]
+pub(crate) struct PackedObjects {
+    pub spheres: SpherePacket,
+    /// Scene index of each packed sphere
+    pub sphere_objects: Vec<usize>,
+    /// Scene indices of the other visible objects, in order
+    pub others: Vec<usize>,
+}
+
+impl PackedObjects {
+    pub fn new(objects: &[Object], kind: Visibility) -> Self {
+        let mut spheres = Vec::new();
+        let mut sphere_objects = Vec::new();
+        let mut others = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            if !object.is_visible_to(kind) {
+                continue;
+            }
+            match object.shape {
+                Shape::Sphere { radius }
+                    if object.motion.is_zero()
+                        && object.angular_motion.is_zero()
+                        && object.rotation.v.is_zero() =>
+                {
+                    spheres.push((object.position, radius));
+                    sphere_objects.push(i);
+                }
+                _ => others.push(i),
+            }
+        }
+        Self {
+            spheres: SpherePacket::new(spheres),
+            sphere_objects,
+            others,
+        }
+    }
+
+    /// Objects that may hold the nearest hit of `ray`: the nearest packed sphere and all
+    /// unpacked objects, in scene order. Also returns whether a packed sphere was ignored.
+    pub fn candidates<'a>(
+        &'a self,
+        objects: &'a [Object],
+        ray: &Ray,
+    ) -> (impl Iterator<Item = &'a Object>, bool) {
+        let hit = self.spheres.nearest(ray);
+        let nearest = hit.nearest.map(|(i, _)| self.sphere_objects[i]);
+        // Keeping scene order lets exact ties resolve as they would without packing
+        let split = nearest.map_or(self.others.len(), |nearest| {
+            self.others.partition_point(|&i| i < nearest)
+        });
+        let (before, after) = self.others.split_at(split);
+        let candidates = before
+            .iter()
+            .copied()
+            .chain(nearest)
+            .chain(after.iter().copied())
+            .map(|i| &objects[i]);
+        (candidates, hit.ignored)
+    }
+}
@@ -0,0 +1,431 @@
+use crate::mesh::TriangleMesh;
+use crate::scene::{
+    DisneyMaterial, MaterialBuilder, Object, Rgb8ColorPixelConverter, Shape, Texture,
+};
+use crate::types::colorspace::{ColorSpace, ColorTransform};
+use crate::types::{color, Color, Mat3, Mat4, Pt2, Pt3, R8G8B8Color, Scalar, Vec3};
+use cgmath::{point2, EuclideanSpace, InnerSpace, Matrix, SquareMatrix, Transform};
+use gltf::image::Format;
+use gltf::mesh::Mode;
+use gltf::{Document, Gltf, Node, Primitive};
+use image::{GrayImage, Luma, Rgb, RgbImage};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Loads the default scene of a glTF 2.0 file, or its first scene, as one object per mesh
+/// primitive. Node transforms are baked into the vertices, so every object sits at the origin.
+///
+/// Metallic-roughness materials map onto the Disney material, colors are converted from linear
+/// sRGB to `color_space`. Texels are read the same way as scene textures. Textures that fail to
+/// load are left out with a warning and only their material factors are used.
+pub fn load_gltf(path: impl AsRef<Path>, color_space: ColorSpace) -> Result<Vec<Object>, String> {
+    let path = path.as_ref();
+    let fail = |err: &dyn std::fmt::Display| format!("failed to load {}: {err}", path.display());
+    let Gltf { document, blob } = Gltf::open(path).map_err(|err| fail(&err))?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    let buffers = gltf::import_buffers(&document, Some(base), blob).map_err(|err| fail(&err))?;
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| fail(&"the file has no scene"))?;
+    let mut loader = Loader {
+        document: &document,
+        buffers: &buffers,
+        base,
+        images: HashMap::new(),
+        transform: ColorSpace::LinearSrgb.transform_to(color_space),
+        objects: Vec::new(),
+    };
+    for node in scene.nodes() {
+        loader.load_node(&node, Mat4::identity())?;
+    }
+    Ok(loader.objects)
+}
+
+struct Loader<'a> {
+    document: &'a Document,
+    buffers: &'a [gltf::buffer::Data],
+    base: &'a Path,
+    /// Decoded images by index, `None` for images that failed to load
+    images: HashMap<usize, Option<RgbImage>>,
+    transform: ColorTransform,
+    objects: Vec<Object>,
+}
+
+impl Loader<'_> {
+    fn load_node(&mut self, node: &Node, parent: Mat4) -> Result<(), String> {
+        let local = Mat4::from(
+            node.transform()
+                .matrix()
+                .map(|column| column.map(Scalar::from)),
+        );
+        let transform = parent * local;
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                if let Some(object) = self.load_primitive(&primitive, transform)? {
+                    self.objects.push(object);
+                }
+            }
+        }
+        for child in node.children() {
+            self.load_node(&child, transform)?;
+        }
+        Ok(())
+    }
+
+    fn load_primitive(
+        &mut self,
+        primitive: &Primitive,
+        transform: Mat4,
+    ) -> Result<Option<Object>, String> {
+        let buffers = self.buffers;
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data.0[..]));
+        let positions = reader
+            .read_positions()
+            .ok_or("mesh primitive has no positions")?
+            .map(|p| transform.transform_point(Pt3::from(p.map(Scalar::from))))
+            .collect::<Vec<_>>();
+
+        // Normals move with the inverse transpose, singular transforms leave them out
+        let linear = Mat3::from_cols(
+            transform.x.truncate(),
+            transform.y.truncate(),
+            transform.z.truncate(),
+        );
+        let normal_matrix = linear.invert().map(|inverse| inverse.transpose());
+        let normals = reader
+            .read_normals()
+            .zip(normal_matrix)
+            .map(|(normals, matrix)| {
+                normals
+                    .map(|n| (matrix * Vec3::from(n.map(Scalar::from))).normalize())
+                    .collect::<Vec<_>>()
+            });
+        let uvs = reader.read_tex_coords(0).map(|uvs| {
+            uvs.into_f32()
+                .map(|[u, v]| point2(Scalar::from(u), Scalar::from(v)))
+                .collect::<Vec<Pt2>>()
+        });
+
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect::<Vec<_>>(),
+        };
+        let mut triangles = match primitive.mode() {
+            Mode::Triangles => indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect::<Vec<_>>(),
+            Mode::TriangleStrip => (2..indices.len())
+                .map(|i| {
+                    // Every other triangle of a strip is wound the other way
+                    if i % 2 == 0 {
+                        [indices[i - 2], indices[i - 1], indices[i]]
+                    } else {
+                        [indices[i - 1], indices[i - 2], indices[i]]
+                    }
+                })
+                .collect(),
+            Mode::TriangleFan => (2..indices.len())
+                .map(|i| [indices[0], indices[i - 1], indices[i]])
+                .collect(),
+            mode => {
+                eprintln!("Warning: skipping glTF primitive drawn as {mode:?}, not triangles");
+                return Ok(None);
+            }
+        };
+        if triangles.is_empty() {
+            return Ok(None);
+        }
+        // Mirroring transforms flip the winding
+        if linear.determinant() < 0.0 {
+            for triangle in &mut triangles {
+                triangle.swap(1, 2);
+            }
+        }
+        if triangles
+            .iter()
+            .flatten()
+            .any(|&i| i as usize >= positions.len())
+            || normals.as_ref().is_some_and(|n| n.len() != positions.len())
+            || uvs.as_ref().is_some_and(|uv| uv.len() != positions.len())
+        {
+            return Err("mesh primitive has mismatched vertex attributes".to_owned());
+        }
+
+        let mesh = TriangleMesh::new(positions, normals, uvs, triangles);
+        let material = self.load_material(&primitive.material());
+        Ok(Some(Object::new(
+            Shape::Mesh(mesh),
+            Pt3::origin(),
+            material,
+        )))
+    }
+
+    fn load_material(&mut self, material: &gltf::Material) -> DisneyMaterial {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _alpha] = pbr.base_color_factor().map(Scalar::from);
+        let base_color = self.color_texture(
+            color(r, g, b),
+            pbr.base_color_texture().map(|info| info.texture()),
+        );
+
+        let metallic_factor = Scalar::from(pbr.metallic_factor());
+        let roughness_factor = Scalar::from(pbr.roughness_factor());
+        let metallic_roughness = pbr
+            .metallic_roughness_texture()
+            .and_then(|info| self.image(&info.texture()));
+        // Roughness is in the green channel and metallic in the blue one
+        let (metallic, roughness) = match metallic_roughness {
+            Some(image) => (
+                Texture::Image(channel(&image, 2, metallic_factor)),
+                Texture::Image(channel(&image, 1, roughness_factor)),
+            ),
+            None => (
+                Texture::Value(metallic_factor),
+                Texture::Value(roughness_factor),
+            ),
+        };
+
+        let [r, g, b] = material.emissive_factor().map(Scalar::from);
+        let emission = self.color_texture(
+            color(r, g, b),
+            material.emissive_texture().map(|info| info.texture()),
+        );
+
+        let mut builder = MaterialBuilder::new()
+            .base_color(base_color)
+            .metallic(metallic)
+            .roughness(roughness)
+            .emission(emission);
+        if let Some(normal) = material.normal_texture() {
+            if let Some(image) = self.image(&normal.texture()) {
+                builder = builder.normal_map(Texture::Image(scale_normals(
+                    image,
+                    Scalar::from(normal.scale()),
+                )));
+            }
+        }
+        builder.build()
+    }
+
+    /// `factor` times the texture, in the working space
+    fn color_texture(
+        &mut self,
+        factor: Color,
+        texture: Option<gltf::Texture>,
+    ) -> Texture<Color, Rgb8ColorPixelConverter> {
+        let Some(mut image) = texture.and_then(|texture| self.image(&texture)) else {
+            return Texture::Value(self.transform.apply(factor));
+        };
+        for pixel in image.pixels_mut() {
+            let texel = Color::from(R8G8B8Color(pixel.0)) * factor;
+            *pixel = R8G8B8Color::from(self.transform.apply(texel)).into();
+        }
+        Texture::Image(image)
+    }
+
+    fn image(&mut self, texture: &gltf::Texture) -> Option<RgbImage> {
+        let source = texture.source();
+        let (document, buffers, base) = (self.document, self.buffers, self.base);
+        self.images
+            .entry(source.index())
+            .or_insert_with(|| {
+                let image = document.images().nth(source.index())?;
+                gltf::image::Data::from_source(image.source(), Some(base), buffers)
+                    .map_err(|err| err.to_string())
+                    .and_then(to_rgb8)
+                    .map_err(|err| {
+                        eprintln!(
+                            "Warning: couldn't load glTF image {}: {err}, using the material factors",
+                            source.index()
+                        )
+                    })
+                    .ok()
+            })
+            .clone()
+    }
+}
+
+/// Drops alpha and keeps the top 8 bits of 16 bit channels
+fn to_rgb8(data: gltf::image::Data) -> Result<RgbImage, String> {
+    let (channels, bytes) = match data.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        format => return Err(format!("{format:?} images are not supported")),
+    };
+    let texel = |i: usize, channel: usize| {
+        // Grey images repeat their only channel, two channel ones have no blue
+        let channel = if channels == 1 { 0 } else { channel };
+        if channel >= channels {
+            return 0;
+        }
+        let offset = (i * channels + channel) * bytes;
+        // 16 bit channels are stored little endian
+        data.pixels[offset + bytes - 1]
+    };
+    let pixels = (0..(data.width * data.height) as usize)
+        .flat_map(|i| [texel(i, 0), texel(i, 1), texel(i, 2)])
+        .collect();
+    RgbImage::from_raw(data.width, data.height, pixels).ok_or_else(|| "truncated image".to_owned())
+}
+
+/// One channel of `image` scaled by `factor`
+fn channel(image: &RgbImage, channel: usize, factor: Scalar) -> GrayImage {
+    GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let value = image.get_pixel(x, y).0[channel] as Scalar * factor;
+        Luma([value.round().clamp(0.0, 255.0) as u8])
+    })
+}
+
+/// Scales the x and y of tangent space normals by `scale`, as the glTF normal texture scale does
+fn scale_normals(mut image: RgbImage, scale: Scalar) -> RgbImage {
+    if scale == 1.0 {
+        return image;
+    }
+    for pixel in image.pixels_mut() {
+        let n = Vec3::from(pixel.0.map(|c| c as Scalar / 255.0 * 2.0 - 1.0));
+        let n = Vec3::new(n.x * scale, n.y * scale, n.z).normalize();
+        *pixel =
+            Rgb([n.x, n.y, n.z].map(|c| ((c + 1.0) / 2.0 * 255.0).round().clamp(0.0, 255.0) as u8));
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Ray;
+    use cgmath::{abs_diff_eq, assert_abs_diff_eq, point3, vec3};
+
+    /// Little endian bytes of the values, base64 encoded
+    fn base64(values: &[f32]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let bytes = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+        encoded
+    }
+
+    /// A unit right triangle in the xy plane, placed by a translated and scaled child node
+    fn triangle_gltf(material: &str) -> String {
+        let positions = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+        format!(
+            r#"{{
+  "asset": {{ "version": "2.0" }},
+  "scene": 0,
+  "scenes": [{{ "nodes": [0] }}],
+  "nodes": [
+    {{ "translation": [0.0, 0.0, 5.0], "children": [1] }},
+    {{ "scale": [2.0, 2.0, 2.0], "mesh": 0 }}
+  ],
+  "meshes": [{{ "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "material": 0 }}] }}],
+  "materials": [{material}],
+  "textures": [{{ "source": 0 }}],
+  "images": [{{ "uri": "missing.png" }}],
+  "accessors": [{{
+    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+    "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+  }}],
+  "bufferViews": [{{ "buffer": 0, "byteLength": 36 }}],
+  "buffers": [{{ "byteLength": 36, "uri": "data:application/octet-stream;base64,{}" }}]
+}}"#,
+            base64(&positions)
+        )
+    }
+
+    fn load(material: &str) -> Vec<Object> {
+        let path = std::env::temp_dir().join(format!(
+            "pbrtrs_{}_{}.gltf",
+            std::process::id(),
+            material.len()
+        ));
+        std::fs::write(&path, triangle_gltf(material)).unwrap();
+        let objects = load_gltf(&path, ColorSpace::LinearSrgb);
+        std::fs::remove_file(&path).unwrap();
+        objects.unwrap()
+    }
+
+    #[test]
+    fn flattens_node_transforms() {
+        let objects =
+            load(r#"{ "pbrMetallicRoughness": { "baseColorFactor": [0.5, 0.25, 1.0, 1.0] } }"#);
+        assert_eq!(objects.len(), 1);
+        let object = &objects[0];
+        assert_eq!(object.position, Pt3::origin());
+
+        let Shape::Mesh(mesh) = &object.shape else {
+            panic!("Expected a mesh");
+        };
+        assert_abs_diff_eq!(mesh.area(), 2.0, epsilon = 1e-5);
+        let ray = Ray::new(point3(0.5, 0.5, 0.0), vec3(0.0, 0.0, 1.0), 0.0);
+        let hit = mesh.intersect(&ray).unwrap();
+        assert_abs_diff_eq!(hit.distance, 5.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(hit.normal, vec3(0.0, 0.0, 1.0), epsilon = 1e-5);
+        assert!(mesh
+            .intersect(&Ray::new(point3(1.5, 0.6, 0.0), vec3(0.0, 0.0, 1.0), 0.0))
+            .is_none());
+
+        assert!(matches!(
+            object.material.base_color,
+            Texture::Value(c) if c == color(0.5, 0.25, 1.0)
+        ));
+        // The glTF defaults for metallic and roughness
+        assert!(matches!(object.material.metallic, Texture::Value(m) if m == 1.0));
+        assert!(matches!(object.material.roughness, Texture::Value(r) if r == 1.0));
+    }
+
+    #[test]
+    fn missing_textures_fall_back_to_factors() {
+        let objects = load(
+            r#"{
+  "pbrMetallicRoughness": {
+    "baseColorFactor": [0.2, 0.4, 0.6, 1.0],
+    "baseColorTexture": { "index": 0 },
+    "metallicFactor": 0.0,
+    "roughnessFactor": 0.3,
+    "metallicRoughnessTexture": { "index": 0 }
+  },
+  "normalTexture": { "index": 0 },
+  "emissiveFactor": [1.0, 0.5, 0.0]
+}"#,
+        );
+        let material = &objects[0].material;
+        assert!(
+            matches!(material.base_color, Texture::Value(c) if abs_diff_eq!(c, color(0.2, 0.4, 0.6), epsilon = 1e-6))
+        );
+        assert!(matches!(material.metallic, Texture::Value(m) if m == 0.0));
+        assert!(
+            matches!(material.roughness, Texture::Value(r) if abs_diff_eq!(r, 0.3, epsilon = 1e-6))
+        );
+        assert!(matches!(material.emission, Texture::Value(c) if c == color(1.0, 0.5, 0.0)));
+        assert!(material.normal_map.is_none());
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(load_gltf("does/not/exist.gltf", ColorSpace::LinearSrgb).is_err());
+    }
+}
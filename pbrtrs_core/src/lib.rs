@@ -1,22 +1,31 @@
 extern crate bumpalo;
 extern crate cgmath;
 extern crate fastrand;
+extern crate gltf;
 extern crate image;
 extern crate serde;
 extern crate serde_derive;
+extern crate serde_json;
 extern crate smallvec;
 extern crate toml;
 
 #[cfg(feature = "enable_oidn")]
 extern crate oidn;
+#[cfg(feature = "enable_debugger")]
+extern crate xml;
 
 pub mod bxdf;
 pub mod debugger;
 pub mod intersect;
-mod light;
+pub mod io;
+pub mod light;
 pub mod material;
+pub mod medium;
+pub mod mesh;
 pub mod postprocess;
 pub mod raytracer;
+pub mod sampling;
 pub mod scene;
+pub mod stats;
 pub mod types;
 pub mod util;
@@ -1,18 +1,27 @@
-use crate::bxdf::{BxDFKind, BSDF};
+use crate::bxdf::{BxDFKind, Wavelength, BSDF};
 use crate::debugger;
-use crate::intersect::Intersection;
+use crate::intersect::{sphere_uv, Intersection, PossibleIntersection, Transform};
+use crate::light::cubemap::CubeMapLight;
 use crate::light::hdri::Hdri;
+use crate::light::ies::IesProfile;
 use crate::material::{Material, TransportMode};
-use crate::scene::{Scene, Shape};
-use crate::types::color::{BLACK};
+use crate::medium::PhaseFunction;
+use crate::scene::{Object, Rgb8ColorPixelConverter, SampledDisneyMaterial, Scene, Shape, Texture};
+use crate::stats::{self, RayStats};
+use crate::types::color::{BLACK, WHITE};
 use crate::types::scalar::consts::PI;
+use crate::types::{color, scalar};
 use crate::types::{Color, Pt2, Pt3, Quaternion, Ray, Scalar, Vec3};
-use crate::util::{bitfield_methods, random_unit_vec};
+use crate::util::{bitfield_methods, coordinate_system, offset_ray_origin, random_unit_vec};
 use bumpalo::Bump;
-use cgmath::{ElementWise, InnerSpace, Zero};
+use cgmath::{point2, vec3, EuclideanSpace, InnerSpace, MetricSpace, Zero};
+use serde::Deserialize;
 use std::fmt::{Debug, Formatter};
+use std::ops::{AddAssign, Div, Mul};
 
+pub mod cubemap;
 pub mod hdri;
+pub mod ies;
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
@@ -42,6 +51,7 @@ impl LightKind {
     pub const DELTA_DIRECTION: LightKind = LightKind(1 << 1);
     pub const AREA: LightKind = LightKind(1 << 2);
     pub const INFINITE: LightKind = LightKind(1 << 3);
+    /// Infinite light that camera rays escaping the scene don't see, only the background
     pub const NO_BG: LightKind = LightKind(1 << 4);
 }
 
@@ -61,6 +71,28 @@ pub trait LightTrait {
 
     fn pdf_li<M, O>(&self, intersection: &Intersection<M, O>, wi: Vec3) -> Scalar;
 
+    /// Approximate emitted power, used to choose lights in proportion to their contribution.
+    /// `world_radius` bounds the scene for directional and infinite lights.
+    fn power(&self, world_radius: Scalar) -> Scalar;
+
+    /// Distance along `ray` to where this light emits from. Only hits closer than this occlude
+    /// the light.
+    fn occlusion_distance(&self, _ray: &Ray) -> Scalar {
+        Scalar::INFINITY
+    }
+
+    /// Radiance from this light carried by `ray`, assuming nothing occludes it
+    fn le_unoccluded(&self, ray: &Ray) -> Color {
+        self.le(ray)
+    }
+
+    /// [`LightTrait::le`] averaged over about `footprint` steradians around `ray`, see
+    /// [`lobe_footprint`]. Lights with fine detail filter it so a single bright texel hit by a
+    /// rough lobe can't become a firefly.
+    fn le_filtered(&self, ray: &Ray, _footprint: Scalar) -> Color {
+        self.le(ray)
+    }
+
     fn is_delta(&self) -> bool {
         self.kind().has(LightKind::DELTA_POSITION) || self.kind().has(LightKind::DELTA_DIRECTION)
     }
@@ -70,16 +102,104 @@ pub trait LightTrait {
     }
 }
 
+/// Solid angle of the lobe a direction sampled with density `pdf` came from, roughly, at most a
+/// hemisphere. Both halves of an MIS estimate of an infinite light filter it by the footprint of
+/// the BSDF's density, so they estimate the same filtered radiance.
+pub fn lobe_footprint(pdf: Scalar) -> Scalar {
+    if pdf > 0.0 {
+        (1.0 / pdf).min(2.0 * PI)
+    } else {
+        0.0
+    }
+}
+
 pub fn power_heuristic(nf: Scalar, f_pdf: Scalar, ng: Scalar, g_pdf: Scalar) -> Scalar {
     let f = nf * f_pdf;
     let g = ng * g_pdf;
     (f * f) / (f * f + g * g)
 }
 
+/// Distance falloff of positional lights
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Attenuation {
+    /// Physically based `1 / d^2` falloff, with the distance clamped to `min_distance` to avoid
+    /// the singularity at the light position
+    InverseSquare { min_distance: Scalar },
+    /// The original `1 / (d + 1)^2` falloff
+    Legacy,
+}
+
+impl Attenuation {
+    pub const DEFAULT_MIN_DISTANCE: Scalar = 0.01;
+
+    pub fn attenuate(self, distance: Scalar) -> Scalar {
+        match self {
+            Self::InverseSquare { min_distance } => 1.0 / distance.max(min_distance).powi(2),
+            Self::Legacy => 1.0 / (distance + 1.0).powi(2),
+        }
+    }
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self::InverseSquare {
+            min_distance: Self::DEFAULT_MIN_DISTANCE,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PointLight {
     pub position: Pt3,
+    /// Radiant intensity, in the brightest direction of the IES profile if there is one
     pub radiance: Color,
+    pub attenuation: Attenuation,
+    /// Photometric profile, with its nadir pointing down (-y) and horizontal angle 0 along +x
+    pub ies: Option<IesProfile>,
+}
+
+impl PointLight {
+    pub fn new(position: Pt3, color: Color) -> Self {
+        Self {
+            position,
+            radiance: color,
+            attenuation: Attenuation::default(),
+            ies: None,
+        }
+    }
+
+    /// Scales the light so it emits `power` watts in total, set the IES profile first
+    pub fn with_power(mut self, power: Scalar) -> Self {
+        self.radiance *= power / self.solid_angle();
+        self
+    }
+
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        self.attenuation = attenuation;
+        self
+    }
+
+    pub fn with_ies(mut self, ies: IesProfile) -> Self {
+        self.ies = Some(ies);
+        self
+    }
+
+    /// Integral of the relative intensity over all directions
+    fn solid_angle(&self) -> Scalar {
+        match &self.ies {
+            Some(ies) => ies.integral(),
+            None => 4.0 * PI,
+        }
+    }
+
+    /// Relative intensity of light leaving in direction `w`
+    fn intensity(&self, w: Vec3) -> Scalar {
+        match &self.ies {
+            // The luminaire frame has +z at the nadir, +x at 0 and +y at 90 degrees
+            Some(ies) => ies.intensity(vec3(w.x, w.z, -w.y)),
+            None => 1.0,
+        }
+    }
 }
 
 impl LightTrait for PointLight {
@@ -101,12 +221,52 @@ impl LightTrait for PointLight {
         let distance = to_light.magnitude();
         *wi = to_light / distance;
         *pdf = 1.0;
-        self.radiance / (distance + 1.0).powi(2)
+        self.radiance * self.intensity(-*wi) * self.attenuation.attenuate(distance)
     }
 
     fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, _wi: Vec3) -> Scalar {
         0.0
     }
+
+    fn power(&self, _world_radius: Scalar) -> Scalar {
+        self.solid_angle() * color::luminance(self.radiance)
+    }
+
+    fn occlusion_distance(&self, ray: &Ray) -> Scalar {
+        ray.origin.distance(self.position)
+    }
+}
+
+/// Curve of a spot light's intensity across the penumbra, between the cone's edge and the
+/// falloff angle
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpotFalloff {
+    /// `t^4`, as in pbrt
+    Quartic,
+    /// `t^2 (3 - 2t)`, which has no kinks at either end of the penumbra
+    #[default]
+    Smoothstep,
+    Linear,
+}
+
+impl SpotFalloff {
+    /// Relative intensity at `t`, which goes from 0 at the cone's edge to 1 at the falloff angle
+    fn apply(self, t: Scalar) -> Scalar {
+        match self {
+            SpotFalloff::Quartic => t.powi(4),
+            SpotFalloff::Smoothstep => t * t * (3.0 - 2.0 * t),
+            SpotFalloff::Linear => t,
+        }
+    }
+
+    /// Integral of the curve over t in [0, 1]
+    fn integral(self) -> Scalar {
+        match self {
+            SpotFalloff::Quartic => 0.2,
+            SpotFalloff::Smoothstep | SpotFalloff::Linear => 0.5,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -115,18 +275,113 @@ pub struct SpotLight {
     pub direction: Vec3,
     pub cos_angle: Scalar,
     pub cos_falloff: Scalar,
+    pub falloff_mode: SpotFalloff,
+    /// Radiant intensity along the spot axis
     pub radiance: Color,
+    pub attenuation: Attenuation,
+    /// Pattern projected by the light, looked up by the angular offset from the spot axis
+    pub gobo: Option<Texture<Color, Rgb8ColorPixelConverter>>,
+    /// Photometric profile replacing the cone, its nadir points along the spot axis
+    pub ies: Option<IesProfile>,
+    /// Frame the gobo is projected in, fixed at construction so the pattern doesn't rotate
+    tangent: Vec3,
+    bitangent: Vec3,
 }
 
 impl SpotLight {
+    /// Creates a spot light from its cone `angle` and inner `falloff` angle, both in degrees
+    pub fn new(
+        position: Pt3,
+        direction: Vec3,
+        angle: Scalar,
+        falloff: Scalar,
+        color: Color,
+    ) -> Self {
+        let direction = direction.normalize();
+        let (tangent, bitangent) = coordinate_system(direction);
+        Self {
+            position,
+            direction,
+            cos_angle: angle.to_radians().cos(),
+            cos_falloff: falloff.to_radians().cos(),
+            falloff_mode: SpotFalloff::default(),
+            radiance: color,
+            attenuation: Attenuation::default(),
+            gobo: None,
+            ies: None,
+            tangent,
+            bitangent,
+        }
+    }
+
+    /// Scales the light so it emits `power` watts in total, set the IES profile first
+    pub fn with_power(mut self, power: Scalar) -> Self {
+        self.radiance *= power / self.solid_angle();
+        self
+    }
+
+    pub fn with_attenuation(mut self, attenuation: Attenuation) -> Self {
+        self.attenuation = attenuation;
+        self
+    }
+
+    /// Sets the penumbra's curve, set it before the power
+    pub fn with_falloff_mode(mut self, falloff_mode: SpotFalloff) -> Self {
+        self.falloff_mode = falloff_mode;
+        self
+    }
+
+    pub fn with_gobo(mut self, gobo: Texture<Color, Rgb8ColorPixelConverter>) -> Self {
+        self.gobo = Some(gobo);
+        self
+    }
+
+    pub fn with_ies(mut self, ies: IesProfile) -> Self {
+        self.ies = Some(ies);
+        self
+    }
+
+    /// Integral of the relative intensity over all directions
+    fn solid_angle(&self) -> Scalar {
+        match &self.ies {
+            Some(ies) => ies.integral(),
+            // Full intensity inside the falloff angle, the penumbra's curve is linear in cos theta
+            None => {
+                let penumbra = (self.cos_falloff - self.cos_angle) * self.falloff_mode.integral();
+                2.0 * PI * (1.0 - self.cos_falloff + penumbra)
+            }
+        }
+    }
+
+    /// Relative intensity of light leaving in direction `w`
+    fn intensity(&self, w: Vec3) -> Scalar {
+        match &self.ies {
+            Some(ies) => ies.intensity(vec3(
+                w.dot(self.tangent),
+                w.dot(self.bitangent),
+                w.dot(self.direction),
+            )),
+            None => self.falloff(w.dot(self.direction)),
+        }
+    }
+
+    /// Maps a direction leaving the light to gobo coordinates, the cone's edge along the
+    /// tangent and bitangent is at 0 and 1
+    fn gobo_uv(&self, w: Vec3) -> Pt2 {
+        let angle = self.cos_angle.acos();
+        let along_axis = w.dot(self.direction);
+        let offset = |axis: Vec3| w.dot(axis).atan2(along_axis) / (2.0 * angle) + 0.5;
+        point2(offset(self.tangent), offset(self.bitangent))
+    }
+
     fn falloff(&self, cos_theta: Scalar) -> Scalar {
         if cos_theta < self.cos_angle {
             0.0
         } else if cos_theta > self.cos_falloff {
             1.0
         } else {
-            let delta = (cos_theta - self.cos_angle) / (self.cos_falloff - self.cos_angle);
-            delta.powi(4)
+            let t = (cos_theta - self.cos_angle) / (self.cos_falloff - self.cos_angle);
+            self.falloff_mode.apply(t)
         }
     }
 }
@@ -149,19 +404,31 @@ impl LightTrait for SpotLight {
         let to_light = self.position - intersection.point;
         let distance = to_light.magnitude();
         *wi = to_light / distance;
-        let cos_wi_dir = (-*wi).dot(self.direction);
-        if cos_wi_dir < self.cos_angle {
+        let intensity = self.intensity(-*wi);
+        if intensity <= 0.0 {
             *pdf = 0.0;
             BLACK
         } else {
             *pdf = 1.0;
-            self.radiance * self.falloff(cos_wi_dir) / (distance + 1.0).powi(2)
+            let radiance = self.radiance * intensity * self.attenuation.attenuate(distance);
+            match &self.gobo {
+                Some(gobo) => radiance * gobo.get(self.gobo_uv(-*wi)),
+                None => radiance,
+            }
         }
     }
 
     fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, _wi: Vec3) -> Scalar {
         0.0
     }
+
+    fn power(&self, _world_radius: Scalar) -> Scalar {
+        self.solid_angle() * color::luminance(self.radiance)
+    }
+
+    fn occlusion_distance(&self, ray: &Ray) -> Scalar {
+        ray.origin.distance(self.position)
+    }
 }
 
 #[derive(Debug)]
@@ -169,6 +436,12 @@ pub struct AmbientLight {
     pub radiance: Color,
 }
 
+impl AmbientLight {
+    pub fn new(color: Color) -> Self {
+        Self { radiance: color }
+    }
+}
+
 impl LightTrait for AmbientLight {
     fn kind(&self) -> LightKind {
         LightKind::INFINITE.set(LightKind::NO_BG)
@@ -192,6 +465,10 @@ impl LightTrait for AmbientLight {
     fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, _wi: Vec3) -> Scalar {
         1.0 / (4.0 * PI)
     }
+
+    fn power(&self, world_radius: Scalar) -> Scalar {
+        PI * world_radius * world_radius * color::luminance(self.radiance)
+    }
 }
 
 #[derive(Debug)]
@@ -200,6 +477,21 @@ pub struct DirectionLight {
     pub radiance: Color,
 }
 
+impl DirectionLight {
+    pub fn new(direction: Vec3, color: Color) -> Self {
+        Self {
+            direction: direction.normalize(),
+            radiance: color,
+        }
+    }
+
+    /// Scales the light so it delivers `irradiance` to a perpendicular surface
+    pub fn with_power(mut self, irradiance: Scalar) -> Self {
+        self.radiance *= irradiance;
+        self
+    }
+}
+
 impl LightTrait for DirectionLight {
     fn kind(&self) -> LightKind {
         LightKind::DELTA_DIRECTION
@@ -223,6 +515,10 @@ impl LightTrait for DirectionLight {
     fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, _wi: Vec3) -> Scalar {
         0.0
     }
+
+    fn power(&self, world_radius: Scalar) -> Scalar {
+        PI * world_radius * world_radius * color::luminance(self.radiance)
+    }
 }
 
 #[derive(Debug)]
@@ -231,13 +527,49 @@ pub struct AreaLight {
     pub position: Pt3,
     pub shape: Shape,
     pub radiance: Color,
+    /// Scales the radiance across the surface, looked up by the shape's uv
+    pub texture: Option<Texture<Color, Rgb8ColorPixelConverter>>,
+}
+
+impl AreaLight {
+    pub fn new(position: Pt3, rotation: Quaternion, shape: Shape, color: Color) -> Self {
+        assert!(shape.is_bounded(), "Area lights need a bounded shape");
+        Self {
+            rotation,
+            position,
+            shape,
+            radiance: color,
+            texture: None,
+        }
+    }
+
+    pub fn with_texture(mut self, texture: Texture<Color, Rgb8ColorPixelConverter>) -> Self {
+        self.texture = Some(texture);
+        self
+    }
+
+    /// Radiance emitted at `uv` on the surface
+    pub fn emission(&self, uv: Pt2) -> Color {
+        match &self.texture {
+            Some(texture) => self.radiance * texture.get(uv),
+            None => self.radiance,
+        }
+    }
+
+    fn intersect_self(&self, ray: &Ray) -> PossibleIntersection<'_, Color, AreaLight> {
+        self.shape.intersect(ray, self.transform(), self, self)
+    }
+
+    pub fn transform(&self) -> Transform {
+        Transform::new(self.rotation, self.position.to_vec())
+    }
 }
 
 impl Material for AreaLight {
     type Sampled = Color;
 
-    fn sample(&self, _uv: Pt2) -> Self::Sampled {
-        self.radiance
+    fn sample(&self, uv: Pt2) -> Self::Sampled {
+        self.emission(uv)
     }
 
     fn compute_scattering<'arena, O>(
@@ -245,6 +577,8 @@ impl Material for AreaLight {
         _arena: &'arena Bump,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
+        _outside_ior: Scalar,
+        _wavelength: Option<Wavelength>,
     ) -> BSDF<'arena> {
         panic!()
     }
@@ -255,22 +589,219 @@ impl LightTrait for AreaLight {
         LightKind::AREA
     }
 
-    fn le(&self, _wi: &Ray) -> Color {
-        self.radiance
+    fn le(&self, wi: &Ray) -> Color {
+        self.le_unoccluded(wi)
     }
 
     fn sample_li<M, O>(
         &self,
-        _intersection: &Intersection<M, O>,
-        _wi: &mut Vec3,
+        intersection: &Intersection<M, O>,
+        wi: &mut Vec3,
         pdf: &mut Scalar,
     ) -> Color {
-        *pdf = 0.0;
+        let (_, uv) = self
+            .shape
+            .sample_from(self.transform(), intersection.point, wi, pdf);
+        if *pdf == 0.0 {
+            BLACK
+        } else {
+            self.emission(uv)
+        }
+    }
+
+    fn pdf_li<M, O>(&self, intersection: &Intersection<M, O>, wi: Vec3) -> Scalar {
+        self.shape
+            .pdf_from(self.transform(), intersection.point, wi)
+    }
+
+    fn power(&self, _world_radius: Scalar) -> Scalar {
+        PI * self.shape.area() * color::luminance(self.emission(point2(0.5, 0.5)))
+    }
+
+    fn occlusion_distance(&self, ray: &Ray) -> Scalar {
+        match self.intersect_self(ray) {
+            PossibleIntersection::Hit(hit) => hit.distance,
+            _ => Scalar::INFINITY,
+        }
+    }
+
+    fn le_unoccluded(&self, ray: &Ray) -> Color {
+        match self.intersect_self(ray) {
+            PossibleIntersection::Hit(hit) => hit.sampled_material,
+            _ => BLACK,
+        }
+    }
+}
+
+impl Shape {
+    /// Samples a direction towards the shape placed by `transform` as seen from `reference`.
+    /// Returns the distance to the sampled point on the shape and its texture coordinate, `pdf`
+    /// is with respect to solid angle.
+    pub fn sample_from(
+        &self,
+        transform: Transform,
+        reference: Pt3,
+        wi: &mut Vec3,
+        pdf: &mut Scalar,
+    ) -> (Scalar, Pt2) {
+        let center = Pt3::from_vec(transform.translation);
+        match self {
+            Self::Sphere { radius } => {
+                let uv_towards = |wi: Vec3, distance: Scalar| {
+                    let normal = (reference + wi * distance - center) / *radius;
+                    sphere_uv(transform.to_local_vector(normal).normalize())
+                };
+                let dc2 = reference.distance2(center);
+                if dc2 <= radius * radius {
+                    // Inside the sphere, sample the whole surface uniformly
+                    let normal = random_unit_vec();
+                    let to_point = center + normal * *radius - reference;
+                    let distance = to_point.magnitude();
+                    if distance == 0.0 {
+                        *pdf = 0.0;
+                        return (0.0, point2(0.0, 0.0));
+                    }
+                    *wi = to_point / distance;
+                    let cos_light = normal.dot(-*wi).abs();
+                    *pdf = if cos_light == 0.0 {
+                        0.0
+                    } else {
+                        distance * distance / (cos_light * 4.0 * PI * radius * radius)
+                    };
+                    return (distance, uv_towards(*wi, distance));
+                }
+
+                // Sample uniformly within the cone subtended by the sphere
+                let dc = dc2.sqrt();
+                let wc = (center - reference) / dc;
+                let (wc_x, wc_y) = coordinate_system(wc);
+
+                let sin2_theta_max = radius * radius / dc2;
+                let cos_theta_max = (1.0 - sin2_theta_max).max(0.0).sqrt();
+
+                let u = scalar::rand();
+                let cos_theta = (1.0 - u) + u * cos_theta_max;
+                let sin2_theta = (1.0 - cos_theta * cos_theta).max(0.0);
+                let phi = scalar::rand() * 2.0 * PI;
+
+                *wi = (wc_x * sin2_theta.sqrt() * phi.cos()
+                    + wc_y * sin2_theta.sqrt() * phi.sin()
+                    + wc * cos_theta)
+                    .normalize();
+                *pdf = 1.0 / (2.0 * PI * (1.0 - cos_theta_max));
+
+                let distance =
+                    dc * cos_theta - (radius * radius - dc2 * sin2_theta).max(0.0).sqrt();
+                (distance, uv_towards(*wi, distance))
+            }
+            Self::Plane { .. } => unreachable!("unbounded shapes aren't sampled as lights"),
+            Self::Mesh(mesh) => {
+                let (point, normal, uv) = mesh.sample();
+                let to_point = transform.to_world_point(point) - reference;
+                let distance = to_point.magnitude();
+                if distance == 0.0 {
+                    *pdf = 0.0;
+                    return (0.0, uv);
+                }
+                *wi = to_point / distance;
+                let cos_light = transform.to_world_vector(normal).dot(-*wi).abs();
+                *pdf = if cos_light == 0.0 {
+                    0.0
+                } else {
+                    distance * distance / (cos_light * mesh.area())
+                };
+                (distance, uv)
+            }
+        }
+    }
+
+    /// Solid angle density of [`Shape::sample_from`] sampling `wi` from `reference`
+    pub fn pdf_from(&self, transform: Transform, reference: Pt3, wi: Vec3) -> Scalar {
+        let center = Pt3::from_vec(transform.translation);
+        match self {
+            Self::Sphere { radius } => {
+                let dc2 = reference.distance2(center);
+                if dc2 <= radius * radius {
+                    let oc = reference - center;
+                    let h = oc.dot(wi);
+                    let discriminant = h * h - (dc2 - radius * radius);
+                    let distance = -h + discriminant.max(0.0).sqrt();
+                    let normal = (reference + wi * distance - center) / *radius;
+                    let cos_light = normal.dot(-wi).abs();
+                    if cos_light == 0.0 {
+                        0.0
+                    } else {
+                        distance * distance / (cos_light * 4.0 * PI * radius * radius)
+                    }
+                } else {
+                    let cos_theta_max = (1.0 - radius * radius / dc2).max(0.0).sqrt();
+                    1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+                }
+            }
+            Self::Plane { .. } => unreachable!("unbounded shapes aren't sampled as lights"),
+            Self::Mesh(mesh) => mesh.pdf(&transform.to_local_ray(&Ray::new(reference, wi, 0.0))),
+        }
+    }
+}
+
+impl Object {
+    pub(crate) fn intersect_self(
+        &self,
+        ray: &Ray,
+    ) -> PossibleIntersection<'_, SampledDisneyMaterial, Object> {
+        self.shape
+            .intersect(ray, self.transform_at(ray.time), &self.material, self)
+    }
+}
+
+/// Objects with an emissive material are sampled as area lights
+impl LightTrait for Object {
+    fn kind(&self) -> LightKind {
+        LightKind::AREA
+    }
+
+    fn le(&self, _wi: &Ray) -> Color {
         BLACK
     }
 
-    fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, _wi: Vec3) -> Scalar {
-        0.0
+    fn sample_li<M, O>(
+        &self,
+        intersection: &Intersection<M, O>,
+        wi: &mut Vec3,
+        pdf: &mut Scalar,
+    ) -> Color {
+        let transform = self.transform_at(intersection.time);
+        let (_, uv) = self
+            .shape
+            .sample_from(transform, intersection.point, wi, pdf);
+        if *pdf == 0.0 {
+            return BLACK;
+        }
+        self.material.emission.get(uv) * self.material.emission_strength
+    }
+
+    fn pdf_li<M, O>(&self, intersection: &Intersection<M, O>, wi: Vec3) -> Scalar {
+        self.shape
+            .pdf_from(self.transform_at(intersection.time), intersection.point, wi)
+    }
+
+    fn power(&self, _world_radius: Scalar) -> Scalar {
+        let emission = self.material.emission.get(point2(0.5, 0.5));
+        PI * self.shape.area() * color::luminance(emission) * self.material.emission_strength
+    }
+
+    fn occlusion_distance(&self, ray: &Ray) -> Scalar {
+        match self.intersect_self(ray) {
+            PossibleIntersection::Hit(hit) => hit.distance,
+            _ => Scalar::INFINITY,
+        }
+    }
+
+    fn le_unoccluded(&self, ray: &Ray) -> Color {
+        match self.intersect_self(ray) {
+            PossibleIntersection::Hit(hit) => hit.sampled_material.emission,
+            _ => BLACK,
+        }
     }
 }
 
@@ -280,10 +811,33 @@ pub enum Light {
     Spot(SpotLight),
     Direction(DirectionLight),
     Hdri(Hdri),
+    CubeMap(CubeMapLight),
     Area(AreaLight),
     Ambient(AmbientLight),
 }
 
+macro_rules! light_from_impl {
+    ($($variant: ident($light: ty)),*) => {
+        $(
+            impl From<$light> for Light {
+                fn from(light: $light) -> Self {
+                    Light::$variant(light)
+                }
+            }
+        )*
+    };
+}
+
+light_from_impl!(
+    Point(PointLight),
+    Spot(SpotLight),
+    Direction(DirectionLight),
+    Hdri(Hdri),
+    CubeMap(CubeMapLight),
+    Area(AreaLight),
+    Ambient(AmbientLight)
+);
+
 macro_rules! indirect_light_trait {
     ($self:expr, $fn_name:ident ( $($args: expr),* ) ) => {
         match $self {
@@ -291,6 +845,7 @@ macro_rules! indirect_light_trait {
             Light::Spot(light) => light.$fn_name($($args),*),
             Light::Direction(light) => light.$fn_name($($args),*),
             Light::Hdri(light) => light.$fn_name($($args),*),
+            Light::CubeMap(light) => light.$fn_name($($args),*),
             Light::Area(light) => light.$fn_name($($args),*),
             Light::Ambient(light) => light.$fn_name($($args),*),
         }
@@ -318,89 +873,466 @@ impl LightTrait for Light {
     fn pdf_li<M, O>(&self, intersection: &Intersection<M, O>, wi: Vec3) -> Scalar {
         indirect_light_trait!(self, pdf_li(intersection, wi))
     }
+
+    fn power(&self, world_radius: Scalar) -> Scalar {
+        indirect_light_trait!(self, power(world_radius))
+    }
+
+    fn occlusion_distance(&self, ray: &Ray) -> Scalar {
+        indirect_light_trait!(self, occlusion_distance(ray))
+    }
+
+    fn le_unoccluded(&self, ray: &Ray) -> Color {
+        indirect_light_trait!(self, le_unoccluded(ray))
+    }
+
+    fn le_filtered(&self, ray: &Ray, footprint: Scalar) -> Color {
+        indirect_light_trait!(self, le_filtered(ray, footprint))
+    }
 }
 
-pub fn sample_one_light<M, O>(
+/// How direct lighting picks lights at each intersection
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightSampling {
+    /// One light chosen uniformly at random
+    UniformOne,
+    /// Every light, useful for scenes with few lights
+    All,
+    /// One light chosen in proportion to its approximate power, so dim lights don't take samples
+    /// away from the ones dominating the scene
+    #[default]
+    Power,
+}
+
+/// Which halves of the MIS estimate light sampling at a surface takes
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LightSamplingMode {
+    /// Both the light and the BSDF sampled half of every light
+    Full,
+    /// Lights the path can escape to are only light sampled, see [`sample_lights_for_path`]
+    Path,
+    /// [`LightSamplingMode::Full`] as if nothing in the scene cast shadows
+    Unoccluded,
+}
+
+impl LightSamplingMode {
+    fn occluded(self) -> bool {
+        self != LightSamplingMode::Unoccluded
+    }
+}
+
+/// Most light groups a scene can have
+pub const MAX_LIGHT_GROUPS: usize = 8;
+/// Group slot of light that isn't in a group
+pub const UNGROUPED: usize = 0;
+
+/// Light split by the group of the light it came from. Slot 0 holds the light that isn't in a
+/// group: ungrouped lights, emissive objects and the background. The scene's `i`th group is slot
+/// `i + 1`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GroupedLight([Color; MAX_LIGHT_GROUPS + 1]);
+
+impl GroupedLight {
+    /// `light` in the group slot `group`
+    pub fn single(group: usize, light: Color) -> Self {
+        let mut grouped = GroupedLight::default();
+        grouped.0[group] = light;
+        grouped
+    }
+
+    pub fn add(&mut self, group: usize, light: Color) {
+        self.0[group] += light;
+    }
+
+    pub fn group(&self, group: usize) -> Color {
+        self.0[group]
+    }
+
+    pub fn total(&self) -> Color {
+        self.0.iter().copied().sum()
+    }
+}
+
+impl AddAssign for GroupedLight {
+    fn add_assign(&mut self, rhs: GroupedLight) {
+        for (light, rhs) in self.0.iter_mut().zip(rhs.0) {
+            *light += rhs;
+        }
+    }
+}
+
+impl Mul<Color> for GroupedLight {
+    type Output = GroupedLight;
+
+    fn mul(self, rhs: Color) -> GroupedLight {
+        GroupedLight(self.0.map(|light| light * rhs))
+    }
+}
+
+impl Mul<Scalar> for GroupedLight {
+    type Output = GroupedLight;
+
+    fn mul(self, rhs: Scalar) -> GroupedLight {
+        GroupedLight(self.0.map(|light| light * rhs))
+    }
+}
+
+impl Div<Scalar> for GroupedLight {
+    type Output = GroupedLight;
+
+    fn div(self, rhs: Scalar) -> GroupedLight {
+        GroupedLight(self.0.map(|light| light / rhs))
+    }
+}
+
+/// Estimates direct lighting at `intersection` using the scene's light sampling strategy
+pub fn sample_lights<M, O>(
     ray: &Ray,
     intersection: &Intersection<M, O>,
     bsdf: &BSDF,
     scene: &Scene,
+    stats: &RayStats,
 ) -> Color {
-    let num_lights = scene.lights.iter().filter(|light| !light.is_area()).count();
+    sample_lights_by_group(
+        ray,
+        intersection,
+        bsdf,
+        scene,
+        stats,
+        LightSamplingMode::Full,
+    )
+    .total()
+}
 
-    if num_lights == 0 {
-        return BLACK;
+/// [`sample_lights`] split by the groups of the sampled lights, with the halves of the estimate
+/// `mode` asks for
+pub fn sample_lights_by_group<M, O>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    mode: LightSamplingMode,
+) -> GroupedLight {
+    match scene.light_sampling {
+        LightSampling::UniformOne => sample_one_light(ray, intersection, bsdf, scene, stats, mode),
+        LightSampling::All => sample_all_lights(ray, intersection, bsdf, scene, stats, mode),
+        LightSampling::Power => sample_light_by_power(ray, intersection, bsdf, scene, stats, mode),
     }
+}
 
-    let light = scene
-        .lights
-        .iter()
-        .filter(|light| !light.is_area())
-        .nth(fastrand::usize(..num_lights))
-        .unwrap();
-    let pdf_scale = 1.0 / scene.lights.len() as Scalar;
-
-    estimate_direct(ray, intersection, light, bsdf, scene, false) / pdf_scale
+/// Whether paths that escape the scene see the light. Lights without a background are never
+/// hit, so their estimates always take the BSDF sample themselves.
+fn is_escape_sampled(light: &Light) -> bool {
+    let kind = light.kind();
+    kind.has(LightKind::INFINITE) && !kind.has(LightKind::NO_BG)
 }
 
-pub fn estimate_direct<M, O>(
+/// Like [`sample_lights`], but as if nothing in the scene cast shadows. Draws the same random
+/// numbers, so with the same seed the two estimates use the same light samples.
+pub fn sample_lights_unoccluded<M, O>(
     ray: &Ray,
     intersection: &Intersection<M, O>,
-    light: &Light,
     bsdf: &BSDF,
     scene: &Scene,
-    specular: bool,
+    stats: &RayStats,
 ) -> Color {
-    let mut ld = BLACK;
+    sample_lights_by_group(
+        ray,
+        intersection,
+        bsdf,
+        scene,
+        stats,
+        LightSamplingMode::Unoccluded,
+    )
+    .total()
+}
+
+/// Like [`sample_lights_by_group`], but lights the path can escape to are only light sampled.
+/// Their BSDF sampled half is the path's next ray, which adds [`escaped_light`] if it escapes, so
+/// each direction is counted once.
+pub fn sample_lights_for_path<M, O>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+) -> GroupedLight {
+    sample_lights_by_group(
+        ray,
+        intersection,
+        bsdf,
+        scene,
+        stats,
+        LightSamplingMode::Path,
+    )
+}
 
-    let mut scattering_pdf = 0.0;
+/// Radiance of the lights a path ray escaping the scene sees, MIS weighted against the light
+/// samples [`sample_lights_for_path`] took at the vertex the ray left. `bsdf_pdf` is the density
+/// the vertex's BSDF sampled the ray's direction with.
+pub fn escaped_light(ray: &Ray, bsdf_pdf: Scalar, scene: &Scene) -> GroupedLight {
+    let mut le = GroupedLight::default();
+    for (index, light) in scene.sampled_lights().enumerate() {
+        if is_escape_sampled(light) {
+            // Infinite lights are sampled the same from every point
+            let light_pdf = light.pdf_li(&Intersection::dummy(), ray.direction);
+            le.add(
+                scene.candidate_group(index),
+                light.le_filtered(ray, lobe_footprint(bsdf_pdf))
+                    * power_heuristic(1.0, bsdf_pdf, 1.0, light_pdf),
+            );
+        }
+    }
+    le
+}
 
-    let mut wi = Vec3::zero();
-    let mut light_pdf = 0.0;
-    let li = light.sample_li(intersection, &mut wi, &mut light_pdf);
+/// [`escaped_light`] for a ray the path didn't trace because it reached the bounce limit
+pub fn escaped_light_unless_occluded(
+    ray: &Ray,
+    bsdf_pdf: Scalar,
+    scene: &Scene,
+    stats: &RayStats,
+) -> GroupedLight {
+    let le = escaped_light(ray, bsdf_pdf, scene);
+    if le.total() == BLACK {
+        return le;
+    }
+    le * shadow_transmittance(scene, stats, ray, Scalar::INFINITY, true)
+}
 
-    let bxdf_kind = if specular {
-        BxDFKind::ALL
+/// Estimates direct lighting from the `index`th light candidate, in the candidate's group.
+/// Candidates are the sampled lights of the scene followed by its emissive objects.
+fn estimate_candidate<M, O>(
+    index: usize,
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    mode: LightSamplingMode,
+) -> GroupedLight {
+    let num_lights = scene.sampled_lights().len();
+    let ld = if index < num_lights {
+        let light = scene.sampled_lights().nth(index).unwrap();
+        if mode == LightSamplingMode::Path && is_escape_sampled(light) {
+            estimate_light_sample(ray, intersection, light, bsdf, scene, stats)
+        } else {
+            estimate_direct_with(
+                ray,
+                intersection,
+                light,
+                bsdf,
+                scene,
+                stats,
+                false,
+                mode.occluded(),
+            )
+        }
     } else {
-        BxDFKind::ALL.unset(BxDFKind::SPECULAR)
+        let object = scene.emissive_objects().nth(index - num_lights).unwrap();
+        estimate_direct_with(
+            ray,
+            intersection,
+            object,
+            bsdf,
+            scene,
+            stats,
+            false,
+            mode.occluded(),
+        )
     };
+    GroupedLight::single(scene.candidate_group(index), ld)
+}
 
-    if light_pdf > 0.0 && li != BLACK {
-        // TODO: handle medium interactions
+fn num_light_candidates(scene: &Scene) -> usize {
+    scene.sampled_lights().len() + scene.emissive_objects().len()
+}
 
-        let inter_to_light = Ray::new(intersection.point, wi, ray.time);
-        if scene.intersect(&inter_to_light).is_miss() {
-            let f = bsdf.f(-ray.direction, wi, bxdf_kind);
-            let f = f * wi.dot(intersection.normal).abs();
-            scattering_pdf = bsdf.pdf(-ray.direction, wi, bxdf_kind);
+pub fn sample_one_light<M, O>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    mode: LightSamplingMode,
+) -> GroupedLight {
+    let num_candidates = num_light_candidates(scene);
+    if num_candidates == 0 {
+        return GroupedLight::default();
+    }
 
-            if f != BLACK {
-                if light.is_delta() {
-                    ld.add_assign_element_wise(f.mul_element_wise(li) / light_pdf);
-                } else {
-                    let weight = power_heuristic(1.0, light_pdf, 1.0, scattering_pdf);
-                    ld.add_assign_element_wise(f.mul_element_wise(li) * weight / light_pdf);
-
-                    debugger::ray_debug! {
-                        f,
-                        wi,
-                        -ray.direction,
-                        (-ray.direction).dot(wi),
-                        wi.dot(intersection.normal),
-                        li,
-                        ld,
-                        weight,
-                        light_pdf,
-                        scattering_pdf
-                    }
-                }
-            }
+    let pdf = 1.0 / num_candidates as Scalar;
+    let index = fastrand::usize(..num_candidates);
+    estimate_candidate(index, ray, intersection, bsdf, scene, stats, mode) / pdf
+}
+
+pub fn sample_all_lights<M, O>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    mode: LightSamplingMode,
+) -> GroupedLight {
+    let mut ld = GroupedLight::default();
+    for index in 0..num_light_candidates(scene) {
+        ld += estimate_candidate(index, ray, intersection, bsdf, scene, stats, mode);
+    }
+    ld
+}
+
+pub fn sample_light_by_power<M, O>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    mode: LightSamplingMode,
+) -> GroupedLight {
+    let distribution = scene.light_power_distribution();
+    if distribution.count() == 0 {
+        return GroupedLight::default();
+    }
+
+    let (index, _) = distribution.sample_discrete(scalar::rand());
+    let pdf = distribution.discrete_pdf(index);
+    if pdf == 0.0 {
+        return GroupedLight::default();
+    }
+    estimate_candidate(index, ray, intersection, bsdf, scene, stats, mode) / pdf
+}
+
+/// Shadow rays stop slightly short of the light so its own surface doesn't occlude it
+const SHADOW_RAY_SHORTEN: Scalar = 1.0 - 1e-4;
+
+/// Traces a shadow ray, returning the fraction of light that reaches `max_distance`. Only the
+/// scene's medium attenuates it unless `occluded`.
+fn shadow_transmittance(
+    scene: &Scene,
+    stats: &RayStats,
+    ray: &Ray,
+    max_distance: Scalar,
+    occluded: bool,
+) -> Color {
+    let transmittance = if !occluded {
+        WHITE
+    } else {
+        stats.add_shadow_ray();
+        stats::stats_count!(shadow_rays);
+        stats::stats_time!(
+            intersection_ns,
+            scene.transmittance_along(ray, max_distance)
+        )
+    };
+    match &scene.medium {
+        Some(medium) if transmittance != BLACK => {
+            transmittance * medium.transmittance(max_distance)
         }
+        _ => transmittance,
     }
+}
+
+/// Estimates direct lighting at a scattering event inside the scene's medium from one
+/// uniformly chosen light. Only the light is sampled, so paths scattered in the medium must
+/// not add the emission of objects they hit afterwards.
+pub fn sample_one_light_in_medium(
+    ray: &Ray,
+    point: Pt3,
+    phase: &impl PhaseFunction,
+    scene: &Scene,
+    stats: &RayStats,
+) -> GroupedLight {
+    let num_candidates = num_light_candidates(scene);
+    if num_candidates == 0 {
+        return GroupedLight::default();
+    }
+
+    let pdf = 1.0 / num_candidates as Scalar;
+    let index = fastrand::usize(..num_candidates);
+    let num_lights = scene.sampled_lights().len();
+    let ld = if index < num_lights {
+        let light = scene.sampled_lights().nth(index).unwrap();
+        estimate_direct_in_medium(ray, point, light, phase, scene, stats)
+    } else {
+        let object = scene.emissive_objects().nth(index - num_lights).unwrap();
+        estimate_direct_in_medium(ray, point, object, phase, scene, stats)
+    };
+    GroupedLight::single(scene.candidate_group(index), ld / pdf)
+}
+
+fn estimate_direct_in_medium<L: LightTrait>(
+    ray: &Ray,
+    point: Pt3,
+    light: &L,
+    phase: &impl PhaseFunction,
+    scene: &Scene,
+    stats: &RayStats,
+) -> Color {
+    let reference = Intersection {
+        point,
+        time: ray.time,
+        ..Intersection::dummy()
+    };
+    let mut wi = Vec3::zero();
+    let mut light_pdf = 0.0;
+    let li = light.sample_li(&reference, &mut wi, &mut light_pdf);
+    if light_pdf == 0.0 || li == BLACK {
+        return BLACK;
+    }
+
+    let p = phase.p(-ray.direction, wi);
+    let to_light = Ray::new(point, wi, ray.time);
+    let max_distance = light.occlusion_distance(&to_light) * SHADOW_RAY_SHORTEN;
+    let transmittance = shadow_transmittance(scene, stats, &to_light, max_distance, true);
+    li * transmittance * p / light_pdf
+}
+
+pub fn estimate_direct<M, O, L: LightTrait>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    light: &L,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    specular: bool,
+) -> Color {
+    estimate_direct_with(ray, intersection, light, bsdf, scene, stats, specular, true)
+}
 
-    // TODO: handle medium interactions
+/// [`estimate_direct`], ignoring what blocks the light unless `occluded`
+#[allow(clippy::too_many_arguments)]
+fn estimate_direct_with<M, O, L: LightTrait>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    light: &L,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    specular: bool,
+    occluded: bool,
+) -> Color {
+    let bxdf_kind = if specular {
+        BxDFKind::ALL
+    } else {
+        BxDFKind::ALL.unset(BxDFKind::SPECULAR)
+    };
+    let mut ld = sample_light(
+        ray,
+        intersection,
+        light,
+        bsdf,
+        scene,
+        stats,
+        bxdf_kind,
+        bxdf_kind,
+        occluded,
+    );
 
     if !light.is_delta() {
+        let mut wi = Vec3::zero();
+        let mut scattering_pdf = 0.0;
         let mut sampled_kind = BxDFKind::ALL;
 
         let f = bsdf.sample_f(
@@ -414,31 +1346,43 @@ pub fn estimate_direct<M, O>(
         let sampled_specular = sampled_kind.has(BxDFKind::SPECULAR);
 
         if f != BLACK && scattering_pdf > 0.0 {
+            // Directions the light can't sample get the full weight
             let weight = if sampled_specular {
                 1.0
             } else {
                 let light_pdf = light.pdf_li(intersection, wi);
-                if light_pdf == 0.0 {
-                    return ld;
-                }
                 power_heuristic(1.0, scattering_pdf, 1.0, light_pdf)
             };
 
-            let ray = Ray::new(intersection.point, wi, ray.time);
+            let origin = offset_ray_origin(
+                intersection.point,
+                intersection.error,
+                intersection.normal,
+                wi,
+            );
+            let ray = Ray::new(origin, wi, ray.time);
 
-            if scene.intersect(&ray).is_miss() {
-                let li = light.le(&ray);
-                if li != BLACK {
-                    ld.add_assign_element_wise(f.mul_element_wise(li) * weight / scattering_pdf);
-
-                    debugger::ray_debug! {
-                        f,
-                        wi,
-                        -ray.direction,
-                        wi.dot(intersection.normal),
-                        li,
-                        ld
-                    }
+            let li = if light.kind().has(LightKind::INFINITE) && !sampled_specular {
+                light.le_filtered(&ray, lobe_footprint(scattering_pdf))
+            } else {
+                light.le_unoccluded(&ray)
+            };
+            let max_distance = light.occlusion_distance(&ray) * SHADOW_RAY_SHORTEN;
+            let li = if li != BLACK {
+                li * shadow_transmittance(scene, stats, &ray, max_distance, occluded)
+            } else {
+                BLACK
+            };
+            if li != BLACK {
+                ld += f * li * weight / scattering_pdf;
+
+                debugger::ray_debug! {
+                    f,
+                    wi,
+                    -ray.direction,
+                    wi.dot(intersection.normal),
+                    li,
+                    ld
                 }
             }
         }
@@ -446,3 +1390,585 @@ pub fn estimate_direct<M, O>(
 
     ld
 }
+
+/// The light sampled half of [`estimate_direct`] for a light the path samples the BSDF of with
+/// its next ray, see [`sample_lights_for_path`]. Paths sample every lobe, so the weight is
+/// against the density of all of them.
+fn estimate_light_sample<M, O, L: LightTrait>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    light: &L,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+) -> Color {
+    let bxdf_kind = BxDFKind::ALL.unset(BxDFKind::SPECULAR);
+    sample_light(
+        ray,
+        intersection,
+        light,
+        bsdf,
+        scene,
+        stats,
+        bxdf_kind,
+        BxDFKind::ALL,
+        true,
+    )
+}
+
+/// Samples a direction towards `light` and evaluates the `bxdf_kind` lobes for it, MIS weighted
+/// against the density of sampling it from the `pdf_kind` lobes. Shadows are ignored unless
+/// `occluded`.
+#[allow(clippy::too_many_arguments)]
+fn sample_light<M, O, L: LightTrait>(
+    ray: &Ray,
+    intersection: &Intersection<M, O>,
+    light: &L,
+    bsdf: &BSDF,
+    scene: &Scene,
+    stats: &RayStats,
+    bxdf_kind: BxDFKind,
+    pdf_kind: BxDFKind,
+    occluded: bool,
+) -> Color {
+    let mut wi = Vec3::zero();
+    let mut light_pdf = 0.0;
+    let li = light.sample_li(intersection, &mut wi, &mut light_pdf);
+    if light_pdf == 0.0 || li == BLACK {
+        return BLACK;
+    }
+
+    let origin = offset_ray_origin(
+        intersection.point,
+        intersection.error,
+        intersection.normal,
+        wi,
+    );
+    let inter_to_light = Ray::new(origin, wi, ray.time);
+    let max_distance = light.occlusion_distance(&inter_to_light) * SHADOW_RAY_SHORTEN;
+    let transmittance = shadow_transmittance(scene, stats, &inter_to_light, max_distance, occluded);
+    if transmittance == BLACK {
+        return BLACK;
+    }
+
+    let li = li * transmittance;
+    let f = bsdf.f(-ray.direction, wi, bxdf_kind);
+    let f = f * wi.dot(intersection.normal).abs();
+    if f == BLACK {
+        return BLACK;
+    }
+    if light.is_delta() {
+        return f * li / light_pdf;
+    }
+
+    let scattering_pdf = bsdf.pdf(-ray.direction, wi, pdf_kind);
+    let weight = power_heuristic(1.0, light_pdf, 1.0, scattering_pdf);
+    // Filtered like the BSDF sample of the same direction would be
+    let li = if light.kind().has(LightKind::INFINITE) {
+        light.le_filtered(&inter_to_light, lobe_footprint(scattering_pdf)) * transmittance
+    } else {
+        li
+    };
+    let ld = f * li * weight / light_pdf;
+
+    debugger::ray_debug! {
+        f,
+        wi,
+        -ray.direction,
+        (-ray.direction).dot(wi),
+        wi.dot(intersection.normal),
+        li,
+        ld,
+        weight,
+        light_pdf,
+        scattering_pdf
+    }
+    ld
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bxdf::Lambertian;
+    use crate::scene::{CameraBuilder, MaterialBuilder, SceneBuilder};
+    use crate::types::color::WHITE;
+    use cgmath::{assert_abs_diff_eq, point2, point3, vec3, Zero};
+
+    #[test]
+    fn point_light_power_irradiance() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .build();
+        let (si, bsdf, ray) = lambertian_patch(point3(0.0, 0.0, 0.0));
+
+        let power = 100.0;
+        for distance in [0.5, 2.0, 10.0] {
+            let light = PointLight::new(point3(0.0, distance, 0.0), WHITE).with_power(power);
+            let ld = estimate_direct(&ray, &si, &light, &bsdf, &scene, &RayStats::new(), false);
+
+            // Lambertian with albedo 1 reflects E / PI
+            let irradiance = power / (4.0 * PI * distance * distance);
+            assert_abs_diff_eq!(ld.r, irradiance / PI, epsilon = 1e-4 * irradiance);
+        }
+
+        let light =
+            PointLight::new(point3(0.0, 2.0, 0.0), WHITE).with_attenuation(Attenuation::Legacy);
+        let ld = estimate_direct(&ray, &si, &light, &bsdf, &scene, &RayStats::new(), false);
+        assert_abs_diff_eq!(ld.r, 1.0 / (9.0 * PI), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn ies_profile_shapes_point_and_spot_lights() {
+        // Full intensity straight down, falling off linearly to nothing at 60 degrees
+        let ies =
+            IesProfile::parse("TILT=NONE\n1 -1 1 3 1 1 1 0 0 0\n1 1 0\n0 60 180\n0\n800 0 0\n")
+                .unwrap();
+        let intensity = |light: &dyn Fn(&Intersection<(), ()>) -> Color, point| {
+            let si = Intersection {
+                point,
+                ..Intersection::dummy()
+            };
+            light(&si).r
+        };
+
+        let point = PointLight::new(point3(0.0, 2.0, 0.0), WHITE)
+            .with_ies(ies.clone())
+            .with_power(100.0);
+        assert_abs_diff_eq!(point.power(1.0), 100.0, epsilon = 1e-3);
+        let point_li = |si: &Intersection<(), ()>| {
+            let (mut wi, mut pdf) = (Vec3::zero(), 0.0);
+            point.sample_li(si, &mut wi, &mut pdf)
+        };
+        let below = intensity(&point_li, point3(0.0, 0.0, 0.0));
+        assert_abs_diff_eq!(below, 100.0 / ies.integral() / 4.0, epsilon = 1e-3);
+        // 45 degrees off the nadir is a quarter of the way from 60 degrees
+        let off_axis = intensity(&point_li, point3(2.0, 0.0, 0.0));
+        assert_abs_diff_eq!(off_axis, below / 8.0, epsilon = 1e-3 * below);
+        assert_eq!(intensity(&point_li, point3(0.0, 4.0, 0.0)), 0.0);
+
+        // The profile follows the spot axis and replaces the cone
+        let spot = SpotLight::new(point3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), 10.0, 5.0, WHITE)
+            .with_ies(ies);
+        let spot_li = |si: &Intersection<(), ()>| {
+            let (mut wi, mut pdf) = (Vec3::zero(), 0.0);
+            spot.sample_li(si, &mut wi, &mut pdf)
+        };
+        assert_abs_diff_eq!(intensity(&spot_li, point3(1.0, 0.0, 0.0)), 1.0);
+        assert_abs_diff_eq!(
+            intensity(&spot_li, point3(1.0, 0.0, 1.0)),
+            0.25 / 2.0,
+            epsilon = 1e-4
+        );
+        assert_eq!(intensity(&spot_li, point3(0.0, -1.0, 0.0)), 0.0);
+    }
+
+    #[test]
+    fn smoothstep_spot_penumbra() {
+        let spot = SpotLight::new(
+            point3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            30.0,
+            20.0,
+            WHITE,
+        );
+        assert_eq!(spot.falloff_mode, SpotFalloff::Smoothstep);
+        let intensity = |spot: &SpotLight, degrees: Scalar| {
+            let (sin, cos) = degrees.to_radians().sin_cos();
+            spot.intensity(vec3(sin, 0.0, cos))
+        };
+
+        let across = (0..=400)
+            .map(|i| intensity(&spot, i as Scalar * 0.1))
+            .collect::<Vec<_>>();
+        assert_eq!(across[0], 1.0);
+        assert_eq!(across[400], 0.0);
+        assert!(across.windows(2).all(|pair| pair[1] <= pair[0]));
+
+        // The slopes on either side of the penumbra's ends match, unlike a quartic's at the
+        // falloff angle
+        let slopes = |spot: &SpotLight, degrees: Scalar| {
+            let h = 0.01;
+            let at = intensity(spot, degrees);
+            (
+                (at - intensity(spot, degrees - h)) / h,
+                (intensity(spot, degrees + h) - at) / h,
+            )
+        };
+        for edge in [20.0, 30.0] {
+            let (inside, outside) = slopes(&spot, edge);
+            assert_abs_diff_eq!(inside, outside, epsilon = 2e-3);
+        }
+        let (_, middle) = slopes(&spot, 25.0);
+        assert!(middle < -0.1);
+        let quartic = SpotLight::new(
+            point3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            30.0,
+            20.0,
+            WHITE,
+        )
+        .with_falloff_mode(SpotFalloff::Quartic);
+        let (inside, outside) = slopes(&quartic, 20.0);
+        assert!(inside - outside > 0.1);
+
+        // Power normalization integrates the curve
+        for mode in [
+            SpotFalloff::Quartic,
+            SpotFalloff::Smoothstep,
+            SpotFalloff::Linear,
+        ] {
+            let spot = SpotLight::new(
+                point3(0.0, 0.0, 0.0),
+                vec3(0.0, 0.0, 1.0),
+                30.0,
+                20.0,
+                WHITE,
+            )
+            .with_falloff_mode(mode);
+            const N: usize = 2000;
+            let solid_angle = (0..N)
+                .map(|i| {
+                    let cos_theta = 1.0 - (i as Scalar + 0.5) / N as Scalar;
+                    spot.falloff(cos_theta) * 2.0 * PI / N as Scalar
+                })
+                .sum::<Scalar>();
+            assert_abs_diff_eq!(solid_angle, spot.solid_angle(), epsilon = 1e-3);
+        }
+    }
+
+    /// A white Lambertian patch at `point` facing +y, seen from above
+    fn lambertian_patch(point: Pt3) -> (Intersection<'static, (), ()>, BSDF<'static>, Ray) {
+        let si = Intersection {
+            distance: 1.0,
+            normal: vec3(0.0, 1.0, 0.0),
+            tangent: vec3(1.0, 0.0, 0.0),
+            point,
+            error: 0.0,
+            front_face: true,
+            sampled_material: (),
+            object: &(),
+            uv: point2(0.0, 0.0),
+            time: 0.0,
+        };
+        let mut bsdf = BSDF::new(&si);
+        bsdf.add(&Lambertian(WHITE));
+        let ray = Ray::new(point + vec3(0.0, 1.0, -1.0), vec3(0.0, -1.0, 1.0), 0.0);
+        (si, bsdf, ray)
+    }
+
+    /// Averages `sample_lights` on a white Lambertian patch at the origin facing +y
+    fn mean_direct_lighting(scene: &Scene, samples: usize) -> Color {
+        let (si, bsdf, ray) = lambertian_patch(point3(0.0, 0.0, 0.0));
+
+        let stats = RayStats::new();
+        let total = (0..samples).fold(BLACK, |total, _| {
+            total + sample_lights(&ray, &si, &bsdf, scene, &stats)
+        });
+        total / samples as Scalar
+    }
+
+    fn two_light_scene(light_sampling: LightSampling) -> Scene {
+        SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_light(PointLight::new(point3(1.0, 2.0, 0.0), WHITE).with_power(50.0))
+            .add_light(PointLight::new(point3(-1.0, 1.0, 1.0), WHITE).with_power(5.0))
+            .light_sampling(light_sampling)
+            .build()
+    }
+
+    #[test]
+    fn light_sampling_strategies_converge() {
+        fastrand::seed(3);
+        let all = mean_direct_lighting(&two_light_scene(LightSampling::All), 1);
+        assert_eq!(LightSampling::default(), LightSampling::Power);
+        for strategy in [LightSampling::UniformOne, LightSampling::Power] {
+            let mean = mean_direct_lighting(&two_light_scene(strategy), 20_000);
+            assert_abs_diff_eq!(mean, all, epsilon = 0.02 * all.r);
+        }
+    }
+
+    #[test]
+    fn uniform_one_matches_sampling_all_lights() {
+        fastrand::seed(5);
+        let hdri = Hdri::new(
+            image::Rgb32FImage::from_pixel(16, 8, image::Rgb([0.5, 0.5, 0.5])),
+            1.0,
+        );
+        let scene = |light_sampling| {
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_light(PointLight::new(point3(0.0, 2.0, 0.0), WHITE).with_power(20.0))
+                .add_light(Hdri::new(hdri.image.clone(), 1.0))
+                // Below the patch, so it is sampled but never contributes
+                .add_light(AreaLight::new(
+                    point3(0.0, -5.0, 0.0),
+                    Quaternion::zero(),
+                    Shape::Sphere { radius: 1.0 },
+                    WHITE,
+                ))
+                .light_sampling(light_sampling)
+                .build()
+        };
+
+        let all = mean_direct_lighting(&scene(LightSampling::All), 20_000);
+        let one = mean_direct_lighting(&scene(LightSampling::UniformOne), 40_000);
+        assert_abs_diff_eq!(one, all, epsilon = 0.03 * all.r);
+
+        // Constant environment of radiance L gives E = PI * L on the patch
+        let point_only = 20.0 / (4.0 * PI * 4.0) / PI;
+        assert_abs_diff_eq!(all.r, point_only + 0.5, epsilon = 0.02);
+    }
+
+    #[test]
+    fn sphere_area_light_matches_irradiance() {
+        fastrand::seed(9);
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_light(AreaLight::new(
+                point3(0.0, 3.0, 0.0),
+                Quaternion::zero(),
+                Shape::Sphere { radius: 1.0 },
+                WHITE,
+            ))
+            .build();
+
+        // A sphere of radiance L subtending a cone of half angle theta above the patch gives
+        // E = PI * L * sin^2(theta), and a white lambertian patch reflects E / PI
+        let mean = mean_direct_lighting(&scene, 20_000);
+        assert_abs_diff_eq!(mean.r, 1.0 / 9.0, epsilon = 0.01);
+    }
+
+    #[test]
+    fn textured_area_light() {
+        fastrand::seed(9);
+        // The sphere's u runs from the +y pole to the -y pole, so the first column covers the
+        // upper half and the second the lower half
+        let light = |upper: u8, lower: u8| {
+            let image = image::RgbImage::from_fn(2, 1, |x, _| {
+                image::Rgb([if x == 0 { upper } else { lower }; 3])
+            });
+            AreaLight::new(
+                point3(0.0, 3.0, 0.0),
+                Quaternion::zero(),
+                Shape::Sphere { radius: 1.0 },
+                WHITE,
+            )
+            .with_texture(Texture::Image(image))
+        };
+        let direct = |light| {
+            let scene = SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_light(light)
+                .build();
+            mean_direct_lighting(&scene, 20_000)
+        };
+
+        // Only the lower half faces the patch
+        assert_eq!(direct(light(255, 0)), BLACK);
+        assert_abs_diff_eq!(direct(light(0, 255)).r, 1.0 / 9.0, epsilon = 0.01);
+
+        let up = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), 0.0);
+        let down = Ray::new(point3(0.0, 6.0, 0.0), vec3(0.0, -1.0, 0.0), 0.0);
+        assert_eq!(light(255, 0).le(&up), BLACK);
+        assert_eq!(light(255, 0).le(&down), WHITE);
+    }
+
+    #[test]
+    fn objects_behind_point_light_do_not_shadow() {
+        let sphere = |y, radius| {
+            Object::new(
+                Shape::Sphere { radius },
+                point3(0.0, y, 0.0),
+                MaterialBuilder::new().build(),
+            )
+        };
+        let light = || PointLight::new(point3(0.0, 3.0, 0.0), WHITE);
+        let near_only = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(sphere(0.0, 1.0))
+            .add_light(light())
+            .build();
+        let both = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(sphere(0.0, 1.0))
+            .add_object(sphere(6.0, 1.0))
+            .add_light(light())
+            .build();
+        let blocked = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(sphere(0.0, 1.0))
+            .add_object(sphere(2.0, 0.5))
+            .add_light(light())
+            .build();
+
+        // Top of the near sphere
+        let (si, bsdf, ray) = lambertian_patch(point3(0.0, 1.0, 0.0));
+
+        let unshadowed = estimate_direct(
+            &ray,
+            &si,
+            &light(),
+            &bsdf,
+            &near_only,
+            &RayStats::new(),
+            false,
+        );
+        assert!(unshadowed.r > 0.0);
+        assert_eq!(
+            estimate_direct(&ray, &si, &light(), &bsdf, &both, &RayStats::new(), false),
+            unshadowed
+        );
+        assert_eq!(
+            estimate_direct(
+                &ray,
+                &si,
+                &light(),
+                &bsdf,
+                &blocked,
+                &RayStats::new(),
+                false
+            ),
+            BLACK
+        );
+    }
+
+    #[test]
+    fn glass_casts_colored_shadows() {
+        let tint = color(1.0, 0.5, 0.25);
+        let scene = |glass: bool| {
+            let builder = SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_light(PointLight::new(point3(0.0, 3.0, 0.0), WHITE));
+            if glass {
+                builder.add_object(Object::new(
+                    Shape::Sphere { radius: 0.5 },
+                    point3(0.0, 1.5, 0.0),
+                    MaterialBuilder::new()
+                        .base_color(tint)
+                        .transmission(1.0)
+                        .build(),
+                ))
+            } else {
+                builder
+            }
+            .build()
+        };
+
+        // Floor below the glass sphere
+        let (si, bsdf, ray) = lambertian_patch(point3(0.0, 0.0, 0.0));
+        let light = PointLight::new(point3(0.0, 3.0, 0.0), WHITE);
+
+        let stats = RayStats::new();
+        let lit = estimate_direct(&ray, &si, &light, &bsdf, &scene(false), &stats, false);
+        let shadowed = estimate_direct(&ray, &si, &light, &bsdf, &scene(true), &stats, false);
+        assert_eq!(stats.shadow_rays(), 2);
+        // The shadow ray enters and leaves the sphere, crossing two interfaces
+        let expected = lit * tint * tint;
+        assert_abs_diff_eq!(shadowed, expected, epsilon = 1e-6);
+        assert!(shadowed.b > 0.0);
+    }
+
+    #[test]
+    fn sphere_light_sample_li_matches_pdf_li() {
+        use cgmath::{Deg, Rotation3};
+
+        fastrand::seed(17);
+        let center = point3(1.0, 3.0, -2.0);
+        let rotation = Quaternion::from_axis_angle(vec3(1.0, 1.0, 0.0).normalize(), Deg(70.0));
+        let light = AreaLight::new(center, rotation, Shape::Sphere { radius: 1.5 }, WHITE);
+        let object = Object::new(
+            Shape::Sphere { radius: 1.5 },
+            center,
+            MaterialBuilder::new().emission(WHITE).build(),
+        );
+
+        // Outside the sphere the visible cone is sampled, inside it the surface is
+        for point in [point3(0.0, 0.0, 0.0), point3(1.5, 3.2, -1.8)] {
+            let reference = Intersection {
+                point,
+                ..Intersection::dummy()
+            };
+            for _ in 0..200 {
+                for (le, pdf_li) in [
+                    sample_and_pdf(&light, &reference),
+                    sample_and_pdf(&object, &reference),
+                ] {
+                    assert_ne!(le, BLACK);
+                    assert_abs_diff_eq!(pdf_li.0, pdf_li.1, epsilon = pdf_li.0 * 1e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn moving_emitter_is_sampled_where_it_is_at_the_ray_time() {
+        fastrand::seed(23);
+        let start = point3(-2.0, 1.0, 4.0);
+        let motion = vec3(4.0, 0.0, 1.0);
+        let emitter = |center| {
+            Object::new(
+                Shape::Sphere { radius: 0.5 },
+                center,
+                MaterialBuilder::new().emission(WHITE).build(),
+            )
+        };
+        let moving = emitter(start).with_motion(motion);
+
+        for time in [0.25, 0.75, 1.0] {
+            let reference = Intersection {
+                time,
+                ..Intersection::dummy()
+            };
+            // The same as a still sphere where the moving one is at `time`
+            let still = emitter(start + motion * time);
+            for _ in 0..100 {
+                let (le, pdf_li) = sample_and_pdf(&moving, &reference);
+                assert_ne!(le, BLACK);
+                assert_abs_diff_eq!(pdf_li.0, pdf_li.1, epsilon = pdf_li.0 * 1e-3);
+                let mut wi = Vec3::zero();
+                let mut pdf = 0.0;
+                moving.sample_li(&reference, &mut wi, &mut pdf);
+                assert_abs_diff_eq!(pdf, still.pdf_li(&reference, wi), epsilon = pdf * 1e-3);
+            }
+        }
+    }
+
+    /// Samples `light` from `reference`, returns the radiance along the sampled direction and the
+    /// pdfs of `sample_li` and `pdf_li`
+    fn sample_and_pdf<L: LightTrait>(
+        light: &L,
+        reference: &Intersection<(), ()>,
+    ) -> (Color, (Scalar, Scalar)) {
+        let mut wi = Vec3::zero();
+        let mut pdf = 0.0;
+        light.sample_li(reference, &mut wi, &mut pdf);
+        assert!(pdf > 0.0);
+        let le = light.le_unoccluded(&Ray::new(reference.point, wi, reference.time));
+        (le, (pdf, light.pdf_li(reference, wi)))
+    }
+
+    #[test]
+    fn sphere_sample_pdf_matches_pdf_from() {
+        let shape = Shape::Sphere { radius: 1.0 };
+        let center = point3(0.0, 3.0, 0.0);
+        let transform = Transform::new(Quaternion::zero(), center.to_vec());
+        for reference in [point3(0.0, 0.0, 0.0), point3(0.2, 3.1, -0.3)] {
+            for _ in 0..100 {
+                let mut wi = Vec3::zero();
+                let mut pdf = 0.0;
+                let (distance, _) = shape.sample_from(transform, reference, &mut wi, &mut pdf);
+                assert!(pdf > 0.0);
+                assert_abs_diff_eq!(
+                    (reference + wi * distance).distance(center),
+                    1.0,
+                    epsilon = 1e-3
+                );
+                assert_abs_diff_eq!(
+                    shape.pdf_from(transform, reference, wi),
+                    pdf,
+                    epsilon = pdf * 1e-3
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use crate::intersect::Intersection;
+use crate::light::{LightKind, LightTrait};
+use crate::sampling::{Distribution1D, Distribution2D};
+use crate::types::color::BLACK;
+use crate::types::scalar::consts::PI;
+use crate::types::{color, scalar, Color, Pt2, Ray, Scalar, Vec3};
+use cgmath::{point2, vec3, InnerSpace};
+use image::Rgb32FImage;
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+
+/// Face order of a cube map: +x, -x, +y, -y, +z, -z
+pub const FACE_NAMES: [&str; 6] = ["+x", "-x", "+y", "-y", "+z", "-z"];
+
+/// Maps a direction to a cube face and uv coordinates on that face, using the
+/// OpenGL cube map layout. Directions on an edge or corner always pick the
+/// first face in x, y, z order so neighbouring faces never both claim them.
+pub fn dir_to_face_uv(direction: Vec3) -> (usize, Pt2) {
+    let (ax, ay, az) = (direction.x.abs(), direction.y.abs(), direction.z.abs());
+    let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+        if direction.x >= 0.0 {
+            (0, -direction.z, -direction.y, ax)
+        } else {
+            (1, direction.z, -direction.y, ax)
+        }
+    } else if ay >= az {
+        if direction.y >= 0.0 {
+            (2, direction.x, direction.z, ay)
+        } else {
+            (3, direction.x, -direction.z, ay)
+        }
+    } else if direction.z >= 0.0 {
+        (4, direction.x, -direction.y, az)
+    } else {
+        (5, -direction.x, -direction.y, az)
+    };
+    let u = (0.5 * (sc / ma + 1.0)).clamp(0.0, 1.0);
+    let v = (0.5 * (tc / ma + 1.0)).clamp(0.0, 1.0);
+    (face, point2(u, v))
+}
+
+/// Inverse of [`dir_to_face_uv`]. The result lies on the unit cube and is not
+/// normalized.
+pub fn face_uv_to_dir(face: usize, uv: Pt2) -> Vec3 {
+    let sc = 2.0 * uv.x - 1.0;
+    let tc = 2.0 * uv.y - 1.0;
+    match face {
+        0 => vec3(1.0, -tc, -sc),
+        1 => vec3(-1.0, -tc, sc),
+        2 => vec3(sc, 1.0, tc),
+        3 => vec3(sc, -1.0, -tc),
+        4 => vec3(sc, -tc, 1.0),
+        5 => vec3(-sc, -tc, -1.0),
+        _ => panic!("Invalid cube map face {face}"),
+    }
+}
+
+/// Converts a density over a face's uv square to a density over solid angle
+fn uv_to_solid_angle_pdf(face: usize, uv: Pt2) -> Scalar {
+    // A face spans 2x2 units at distance 1, and dw = dA cos / r^2 = dA / r^3
+    face_uv_to_dir(face, uv).magnitude().powi(3) / 4.0
+}
+
+pub struct CubeMapLight {
+    pub faces: [Rgb32FImage; 6],
+    pub face_distributions: Vec<Distribution2D>,
+    pub face_distribution: Distribution1D,
+    pub strength: Scalar,
+}
+
+impl CubeMapLight {
+    pub fn new(faces: [Rgb32FImage; 6], strength: Scalar) -> Self {
+        for face in &faces {
+            assert!(
+                face.width() > 0 && face.width() == face.height(),
+                "Cube map faces must be square"
+            );
+        }
+
+        let face_distributions = faces
+            .iter()
+            .enumerate()
+            .map(|(face, image)| {
+                let size = image.width() as Scalar;
+                Distribution2D::new(image.rows().enumerate().map(|(y, row)| {
+                    row.enumerate()
+                        .map(|(x, p)| {
+                            let uv = point2((x as Scalar + 0.5) / size, (y as Scalar + 0.5) / size);
+                            color::luminance(Color::from(*p)) * strength
+                                / uv_to_solid_angle_pdf(face, uv)
+                        })
+                        .collect::<Vec<_>>()
+                }))
+            })
+            .collect::<Vec<_>>();
+        let face_distribution =
+            Distribution1D::new(face_distributions.iter().map(|d| d.integral()).collect());
+
+        Self {
+            faces,
+            face_distributions,
+            face_distribution,
+            strength,
+        }
+    }
+
+    /// Loads the faces in [`FACE_NAMES`] order
+    pub fn from_paths<P: AsRef<Path>>(paths: [P; 6], strength: Scalar) -> Self {
+        let faces = paths.map(|path| {
+            let image = image::io::Reader::open(path).unwrap().decode().unwrap();
+            image.into_rgb32f()
+        });
+        CubeMapLight::new(faces, strength)
+    }
+
+    fn texel(&self, face: usize, x: i64, y: i64) -> Color {
+        Color::from(*self.faces[face].get_pixel(x as u32, y as u32))
+    }
+
+    /// Bilinearly filtered lookup, clamped to the edges of the face
+    pub fn lookup(&self, face: usize, uv: Pt2) -> Color {
+        let size = self.faces[face].width() as i64;
+
+        let x = uv.x * size as Scalar - 0.5;
+        let y = uv.y * size as Scalar - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+
+        let x1 = (x0 as i64 + 1).clamp(0, size - 1);
+        let x0 = (x0 as i64).clamp(0, size - 1);
+        let y1 = (y0 as i64 + 1).clamp(0, size - 1);
+        let y0 = (y0 as i64).clamp(0, size - 1);
+
+        let top = self.texel(face, x0, y0) * (1.0 - fx) + self.texel(face, x1, y0) * fx;
+        let bottom = self.texel(face, x0, y1) * (1.0 - fx) + self.texel(face, x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy) * self.strength
+    }
+}
+
+impl LightTrait for CubeMapLight {
+    fn kind(&self) -> LightKind {
+        LightKind::INFINITE
+    }
+
+    fn le(&self, ray: &Ray) -> Color {
+        let (face, uv) = dir_to_face_uv(ray.direction);
+        self.lookup(face, uv)
+    }
+
+    fn sample_li<M, O>(
+        &self,
+        _intersection: &Intersection<M, O>,
+        wi: &mut Vec3,
+        pdf: &mut Scalar,
+    ) -> Color {
+        let (face, _) = self.face_distribution.sample_discrete(scalar::rand());
+        let face_pdf = self.face_distribution.discrete_pdf(face);
+
+        let u = point2(scalar::rand(), scalar::rand());
+        let mut map_pdf = 0.0;
+        let uv = self.face_distributions[face].sample_continuous(u, &mut map_pdf);
+
+        if face_pdf == 0.0 || map_pdf == 0.0 {
+            return BLACK;
+        }
+
+        *wi = face_uv_to_dir(face, uv).normalize();
+        *pdf = face_pdf * map_pdf * uv_to_solid_angle_pdf(face, uv);
+
+        self.lookup(face, uv)
+    }
+
+    fn power(&self, world_radius: Scalar) -> Scalar {
+        let total = self
+            .faces
+            .iter()
+            .enumerate()
+            .flat_map(|(face, image)| {
+                let size = image.width() as Scalar;
+                image.enumerate_pixels().map(move |(x, y, p)| {
+                    let uv = point2((x as Scalar + 0.5) / size, (y as Scalar + 0.5) / size);
+                    let solid_angle = 1.0 / (uv_to_solid_angle_pdf(face, uv) * size * size);
+                    color::luminance(Color::from(*p)) * solid_angle
+                })
+            })
+            .sum::<Scalar>();
+        let average = total / (4.0 * PI);
+        PI * world_radius * world_radius * average * self.strength
+    }
+
+    fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, wi: Vec3) -> Scalar {
+        let (face, uv) = dir_to_face_uv(wi);
+        self.face_distribution.discrete_pdf(face)
+            * self.face_distributions[face].pdf(uv)
+            * uv_to_solid_angle_pdf(face, uv)
+    }
+}
+
+impl Debug for CubeMapLight {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[cube map]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_unit_vec;
+    use cgmath::{assert_abs_diff_eq, point3};
+    use image::Rgb;
+
+    #[test]
+    fn dir_face_uv_round_trip() {
+        fastrand::seed(3);
+        for _ in 0..1000 {
+            let d = random_unit_vec();
+            let (face, uv) = dir_to_face_uv(d);
+            assert_abs_diff_eq!(face_uv_to_dir(face, uv).normalize(), d, epsilon = 1e-4);
+        }
+        for (face, d) in [
+            vec3(1.0, 0.0, 0.0),
+            vec3(-1.0, 0.0, 0.0),
+            vec3(0.0, 1.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, 0.0, -1.0),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            assert_eq!(dir_to_face_uv(d), (face, point2(0.5, 0.5)));
+        }
+    }
+
+    #[test]
+    fn edges_pick_one_face() {
+        assert_eq!(dir_to_face_uv(vec3(1.0, 1.0, 0.0)).0, 0);
+        assert_eq!(dir_to_face_uv(vec3(-1.0, 0.0, -1.0)).0, 1);
+        assert_eq!(dir_to_face_uv(vec3(0.0, -1.0, 1.0)).0, 3);
+        assert_eq!(dir_to_face_uv(vec3(1.0, -1.0, 1.0)).0, 0);
+    }
+
+    #[test]
+    fn importance_sampled_irradiance_matches_uniform() {
+        fastrand::seed(13);
+        // Dim faces with a bright patch near the edge of +y
+        let faces = [0, 1, 2, 3, 4, 5].map(|face| {
+            Rgb32FImage::from_fn(8, 8, |x, y| {
+                if face == 2 && x >= 6 && y < 3 {
+                    Rgb([40.0, 30.0, 20.0])
+                } else {
+                    Rgb([0.5, 0.6, 0.8])
+                }
+            })
+        });
+        let light = CubeMapLight::new(faces, 1.0);
+
+        let si = Intersection {
+            distance: 0.0,
+            normal: vec3(0.0, 1.0, 0.0),
+            tangent: vec3(1.0, 0.0, 0.0),
+            point: point3(0.0, 0.0, 0.0),
+
+            error: 0.0,
+
+            front_face: true,
+            sampled_material: (),
+            object: &(),
+            uv: point2(0.0, 0.0),
+            time: 0.0,
+        };
+
+        for normal in [
+            vec3(0.0, 1.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 0.5, -1.0).normalize(),
+        ] {
+            const N: usize = 400_000;
+
+            let mut importance = 0.0;
+            for _ in 0..N {
+                let (mut wi, mut pdf) = (vec3(0.0, 0.0, 0.0), 0.0);
+                let li = light.sample_li(&si, &mut wi, &mut pdf);
+                if pdf > 0.0 {
+                    assert_abs_diff_eq!(pdf, light.pdf_li(&si, wi), epsilon = 1e-3 * pdf);
+                    importance += li.r * wi.dot(normal).max(0.0) / pdf;
+                }
+            }
+            importance /= N as Scalar;
+
+            let mut uniform = 0.0;
+            for _ in 0..N {
+                let wi = random_unit_vec();
+                let li = light.le(&Ray::new(point3(0.0, 0.0, 0.0), wi, 0.0));
+                uniform += li.r * wi.dot(normal).max(0.0) * 4.0 * PI;
+            }
+            uniform /= N as Scalar;
+
+            assert_abs_diff_eq!(importance, uniform, epsilon = 0.03 * uniform);
+        }
+    }
+
+    #[test]
+    fn constant_power() {
+        let faces = [(); 6].map(|_| Rgb32FImage::from_pixel(16, 16, Rgb([2.0, 2.0, 2.0])));
+        let light = CubeMapLight::new(faces, 0.5);
+        assert_abs_diff_eq!(light.power(1.0), PI, epsilon = 1e-2);
+    }
+}
@@ -1,169 +1,281 @@
 use crate::intersect::Intersection;
 use crate::light::{LightKind, LightTrait};
+use crate::sampling::{Distribution1D, Distribution2D};
+use crate::stats;
 use crate::types::color::BLACK;
 use crate::types::scalar::consts::PI;
 use crate::types::{color, scalar, Color, Pt2, Ray, Scalar, Vec3};
 use cgmath::{point2, vec3, InnerSpace};
 use image::Rgb32FImage;
 use std::fmt::{Debug, Formatter};
-
-fn binary_search_cdf(cdf: &[Scalar], value: Scalar) -> usize {
-    let mut low = 0;
-    let mut high = cdf.len() - 1;
-    while low < high {
-        let mid = (low + high) / 2;
-        if cdf[mid] <= value {
-            low = mid + 1;
-        } else {
-            high = mid;
-        }
-    }
-    (low as isize - 1).clamp(0, cdf.len() as isize - 1) as usize
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::thread;
+
+/// Maps a direction to equirectangular uv coordinates, with v = 0 at +y.
+pub fn dir_to_uv(direction: Vec3) -> Pt2 {
+    let direction = direction.normalize();
+    let u = (direction.x.atan2(direction.z) + PI) / (2.0 * PI);
+    let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+    point2(u, v)
 }
 
-pub struct Distribution1D {
-    cdf: Vec<Scalar>,
-    func: Vec<Scalar>,
-    integral: Scalar,
+/// Inverse of [`dir_to_uv`]
+pub fn uv_to_dir(uv: Pt2) -> Vec3 {
+    let phi = uv.x * 2.0 * PI - PI;
+    let theta = uv.y * PI;
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    let (sin_phi, cos_phi) = phi.sin_cos();
+    vec3(sin_theta * sin_phi, cos_theta, sin_theta * cos_phi)
 }
 
-impl Distribution1D {
-    #[allow(clippy::needless_range_loop)]
-    pub fn new(func: Vec<Scalar>) -> Self {
-        let n = func.len();
-        let mut cdf = vec![0.0; n + 1];
+/// Longest side of the luminance image directions are importance sampled from, larger
+/// environment maps are averaged down to it
+pub const MAX_DISTRIBUTION_SIZE: u32 = 1024;
 
-        for i in 1..(n + 1) {
-            cdf[i] = cdf[i - 1] + func[i - 1] / n as Scalar;
-        }
+/// Levels finer than the footprint of a filtered lookup are used, so only about a 64th of the
+/// lobe is averaged. Blurring the whole lobe would also spread bright spots over its shadows.
+const LOD_BIAS: Scalar = 3.0;
 
-        let integral = cdf[n];
-        if integral == 0.0 {
-            for i in 1..(n + 1) {
-                cdf[i] = i as Scalar / n as Scalar;
-            }
-        } else {
-            for i in 1..(n + 1) {
-                cdf[i] /= integral;
-            }
-        }
+pub struct Hdri {
+    pub image: Rgb32FImage,
+    /// Successive halvings of `image` down to a single texel, for filtered lookups
+    pub mips: Vec<Rgb32FImage>,
+    pub distribution: Distribution2D,
+    pub strength: Scalar,
+    /// Whether camera rays that miss the scene see the map. Hidden maps still light the scene
+    /// and show in reflections and refractions.
+    pub visible_to_camera: bool,
+}
 
+impl Hdri {
+    pub fn new(image: Rgb32FImage, strength: Scalar) -> Self {
+        let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let distribution =
+            importance_distribution(&image, strength, MAX_DISTRIBUTION_SIZE, threads);
         Self {
-            cdf,
-            integral,
-            func,
+            mips: mip_pyramid(&image),
+            image,
+            distribution,
+            strength,
+            visible_to_camera: true,
         }
     }
 
-    pub fn count(&self) -> usize {
-        self.func.len()
+    pub fn with_visible_to_camera(mut self, visible_to_camera: bool) -> Self {
+        self.visible_to_camera = visible_to_camera;
+        self
+    }
+
+    pub fn from_path(path: impl AsRef<Path>, strength: Scalar) -> Self {
+        let image = image::io::Reader::open(path).unwrap().decode().unwrap();
+        Hdri::new(image.into_rgb32f(), strength)
     }
 
-    pub fn sample_continuous(&self, u: Scalar, pdf: &mut Scalar) -> (usize, Scalar) {
-        let offset = binary_search_cdf(&self.cdf, u);
-        let mut du = u - self.cdf[offset];
+    /// Bilinearly filtered lookup. The u axis wraps around and the v axis is
+    /// clamped at the poles.
+    pub fn lookup(&self, uv: Pt2) -> Color {
+        bilinear(&self.image, uv) * self.strength
+    }
 
-        if (self.cdf[offset + 1] - self.cdf[offset]) > 0.0 {
-            du /= self.cdf[offset + 1] - self.cdf[offset];
+    /// Lookup trilinearly filtered between the mip levels around `lod`, where level 0 is the
+    /// image and each level halves the one before
+    pub fn lookup_lod(&self, uv: Pt2, lod: Scalar) -> Color {
+        let lod = lod.clamp(0.0, self.mips.len() as Scalar);
+        let level = lod.floor();
+        let t = lod - level;
+        let fine = bilinear(self.level(level as usize), uv);
+        if t == 0.0 {
+            return fine * self.strength;
         }
+        let coarse = bilinear(self.level(level as usize + 1), uv);
+        (fine * (1.0 - t) + coarse * t) * self.strength
+    }
 
-        *pdf = self.func[offset] / self.integral;
+    fn level(&self, level: usize) -> &Rgb32FImage {
+        match level {
+            0 => &self.image,
+            level => &self.mips[level - 1],
+        }
+    }
 
-        (offset, (offset as Scalar + du) / self.cdf.len() as Scalar)
+    /// Level of detail for averaging over `footprint` steradians
+    fn footprint_lod(&self, footprint: Scalar) -> Scalar {
+        // Texels are largest at the equator
+        let texel = 2.0 * PI * PI / (self.image.width() * self.image.height()) as Scalar;
+        (0.5 * (footprint / texel).log2() - LOD_BIAS).max(0.0)
     }
 
-    #[allow(unused)]
-    pub fn sample_discrete(&self, u: Scalar) -> (usize, Scalar) {
-        let offset = binary_search_cdf(&self.cdf, u);
-        let u_prime = (u - self.cdf[offset]) / (self.cdf[offset + 1] - self.cdf[offset]);
-        (offset, u_prime)
+    /// Solid angle density of sampling the direction at `uv`
+    fn uv_pdf(&self, uv: Pt2) -> Scalar {
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta <= 0.0 {
+            0.0
+        } else {
+            self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+        }
     }
 }
 
-pub struct Distribution2D {
-    p_conditional_v: Vec<Distribution1D>,
-    p_marginal: Distribution1D,
+fn texel(image: &Rgb32FImage, x: i64, y: i64) -> Color {
+    Color::from(*image.get_pixel(x as u32, y as u32))
 }
 
-impl Distribution2D {
-    pub fn new(f: impl ExactSizeIterator<Item = Vec<Scalar>>) -> Self {
-        let p_conditional_v = f.map(Distribution1D::new).collect::<Vec<Distribution1D>>();
-
-        let p_integral = p_conditional_v
-            .iter()
-            .map(|p| p.integral)
-            .collect::<Vec<_>>();
-
-        let p_marginal = Distribution1D::new(p_integral.to_vec());
+/// Bilinear lookup in an equirectangular `image`, see [`Hdri::lookup`]
+fn bilinear(image: &Rgb32FImage, uv: Pt2) -> Color {
+    let width = image.width() as i64;
+    let height = image.height() as i64;
+
+    let x = uv.x * width as Scalar - 0.5;
+    let y = uv.y * height as Scalar - 0.5;
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let fx = x - x0;
+    let fy = y - y0;
+
+    let x1 = (x0 as i64 + 1).rem_euclid(width);
+    let x0 = (x0 as i64).rem_euclid(width);
+    let y1 = (y0 as i64 + 1).clamp(0, height - 1);
+    let y0 = (y0 as i64).clamp(0, height - 1);
+
+    let top = texel(image, x0, y0) * (1.0 - fx) + texel(image, x1, y0) * fx;
+    let bottom = texel(image, x0, y1) * (1.0 - fx) + texel(image, x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
 
-        Self {
-            p_conditional_v,
-            p_marginal,
+/// Successive 2×2 box averages of `image` down to a single texel. Odd sides repeat their last
+/// row or column.
+fn mip_pyramid(image: &Rgb32FImage) -> Vec<Rgb32FImage> {
+    let mut levels: Vec<Rgb32FImage> = Vec::new();
+    loop {
+        let previous = levels.last().unwrap_or(image);
+        let (width, height) = previous.dimensions();
+        if width <= 1 && height <= 1 {
+            return levels;
         }
+        let next = Rgb32FImage::from_fn(width.div_ceil(2), height.div_ceil(2), |x, y| {
+            let texel = |dx: u32, dy: u32| {
+                let x = (2 * x + dx).min(width - 1);
+                let y = (2 * y + dy).min(height - 1);
+                Color::from(*previous.get_pixel(x, y))
+            };
+            ((texel(0, 0) + texel(1, 0) + texel(0, 1) + texel(1, 1)) * 0.25).into()
+        });
+        levels.push(next);
     }
+}
 
-    pub fn pdf(&self, u: Pt2) -> Scalar {
-        let iu = ((u[0] * self.p_conditional_v[0].count() as Scalar) as usize)
-            .clamp(0, self.p_conditional_v[0].count() - 1);
-        let iv = ((u[1] * self.p_marginal.count() as Scalar) as usize)
-            .clamp(0, self.p_marginal.count() - 1);
-        self.p_conditional_v[iv].func[iu] / self.p_marginal.integral
-    }
-
-    pub fn sample_continuous(&self, u: Pt2, pdf: &mut Scalar) -> Pt2 {
-        let (mut pdf_0, mut pdf_1) = (0.0, 0.0);
-        let (v, d1) = self.p_marginal.sample_continuous(u[1], &mut pdf_1);
-        let (_, d0) = self.p_conditional_v[v].sample_continuous(u[0], &mut pdf_0);
-
-        *pdf = pdf_0 * pdf_1;
-
-        point2(d0, d1)
+/// Distribution of the image's luminance over solid angle. Images with a side longer than
+/// `max_size` are area averaged down to it, which preserves the total power. The rows are built
+/// on `threads` threads, the result is the same for any number of them.
+pub fn importance_distribution(
+    image: &Rgb32FImage,
+    strength: Scalar,
+    max_size: u32,
+    threads: usize,
+) -> Distribution2D {
+    let (width, height) = image.dimensions();
+    let sin_theta = (0..height)
+        .map(|v| (PI * (v as Scalar + 0.5) / height as Scalar).sin())
+        .collect::<Vec<_>>();
+    let value = |x: usize, y: usize| {
+        color::luminance(Color::from(*image.get_pixel(x as u32, y as u32)))
+            * sin_theta[y]
+            * strength
+    };
+    let longest = width.max(height);
+    if longest <= max_size {
+        return parallel_rows(height as usize, threads, |y| {
+            (0..width as usize).map(|x| value(x, y)).collect()
+        });
     }
-}
 
-pub struct Hdri {
-    pub image: Rgb32FImage,
-    pub distribution: Distribution2D,
-    pub strength: Scalar,
+    let scale = max_size as Scalar / longest as Scalar;
+    let scaled = |size: u32| ((size as Scalar * scale).round() as usize).max(1);
+    let column_weights = area_weights(width as usize, scaled(width));
+    let row_weights = area_weights(height as usize, scaled(height));
+    // Each row is averaged from the narrowed rows it overlaps, rows on the boundary of two are
+    // narrowed twice rather than keeping all of them around
+    parallel_rows(row_weights.len(), threads, |v| {
+        let narrowed = row_weights[v]
+            .iter()
+            .map(|&(y, w)| (average(&column_weights, |x| value(x, y)), w))
+            .collect::<Vec<_>>();
+        (0..column_weights.len())
+            .map(|x| narrowed.iter().map(|(row, w)| row[x] * w).sum())
+            .collect()
+    })
 }
 
-impl Hdri {
-    pub fn new(image: Rgb32FImage, strength: Scalar) -> Self {
-        let distribution = Distribution2D::new(image.rows().enumerate().map(|(v, row)| {
-            let sin_theta = (PI * (v as Scalar + 0.5) / image.height() as Scalar).sin();
-            row.map(|p| {
-                let luminance = 0.299 * p.0[0] + 0.587 * p.0[1] + 0.114 * p.0[2];
-                luminance * sin_theta * strength
+/// Distribution of `count` rows given by `row`, built in contiguous chunks on `threads` threads
+fn parallel_rows(
+    count: usize,
+    threads: usize,
+    row: impl Fn(usize) -> Vec<Scalar> + Sync,
+) -> Distribution2D {
+    let chunk_size = count.div_ceil(threads.max(1)).max(1);
+    let row = &row;
+    let conditionals = thread::scope(|scope| {
+        let chunks = (0..count)
+            .step_by(chunk_size)
+            .map(|start| {
+                let end = (start + chunk_size).min(count);
+                scope.spawn(move || {
+                    (start..end)
+                        .map(|v| Distribution1D::new(row(v)))
+                        .collect::<Vec<_>>()
+                })
             })
-            .collect::<Vec<_>>()
-        }));
+            .collect::<Vec<_>>();
+        chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.join().unwrap())
+            .collect()
+    });
+    Distribution2D::from_conditionals(conditionals)
+}
 
-        Self {
-            image,
-            distribution,
-            strength,
-        }
-    }
+/// For each of `to` equal cells covering `from` cells, the cells it overlaps and the fraction
+/// of it they cover
+fn area_weights(from: usize, to: usize) -> Vec<Vec<(usize, Scalar)>> {
+    let ratio = from as Scalar / to as Scalar;
+    (0..to)
+        .map(|i| {
+            let start = i as Scalar * ratio;
+            let end = (i + 1) as Scalar * ratio;
+            (start.floor() as usize..(end.ceil() as usize).min(from))
+                .map(|j| {
+                    let overlap = end.min((j + 1) as Scalar) - start.max(j as Scalar);
+                    (j, overlap / ratio)
+                })
+                .collect()
+        })
+        .collect()
+}
 
-    pub fn lookup(&self, uv: Pt2) -> Color {
-        let x = ((self.image.width() as Scalar * uv.x) as u32).min(self.image.width() - 1);
-        let y = ((self.image.height() as Scalar * uv.y) as u32).min(self.image.height() - 1);
-        let [r, g, b] = self.image.get_pixel(x, y).0;
-        color(r, g, b) * self.strength
-    }
+fn average(weights: &[Vec<(usize, Scalar)>], value: impl Fn(usize) -> Scalar) -> Vec<Scalar> {
+    weights
+        .iter()
+        .map(|weights| weights.iter().map(|&(i, w)| value(i) * w).sum())
+        .collect()
 }
 
 impl LightTrait for Hdri {
     fn kind(&self) -> LightKind {
-        LightKind::INFINITE
+        if self.visible_to_camera {
+            LightKind::INFINITE
+        } else {
+            LightKind::INFINITE.set(LightKind::NO_BG)
+        }
     }
 
     fn le(&self, ray: &Ray) -> Color {
-        let direction = ray.direction;
-        let u = (direction.x.atan2(direction.z) + PI) / (2.0 * PI);
-        let v = direction.angle(vec3(0.0, 1.0, 0.0)).0 / PI;
+        self.lookup(dir_to_uv(ray.direction))
+    }
 
-        self.lookup(point2(u, v))
+    fn le_filtered(&self, ray: &Ray, footprint: Scalar) -> Color {
+        let lod = self.footprint_lod(footprint);
+        self.lookup_lod(dir_to_uv(ray.direction), lod)
     }
 
     fn sample_li<M, O>(
@@ -172,6 +284,7 @@ impl LightTrait for Hdri {
         wi: &mut Vec3,
         pdf: &mut Scalar,
     ) -> Color {
+        stats::stats_count!(hdri_samples);
         let u = point2(scalar::rand(), scalar::rand());
 
         let mut map_pdf = 0.0;
@@ -181,33 +294,30 @@ impl LightTrait for Hdri {
             return BLACK;
         }
 
-        let phi = uv.x * 2.0 * PI - PI;
-        let theta = uv.y * PI;
-        let cos_theta = theta.cos();
-        let sin_theta = theta.sin();
-        let cos_phi = phi.cos();
-        let sin_phi = phi.sin();
-        *wi = vec3(sin_theta * sin_phi, cos_theta, sin_theta * cos_phi).normalize();
-
-        *pdf = if sin_theta == 0.0 {
-            0.0
-        } else {
-            map_pdf / (2.0 * PI * PI * sin_theta)
-        };
+        // The radiance and pdf are evaluated from the direction, like `le` and `pdf_li` do, so
+        // light and BSDF samples of the same direction are weighted alike
+        *wi = uv_to_dir(uv);
+        let uv = dir_to_uv(*wi);
+        *pdf = self.uv_pdf(uv);
+        if *pdf == 0.0 {
+            return BLACK;
+        }
 
         self.lookup(uv)
     }
 
+    fn power(&self, world_radius: Scalar) -> Scalar {
+        let total = self
+            .image
+            .pixels()
+            .map(|p| color::luminance(Color::from(*p)))
+            .sum::<Scalar>();
+        let average = total / (self.image.width() * self.image.height()) as Scalar;
+        PI * world_radius * world_radius * average * self.strength
+    }
+
     fn pdf_li<M, O>(&self, _intersection: &Intersection<M, O>, wi: Vec3) -> Scalar {
-        let theta = wi.angle(vec3(0.0, 1.0, 0.0)).0;
-        let phi = wi.x.atan2(wi.z) + PI;
-        let sin_theta = theta.sin();
-        if sin_theta == 0.0 {
-            0.0
-        } else {
-            self.distribution.pdf(point2(phi / (2.0 * PI), theta / PI))
-                / (2.0 * PI * PI * sin_theta)
-        }
+        self.uv_pdf(dir_to_uv(wi))
     }
 }
 
@@ -216,3 +326,294 @@ impl Debug for Hdri {
         write!(f, "[hdri]")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_unit_vec;
+    use cgmath::{assert_abs_diff_eq, point3};
+    use image::Rgb;
+
+    fn test_image() -> Rgb32FImage {
+        Rgb32FImage::from_fn(4, 3, |x, y| Rgb([x as f32, y as f32, 1.0]))
+    }
+
+    #[test]
+    fn dir_uv_round_trip() {
+        fastrand::seed(7);
+        for _ in 0..1000 {
+            let d = random_unit_vec();
+            assert_abs_diff_eq!(uv_to_dir(dir_to_uv(d)), d, epsilon = 1e-4);
+        }
+        assert_abs_diff_eq!(dir_to_uv(vec3(0.0, 1.0, 0.0)).y, 0.0);
+        assert_abs_diff_eq!(dir_to_uv(vec3(0.0, -1.0, 0.0)).y, 1.0);
+    }
+
+    #[test]
+    fn importance_sampled_irradiance_matches_uniform() {
+        fastrand::seed(11);
+        // Dim sky with a bright sun patch above the horizon
+        let image = Rgb32FImage::from_fn(32, 16, |x, y| {
+            if (20..24).contains(&x) && (3..6).contains(&y) {
+                Rgb([40.0, 30.0, 20.0])
+            } else {
+                Rgb([0.5, 0.6, 0.8])
+            }
+        });
+        let hdri = Hdri::new(image, 1.0);
+
+        let si = Intersection {
+            distance: 0.0,
+            normal: vec3(0.0, 1.0, 0.0),
+            tangent: vec3(1.0, 0.0, 0.0),
+            point: point3(0.0, 0.0, 0.0),
+
+            error: 0.0,
+
+            front_face: true,
+            sampled_material: (),
+            object: &(),
+            uv: point2(0.0, 0.0),
+            time: 0.0,
+        };
+
+        for normal in [
+            vec3(0.0, 1.0, 0.0),
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 0.5, -1.0).normalize(),
+        ] {
+            const N: usize = 400_000;
+
+            let mut importance = 0.0;
+            for _ in 0..N {
+                let (mut wi, mut pdf) = (vec3(0.0, 0.0, 0.0), 0.0);
+                let li = hdri.sample_li(&si, &mut wi, &mut pdf);
+                if pdf > 0.0 {
+                    importance += li.r * wi.dot(normal).max(0.0) / pdf;
+                }
+            }
+            importance /= N as Scalar;
+
+            let mut uniform = 0.0;
+            for _ in 0..N {
+                let wi = random_unit_vec();
+                let li = hdri.le(&Ray::new(point3(0.0, 0.0, 0.0), wi, 0.0));
+                uniform += li.r * wi.dot(normal).max(0.0) * 4.0 * PI;
+            }
+            uniform /= N as Scalar;
+
+            assert_abs_diff_eq!(importance, uniform, epsilon = 0.03 * uniform);
+        }
+    }
+
+    #[test]
+    fn sampled_directions_match_pdf_li_and_le() {
+        fastrand::seed(5);
+        let image = Rgb32FImage::from_fn(32, 16, |x, y| {
+            // Black rows and columns next to bright ones put samples near zero pdf texels
+            if x % 4 == 0 || y % 3 == 0 {
+                Rgb([0.0, 0.0, 0.0])
+            } else {
+                Rgb([x as f32, y as f32, 1.0])
+            }
+        });
+        let hdri = Hdri::new(image, 2.0);
+        let si = Intersection::dummy();
+        for _ in 0..10_000 {
+            let (mut wi, mut pdf) = (vec3(0.0, 0.0, 0.0), 0.0);
+            let li = hdri.sample_li(&si, &mut wi, &mut pdf);
+            if pdf == 0.0 {
+                continue;
+            }
+            assert_eq!(pdf, hdri.pdf_li(&si, wi));
+            let le = hdri.le(&Ray::new(point3(0.0, 0.0, 0.0), wi, 0.0));
+            assert_abs_diff_eq!(li, le, epsilon = 1e-5 * le.max_component());
+        }
+    }
+
+    #[test]
+    fn downsampled_distribution_preserves_power() {
+        fastrand::seed(13);
+        // Noise with a bright sun that doesn't line up with the downsampled texels
+        let image = Rgb32FImage::from_fn(300, 150, |x, y| {
+            if (201..205).contains(&x) && (37..40).contains(&y) {
+                Rgb([500.0, 400.0, 300.0])
+            } else {
+                Rgb([fastrand::f32(), fastrand::f32(), fastrand::f32()])
+            }
+        });
+        let full = importance_distribution(&image, 2.0, 300, 4);
+        for max_size in [128, 64, 7] {
+            let downsampled = importance_distribution(&image, 2.0, max_size, 4);
+            assert_abs_diff_eq!(
+                downsampled.integral(),
+                full.integral(),
+                epsilon = 1e-4 * full.integral()
+            );
+        }
+
+        // Sampling from the small distribution still finds the sun, the estimates of the
+        // power agree with sampling at full resolution
+        let full = Hdri::new(image, 1.0);
+        let downsampled = Hdri {
+            image: full.image.clone(),
+            distribution: importance_distribution(&full.image, 1.0, 64, 4),
+            mips: Vec::new(),
+            strength: 1.0,
+            visible_to_camera: true,
+        };
+        let power = |hdri: &Hdri| {
+            let si = Intersection::dummy();
+            let (mut wi, mut pdf) = (vec3(0.0, 0.0, 0.0), 0.0);
+            const N: usize = 100_000;
+            (0..N).fold(0.0, |power, _| {
+                let li = hdri.sample_li(&si, &mut wi, &mut pdf);
+                if pdf > 0.0 {
+                    power + li.r / pdf
+                } else {
+                    power
+                }
+            }) / N as Scalar
+        };
+        let expected = power(&full);
+        assert_abs_diff_eq!(power(&downsampled), expected, epsilon = 0.02 * expected);
+    }
+
+    #[test]
+    fn threaded_distribution_matches_serial() {
+        // The construction before it was split over threads, with every row kept in memory
+        fn serial(image: &Rgb32FImage, strength: Scalar, max_size: u32) -> Distribution2D {
+            let (width, height) = image.dimensions();
+            let rows = image.rows().enumerate().map(|(v, row)| {
+                let sin_theta = (PI * (v as Scalar + 0.5) / height as Scalar).sin();
+                row.map(|p| color::luminance(Color::from(*p)) * sin_theta * strength)
+                    .collect::<Vec<_>>()
+            });
+            let longest = width.max(height);
+            if longest <= max_size {
+                return Distribution2D::new(rows);
+            }
+            let scale = max_size as Scalar / longest as Scalar;
+            let scaled = |size: u32| ((size as Scalar * scale).round() as usize).max(1);
+            let column_weights = area_weights(width as usize, scaled(width));
+            let rows = rows
+                .map(|row| average(&column_weights, |x| row[x]))
+                .collect::<Vec<_>>();
+            let row_weights = area_weights(height as usize, scaled(height));
+            Distribution2D::new(row_weights.iter().map(|weights| {
+                (0..rows[0].len())
+                    .map(|x| weights.iter().map(|&(y, w)| rows[y][x] * w).sum())
+                    .collect::<Vec<_>>()
+            }))
+        }
+
+        fastrand::seed(37);
+        let image = Rgb32FImage::from_fn(61, 29, |_, _| {
+            Rgb([fastrand::f32(), fastrand::f32(), fastrand::f32()].map(|c| c * c * 20.0))
+        });
+        for max_size in [64, 40, 9] {
+            let expected = serial(&image, 1.5, max_size);
+            for threads in [1, 3, 8, 100] {
+                assert_eq!(
+                    importance_distribution(&image, 1.5, max_size, threads),
+                    expected,
+                    "{max_size} {threads}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mip_levels_preserve_average() {
+        fastrand::seed(41);
+        let image = Rgb32FImage::from_fn(16, 8, |_, _| {
+            Rgb([fastrand::f32(), fastrand::f32(), fastrand::f32()].map(|c| c * 10.0))
+        });
+        let hdri = Hdri::new(image, 2.0);
+        let sizes = hdri.mips.iter().map(|mip| mip.dimensions());
+        assert_eq!(sizes.collect::<Vec<_>>(), [(8, 4), (4, 2), (2, 1), (1, 1)]);
+
+        let average = |image: &Rgb32FImage| {
+            let sum = image.pixels().fold(BLACK, |sum, p| sum + Color::from(*p));
+            sum / (image.width() * image.height()) as Scalar
+        };
+        let expected = average(&hdri.image);
+        for mip in &hdri.mips {
+            assert_abs_diff_eq!(average(mip), expected, epsilon = 1e-4);
+        }
+        // The top level is the average everywhere
+        for uv in [point2(0.1, 0.2), point2(0.7, 0.95)] {
+            assert_abs_diff_eq!(hdri.lookup_lod(uv, 4.0), expected * 2.0, epsilon = 1e-4);
+            assert_abs_diff_eq!(hdri.lookup_lod(uv, 20.0), expected * 2.0, epsilon = 1e-4);
+        }
+
+        // Odd sides repeat their edge, which only moves the average a little
+        let odd = Hdri::new(
+            Rgb32FImage::from_fn(13, 7, |x, y| {
+                Rgb([1.0 + x as f32 * 0.1, 1.0 + y as f32 * 0.2, 1.5])
+            }),
+            1.0,
+        );
+        assert_eq!(odd.mips.last().unwrap().dimensions(), (1, 1));
+        let expected = average(&odd.image);
+        for mip in &odd.mips {
+            assert_abs_diff_eq!(
+                average(mip),
+                expected,
+                epsilon = 0.1 * expected.max_component()
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_lod_zero_matches_lookup() {
+        fastrand::seed(43);
+        let hdri = Hdri::new(
+            Rgb32FImage::from_fn(9, 5, |_, _| Rgb([fastrand::f32(); 3])),
+            1.5,
+        );
+        for _ in 0..100 {
+            let uv = point2(scalar::rand(), scalar::rand());
+            assert_eq!(hdri.lookup_lod(uv, 0.0), hdri.lookup(uv));
+            // Between levels the lookup is between the levels' lookups
+            let (fine, coarse) = (hdri.lookup_lod(uv, 1.0), hdri.lookup_lod(uv, 2.0));
+            let between = hdri.lookup_lod(uv, 1.25);
+            assert_abs_diff_eq!(between, fine * 0.75 + coarse * 0.25, epsilon = 1e-5);
+        }
+        // A footprint smaller than a texel isn't filtered
+        let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.3, 0.4, 0.5), 0.0);
+        assert_eq!(hdri.le_filtered(&ray, 1e-4), hdri.le(&ray));
+    }
+
+    #[test]
+    fn lookup_texel_center() {
+        let hdri = Hdri::new(test_image(), 1.0);
+        for x in 0..4 {
+            for y in 0..3 {
+                let uv = point2((x as Scalar + 0.5) / 4.0, (y as Scalar + 0.5) / 3.0);
+                assert_abs_diff_eq!(hdri.lookup(uv), color(x as Scalar, y as Scalar, 1.0));
+            }
+        }
+    }
+
+    #[test]
+    fn lookup_wraps_longitude() {
+        let image = Rgb32FImage::from_fn(8, 4, |_, y| Rgb([y as f32, 1.0, 2.0]));
+        let hdri = Hdri::new(image, 1.0);
+        for v in [0.0, 0.3, 0.7, 1.0] {
+            assert_abs_diff_eq!(
+                hdri.lookup(point2(0.999, v)),
+                hdri.lookup(point2(0.001, v)),
+                epsilon = 1e-5
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_poles_in_bounds() {
+        let hdri = Hdri::new(test_image(), 2.0);
+        assert_abs_diff_eq!(hdri.lookup(point2(0.125, 0.0)), color(0.0, 0.0, 2.0));
+        assert_abs_diff_eq!(hdri.lookup(point2(0.125, 1.0)), color(0.0, 4.0, 2.0));
+        hdri.lookup(point2(1.0, 1.0));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::sampling::Distribution2D;
+use crate::types::scalar::consts::{PI, TAU};
+use crate::types::{Scalar, Vec3};
+use std::path::Path;
+
+/// Resolution of the grid the profile is tabulated on for its distribution
+const DISTRIBUTION_THETA: usize = 32;
+const DISTRIBUTION_PHI: usize = 64;
+
+/// Photometric profile of a luminaire from an IES LM-63 file, only type C photometry is
+/// supported.
+///
+/// Directions are in the luminaire's frame, +z is the nadir (vertical angle 0) and +x and +y are
+/// the horizontal angles 0 and 90 degrees.
+#[derive(Debug, Clone)]
+pub struct IesProfile {
+    /// Vertical angles in radians, increasing
+    vertical_angles: Vec<Scalar>,
+    /// Horizontal angles in radians, increasing
+    horizontal_angles: Vec<Scalar>,
+    /// Intensity for each horizontal angle, then each vertical angle, scaled so the peak is 1
+    intensity: Vec<Vec<Scalar>>,
+    /// Intensity times sin(theta) over (phi / 2pi, theta / pi)
+    pub distribution: Distribution2D,
+}
+
+impl IesProfile {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut lines = source.lines();
+        let tilt = lines
+            .by_ref()
+            .find_map(|line| line.trim().strip_prefix("TILT="))
+            .ok_or("missing TILT line")?;
+
+        let mut values = lines
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|value| !value.is_empty())
+            .map(|value| {
+                value
+                    .parse::<Scalar>()
+                    .map_err(|_| format!("invalid number '{value}'"))
+            });
+        let mut next = || values.next().ok_or("unexpected end of file".to_owned())?;
+
+        match tilt.trim() {
+            "NONE" => {}
+            "INCLUDE" => {
+                // Lamp to luminaire geometry, then the tilt angles and their factors
+                next()?;
+                let pairs = next()? as usize;
+                for _ in 0..2 * pairs {
+                    next()?;
+                }
+            }
+            file => return Err(format!("tilt file '{file}' is not supported")),
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let num_vertical = next()? as usize;
+        let num_horizontal = next()? as usize;
+        let photometric_type = next()?;
+        // Units and luminous opening, then ballast factor, future use and input watts
+        for _ in 0..7 {
+            next()?;
+        }
+        if photometric_type != 1.0 {
+            return Err(format!(
+                "only type C photometry is supported, got type {photometric_type}"
+            ));
+        }
+        if num_vertical == 0 || num_horizontal == 0 {
+            return Err("profile has no angles".to_owned());
+        }
+
+        let mut angles = |count| {
+            (0..count)
+                .map(|_| next().map(Scalar::to_radians))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let vertical_angles = angles(num_vertical)?;
+        let horizontal_angles = angles(num_horizontal)?;
+        let increasing = |angles: &[Scalar]| angles.windows(2).all(|pair| pair[0] < pair[1]);
+        if !increasing(&vertical_angles) || !increasing(&horizontal_angles) {
+            return Err("angles must be increasing".to_owned());
+        }
+
+        let mut intensity = (0..num_horizontal)
+            .map(|_| {
+                (0..num_vertical)
+                    .map(|_| next().map(|candela| candela * multiplier))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let peak = intensity.iter().flatten().copied().fold(0.0, Scalar::max);
+        if peak <= 0.0 {
+            return Err("profile emits no light".to_owned());
+        }
+        for value in intensity.iter_mut().flatten() {
+            *value /= peak;
+        }
+
+        Ok(Self::new(vertical_angles, horizontal_angles, intensity))
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        Self::parse(&source).map_err(|err| format!("invalid IES file {}: {err}", path.display()))
+    }
+
+    fn new(
+        vertical_angles: Vec<Scalar>,
+        horizontal_angles: Vec<Scalar>,
+        intensity: Vec<Vec<Scalar>>,
+    ) -> Self {
+        let mut profile = Self {
+            vertical_angles,
+            horizontal_angles,
+            intensity,
+            // Tabulated from the profile below
+            distribution: Distribution2D::new(Vec::new().into_iter()),
+        };
+        profile.distribution = Distribution2D::new((0..DISTRIBUTION_THETA).map(|v| {
+            let theta = PI * (v as Scalar + 0.5) / DISTRIBUTION_THETA as Scalar;
+            (0..DISTRIBUTION_PHI)
+                .map(|u| {
+                    let phi = TAU * (u as Scalar + 0.5) / DISTRIBUTION_PHI as Scalar;
+                    profile.intensity_at(theta, phi) * theta.sin()
+                })
+                .collect::<Vec<_>>()
+        }));
+        profile
+    }
+
+    /// Intensity relative to the peak at the vertical angle `theta` and horizontal angle `phi`,
+    /// in radians
+    pub fn intensity_at(&self, theta: Scalar, phi: Scalar) -> Scalar {
+        let (first, last) = (
+            self.vertical_angles[0],
+            *self.vertical_angles.last().unwrap(),
+        );
+        if theta < first || theta > last {
+            return 0.0;
+        }
+        let phi = self.fold_horizontal(phi.rem_euclid(TAU));
+
+        let (v, tv) = locate(&self.vertical_angles, theta);
+        let (h, th) = locate(&self.horizontal_angles, phi);
+        let at = |h: usize, v: usize| {
+            let column = &self.intensity[h.min(self.horizontal_angles.len() - 1)];
+            column[v.min(self.vertical_angles.len() - 1)]
+        };
+        let near = at(h, v) * (1.0 - tv) + at(h, v + 1) * tv;
+        let far = at(h + 1, v) * (1.0 - tv) + at(h + 1, v + 1) * tv;
+        near * (1.0 - th) + far * th
+    }
+
+    /// Intensity relative to the peak in the direction `w` of the luminaire's frame
+    pub fn intensity(&self, w: Vec3) -> Scalar {
+        let theta = w.z.clamp(-1.0, 1.0).acos();
+        let phi = w.y.atan2(w.x);
+        self.intensity_at(theta, phi)
+    }
+
+    /// Integral of the intensity over the sphere of directions
+    pub fn integral(&self) -> Scalar {
+        PI * TAU * self.distribution.integral()
+    }
+
+    /// Maps a horizontal angle in [0, 2pi) into the range the file covers, using the symmetry
+    /// implied by the last horizontal angle
+    fn fold_horizontal(&self, phi: Scalar) -> Scalar {
+        let last = self.horizontal_angles.last().unwrap().to_degrees().round();
+        if last == 0.0 {
+            // Rotationally symmetric
+            0.0
+        } else if last == 90.0 {
+            // Symmetric in each quadrant
+            let phi = phi % PI;
+            if phi > PI / 2.0 {
+                PI - phi
+            } else {
+                phi
+            }
+        } else if last == 180.0 && phi > PI {
+            // Symmetric about the 0-180 plane
+            TAU - phi
+        } else {
+            phi
+        }
+    }
+}
+
+/// Index of the interval of `angles` containing `x` and the position of `x` in it, clamped to
+/// the ends
+fn locate(angles: &[Scalar], x: Scalar) -> (usize, Scalar) {
+    let i = angles
+        .partition_point(|&angle| angle <= x)
+        .saturating_sub(1)
+        .min(angles.len().saturating_sub(2));
+    match angles.get(i + 1) {
+        Some(&next) => (i, ((x - angles[i]) / (next - angles[i])).clamp(0.0, 1.0)),
+        None => (i, 0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{assert_abs_diff_eq, vec3, InnerSpace};
+
+    const DOWNLIGHT: &str = "IESNA:LM-63-2002
+[TEST] downlight
+[MANUFAC] test
+TILT=NONE
+1 1000 2.0 4 1 1 2 0.1 0.1 0.0
+1.0 1.0 10
+0 45 90 180
+0
+100, 50, 0, 0
+";
+
+    #[test]
+    fn parse_symmetric_profile() {
+        let profile = IesProfile::parse(DOWNLIGHT).unwrap();
+        assert_eq!(profile.intensity_at(0.0, 0.0), 1.0);
+        assert_abs_diff_eq!(profile.intensity_at(PI / 8.0, 1.0), 0.75, epsilon = 1e-5);
+        assert_eq!(profile.intensity_at(PI / 2.0, 3.0), 0.0);
+        // The nadir is +z in the luminaire's frame
+        assert_eq!(profile.intensity(vec3(0.0, 0.0, 1.0)), 1.0);
+        assert_eq!(profile.intensity(vec3(0.0, 0.0, -1.0)), 0.0);
+        assert_abs_diff_eq!(
+            profile.intensity(vec3(1.0, 1.0, 0.0).normalize()),
+            0.0,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn horizontal_symmetry() {
+        // Quadrant symmetric, brighter towards 90 degrees
+        let source = "TILT=NONE
+1 -1 1 2 3 1 1 0 0 0
+1 1 0
+0 90
+0 45 90
+10 10
+20 20
+40 40
+";
+        let profile = IesProfile::parse(source).unwrap();
+        let at = |degrees: Scalar| profile.intensity_at(0.5, degrees.to_radians());
+        assert_abs_diff_eq!(at(0.0), 0.25, epsilon = 1e-5);
+        assert_abs_diff_eq!(at(90.0), 1.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(at(135.0), at(45.0), epsilon = 1e-5);
+        assert_abs_diff_eq!(at(200.0), at(20.0), epsilon = 1e-5);
+        assert_abs_diff_eq!(at(300.0), at(60.0), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn isotropic_integral() {
+        let source = "TILT=NONE
+1 -1 1 2 1 1 1 0 0 0
+1 1 0
+0 180
+0
+5 5
+";
+        let profile = IesProfile::parse(source).unwrap();
+        assert_eq!(profile.intensity(vec3(0.3, -0.2, 0.5).normalize()), 1.0);
+        assert_abs_diff_eq!(profile.integral(), 4.0 * PI, epsilon = 1e-2);
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(IesProfile::parse("").is_err());
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 4 1").is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("4 1 1 2", "4 1 2 2")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("0 45 90", "0 90 45")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("100, 50", "0, 0")).is_err());
+        assert!(IesProfile::parse(&DOWNLIGHT.replace("TILT=NONE", "TILT=lamp.tlt")).is_err());
+    }
+}
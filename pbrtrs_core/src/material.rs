@@ -1,13 +1,15 @@
 use crate::bxdf::distribution::TrowbridgeReitzDistribution;
 use crate::bxdf::{
-    BxDF, FresnelSchlick, FresnelSpecular, Lambertian, MicrofacetReflection, BSDF,
+    BxDF, DisneyDiffuse, Dispersion, FresnelSchlick, FresnelSpecular, Lambertian,
+    MicrofacetReflection, OrenNayar, Wavelength, BSDF,
 };
 use crate::intersect::Intersection;
-use crate::scene::{DisneyMaterial, SampledDisneyMaterial};
+use crate::scene::{DiffuseModel, DisneyMaterial, SampledDisneyMaterial};
 use crate::types::color::WHITE;
-use crate::types::{color, Color, Pt2};
+use crate::types::scalar::consts::PI;
+use crate::types::{color, Color, Pt2, Scalar};
 use bumpalo::Bump;
-use cgmath::{point2, Array};
+use cgmath::{point2, vec3, Array};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum TransportMode {
@@ -20,11 +22,15 @@ pub trait Material {
 
     fn sample(&self, uv: Pt2) -> Self::Sampled;
 
+    /// `outside_ior` is the IOR of the medium on the side of the surface the normal points to.
+    /// `wavelength` is the channel dispersive surfaces refract, `None` for other surfaces.
     fn compute_scattering<'arena, O>(
         si: &Intersection<Self::Sampled, O>,
         arena: &'arena Bump,
         mode: TransportMode,
         allow_multiple_lobes: bool,
+        outside_ior: Scalar,
+        wavelength: Option<Wavelength>,
     ) -> BSDF<'arena>;
 }
 
@@ -40,12 +46,21 @@ impl Material for DisneyMaterial {
             specular_tint: self.specular_tint.get(uv),
             roughness: self.roughness.get(uv),
             anisotropic: self.anisotropic.get(uv),
+            anisotropic_rotation: self.anisotropic_rotation.get(uv),
             sheen: self.sheen.get(uv),
             sheen_tint: self.sheen_tint.get(uv),
             clearcoat: self.clearcoat.get(uv),
             clearcoat_gloss: self.clearcoat_gloss.get(uv),
             transmission: self.transmission.get(uv),
             ior: self.ior.get(uv),
+            dispersion: self.dispersion,
+            emission: self.emission.get(uv) * self.emission_strength,
+            normal: self.normal_map.as_ref().map(|map| {
+                let texel = map.get(uv);
+                vec3(texel.r, texel.g, texel.b) * 2.0 - vec3(1.0, 1.0, 1.0)
+            }),
+            conductor: self.conductor,
+            diffuse_model: self.diffuse_model,
         }
     }
 
@@ -54,9 +69,12 @@ impl Material for DisneyMaterial {
         arena: &'arena Bump,
         transport_mode: TransportMode,
         allow_multiple_lobes: bool,
+        outside_ior: Scalar,
+        wavelength: Option<Wavelength>,
     ) -> BSDF<'arena> {
         let SampledDisneyMaterial {
             base_color,
+            subsurface,
             metallic,
             specular: specular_level,
             specular_tint,
@@ -64,26 +82,65 @@ impl Material for DisneyMaterial {
             clearcoat,
             clearcoat_gloss,
             anisotropic,
+            anisotropic_rotation,
             transmission,
             ior,
+            dispersion,
+            conductor,
+            diffuse_model,
             ..
         } = si.sampled_material;
         let mut bsdf = BSDF::new(si);
+        if let Some(normal) = si.sampled_material.normal {
+            let bitangent = si.normal.cross(si.tangent);
+            bsdf.set_shading_normal(
+                si.tangent * normal.x + bitangent * normal.y + si.normal * normal.z,
+            );
+        }
 
         if transmission > 0.0 {
             let transmission = arena.alloc(FresnelSpecular {
                 color: base_color,
-                eta_a: 1.0,
+                eta_a: outside_ior,
                 eta_b: ior,
                 transport_mode,
+                dispersion: wavelength
+                    .filter(|_| dispersion > 0.0)
+                    .map(|wavelength| Dispersion {
+                        abbe_number: dispersion,
+                        wavelength,
+                    }),
             });
             bsdf.add(transmission);
             return bsdf;
         }
 
+        // Opaque surfaces are shaded on the side they're seen from
+        if !si.front_face {
+            bsdf.flip_normal();
+        }
+
         if metallic != 1.0 {
-            let lambert = arena.alloc(Lambertian(base_color).scale(1.0 - metallic));
-            bsdf.add(lambert);
+            match diffuse_model {
+                DiffuseModel::Lambertian if subsurface > 0.0 => {
+                    let diffuse = DisneyDiffuse {
+                        color: base_color,
+                        roughness,
+                        subsurface,
+                    };
+                    bsdf.add(arena.alloc(diffuse.scale(1.0 - metallic)));
+                }
+                DiffuseModel::Lambertian => {
+                    bsdf.add(arena.alloc(Lambertian(base_color).scale(1.0 - metallic)));
+                }
+                DiffuseModel::OrenNayar => {
+                    bsdf.add(arena.alloc(OrenNayar(base_color, roughness).scale(1.0 - metallic)));
+                }
+            }
+        }
+
+        if anisotropic_rotation != 0.0 {
+            bsdf.rotate_tangent(anisotropic_rotation * PI);
         }
 
         let alpha = roughness.powi(2);
@@ -97,12 +154,25 @@ impl Material for DisneyMaterial {
         ));
 
         let distribution = TrowbridgeReitzDistribution::new(alpha);
-        let specular = arena.alloc(MicrofacetReflection {
-            color: color::mix(WHITE, base_color, specular_tint),
-            distribution,
-            fresnel,
-        });
-        bsdf.add(specular);
+        match conductor {
+            // The conductor's complex IOR determines the color, not the base color
+            Some(conductor) if metallic == 1.0 => {
+                let specular = arena.alloc(MicrofacetReflection {
+                    color: WHITE,
+                    distribution,
+                    fresnel: conductor,
+                });
+                bsdf.add(specular);
+            }
+            _ => {
+                let specular = arena.alloc(MicrofacetReflection {
+                    color: color::mix(WHITE, base_color, specular_tint),
+                    distribution,
+                    fresnel,
+                });
+                bsdf.add(specular);
+            }
+        }
 
         if allow_multiple_lobes && clearcoat != 0.0 {
             // TODO: use isotropic Trowbridge-Reitz with gamma=1
@@ -132,7 +202,106 @@ impl Material for EmptyMaterial {
         _arena: &'arena Bump,
         _mode: TransportMode,
         _allow_multiple_lobes: bool,
+        _outside_ior: Scalar,
+        _wavelength: Option<Wavelength>,
     ) -> BSDF<'arena> {
         BSDF::new(si)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bxdf::BxDFKind;
+    use crate::scene::MaterialBuilder;
+    use crate::types::Vec3;
+    use cgmath::{assert_abs_diff_eq, point3, vec3, InnerSpace};
+
+    #[test]
+    fn anisotropic_rotation_swaps_axes() {
+        let bsdf_f = |rotation: Scalar, wo: Vec3, wi: Vec3| {
+            let material = MaterialBuilder::new()
+                .base_color(WHITE)
+                .metallic(1.0)
+                .roughness(0.5)
+                .anisotropic(0.9)
+                .anisotropic_rotation(rotation)
+                .build();
+            let si = Intersection {
+                distance: 1.0,
+                normal: vec3(0.0, 0.0, 1.0),
+                tangent: vec3(1.0, 0.0, 0.0),
+                point: point3(0.0, 0.0, 0.0),
+                error: 0.0,
+                front_face: true,
+                sampled_material: material.sample(point2(0.5, 0.5)),
+                object: &(),
+                uv: point2(0.5, 0.5),
+                time: 0.0,
+            };
+            let arena = Bump::new();
+            let bsdf = DisneyMaterial::compute_scattering(
+                &si,
+                &arena,
+                TransportMode::Radiance,
+                true,
+                1.0,
+                None,
+            );
+            bsdf.f(wo, wi, BxDFKind::ALL).r
+        };
+        // Quarter turn around the normal
+        let turn = |v: Vec3| vec3(-v.y, v.x, v.z);
+
+        let wo = vec3(0.6, 0.2, 0.7).normalize();
+        let wi = vec3(-0.5, 0.3, 0.8).normalize();
+        let unrotated = bsdf_f(0.0, wo, wi);
+        // Rotating the anisotropy by 90 degrees matches turning both directions instead
+        assert_abs_diff_eq!(
+            bsdf_f(0.5, turn(wo), turn(wi)),
+            unrotated,
+            epsilon = 1e-4 * unrotated
+        );
+        assert!((bsdf_f(0.5, wo, wi) - unrotated).abs() > 0.05 * unrotated);
+        // A half turn is symmetric for the microfacet distribution
+        assert_abs_diff_eq!(bsdf_f(1.0, wo, wi), unrotated, epsilon = 1e-4 * unrotated);
+    }
+
+    #[test]
+    fn subsurface_replaces_lambertian() {
+        let bsdf_f = |subsurface: Scalar| {
+            let material = MaterialBuilder::new()
+                .base_color(WHITE)
+                .subsurface(subsurface)
+                .specular(0.0)
+                .build();
+            let si = Intersection {
+                distance: 1.0,
+                normal: vec3(0.0, 0.0, 1.0),
+                tangent: vec3(1.0, 0.0, 0.0),
+                point: point3(0.0, 0.0, 0.0),
+                error: 0.0,
+                front_face: true,
+                sampled_material: material.sample(point2(0.5, 0.5)),
+                object: &(),
+                uv: point2(0.5, 0.5),
+                time: 0.0,
+            };
+            let arena = Bump::new();
+            let bsdf = DisneyMaterial::compute_scattering(
+                &si,
+                &arena,
+                TransportMode::Radiance,
+                true,
+                1.0,
+                None,
+            );
+            let normal = vec3(0.0, 0.0, 1.0);
+            bsdf.f(normal, normal, BxDFKind::ALL).r
+        };
+
+        assert_abs_diff_eq!(bsdf_f(0.0), 1.0 / PI, epsilon = 1e-6);
+        // Head on the subsurface approximation is darker than the diffuse lobe it replaces
+        assert!(bsdf_f(1.0) < 0.7 / PI);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::types::color::BLACK;
+use crate::types::scalar::consts::PI;
+use crate::types::{Color, Pt2, Scalar, Vec3};
+use crate::util::coordinate_system;
+use cgmath::InnerSpace;
+use serde::de::Error as SerdeError;
+use serde::{Deserialize as DeserializeTrait, Deserialize, Deserializer};
+
+/// Angular distribution of light scattered inside a medium
+pub trait PhaseFunction {
+    /// Density of scattering from `wo` into `wi`, both pointing away from the scattering point
+    fn p(&self, wo: Vec3, wi: Vec3) -> Scalar;
+
+    /// Samples an incident direction, returning its pdf which is equal to the phase function
+    fn sample_p(&self, wo: Vec3, u: Pt2, wi: &mut Vec3) -> Scalar;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HenyeyGreenstein {
+    /// Anisotropy in (-1, 1), positive values scatter forward
+    pub g: Scalar,
+}
+
+fn henyey_greenstein(cos_theta: Scalar, g: Scalar) -> Scalar {
+    let denom = 1.0 + g * g + 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denom * denom.sqrt())
+}
+
+impl PhaseFunction for HenyeyGreenstein {
+    fn p(&self, wo: Vec3, wi: Vec3) -> Scalar {
+        henyey_greenstein(wo.dot(wi), self.g)
+    }
+
+    fn sample_p(&self, wo: Vec3, u: Pt2, wi: &mut Vec3) -> Scalar {
+        let g = self.g;
+        // Cosine of the angle between -wo and wi
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.x
+        } else {
+            let sqr_term = (1.0 - g * g) / (1.0 + g - 2.0 * g * u.x);
+            (1.0 + g * g - sqr_term * sqr_term) / (2.0 * g)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.y;
+
+        let (v1, v2) = coordinate_system(-wo);
+        *wi = v1 * sin_theta * phi.cos() + v2 * sin_theta * phi.sin() - wo * cos_theta;
+        henyey_greenstein(-cos_theta, g)
+    }
+}
+
+/// Result of sampling a distance along a ray through a medium
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediumSample {
+    /// Factor to scale the path throughput by
+    pub weight: Color,
+    /// Distance to the sampled scattering event, `None` if the ray passed through the medium
+    pub scatter_distance: Option<Scalar>,
+}
+
+fn is_non_negative(c: Color) -> bool {
+    c.r >= 0.0 && c.g >= 0.0 && c.b >= 0.0
+}
+
+#[derive(Debug, Deserialize)]
+struct HomogeneousMediumRaw {
+    sigma_a: Color,
+    sigma_s: Color,
+    #[serde(default)]
+    g: Scalar,
+}
+
+/// A medium with constant density filling the whole scene, e.g. fog
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HomogeneousMedium {
+    /// Absorption coefficient per unit distance
+    pub sigma_a: Color,
+    /// Scattering coefficient per unit distance
+    pub sigma_s: Color,
+    /// Henyey-Greenstein anisotropy of the scattered light
+    pub g: Scalar,
+}
+
+impl HomogeneousMedium {
+    pub fn new(sigma_a: Color, sigma_s: Color, g: Scalar) -> Self {
+        assert!(
+            g > -1.0 && g < 1.0,
+            "Medium anisotropy must be in (-1, 1), got {g}"
+        );
+        assert!(
+            is_non_negative(sigma_a) && is_non_negative(sigma_s),
+            "Medium coefficients must be non-negative"
+        );
+        Self {
+            sigma_a,
+            sigma_s,
+            g,
+        }
+    }
+
+    /// Extinction coefficient, the sum of absorption and scattering
+    pub fn sigma_t(&self) -> Color {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn phase(&self) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: self.g }
+    }
+
+    /// Fraction of light that passes through `distance` of the medium (Beer-Lambert law)
+    pub fn transmittance(&self, distance: Scalar) -> Color {
+        // Avoids 0 * inf for channels the medium doesn't attenuate
+        self.sigma_t()
+            .map(|s| if s == 0.0 { 1.0 } else { (-s * distance).exp() })
+    }
+
+    /// Samples a scattering distance along a ray that hits a surface at `max_distance`, choosing
+    /// a color channel uniformly to sample the exponential falloff with.
+    pub fn sample(&self, max_distance: Scalar, u: Pt2) -> MediumSample {
+        let sigma_t = self.sigma_t();
+        let channel = ((u.x * 3.0) as usize).min(2);
+        let distance = if sigma_t[channel] == 0.0 {
+            Scalar::INFINITY
+        } else {
+            -(1.0 - u.y).ln() / sigma_t[channel]
+        };
+
+        let scattered = distance < max_distance;
+        let distance = distance.min(max_distance);
+        let tr = self.transmittance(distance);
+        let density = if scattered { sigma_t * tr } else { tr };
+        let pdf = density.average();
+        if pdf == 0.0 {
+            return MediumSample {
+                weight: BLACK,
+                scatter_distance: None,
+            };
+        }
+
+        if scattered {
+            MediumSample {
+                weight: tr * self.sigma_s / pdf,
+                scatter_distance: Some(distance),
+            }
+        } else {
+            MediumSample {
+                weight: tr / pdf,
+                scatter_distance: None,
+            }
+        }
+    }
+}
+
+impl<'de> DeserializeTrait<'de> for HomogeneousMedium {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let HomogeneousMediumRaw {
+            sigma_a,
+            sigma_s,
+            g,
+        } = HomogeneousMediumRaw::deserialize(deserializer)?;
+        if !(g > -1.0 && g < 1.0) {
+            return Err(D::Error::custom(format!(
+                "Medium anisotropy g must be in (-1, 1), got {g}"
+            )));
+        }
+        if !is_non_negative(sigma_a) || !is_non_negative(sigma_s) {
+            return Err(D::Error::custom(
+                "Medium sigma_a and sigma_s must be non-negative",
+            ));
+        }
+        Ok(HomogeneousMedium {
+            sigma_a,
+            sigma_s,
+            g,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{color, scalar};
+    use cgmath::{assert_abs_diff_eq, point2, vec3};
+
+    #[test]
+    fn henyey_greenstein_sampling() {
+        fastrand::seed(3);
+        let wo = vec3(0.0, 0.6, 0.8);
+        for g in [-0.7, 0.0, 0.5] {
+            let phase = HenyeyGreenstein { g };
+            let mut mean_cos = 0.0;
+            let n = 20000;
+            for _ in 0..n {
+                let mut wi = vec3(0.0, 0.0, 0.0);
+                let pdf = phase.sample_p(wo, point2(scalar::rand(), scalar::rand()), &mut wi);
+                assert_abs_diff_eq!(wi.magnitude(), 1.0, epsilon = 1e-4);
+                assert_abs_diff_eq!(pdf, phase.p(wo, wi), epsilon = 1e-3 * pdf.max(1.0));
+                mean_cos += (-wo).dot(wi);
+            }
+            // The mean cosine of the scattering angle is g
+            assert_abs_diff_eq!(mean_cos / n as Scalar, g, epsilon = 2e-2);
+        }
+    }
+
+    #[test]
+    fn deserialize() {
+        let medium: HomogeneousMedium =
+            toml::from_str("sigma_a = [0.1, 0.1, 0.1]\nsigma_s = [0.5, 0.5, 0.5]\ng = 0.3")
+                .unwrap();
+        assert_eq!(
+            medium,
+            HomogeneousMedium::new(color(0.1, 0.1, 0.1), color(0.5, 0.5, 0.5), 0.3)
+        );
+        assert!(toml::from_str::<HomogeneousMedium>(
+            "sigma_a = [0.1, 0.1, 0.1]\nsigma_s = [0.5, 0.5, 0.5]\ng = 1.0"
+        )
+        .is_err());
+        assert!(toml::from_str::<HomogeneousMedium>(
+            "sigma_a = [-0.1, 0.1, 0.1]\nsigma_s = [0.5, 0.5, 0.5]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn transmittance() {
+        let medium = HomogeneousMedium::new(color(0.1, 0.0, 0.5), color(0.2, 0.0, 0.0), 0.0);
+        assert_abs_diff_eq!(
+            medium.transmittance(2.0),
+            color((-0.6 as Scalar).exp(), 1.0, (-1.0 as Scalar).exp()),
+            epsilon = 1e-6
+        );
+        assert_eq!(medium.transmittance(Scalar::INFINITY), color(0.0, 1.0, 0.0));
+
+        // Samples that pass through the medium are weighted to average to the transmittance
+        fastrand::seed(5);
+        let n = 50000;
+        let mut passed = color(0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let sample = medium.sample(2.0, point2(scalar::rand(), scalar::rand()));
+            if sample.scatter_distance.is_none() {
+                passed += sample.weight;
+            }
+        }
+        assert_abs_diff_eq!(
+            passed / n as Scalar,
+            medium.transmittance(2.0),
+            epsilon = 2e-2
+        );
+    }
+}
@@ -0,0 +1,673 @@
+use crate::intersect::{LocalHit, RAY_PACKET, T_MIN};
+use crate::sampling::Distribution1D;
+use crate::types::scalar;
+use crate::types::{Pt2, Pt3, Ray, Scalar, Vec3};
+use crate::util::coordinate_system;
+use cgmath::{point2, point3, EuclideanSpace, InnerSpace};
+use std::fmt::{Debug, Formatter};
+
+/// Most triangles in a leaf of the bounding volume hierarchy
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+/// Triangles sharing a vertex list, in the object space of the object they belong to. Triangles
+/// wind counter clockwise around the outward normal.
+pub struct TriangleMesh {
+    positions: Vec<Pt3>,
+    /// Shading normals for each vertex, the faces are flat shaded without them
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<Pt2>>,
+    /// Vertex indices of each triangle, ordered so each leaf of `nodes` covers a range of them
+    triangles: Vec<[u32; 3]>,
+    nodes: Vec<BvhNode>,
+    /// Distribution over the triangles proportional to their area
+    area_distribution: Distribution1D,
+    area: Scalar,
+}
+
+impl Debug for TriangleMesh {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TriangleMesh")
+            .field("vertices", &self.positions.len())
+            .field("triangles", &self.triangles.len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Pt3,
+    max: Pt3,
+}
+
+impl Bounds {
+    const EMPTY: Bounds = Bounds {
+        min: point3(Scalar::INFINITY, Scalar::INFINITY, Scalar::INFINITY),
+        max: point3(
+            Scalar::NEG_INFINITY,
+            Scalar::NEG_INFINITY,
+            Scalar::NEG_INFINITY,
+        ),
+    };
+
+    fn with_point(self, p: Pt3) -> Bounds {
+        Bounds {
+            min: point3(
+                self.min.x.min(p.x),
+                self.min.y.min(p.y),
+                self.min.z.min(p.z),
+            ),
+            max: point3(
+                self.max.x.max(p.x),
+                self.max.y.max(p.y),
+                self.max.z.max(p.z),
+            ),
+        }
+    }
+
+    fn centroid(&self) -> Pt3 {
+        self.min.midpoint(self.max)
+    }
+
+    fn largest_axis(&self) -> usize {
+        let extent = self.max - self.min;
+        if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Whether the ray with direction reciprocal `inv_direction` enters the box before `t_max`
+    fn hit(&self, origin: Pt3, inv_direction: Vec3, t_max: Scalar) -> bool {
+        let mut t0: Scalar = 0.0;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let near = (self.min[axis] - origin[axis]) * inv_direction[axis];
+            let far = (self.max[axis] - origin[axis]) * inv_direction[axis];
+            let (near, far) = if near > far { (far, near) } else { (near, far) };
+            // NaN from 0 * inf leaves the interval unchanged
+            t0 = if near > t0 { near } else { t0 };
+            t1 = if far < t1 { far } else { t1 };
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// [`Bounds::hit`] for each ray of a packet, returns the mask of rays in `active` that enter
+    /// the box. Once the interval is empty it stays empty, so testing it after all three axes
+    /// agrees with returning early.
+    fn hit_packet(&self, rays: &PacketRays, t_max: &[Scalar; RAY_PACKET], active: u8) -> u8 {
+        let mut t0: [Scalar; RAY_PACKET] = [0.0; RAY_PACKET];
+        let mut t1 = *t_max;
+        for axis in 0..3 {
+            for lane in 0..RAY_PACKET {
+                let origin = rays.origin[axis][lane];
+                let inv_direction = rays.inv_direction[axis][lane];
+                let near = (self.min[axis] - origin) * inv_direction;
+                let far = (self.max[axis] - origin) * inv_direction;
+                let (near, far) = if near > far { (far, near) } else { (near, far) };
+                t0[lane] = if near > t0[lane] { near } else { t0[lane] };
+                t1[lane] = if far < t1[lane] { far } else { t1[lane] };
+            }
+        }
+        (0..RAY_PACKET)
+            .filter(|&lane| t0[lane] <= t1[lane])
+            .fold(0, |mask, lane| mask | 1 << lane)
+            & active
+    }
+}
+
+/// Origins and direction reciprocals of a packet of rays, by axis and then by ray
+struct PacketRays {
+    origin: [[Scalar; RAY_PACKET]; 3],
+    inv_direction: [[Scalar; RAY_PACKET]; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Bounds,
+    /// First triangle of a leaf, or the index of the second child of an interior node. The
+    /// first child directly follows its parent.
+    offset: usize,
+    /// Number of triangles in a leaf, zero for interior nodes
+    count: usize,
+    /// Axis an interior node is split along
+    axis: usize,
+}
+
+/// Nearest triangle hit by a ray and the barycentric coordinates of the hit on it
+struct TriangleHit {
+    distance: Scalar,
+    triangle: usize,
+    barycentric: [Scalar; 3],
+}
+
+impl TriangleMesh {
+    /// Panics if an index is out of range or the attributes don't have one value per vertex
+    pub fn new(
+        positions: Vec<Pt3>,
+        normals: Option<Vec<Vec3>>,
+        uvs: Option<Vec<Pt2>>,
+        triangles: Vec<[u32; 3]>,
+    ) -> Self {
+        assert!(!triangles.is_empty(), "Mesh has no triangles");
+        assert!(
+            triangles
+                .iter()
+                .flatten()
+                .all(|&i| (i as usize) < positions.len()),
+            "Mesh triangle index out of range"
+        );
+        assert!(
+            normals.as_ref().is_none_or(|n| n.len() == positions.len())
+                && uvs.as_ref().is_none_or(|uv| uv.len() == positions.len()),
+            "Mesh needs one normal and uv per vertex"
+        );
+
+        let mut mesh = TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            triangles,
+            nodes: Vec::new(),
+            area_distribution: Distribution1D::new(vec![1.0]),
+            area: 0.0,
+        };
+        let mut triangles = std::mem::take(&mut mesh.triangles);
+        mesh.build_node(&mut triangles, 0);
+        mesh.triangles = triangles;
+
+        let areas = (0..mesh.triangles.len())
+            .map(|i| mesh.triangle_area(i))
+            .collect::<Vec<_>>();
+        mesh.area = areas.iter().sum();
+        mesh.area_distribution = Distribution1D::new(areas);
+        mesh
+    }
+
+    pub fn area(&self) -> Scalar {
+        self.area
+    }
+
+    /// Radius of a sphere around the object space origin that contains the mesh
+    pub fn bounding_radius(&self) -> Scalar {
+        self.positions
+            .iter()
+            .map(|p| p.to_vec().magnitude())
+            .fold(0.0, Scalar::max)
+    }
+
+    /// Corners of every triangle
+    pub fn triangles(&self) -> impl Iterator<Item = [Pt3; 3]> + '_ {
+        self.triangles
+            .iter()
+            .map(|t| t.map(|i| self.positions[i as usize]))
+    }
+
+    fn corners(&self, triangle: usize) -> [Pt3; 3] {
+        self.triangles[triangle].map(|i| self.positions[i as usize])
+    }
+
+    fn triangle_area(&self, triangle: usize) -> Scalar {
+        let [p0, p1, p2] = self.corners(triangle);
+        (p1 - p0).cross(p2 - p0).magnitude() / 2.0
+    }
+
+    fn triangle_bounds(&self, triangle: &[u32; 3]) -> Bounds {
+        triangle.iter().fold(Bounds::EMPTY, |b, &i| {
+            b.with_point(self.positions[i as usize])
+        })
+    }
+
+    /// Appends the subtree over `triangles`, which start at index `first` of the mesh's
+    /// triangles. Sorts `triangles` into leaf order.
+    fn build_node(&mut self, triangles: &mut [[u32; 3]], first: usize) {
+        let bounds = triangles
+            .iter()
+            .map(|t| self.triangle_bounds(t))
+            .fold(Bounds::EMPTY, |a, b| a.with_point(b.min).with_point(b.max));
+        let index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            offset: first,
+            count: triangles.len(),
+            axis: 0,
+        });
+        if triangles.len() <= MAX_LEAF_TRIANGLES {
+            return;
+        }
+
+        // Split at the median centroid along the axis the centroids spread the most
+        let centroid_bounds = triangles.iter().fold(Bounds::EMPTY, |b, t| {
+            b.with_point(self.triangle_bounds(t).centroid())
+        });
+        let axis = centroid_bounds.largest_axis();
+        let mid = triangles.len() / 2;
+        triangles.select_nth_unstable_by(mid, |a, b| {
+            let a = self.triangle_bounds(a).centroid()[axis];
+            let b = self.triangle_bounds(b).centroid()[axis];
+            a.total_cmp(&b)
+        });
+
+        let (left, right) = triangles.split_at_mut(mid);
+        self.build_node(left, first);
+        let second = self.nodes.len();
+        self.build_node(right, first + mid);
+        self.nodes[index] = BvhNode {
+            bounds,
+            offset: second,
+            count: 0,
+            axis,
+        };
+    }
+
+    /// Nearest hit of `ray` between `T_MIN` and `ray.t_max`, in object space
+    pub(crate) fn intersect(&self, ray: &Ray) -> Option<LocalHit> {
+        let hit = self.intersect_triangles(ray)?;
+        Some(self.local_hit(hit))
+    }
+
+    fn intersect_triangles(&self, ray: &Ray) -> Option<TriangleHit> {
+        let inv_direction = ray.direction.map(|d| 1.0 / d);
+        let mut nearest: Option<TriangleHit> = None;
+        let mut t_max = ray.t_max;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounds.hit(ray.origin, inv_direction, t_max) {
+                continue;
+            }
+            if node.count > 0 {
+                for triangle in node.offset..node.offset + node.count {
+                    if let Some(hit) = self.intersect_triangle(ray, triangle, t_max) {
+                        t_max = hit.distance;
+                        nearest = Some(hit);
+                    }
+                }
+            } else if ray.direction[node.axis] < 0.0 {
+                // Visit the nearer child first so farther ones are culled by `t_max`
+                stack.push(index + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(index + 1);
+            }
+        }
+        nearest
+    }
+
+    /// [`TriangleMesh::intersect`] for each ray of a packet, traversing the hierarchy once for
+    /// all of them. Each ray only tests the nodes and triangles it would test alone, so the hits
+    /// are the same. Rays heading into different octants visit children in different orders and
+    /// are traced one at a time.
+    pub(crate) fn intersect_packet(
+        &self,
+        rays: &[Ray; RAY_PACKET],
+    ) -> [Option<LocalHit>; RAY_PACKET] {
+        let same_octant = (0..3).all(|axis| {
+            rays.iter()
+                .all(|ray| (ray.direction[axis] < 0.0) == (rays[0].direction[axis] < 0.0))
+        });
+        if !same_octant {
+            return rays.each_ref().map(|ray| self.intersect(ray));
+        }
+
+        let mut packet = PacketRays {
+            origin: [[0.0; RAY_PACKET]; 3],
+            inv_direction: [[0.0; RAY_PACKET]; 3],
+        };
+        for (lane, ray) in rays.iter().enumerate() {
+            for axis in 0..3 {
+                packet.origin[axis][lane] = ray.origin[axis];
+                packet.inv_direction[axis][lane] = 1.0 / ray.direction[axis];
+            }
+        }
+        let mut nearest: [Option<TriangleHit>; RAY_PACKET] = Default::default();
+        let mut t_max = rays.each_ref().map(|ray| ray.t_max);
+        // Each node is pushed with the rays that entered its parent
+        let mut stack = vec![(0, (1 << RAY_PACKET) - 1)];
+        while let Some((index, active)) = stack.pop() {
+            let node = &self.nodes[index];
+            let active = node.bounds.hit_packet(&packet, &t_max, active);
+            if active == 0 {
+                continue;
+            }
+            if node.count > 0 {
+                // The corners are loaded once for all rays
+                for triangle in node.offset..node.offset + node.count {
+                    let edges = self.edges(triangle);
+                    for lane in (0..RAY_PACKET).filter(|lane| active & 1 << lane != 0) {
+                        if let Some(hit) =
+                            intersect_triangle_edges(&rays[lane], edges, triangle, t_max[lane])
+                        {
+                            t_max[lane] = hit.distance;
+                            nearest[lane] = Some(hit);
+                        }
+                    }
+                }
+            } else if rays[0].direction[node.axis] < 0.0 {
+                stack.push((index + 1, active));
+                stack.push((node.offset, active));
+            } else {
+                stack.push((node.offset, active));
+                stack.push((index + 1, active));
+            }
+        }
+        nearest.map(|hit| hit.map(|hit| self.local_hit(hit)))
+    }
+
+    /// Möller-Trumbore intersection with a single triangle
+    fn intersect_triangle(&self, ray: &Ray, triangle: usize, t_max: Scalar) -> Option<TriangleHit> {
+        intersect_triangle_edges(ray, self.edges(triangle), triangle, t_max)
+    }
+
+    /// First corner of a triangle and its edges to the other two
+    fn edges(&self, triangle: usize) -> (Pt3, Vec3, Vec3) {
+        let [p0, p1, p2] = self.corners(triangle);
+        (p0, p1 - p0, p2 - p0)
+    }
+
+    fn local_hit(&self, hit: TriangleHit) -> LocalHit {
+        let [b0, b1, b2] = hit.barycentric;
+        let [p0, p1, p2] = self.corners(hit.triangle);
+        let point = Pt3::from_vec(p0.to_vec() * b0 + p1.to_vec() * b1 + p2.to_vec() * b2);
+        let l1 = |p: Pt3| p.x.abs() + p.y.abs() + p.z.abs();
+        let error = (b0 * l1(p0) + b1 * l1(p1) + b2 * l1(p2)) * 8.0 * Scalar::EPSILON;
+
+        let (normal, tangent, uv) = self.surface_at(hit.triangle, hit.barycentric);
+        LocalHit {
+            distance: hit.distance,
+            point,
+            error,
+            normal,
+            tangent,
+            uv,
+        }
+    }
+
+    /// Shading normal, tangent along +u and texture coordinate at a point of a triangle
+    fn surface_at(&self, triangle: usize, barycentric: [Scalar; 3]) -> (Vec3, Vec3, Pt2) {
+        let indices = self.triangles[triangle].map(|i| i as usize);
+        let [p0, p1, p2] = self.corners(triangle);
+        let face_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let normal = self
+            .normals
+            .as_ref()
+            .map(|normals| {
+                (0..3).fold(Vec3::new(0.0, 0.0, 0.0), |sum, i| {
+                    sum + normals[indices[i]] * barycentric[i]
+                })
+            })
+            .filter(|n| n.magnitude2() > 0.0)
+            .map_or(face_normal, InnerSpace::normalize);
+
+        // Without texture coordinates the corners map to (0, 0), (1, 0) and (1, 1)
+        let corner_uvs = match &self.uvs {
+            Some(uvs) => indices.map(|i| uvs[i]),
+            None => [point2(0.0, 0.0), point2(1.0, 0.0), point2(1.0, 1.0)],
+        };
+        let uv = (0..3).fold(point2(0.0, 0.0), |sum, i| {
+            sum + corner_uvs[i].to_vec() * barycentric[i]
+        });
+        // Texture coordinates repeat, as in glTF
+        let uv = uv.map(|c| c.rem_euclid(1.0));
+
+        let (duv02, duv12) = (corner_uvs[0] - corner_uvs[2], corner_uvs[1] - corner_uvs[2]);
+        let det = duv02.x * duv12.y - duv02.y * duv12.x;
+        let dpdu = if det.abs() > 1e-12 {
+            ((p0 - p2) * duv12.y - (p1 - p2) * duv02.y) / det
+        } else {
+            coordinate_system(normal).0
+        };
+        let tangent = dpdu - normal * normal.dot(dpdu);
+        let tangent = if tangent.magnitude2() > 1e-12 {
+            tangent.normalize()
+        } else {
+            coordinate_system(normal).0
+        };
+        (normal, tangent, uv)
+    }
+
+    /// Samples a point uniformly over the area of the mesh, returning the point, its face normal
+    /// and its texture coordinate
+    pub fn sample(&self) -> (Pt3, Vec3, Pt2) {
+        let (triangle, _) = self.area_distribution.sample_discrete(scalar::rand());
+        let su0 = scalar::rand().sqrt();
+        let b1 = 1.0 - su0;
+        let b2 = scalar::rand() * su0;
+        let barycentric = [1.0 - b1 - b2, b1, b2];
+
+        let [p0, p1, p2] = self.corners(triangle);
+        let point = Pt3::from_vec(
+            p0.to_vec() * barycentric[0]
+                + p1.to_vec() * barycentric[1]
+                + p2.to_vec() * barycentric[2],
+        );
+        let face_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let (_, _, uv) = self.surface_at(triangle, barycentric);
+        (point, face_normal, uv)
+    }
+
+    /// Solid angle density of sampling the point `ray` hits first with [`TriangleMesh::sample`]
+    pub fn pdf(&self, ray: &Ray) -> Scalar {
+        let Some(hit) = self.intersect_triangles(ray) else {
+            return 0.0;
+        };
+        let [p0, p1, p2] = self.corners(hit.triangle);
+        let face_normal = (p1 - p0).cross(p2 - p0).normalize();
+        let cos_light = face_normal.dot(ray.direction).abs();
+        if cos_light == 0.0 {
+            0.0
+        } else {
+            hit.distance * hit.distance / (cos_light * self.area)
+        }
+    }
+}
+
+/// Möller-Trumbore intersection with the triangle at corner `p0` with edges `e1` and `e2`
+fn intersect_triangle_edges(
+    ray: &Ray,
+    (p0, e1, e2): (Pt3, Vec3, Vec3),
+    triangle: usize,
+    t_max: Scalar,
+) -> Option<TriangleHit> {
+    let p = ray.direction.cross(e2);
+    let det = e1.dot(p);
+    if det == 0.0 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+    let s = ray.origin - p0;
+    let b1 = s.dot(p) * inv_det;
+    if !(0.0..=1.0).contains(&b1) {
+        return None;
+    }
+    let q = s.cross(e1);
+    let b2 = ray.direction.dot(q) * inv_det;
+    if b2 < 0.0 || b1 + b2 > 1.0 {
+        return None;
+    }
+    let distance = e2.dot(q) * inv_det;
+    if distance < T_MIN || distance > t_max {
+        return None;
+    }
+    Some(TriangleHit {
+        distance,
+        triangle,
+        barycentric: [1.0 - b1 - b2, b1, b2],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{assert_abs_diff_eq, vec3};
+
+    /// Unit square in the xy plane at z = 0 facing +z, split into two triangles
+    fn quad() -> TriangleMesh {
+        TriangleMesh::new(
+            vec![
+                point3(0.0, 0.0, 0.0),
+                point3(1.0, 0.0, 0.0),
+                point3(1.0, 1.0, 0.0),
+                point3(0.0, 1.0, 0.0),
+            ],
+            None,
+            Some(vec![
+                point2(0.0, 0.0),
+                point2(1.0, 0.0),
+                point2(1.0, 1.0),
+                point2(0.0, 1.0),
+            ]),
+            vec![[0, 1, 2], [0, 2, 3]],
+        )
+    }
+
+    #[test]
+    fn quad_hit() {
+        let mesh = quad();
+        assert_abs_diff_eq!(mesh.area(), 1.0, epsilon = 1e-6);
+
+        let ray = Ray::new(point3(0.25, 0.75, 2.0), vec3(0.0, 0.0, -1.0), 0.0);
+        let hit = mesh.intersect(&ray).unwrap();
+        assert_abs_diff_eq!(hit.distance, 2.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(hit.point, point3(0.25, 0.75, 0.0), epsilon = 1e-6);
+        assert_abs_diff_eq!(hit.normal, vec3(0.0, 0.0, 1.0), epsilon = 1e-6);
+        assert_abs_diff_eq!(hit.tangent, vec3(1.0, 0.0, 0.0), epsilon = 1e-6);
+        assert_abs_diff_eq!(hit.uv, point2(0.25, 0.75), epsilon = 1e-6);
+
+        // Both sides are hit, the normal keeps pointing outwards
+        let below = Ray::new(point3(0.5, 0.2, -1.0), vec3(0.0, 0.0, 1.0), 0.0);
+        assert_abs_diff_eq!(
+            mesh.intersect(&below).unwrap().normal,
+            vec3(0.0, 0.0, 1.0),
+            epsilon = 1e-6
+        );
+        let beside = Ray::new(point3(1.5, 0.5, 1.0), vec3(0.0, 0.0, -1.0), 0.0);
+        assert!(mesh.intersect(&beside).is_none());
+        assert!(mesh.intersect(&ray.with_t_max(1.0)).is_none());
+    }
+
+    #[test]
+    fn bvh_finds_nearest_of_many() {
+        // A row of quads along z, split over several levels of the hierarchy
+        let mut positions = Vec::new();
+        let mut triangles = Vec::new();
+        for layer in 0..32 {
+            let z = layer as Scalar;
+            let base = positions.len() as u32;
+            positions.extend([
+                point3(-1.0, -1.0, z),
+                point3(1.0, -1.0, z),
+                point3(1.0, 1.0, z),
+                point3(-1.0, 1.0, z),
+            ]);
+            triangles.extend([[base, base + 1, base + 2], [base, base + 2, base + 3]]);
+        }
+        let mesh = TriangleMesh::new(positions, None, None, triangles);
+        assert!(mesh.nodes.len() > 1);
+
+        for (origin_z, direction_z, expected) in [(-5.0, 1.0, 5.0), (40.0, -1.0, 9.0)] {
+            let ray = Ray::new(point3(0.1, 0.3, origin_z), vec3(0.0, 0.0, direction_z), 0.0);
+            assert_abs_diff_eq!(
+                mesh.intersect(&ray).unwrap().distance,
+                expected,
+                epsilon = 1e-5
+            );
+        }
+        // Starting on a layer skips it and hits the next one
+        let on_layer = Ray::new(point3(0.1, 0.3, 3.0), vec3(0.0, 0.0, 1.0), 0.0);
+        assert_abs_diff_eq!(
+            mesh.intersect(&on_layer).unwrap().distance,
+            1.0,
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn packet_matches_single_rays() {
+        // Bumpy grid of quads, deep enough for several levels of the hierarchy
+        let size = 16;
+        let height = |x: u32, z: u32| ((x * 7 + z * 13) % 5) as Scalar * 0.1;
+        let positions = (0..=size)
+            .flat_map(|z| (0..=size).map(move |x| point3(x as Scalar, height(x, z), z as Scalar)))
+            .collect();
+        let triangles = (0..size)
+            .flat_map(|z| {
+                (0..size).flat_map(move |x| {
+                    let i = z * (size + 1) + x;
+                    [
+                        [i, i + size + 1, i + 1],
+                        [i + 1, i + size + 1, i + size + 2],
+                    ]
+                })
+            })
+            .collect();
+        let mesh = TriangleMesh::new(positions, None, None, triangles);
+
+        fastrand::seed(9);
+        let random = || fastrand::f64() as Scalar;
+        for packet in 0..200 {
+            let origin = point3(random() * 16.0, 3.0, random() * 16.0);
+            let rays = std::array::from_fn(|_| {
+                let direction = vec3(random() - 0.5, -1.0, random() - 0.5);
+                // Every other packet mixes octants and is traced one ray at a time
+                let direction = if packet % 2 == 0 {
+                    direction
+                } else {
+                    vec3(-direction.x, direction.y, direction.z)
+                };
+                Ray::new(origin, direction, 0.0).with_t_max(2.0 + random() * 2.0)
+            });
+            let hits = mesh.intersect_packet(&rays);
+            for (ray, hit) in rays.iter().zip(hits) {
+                let expected = mesh.intersect(ray).map(|hit| hit.distance.to_bits());
+                assert_eq!(hit.map(|hit| hit.distance.to_bits()), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolated_normals() {
+        let n0 = vec3(-1.0, 0.0, 1.0).normalize();
+        let n1 = vec3(1.0, 0.0, 1.0).normalize();
+        let mesh = TriangleMesh::new(
+            vec![
+                point3(0.0, 0.0, 0.0),
+                point3(1.0, 0.0, 0.0),
+                point3(0.0, 1.0, 0.0),
+            ],
+            Some(vec![n0, n1, n0]),
+            None,
+            vec![[0, 1, 2]],
+        );
+        let ray = Ray::new(point3(0.5, 0.25, 1.0), vec3(0.0, 0.0, -1.0), 0.0);
+        let hit = mesh.intersect(&ray).unwrap();
+        assert_abs_diff_eq!(hit.normal, vec3(0.0, 0.0, 1.0), epsilon = 1e-5);
+        assert_abs_diff_eq!(hit.tangent.dot(hit.normal), 0.0, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn sample_pdf_matches_pdf() {
+        let mesh = quad();
+        let reference = point3(0.3, 0.4, 2.0);
+        fastrand::seed(3);
+        for _ in 0..100 {
+            let (point, normal, uv) = mesh.sample();
+            assert!((0.0..=1.0).contains(&point.x) && (0.0..=1.0).contains(&point.y));
+            assert_eq!(normal, vec3(0.0, 0.0, 1.0));
+            assert_abs_diff_eq!(uv, point2(point.x, point.y), epsilon = 1e-5);
+
+            let to_point = point - reference;
+            let distance = to_point.magnitude();
+            let wi = to_point / distance;
+            let expected = distance * distance / (wi.z.abs() * mesh.area());
+            let pdf = mesh.pdf(&Ray::new(reference, wi, 0.0));
+            assert_abs_diff_eq!(pdf, expected, epsilon = expected * 1e-4);
+        }
+    }
+}
@@ -1,16 +1,48 @@
+use crate::types::{color, scalar, Color, Scalar};
+use image::{ImageBuffer, Luma, Rgb, Rgb32FImage};
+use serde::Deserialize;
+
 #[cfg(feature = "enable_oidn")]
 mod oidn_impl {
+    use super::DenoiseConfig;
     use image::Rgb32FImage;
     use oidn::RayTracing;
 
-    pub fn denoise(image: &mut Rgb32FImage) {
+    /// Denoises `image`, guided by the first-hit `albedo` when given
+    pub fn denoise(image: &mut Rgb32FImage, albedo: Option<&Rgb32FImage>, config: DenoiseConfig) {
+        if !config.enabled {
+            return;
+        }
+        let (width, height) = (image.width() as usize, image.height() as usize);
         let device = oidn::Device::new();
-        RayTracing::new(&device)
+
+        // A prefiltered albedo is denoised on its own first, after which it is noise free
+        let prefiltered;
+        let albedo = match albedo {
+            Some(albedo) if config.prefilter => {
+                let mut filtered = albedo.clone();
+                RayTracing::new(&device)
+                    .srgb(false)
+                    .image_dimensions(width, height)
+                    .hdr(false)
+                    .filter_in_place(&mut filtered)
+                    .unwrap();
+                prefiltered = filtered;
+                Some(&prefiltered)
+            }
+            albedo => albedo,
+        };
+
+        let mut filter = RayTracing::new(&device);
+        filter
             .srgb(false)
-            .image_dimensions(image.width() as usize, image.height() as usize)
-            .hdr(true)
-            .filter_in_place(image)
-            .unwrap();
+            .image_dimensions(width, height)
+            .hdr(config.hdr)
+            .clean_aux(config.clean_aux || (config.prefilter && albedo.is_some()));
+        if let Some(albedo) = albedo {
+            filter.albedo(albedo);
+        }
+        filter.filter_in_place(image).unwrap();
 
         if let Err(e) = device.get_error() {
             println!("Error denoising image: {}", e.1);
@@ -20,3 +52,539 @@ mod oidn_impl {
 
 #[cfg(feature = "enable_oidn")]
 pub use oidn_impl::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToneMapOperator {
+    /// `c / (1 + c)` per channel
+    #[default]
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+
+impl ToneMapOperator {
+    fn apply(self, c: Scalar) -> Scalar {
+        match self {
+            ToneMapOperator::Reinhard => c / (1.0 + c),
+            ToneMapOperator::Aces => {
+                let (a, b, c2, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+                ((c * (a * c + b)) / (c * (c2 * c + d) + e)).clamp(0.0, 1.0)
+            }
+        }
+    }
+}
+
+/// Glow around bright parts of the image, applied to the linear image before tone mapping
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct Bloom {
+    /// Luminance above which pixels start to glow
+    pub threshold: Scalar,
+    /// Blur radius in pixels, three standard deviations of the gaussian
+    pub radius: Scalar,
+    pub intensity: Scalar,
+}
+
+impl Bloom {
+    /// Adds a blurred copy of the parts of the image brighter than the threshold
+    pub fn apply(&self, image: &mut Rgb32FImage) {
+        if self.intensity == 0.0 {
+            return;
+        }
+
+        let mut bright = image.clone();
+        for pixel in bright.pixels_mut() {
+            let luminance = color::luminance(Color::from(*pixel));
+            let scale = if luminance > self.threshold {
+                scalar::to_f32((luminance - self.threshold) / luminance)
+            } else {
+                0.0
+            };
+            pixel.0 = pixel.0.map(|c| c * scale);
+        }
+        let glow = gaussian_blur(&bright, self.radius);
+        let intensity = scalar::to_f32(self.intensity);
+        for (pixel, glow) in image.pixels_mut().zip(glow.pixels()) {
+            for (c, g) in pixel.0.iter_mut().zip(glow.0) {
+                *c += g * intensity;
+            }
+        }
+    }
+}
+
+fn default_exposure() -> Scalar {
+    1.0
+}
+
+/// Settings of the oidn denoiser, set with `denoise = { ... }` in the camera block or on a
+/// `Denoise` post-process step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct DenoiseConfig {
+    /// Disabled denoise steps are dropped from the chain, so no oidn device is created
+    pub enabled: bool,
+    /// Denoises the albedo before using it to guide the image, for albedo that is noisy from
+    /// depth of field or motion blur
+    pub prefilter: bool,
+    /// Whether the image is linear HDR rather than tone mapped to [0, 1]
+    pub hdr: bool,
+    /// Tells oidn the albedo is noise free, so it can preserve more of its detail
+    pub clean_aux: bool,
+}
+
+impl Default for DenoiseConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            prefilter: false,
+            hdr: true,
+            clean_aux: false,
+        }
+    }
+}
+
+/// A single image operation, configured with a `kind` in the scene's `[[postprocess]]` tables
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind")]
+pub enum PostProcessStep {
+    /// Denoises with oidn, skipped when built without the `enable_oidn` feature
+    Denoise(DenoiseConfig),
+    ToneMap {
+        #[serde(default)]
+        operator: ToneMapOperator,
+        /// Scale applied before the tone curve
+        #[serde(default = "default_exposure")]
+        exposure: Scalar,
+    },
+    Bloom(Bloom),
+    /// Darkens the image towards the corners, `strength` is the darkening at the corners
+    Vignette {
+        strength: Scalar,
+    },
+    /// Scales the red and blue channels away from and towards the image center by `amount`
+    ChromaticAberration {
+        amount: Scalar,
+    },
+}
+
+impl PostProcessStep {
+    pub fn apply(&self, image: &mut Rgb32FImage) {
+        self.apply_with_albedo(image, None);
+    }
+
+    /// Applies the step, denoising is guided by `albedo` when given
+    pub fn apply_with_albedo(&self, image: &mut Rgb32FImage, albedo: Option<&Rgb32FImage>) {
+        match *self {
+            PostProcessStep::Denoise(config) => denoise_step(image, albedo, config),
+            PostProcessStep::ToneMap { operator, exposure } => {
+                for pixel in image.pixels_mut() {
+                    pixel.0 = pixel
+                        .0
+                        .map(|c| scalar::to_f32(operator.apply(c as Scalar * exposure)));
+                }
+            }
+            PostProcessStep::Bloom(bloom) => bloom.apply(image),
+            PostProcessStep::Vignette { strength } => vignette(image, strength),
+            PostProcessStep::ChromaticAberration { amount } => chromatic_aberration(image, amount),
+        }
+    }
+}
+
+#[cfg(feature = "enable_oidn")]
+fn denoise_step(image: &mut Rgb32FImage, albedo: Option<&Rgb32FImage>, config: DenoiseConfig) {
+    denoise(image, albedo, config);
+}
+
+#[cfg(not(feature = "enable_oidn"))]
+fn denoise_step(_image: &mut Rgb32FImage, _albedo: Option<&Rgb32FImage>, config: DenoiseConfig) {
+    if config.enabled {
+        eprintln!("Warning: skipping denoise, pbrtrs was built without the enable_oidn feature");
+    }
+}
+
+/// Ordered list of steps applied to the rendered image before it is saved
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct PostProcessChain {
+    steps: Vec<PostProcessStep>,
+}
+
+impl PostProcessChain {
+    pub fn new(steps: Vec<PostProcessStep>) -> Self {
+        Self { steps }
+    }
+
+    pub fn steps(&self) -> &[PostProcessStep] {
+        &self.steps
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Inserts a bloom step before the first tone map, so it runs on the linear image after any
+    /// denoising
+    pub fn with_bloom(mut self, bloom: Bloom) -> Self {
+        let index = self
+            .steps
+            .iter()
+            .position(|step| matches!(step, PostProcessStep::ToneMap { .. }))
+            .unwrap_or(self.steps.len());
+        self.steps.insert(index, PostProcessStep::Bloom(bloom));
+        self
+    }
+
+    /// Uses `config` for every denoise step, adding one at the start if there is none. A disabled
+    /// config removes the denoise steps instead.
+    pub fn with_denoise(mut self, config: DenoiseConfig) -> Self {
+        if !config.enabled {
+            self.steps
+                .retain(|step| !matches!(step, PostProcessStep::Denoise(_)));
+            return self;
+        }
+        let mut found = false;
+        for step in &mut self.steps {
+            if let PostProcessStep::Denoise(step_config) = step {
+                *step_config = config;
+                found = true;
+            }
+        }
+        if !found {
+            self.steps.insert(0, PostProcessStep::Denoise(config));
+        }
+        self
+    }
+
+    /// Appends a step to run after every other step
+    pub fn with_step(mut self, step: PostProcessStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Applies every step in order
+    pub fn run(&self, image: &mut Rgb32FImage) {
+        self.run_with_albedo(image, None);
+    }
+
+    /// Applies every step in order, denoising is guided by `albedo` when given
+    pub fn run_with_albedo(&self, image: &mut Rgb32FImage, albedo: Option<&Rgb32FImage>) {
+        for step in &self.steps {
+            step.apply_with_albedo(image, albedo);
+        }
+    }
+}
+
+/// Normalized gaussian weights for offsets `-radius..=radius`
+fn gaussian_kernel(radius: Scalar) -> Vec<Scalar> {
+    let half_width = radius.ceil() as isize;
+    let sigma = (radius / 3.0).max(Scalar::EPSILON);
+    let weights: Vec<Scalar> = (-half_width..=half_width)
+        .map(|offset| (-(offset * offset) as Scalar / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: Scalar = weights.iter().sum();
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+/// Convolves `image` with `kernel` along one axis, clamping samples to the image edges
+fn blur_axis(image: &Rgb32FImage, kernel: &[Scalar], horizontal: bool) -> Rgb32FImage {
+    let (width, height) = image.dimensions();
+    let half_width = (kernel.len() / 2) as i64;
+    Rgb32FImage::from_fn(width, height, |x, y| {
+        let mut sum = [0.0; 3];
+        for (i, weight) in kernel.iter().enumerate() {
+            let offset = i as i64 - half_width;
+            let (sx, sy) = if horizontal {
+                ((x as i64 + offset).clamp(0, width as i64 - 1) as u32, y)
+            } else {
+                (x, (y as i64 + offset).clamp(0, height as i64 - 1) as u32)
+            };
+            let sample = image.get_pixel(sx, sy);
+            for (s, c) in sum.iter_mut().zip(sample.0) {
+                *s += c * scalar::to_f32(*weight);
+            }
+        }
+        Rgb(sum)
+    })
+}
+
+/// Separable gaussian blur
+pub fn gaussian_blur(image: &Rgb32FImage, radius: Scalar) -> Rgb32FImage {
+    let kernel = gaussian_kernel(radius);
+    let horizontal = blur_axis(image, &kernel, true);
+    blur_axis(&horizontal, &kernel, false)
+}
+
+/// Darkens pixels by `strength` times their squared distance from the center, normalized so the
+/// corners are darkened by `strength`
+pub fn vignette(image: &mut Rgb32FImage, strength: Scalar) {
+    let (width, height) = image.dimensions();
+    let center = (width as Scalar / 2.0, height as Scalar / 2.0);
+    let max_distance2 = center.0 * center.0 + center.1 * center.1;
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let dx = x as Scalar + 0.5 - center.0;
+        let dy = y as Scalar + 0.5 - center.1;
+        let falloff = (1.0 - strength * (dx * dx + dy * dy) / max_distance2).max(0.0);
+        pixel.0 = pixel.0.map(|c| c * scalar::to_f32(falloff));
+    }
+}
+
+/// Bilinearly interpolates `channel` at a continuous pixel position, clamping to the image edges
+fn sample_channel(image: &Rgb32FImage, x: Scalar, y: Scalar, channel: usize) -> Scalar {
+    let (width, height) = image.dimensions();
+    let x = (x - 0.5).clamp(0.0, (width - 1) as Scalar);
+    let y = (y - 0.5).clamp(0.0, (height - 1) as Scalar);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (tx, ty) = (x.fract(), y.fract());
+
+    let at = |x, y| image.get_pixel(x, y)[channel] as Scalar;
+    let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+    let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+/// Samples the red channel `1 + amount` and the blue channel `1 - amount` times further from the
+/// image center than the green channel, like a lens that focuses colors at different sizes
+pub fn chromatic_aberration(image: &mut Rgb32FImage, amount: Scalar) {
+    if amount == 0.0 {
+        return;
+    }
+
+    let source = image.clone();
+    let (width, height) = image.dimensions();
+    let center = (width as Scalar / 2.0, height as Scalar / 2.0);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let dx = x as Scalar + 0.5 - center.0;
+        let dy = y as Scalar + 0.5 - center.1;
+        for (channel, scale) in [(0, 1.0 + amount), (2, 1.0 - amount)] {
+            pixel[channel] = scalar::to_f32(sample_channel(
+                &source,
+                center.0 + dx * scale,
+                center.1 + dy * scale,
+                channel,
+            ));
+        }
+    }
+}
+
+/// False color for `t` in [0, 1], from dark blue through cyan, green and yellow to dark red
+pub fn heatmap_color(t: Scalar) -> Rgb<f32> {
+    let t = 4.0 * t.clamp(0.0, 1.0);
+    let band = |center: Scalar| scalar::to_f32((1.5 - (t - center).abs()).clamp(0.0, 1.0));
+    Rgb([band(3.0), band(2.0), band(1.0)])
+}
+
+/// Colors per-pixel sample counts from blue for the fewest samples to red for the most
+pub fn sample_heatmap(counts: &ImageBuffer<Luma<u32>, Vec<u32>>) -> Rgb32FImage {
+    let min = counts.pixels().map(|p| p[0]).min().unwrap_or(0);
+    let max = counts.pixels().map(|p| p[0]).max().unwrap_or(0);
+    let range = (max - min).max(1) as Scalar;
+    Rgb32FImage::from_fn(counts.width(), counts.height(), |x, y| {
+        heatmap_color((counts.get_pixel(x, y)[0] - min) as Scalar / range)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(width: u32, height: u32, value: f32) -> Rgb32FImage {
+        Rgb32FImage::from_pixel(width, height, Rgb([value; 3]))
+    }
+
+    #[test]
+    fn tone_map() {
+        let mut image = gray(2, 2, 1.0);
+        PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Reinhard,
+            exposure: 3.0,
+        }
+        .apply(&mut image);
+        assert!(image.pixels().all(|p| p.0 == [0.75; 3]));
+
+        let mut image = gray(1, 1, 100.0);
+        PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Aces,
+            exposure: 1.0,
+        }
+        .apply(&mut image);
+        assert_eq!(image.get_pixel(0, 0).0, [1.0; 3]);
+    }
+
+    #[test]
+    fn gaussian_blur_preserves_energy() {
+        let mut image = gray(9, 7, 0.0);
+        image.put_pixel(4, 3, Rgb([1.0, 2.0, 3.0]));
+        let blurred = gaussian_blur(&image, 2.0);
+
+        let total = blurred.pixels().fold([0.0; 3], |sum, p| {
+            [sum[0] + p[0], sum[1] + p[1], sum[2] + p[2]]
+        });
+        for (total, expected) in total.into_iter().zip([1.0, 2.0, 3.0]) {
+            assert!((total - expected).abs() < 1e-5);
+        }
+        // Symmetric around the impulse and peaked at it
+        assert_eq!(blurred.get_pixel(3, 3), blurred.get_pixel(5, 3));
+        assert_eq!(blurred.get_pixel(4, 2), blurred.get_pixel(4, 4));
+        assert!(blurred.get_pixel(4, 3)[0] > blurred.get_pixel(3, 3)[0]);
+    }
+
+    #[test]
+    fn bloom_spreads_highlights() {
+        let mut image = gray(7, 7, 0.5);
+        image.put_pixel(3, 3, Rgb([5.0; 3]));
+        PostProcessStep::Bloom(Bloom {
+            threshold: 1.0,
+            radius: 1.5,
+            intensity: 1.0,
+        })
+        .apply(&mut image);
+
+        // Pixels below the threshold only glow near the highlight
+        assert!(image.get_pixel(3, 2)[0] > 0.5);
+        assert!(image.get_pixel(3, 3)[0] > 5.0);
+        assert_eq!(image.get_pixel(0, 0)[0], 0.5);
+
+        // The threshold is on luminance, a saturated blue highlight this bright doesn't glow
+        let mut image = gray(7, 7, 0.0);
+        image.put_pixel(3, 3, Rgb([0.0, 0.0, 5.0]));
+        let bloom = Bloom {
+            threshold: 1.0,
+            radius: 1.5,
+            intensity: 1.0,
+        };
+        bloom.apply(&mut image);
+        assert_eq!(image.get_pixel(3, 2)[2], 0.0);
+
+        let mut image = gray(7, 7, 0.5);
+        image.put_pixel(3, 3, Rgb([5.0; 3]));
+        let original = image.clone();
+        Bloom {
+            intensity: 0.0,
+            ..bloom
+        }
+        .apply(&mut image);
+        assert_eq!(image, original);
+    }
+
+    #[test]
+    fn bloom_runs_before_tone_mapping() {
+        let bloom = Bloom {
+            threshold: 1.0,
+            radius: 2.0,
+            intensity: 0.5,
+        };
+        let tone_map = PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Aces,
+            exposure: 1.0,
+        };
+        let denoise = PostProcessStep::Denoise(DenoiseConfig::default());
+        let chain = PostProcessChain::new(vec![denoise.clone(), tone_map.clone()]);
+        assert_eq!(
+            chain.with_bloom(bloom).steps(),
+            [denoise, PostProcessStep::Bloom(bloom), tone_map]
+        );
+        assert_eq!(
+            PostProcessChain::default().with_bloom(bloom).steps(),
+            [PostProcessStep::Bloom(bloom)]
+        );
+    }
+
+    #[test]
+    fn denoise_config_controls_denoise_steps() {
+        let tone_map = PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Reinhard,
+            exposure: 1.0,
+        };
+        let config = DenoiseConfig {
+            prefilter: true,
+            clean_aux: true,
+            ..DenoiseConfig::default()
+        };
+        assert_eq!(
+            PostProcessChain::new(vec![tone_map.clone()])
+                .with_denoise(config)
+                .steps(),
+            [PostProcessStep::Denoise(config), tone_map.clone()]
+        );
+
+        let chain = PostProcessChain::new(vec![
+            tone_map.clone(),
+            PostProcessStep::Denoise(DenoiseConfig::default()),
+        ]);
+        assert_eq!(
+            chain.clone().with_denoise(config).steps(),
+            [tone_map.clone(), PostProcessStep::Denoise(config)]
+        );
+        let disabled = DenoiseConfig {
+            enabled: false,
+            ..config
+        };
+        assert_eq!(chain.with_denoise(disabled).steps(), [tone_map]);
+    }
+
+    #[test]
+    fn vignette_darkens_corners() {
+        let mut image = gray(4, 4, 1.0);
+        PostProcessStep::Vignette { strength: 0.5 }.apply(&mut image);
+        let center = image.get_pixel(2, 2)[0];
+        let corner = image.get_pixel(0, 0)[0];
+        assert!(center > 0.9);
+        assert!(corner < center);
+        assert!(corner > 0.5);
+        assert_eq!(image.get_pixel(0, 0), image.get_pixel(3, 3));
+    }
+
+    #[test]
+    fn chromatic_aberration_offsets_red_and_blue() {
+        // Horizontal ramp, so radial offsets change the sampled value
+        let mut image = Rgb32FImage::from_fn(8, 8, |x, _| Rgb([x as f32; 3]));
+        let original = image.clone();
+        chromatic_aberration(&mut image, 0.0);
+        assert_eq!(image, original);
+
+        chromatic_aberration(&mut image, 0.25);
+        for (pixel, original) in image.pixels().zip(original.pixels()) {
+            assert_eq!(pixel[1], original[1]);
+        }
+        // Right of center red is sampled further right and blue closer to the center
+        let pixel = image.get_pixel(6, 4);
+        assert_eq!((pixel[0], pixel[2]), (6.625, 5.375));
+        // Samples past the edge clamp to the edge pixels instead of wrapping around
+        let pixel = image.get_pixel(7, 4);
+        assert_eq!((pixel[0], pixel[2]), (7.0, 6.125));
+        assert_eq!(image.get_pixel(0, 7)[0], 0.0);
+    }
+
+    #[test]
+    fn chain_runs_in_order() {
+        let tone_map = PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Reinhard,
+            exposure: 1.0,
+        };
+        let vignette = PostProcessStep::Vignette { strength: 1.0 };
+
+        let mut a = gray(3, 3, 3.0);
+        PostProcessChain::new(vec![tone_map.clone(), vignette.clone()]).run(&mut a);
+        let mut b = gray(3, 3, 3.0);
+        PostProcessChain::new(vec![vignette, tone_map]).run(&mut b);
+
+        let (corner_a, corner_b) = (a.get_pixel(0, 0)[0], b.get_pixel(0, 0)[0]);
+        let falloff = 1.0 - 2.0 / 4.5;
+        assert!((corner_a - 0.75 * falloff).abs() < 1e-6);
+        assert!((corner_b - 3.0 * falloff / (1.0 + 3.0 * falloff)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sample_heatmap_spans_the_colormap() {
+        let counts = ImageBuffer::from_fn(3, 1, |x, _| Luma([[16, 40, 64][x as usize]]));
+        let heatmap = sample_heatmap(&counts);
+        assert_eq!(heatmap.get_pixel(0, 0).0, [0.0, 0.0, 0.5]);
+        assert_eq!(heatmap.get_pixel(1, 0).0, [0.5, 1.0, 0.5]);
+        assert_eq!(heatmap.get_pixel(2, 0).0, [0.5, 0.0, 0.0]);
+
+        // Without adaptive sampling every pixel gets the same count
+        let uniform = sample_heatmap(&ImageBuffer::from_pixel(2, 2, Luma([8])));
+        assert!(uniform.pixels().all(|p| p.0 == [0.0, 0.0, 0.5]));
+    }
+}
@@ -1,25 +1,367 @@
-use crate::bxdf::BxDFKind;
+use crate::bxdf::{BxDFKind, Wavelength};
 use crate::debugger;
-use crate::intersect::PossibleIntersection;
+use crate::intersect::{Intersection, PossibleIntersection};
 
-use crate::light::{sample_one_light, LightKind, LightTrait};
+use crate::light::{
+    escaped_light, escaped_light_unless_occluded, sample_lights, sample_lights_for_path,
+    sample_lights_unoccluded, sample_one_light_in_medium, GroupedLight, LightKind, LightTrait,
+    UNGROUPED,
+};
 use crate::material::{Material, TransportMode};
-use crate::scene::{DisneyMaterial, Scene};
+use crate::medium::PhaseFunction;
+use crate::scene::{DisneyMaterial, Object, SampledDisneyMaterial, Scene, Visibility};
+use crate::stats::{self, RayStats};
 use crate::types::color::{BLACK, WHITE};
-use crate::types::{scalar, Vec3};
+use crate::types::{scalar, Scalar, Vec3};
 use crate::types::{Color, Ray};
-use crate::util::max_value3;
+use crate::util::offset_ray_origin;
 use bumpalo::Bump;
-use cgmath::{ElementWise, EuclideanSpace, InnerSpace, MetricSpace, Zero};
+use cgmath::{point2, InnerSpace, Zero};
+use serde::Deserialize;
+use smallvec::SmallVec;
 
-pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color {
-    let mut radiance = BLACK;
+/// IORs of the nested transmissive objects a path is inside of, innermost last.
+///
+/// Paths start outside every object, in a medium with an IOR of 1.
+#[derive(Debug, Default, Clone)]
+pub struct MediumStack {
+    iors: SmallVec<[Scalar; 4]>,
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// IOR of the medium the path is currently in
+    pub fn current_ior(&self) -> Scalar {
+        self.iors.last().copied().unwrap_or(1.0)
+    }
+
+    /// Index of the innermost entry for an object with `ior`, used when leaving it
+    fn exit_index(&self, ior: Scalar) -> Option<usize> {
+        self.iors.iter().rposition(|&i| i == ior)
+    }
+
+    /// IOR of the medium on the outer side of a surface of an object with `ior`
+    pub fn outside_ior(&self, entering: bool, ior: Scalar) -> Scalar {
+        if entering {
+            return self.current_ior();
+        }
+        match self.exit_index(ior) {
+            Some(index) => self.iors[..index].last().copied().unwrap_or(1.0),
+            // Leaving an object we never entered, e.g. after a grazing hit missed the entry
+            None => self.current_ior(),
+        }
+    }
+
+    /// Updates the stack after the path is transmitted through a surface of an object with `ior`
+    pub fn transmit(&mut self, entering: bool, ior: Scalar) {
+        if entering {
+            self.iors.push(ior);
+        } else if let Some(index) = self.exit_index(ior) {
+            self.iors.remove(index);
+        }
+    }
+}
+
+/// Number of `sample_f` calls used to estimate a BSDF's reflectance
+const RHO_SAMPLES: usize = 8;
+
+fn rho_samples() -> [[Scalar; 2]; RHO_SAMPLES] {
+    std::array::from_fn(|_| [scalar::rand(), scalar::rand()])
+}
+
+/// Radiance of a camera path split by how the light got to the camera, for compositing. The
+/// components sum to the path radiance. Bounces are classified by the lobe that was sampled.
+/// The radiance is also split by the light groups it came from in `groups`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PathComponents {
+    /// Light sources seen directly or through perfectly specular surfaces
+    pub emission: Color,
+    /// Light sampled at the first diffuse or glossy vertex
+    pub direct: Color,
+    /// Light arriving after a diffuse first bounce, scattering in a medium counts as diffuse
+    pub diffuse_indirect: Color,
+    /// Light arriving after a glossy first bounce
+    pub specular_indirect: Color,
+    pub groups: GroupedLight,
+}
+
+impl PathComponents {
+    pub fn total(&self) -> Color {
+        self.emission + self.direct + self.diffuse_indirect + self.specular_indirect
+    }
+
+    /// Component for light that went through the first non specular bounce `first_bounce`
+    fn indirect(&mut self, first_bounce: BxDFKind) -> &mut Color {
+        if first_bounce.has(BxDFKind::DIFFUSE) {
+            &mut self.diffuse_indirect
+        } else {
+            &mut self.specular_indirect
+        }
+    }
+
+    /// Adds light from the group slot `group` hit by the path, `first_bounce` is `None` until
+    /// the path leaves the chain of specular bounces from the camera
+    fn add_emission(&mut self, first_bounce: Option<BxDFKind>, group: usize, light: Color) {
+        match first_bounce {
+            None => self.emission += light,
+            Some(kind) => *self.indirect(kind) += light,
+        }
+        self.groups.add(group, light);
+    }
+
+    /// Adds light sampled at a vertex of the path
+    fn add_direct(&mut self, first_bounce: Option<BxDFKind>, light: GroupedLight) {
+        let total = light.total();
+        match first_bounce {
+            None => self.direct += total,
+            Some(kind) => *self.indirect(kind) += total,
+        }
+        self.groups += light;
+    }
+}
+
+/// Radiance carried along a camera path with auxiliary values for denoising
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathSample {
+    pub radiance: Color,
+    pub components: PathComponents,
+    /// Reflectance of the first surface hit, black if the path didn't hit a surface
+    pub albedo: Color,
+    /// Number of rays traced along the path, including the camera ray
+    pub bounces: usize,
+    /// Opacity of the shadow on the shadow catcher the camera ray passed through, for
+    /// compositing the catcher's shadows as alpha. 0 if it didn't pass through one.
+    pub shadow: Scalar,
+    /// Coverage of the camera ray for compositing, 1 if it hit an object or a light and
+    /// otherwise `shadow`, so the background is transparent apart from the catchers' shadows
+    pub alpha: Scalar,
+}
+
+impl PathSample {
+    /// Sample of a diagnostic `value`, kept in the emission component so the components still
+    /// sum to the radiance. Diagnostic images are opaque.
+    pub fn diagnostic(value: Color) -> Self {
+        PathSample {
+            radiance: value,
+            components: PathComponents {
+                emission: value,
+                groups: GroupedLight::single(UNGROUPED, value),
+                ..Default::default()
+            },
+            albedo: BLACK,
+            bounces: 0,
+            shadow: 0.0,
+            alpha: 1.0,
+        }
+    }
+}
+
+/// What is rendered to each pixel, the modes other than `Beauty` are for debugging
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderMode {
+    /// Radiance reaching the camera
+    #[default]
+    Beauty,
+    /// Shading normal at the first hit, mapped from [-1, 1] to [0, 1]
+    Normal,
+    /// Distance to the first hit
+    Depth,
+    /// Texture coordinates at the first hit, in the red and green channels
+    Uv,
+    /// Number of rays traced along each path, averaged over the samples
+    Bounces,
+    /// Microseconds spent rendering each pixel
+    Time,
+}
+
+impl RenderMode {
+    /// Whether the mode only needs the first intersection of camera rays
+    pub fn is_first_hit(self) -> bool {
+        matches!(
+            self,
+            RenderMode::Normal | RenderMode::Depth | RenderMode::Uv
+        )
+    }
+}
+
+/// Traces a camera ray for the camera's render mode. The time per pixel isn't known per sample,
+/// so `Time` traces the full path like `Beauty`.
+pub fn trace_camera_ray(ray: &Ray, scene: &Scene, arena: &Bump, stats: &RayStats) -> PathSample {
+    trace_camera_ray_with(ray, None, scene, arena, stats)
+}
+
+/// [`trace_camera_ray`] for a ray whose intersection with the scene is already known, e.g. from
+/// [`Scene::intersect_packet`]
+pub fn trace_camera_ray_from<'a>(
+    ray: &Ray,
+    first_hit: PossibleIntersection<'a, SampledDisneyMaterial, Object>,
+    scene: &'a Scene,
+    arena: &Bump,
+    stats: &RayStats,
+) -> PathSample {
+    trace_camera_ray_with(ray, Some(first_hit), scene, arena, stats)
+}
+
+fn trace_camera_ray_with<'a>(
+    ray: &Ray,
+    first_hit: Option<PossibleIntersection<'a, SampledDisneyMaterial, Object>>,
+    scene: &'a Scene,
+    arena: &Bump,
+    stats: &RayStats,
+) -> PathSample {
+    match scene.camera.mode {
+        RenderMode::Beauty | RenderMode::Time => {
+            trace_path_with(ray, first_hit, scene, arena, stats)
+        }
+        RenderMode::Bounces => {
+            let bounces = trace_path_with(ray, first_hit, scene, arena, stats).bounces;
+            PathSample::diagnostic(WHITE * bounces as Scalar)
+        }
+        mode => PathSample::diagnostic(first_hit_diagnostic_with(
+            ray, first_hit, scene, mode, stats,
+        )),
+    }
+}
+
+/// Value of the first hit `mode` where `ray` first hits the scene, black if it misses
+pub fn first_hit_diagnostic(ray: &Ray, scene: &Scene, mode: RenderMode, stats: &RayStats) -> Color {
+    first_hit_diagnostic_with(ray, None, scene, mode, stats)
+}
+
+fn first_hit_diagnostic_with<'a>(
+    ray: &Ray,
+    first_hit: Option<PossibleIntersection<'a, SampledDisneyMaterial, Object>>,
+    scene: &'a Scene,
+    mode: RenderMode,
+    stats: &RayStats,
+) -> Color {
+    stats.add_primary_ray();
+    stats::stats_count!(rays);
+    let intersection = first_hit.unwrap_or_else(|| {
+        stats::stats_time!(intersection_ns, scene.intersect(ray, Visibility::CAMERA))
+    });
+    let (normal, distance, uv) = match intersection {
+        PossibleIntersection::Hit(hit) => (hit.normal, hit.distance, hit.uv),
+        PossibleIntersection::HitLight(hit) => (hit.normal, hit.distance, hit.uv),
+        PossibleIntersection::Miss | PossibleIntersection::Ignored => return BLACK,
+    };
+    match mode {
+        RenderMode::Normal => Color::new(normal.x + 1.0, normal.y + 1.0, normal.z + 1.0) * 0.5,
+        RenderMode::Depth => WHITE * distance,
+        RenderMode::Uv => Color::new(uv.x, uv.y, 0.0),
+        RenderMode::Beauty | RenderMode::Bounces | RenderMode::Time => {
+            panic!("{mode:?} needs the whole path")
+        }
+    }
+}
+
+/// BSDF sample of a path vertex that also sampled the lights, the path's next ray completes the
+/// MIS estimate of the lights it can escape to
+#[derive(Debug, Clone, Copy)]
+struct EscapeSample {
+    bsdf_pdf: Scalar,
+    /// `first_bounce` at the vertex, so the light goes to the same component as its light samples
+    first_bounce: Option<BxDFKind>,
+}
+
+pub fn ray_color(ray: &Ray, scene: &Scene, arena: &Bump, stats: &RayStats) -> Color {
+    trace_path(ray, scene, arena, stats).radiance
+}
+
+pub fn trace_path(ray: &Ray, scene: &Scene, arena: &Bump, stats: &RayStats) -> PathSample {
+    trace_path_with(ray, None, scene, arena, stats)
+}
+
+/// Traces the path starting with `ray`, whose intersection with the scene may already be known
+fn trace_path_with<'a>(
+    ray: &Ray,
+    mut first_hit: Option<PossibleIntersection<'a, SampledDisneyMaterial, Object>>,
+    scene: &'a Scene,
+    arena: &Bump,
+    stats: &RayStats,
+) -> PathSample {
+    let mut radiance = PathComponents::default();
+    // Kind of the first diffuse or glossy bounce, which decides the component light goes to
+    let mut first_bounce = None;
+    let mut albedo = BLACK;
     let mut beta = WHITE;
     let mut ray = *ray;
     let mut specular_bounce = false;
+    // Whether the ray is still the camera ray, it is only passed through shadow catchers
+    let mut camera_ray = true;
+    let mut escape = None;
+    let mut media = MediumStack::new();
+    // Channel the path carries alone once a dispersive surface refracted it
+    let mut channel = None;
+    let mut bounces = 0;
+    let mut shadow = 0.0;
+    let mut covered = false;
     for bounce_count in 0..scene.camera.bounce_limit {
+        bounces = bounce_count + 1;
+        if bounce_count == 0 {
+            stats.add_primary_ray();
+        } else {
+            stats.add_bounce_ray();
+        }
+        stats::stats_count!(rays);
         debugger::begin_ray!(ray);
-        match scene.intersect(&ray) {
+        let escape_sample = escape.take();
+        let ray_kind = if camera_ray {
+            Visibility::CAMERA
+        } else if specular_bounce {
+            Visibility::SPECULAR
+        } else {
+            Visibility::DIFFUSE
+        };
+        let intersection = match first_hit.take() {
+            Some(hit) => hit,
+            None => stats::stats_time!(intersection_ns, scene.intersect(&ray, ray_kind)),
+        };
+
+        if let Some(medium) = &scene.medium {
+            let surface_distance = match &intersection {
+                PossibleIntersection::Hit(hit) => hit.distance,
+                PossibleIntersection::HitLight(hit) => hit.distance,
+                _ => Scalar::INFINITY,
+            };
+            let sample = medium.sample(surface_distance, point2(scalar::rand(), scalar::rand()));
+            beta *= sample.weight;
+            if beta == BLACK {
+                break;
+            }
+
+            if let Some(distance) = sample.scatter_distance {
+                debugger::ray_print!("Medium Scatter");
+                let point = ray.at(distance);
+                let phase = medium.phase();
+                radiance.add_direct(
+                    first_bounce,
+                    stats::stats_time!(
+                        light_sampling_ns,
+                        sample_one_light_in_medium(&ray, point, &phase, scene, stats)
+                    ) * beta,
+                );
+                first_bounce.get_or_insert(BxDFKind::DIFFUSE);
+
+                // The phase function is sampled exactly, so beta is unchanged
+                let mut wi = Vec3::zero();
+                phase.sample_p(
+                    -ray.direction,
+                    point2(scalar::rand(), scalar::rand()),
+                    &mut wi,
+                );
+                specular_bounce = false;
+                camera_ray = false;
+                ray = Ray::new(point, wi, ray.time);
+                continue;
+            }
+        }
+
+        match intersection {
             PossibleIntersection::Hit(intersection) => {
                 debugger::ray_debug! {
                     intersection.normal,
@@ -30,32 +372,90 @@ pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color
                     intersection.object
                 }
 
-                let bsdf = DisneyMaterial::compute_scattering(
-                    &intersection,
-                    arena,
-                    TransportMode::Importance,
-                    true,
+                if camera_ray && intersection.object.shadow_catcher {
+                    // The camera sees through catchers, darkened by their shadows
+                    let unblocked = shadow_catcher_shadow(&ray, &intersection, arena, scene, stats);
+                    shadow = 1.0 - (1.0 - shadow) * unblocked.luminance();
+                    beta *= unblocked;
+                    specular_bounce = true;
+                    let origin = offset_ray_origin(
+                        intersection.point,
+                        intersection.error,
+                        intersection.normal,
+                        ray.direction,
+                    );
+                    ray = Ray::new(origin, ray.direction, ray.time);
+                    continue;
+                }
+                covered |= camera_ray;
+
+                // Emission after a diffuse or glossy bounce is accounted for by light sampling
+                if bounce_count == 0 || specular_bounce {
+                    radiance.add_emission(
+                        first_bounce,
+                        UNGROUPED,
+                        beta * intersection.sampled_material.emission,
+                    );
+                }
+
+                let entering = intersection.front_face;
+                let ior = intersection.sampled_material.ior;
+                let wavelength = intersection
+                    .sampled_material
+                    .is_dispersive()
+                    .then(|| channel.map_or_else(Wavelength::sample, Wavelength::monochromatic));
+                let bsdf = stats::stats_time!(
+                    bsdf_ns,
+                    DisneyMaterial::compute_scattering(
+                        &intersection,
+                        arena,
+                        TransportMode::Importance,
+                        true,
+                        media.outside_ior(entering, ior),
+                        wavelength,
+                    )
                 );
+                if camera_ray {
+                    albedo = bsdf.rho(-ray.direction, &rho_samples(), BxDFKind::ALL);
+                }
 
                 if bsdf.num_components(BxDFKind::ALL.unset(BxDFKind::SPECULAR)) > 0 {
-                    let ld =
-                        beta.mul_element_wise(sample_one_light(&ray, &intersection, &bsdf, scene));
-                    radiance.add_assign_element_wise(ld);
+                    let ld = stats::stats_time!(
+                        light_sampling_ns,
+                        sample_lights_for_path(&ray, &intersection, &bsdf, scene, stats)
+                    ) * beta;
+                    radiance.add_direct(first_bounce, ld);
                 }
 
                 let mut wi = Vec3::zero();
                 let mut pdf = 0.0;
                 let mut sampled_kind = BxDFKind::ALL;
-                let f = bsdf.sample_f(
-                    -ray.direction,
-                    &mut wi,
-                    &mut pdf,
-                    &mut sampled_kind,
-                    BxDFKind::ALL,
+                stats::stats_count!(bsdf_samples);
+                let f = stats::stats_time!(
+                    bsdf_ns,
+                    bsdf.sample_f(
+                        -ray.direction,
+                        &mut wi,
+                        &mut pdf,
+                        &mut sampled_kind,
+                        BxDFKind::ALL,
+                    )
                 );
                 specular_bounce = sampled_kind.has(BxDFKind::SPECULAR);
+                camera_ray = false;
+                let vertex_first_bounce = first_bounce;
+                if !specular_bounce {
+                    first_bounce.get_or_insert(sampled_kind);
+                }
+                if sampled_kind.has(BxDFKind::TRANSMISSION) {
+                    media.transmit(entering, ior);
+                    // The other channels were refracted in other directions
+                    if let Some(wavelength) = wavelength {
+                        channel = Some(wavelength.channel);
+                    }
+                }
 
-                if f.distance2(Color::origin()) == 0.0 || pdf == 0.0 {
+                if f.is_black() || pdf == 0.0 {
                     debugger::ray_print!("PDF 0 Miss ");
                     debugger::ray_debug! {
                         sampled_kind,
@@ -66,7 +466,7 @@ pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color
                     break;
                 }
 
-                beta.mul_assign_element_wise(f * wi.dot(intersection.normal).abs() / pdf);
+                beta *= f * wi.dot(intersection.normal).abs() / pdf;
 
                 debugger::ray_debug! {
                     wi,
@@ -78,16 +478,44 @@ pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color
                     radiance
                 }
 
-                if bounce_count > 3 && (1.0 - max_value3(beta).max(0.7)) < scalar::rand() {
-                    debugger::ray_print!("Russian Roulette Miss");
-                    break;
+                if bounce_count > scene.camera.roulette_depth {
+                    // Paths are continued in proportion to their throughput, survivors are
+                    // weighted up to keep the estimate unbiased
+                    let survival = beta
+                        .max_component()
+                        .clamp(scene.camera.roulette_min_survival, 1.0);
+                    if scalar::rand() >= survival {
+                        debugger::ray_print!("Russian Roulette Miss");
+                        break;
+                    }
+                    beta /= survival;
                 }
 
-                ray = Ray::new(intersection.point, wi, ray.time);
+                let origin = offset_ray_origin(
+                    intersection.point,
+                    intersection.error,
+                    intersection.normal,
+                    wi,
+                );
+                ray = Ray::new(origin, wi, ray.time);
+                // Lights were sampled here unless the BSDF is only specular
+                escape = (!specular_bounce).then_some(EscapeSample {
+                    bsdf_pdf: pdf,
+                    first_bounce: vertex_first_bounce,
+                });
             }
             PossibleIntersection::HitLight(intersection) => {
-                let area = intersection.object;
-                radiance.add_assign_element_wise(area.le(&ray).mul_element_wise(beta));
+                covered |= camera_ray;
+                // Area lights are sampled directly, so only add their emission when the last
+                // bounce couldn't have sampled them
+                if bounce_count == 0 || specular_bounce {
+                    let area = intersection.object;
+                    radiance.add_emission(
+                        first_bounce,
+                        scene.area_light_group(area),
+                        area.emission(intersection.uv) * beta,
+                    );
+                }
                 break;
             }
             PossibleIntersection::Ignored => {
@@ -97,13 +525,23 @@ pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color
             PossibleIntersection::Miss => {
                 if bounce_count == 0 || specular_bounce {
                     debugger::ray_print!("Sky Specular");
-                    for light in &scene.lights {
-                        if !light.kind().has(LightKind::AREA) && !light.kind().has(LightKind::NO_BG)
-                        {
-                            let light = light.le(&ray);
-                            radiance.add_assign_element_wise(light.mul_element_wise(beta));
+                    radiance.add_emission(first_bounce, UNGROUPED, scene.background * beta);
+                    for (index, light) in scene.lights.iter().enumerate() {
+                        let kind = light.kind();
+                        let hidden = camera_ray && kind.has(LightKind::NO_BG);
+                        if !kind.has(LightKind::AREA) && !hidden {
+                            let le = light.le(&ray);
+                            radiance.add_emission(
+                                first_bounce,
+                                scene.light_group(index),
+                                le * beta,
+                            );
                         }
                     }
+                } else if let Some(escape) = escape_sample {
+                    debugger::ray_print!("Sky Light Sampled");
+                    let light = escaped_light(&ray, escape.bsdf_pdf, scene);
+                    radiance.add_direct(escape.first_bounce, light * beta);
                 } else {
                     debugger::ray_print!("Sky Ignored");
                 }
@@ -112,5 +550,1021 @@ pub fn ray_color<'arena>(ray: &Ray, scene: &Scene, arena: &'arena Bump) -> Color
         }
     }
 
-    radiance
+    // The bounce limit ended the path before its last ray was traced, which still completes the
+    // estimate of the lights sampled at the last vertex
+    if let Some(escape) = escape {
+        let light = escaped_light_unless_occluded(&ray, escape.bsdf_pdf, scene, stats);
+        radiance.add_direct(escape.first_bounce, light * beta);
+    }
+
+    PathSample {
+        radiance: radiance.total(),
+        components: radiance,
+        albedo,
+        bounces,
+        shadow,
+        alpha: if covered { 1.0 } else { shadow },
+    }
+}
+
+/// Fraction of the direct light reaching a shadow catcher that isn't blocked by the scene, white
+/// where no light reaches it at all
+fn shadow_catcher_shadow(
+    ray: &Ray,
+    intersection: &Intersection<SampledDisneyMaterial, Object>,
+    arena: &Bump,
+    scene: &Scene,
+    stats: &RayStats,
+) -> Color {
+    let bsdf = DisneyMaterial::compute_scattering(
+        intersection,
+        arena,
+        TransportMode::Importance,
+        true,
+        1.0,
+        None,
+    );
+    // Both estimates use the same light samples, so the ratio is only noisy in the penumbra
+    let seed = fastrand::get_seed();
+    let shadowed = sample_lights(ray, intersection, &bsdf, scene, stats);
+    fastrand::seed(seed);
+    let unshadowed = sample_lights_unoccluded(ray, intersection, &bsdf, scene, stats);
+    let ratio = |shadowed: Scalar, unshadowed: Scalar| {
+        if unshadowed > 0.0 {
+            (shadowed / unshadowed).clamp(0.0, 1.0)
+        } else {
+            1.0
+        }
+    };
+    Color::new(
+        ratio(shadowed.r, unshadowed.r),
+        ratio(shadowed.g, unshadowed.g),
+        ratio(shadowed.b, unshadowed.b),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::hdri::Hdri;
+    use crate::light::{lobe_footprint, DirectionLight, PointLight};
+    use crate::medium::HomogeneousMedium;
+    use crate::scene::{CameraBuilder, MaterialBuilder, Object, SceneBuilder, Shape, Visibility};
+    use crate::types::scalar::consts::PI;
+    use crate::types::{color, Pt3};
+    use crate::util::random_unit_vec;
+    use cgmath::{assert_abs_diff_eq, point3, vec3, EuclideanSpace};
+    use image::{Rgb, Rgb32FImage};
+
+    #[test]
+    fn absorbing_medium_follows_beer_lambert() {
+        let sigma_a = color(0.1, 0.2, 0.4);
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(1).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new()
+                    .base_color(BLACK)
+                    .specular(0.0)
+                    .emission(WHITE)
+                    .emission_strength(1.0)
+                    .build(),
+            ))
+            .medium(HomogeneousMedium::new(sigma_a, BLACK, 0.0))
+            .build();
+
+        fastrand::seed(11);
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 0.0);
+        let n = 20000;
+        let mut radiance = BLACK;
+        for _ in 0..n {
+            radiance += ray_color(&ray, &scene, &arena, &stats);
+        }
+        // The camera ray travels 3 units through the medium to reach the sphere
+        let expected = sigma_a.map(|s| (-s * 3.0).exp());
+        assert_abs_diff_eq!(radiance / n as Scalar, expected, epsilon = 2e-2);
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        // Inside a closed diffuse sphere most of the light arrives after several bounces
+        let scene = |roulette_min_survival| {
+            SceneBuilder::new()
+                .camera(
+                    CameraBuilder::new()
+                        .bounce_limit(12)
+                        .roulette_depth(0)
+                        .roulette_min_survival(roulette_min_survival)
+                        .build(),
+                )
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 5.0 },
+                    point3(0.0, 0.0, 0.0),
+                    MaterialBuilder::new()
+                        .base_color(color(0.8, 0.8, 0.8))
+                        .specular(0.0)
+                        .build(),
+                ))
+                .add_light(PointLight::new(point3(0.0, 2.0, 0.0), WHITE).with_power(50.0))
+                .build()
+        };
+
+        let mean = |scene: &Scene| {
+            fastrand::seed(3);
+            let arena = Bump::new();
+            let stats = RayStats::new();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.2, -0.3, 1.0), 0.0);
+            let n = 20000;
+            let mut radiance = BLACK;
+            for _ in 0..n {
+                radiance += ray_color(&ray, scene, &arena, &stats);
+            }
+            radiance / n as Scalar
+        };
+
+        let without_roulette = mean(&scene(1.0));
+        let with_roulette = mean(&scene(0.1));
+        assert_abs_diff_eq!(
+            with_roulette,
+            without_roulette,
+            epsilon = 0.03 * without_roulette.r
+        );
+    }
+
+    #[test]
+    fn closed_furnace_with_russian_roulette() {
+        // Inside a closed sphere emitting E with albedo a, every point sees E / (1 - a)
+        let scene = |roulette_min_survival| {
+            SceneBuilder::new()
+                .camera(
+                    CameraBuilder::new()
+                        .bounce_limit(32)
+                        .roulette_depth(0)
+                        .roulette_min_survival(roulette_min_survival)
+                        .build(),
+                )
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 5.0 },
+                    point3(0.0, 0.0, 0.0),
+                    MaterialBuilder::new()
+                        .base_color(color(0.5, 0.5, 0.5))
+                        .specular(0.0)
+                        .roughness(0.0)
+                        .emission(WHITE)
+                        .emission_strength(1.0)
+                        .build(),
+                ))
+                .build()
+        };
+
+        let mean = |scene: &Scene| {
+            fastrand::seed(3);
+            let arena = Bump::new();
+            let stats = RayStats::new();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(-0.2, 0.1, 1.0), 0.0);
+            let n = 20000;
+            let mut radiance = BLACK;
+            for _ in 0..n {
+                radiance += ray_color(&ray, scene, &arena, &stats);
+            }
+            radiance / n as Scalar
+        };
+
+        let with_roulette = mean(&scene(0.05));
+        // The specular lobe reflects a little extra light at grazing angles
+        assert_abs_diff_eq!(with_roulette, color(2.0, 2.0, 2.0), epsilon = 0.1);
+        assert_abs_diff_eq!(with_roulette, mean(&scene(1.0)), epsilon = 0.02);
+    }
+
+    #[test]
+    fn medium_stack() {
+        let mut media = MediumStack::new();
+        assert_eq!(media.outside_ior(false, 1.5), 1.0);
+        // Exiting an object that was never entered leaves the stack alone
+        media.transmit(false, 1.5);
+        assert_eq!(media.current_ior(), 1.0);
+
+        media.transmit(true, 1.5);
+        assert_eq!(media.outside_ior(true, 1.33), 1.5);
+        media.transmit(true, 1.33);
+        assert_eq!(media.current_ior(), 1.33);
+        assert_eq!(media.outside_ior(false, 1.33), 1.5);
+
+        // Overlapping objects can be left in a different order than they were entered
+        media.transmit(false, 1.5);
+        assert_eq!(media.current_ior(), 1.33);
+        assert_eq!(media.outside_ior(false, 1.33), 1.0);
+    }
+
+    #[test]
+    fn nested_dielectrics_refract_at_each_interface() {
+        let glass = |ior: Scalar| MaterialBuilder::new().transmission(1.0).ior(ior).build();
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 2.0 },
+                point3(0.0, 0.0, 5.0),
+                glass(1.5),
+            ))
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 5.0),
+                glass(1.33),
+            ))
+            .build();
+
+        fastrand::seed(7);
+        let arena = Bump::new();
+        let mut media = MediumStack::new();
+        let mut ray = Ray::new(point3(0.3, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 0.0);
+        // Into the glass, into the water, back out into the glass, then out of the glass
+        let interfaces = [(1.0, 1.5), (1.5, 1.33), (1.33, 1.5), (1.5, 1.0)];
+        for (eta_i, eta_t) in interfaces {
+            let intersection = match scene.intersect(&ray, Visibility::CAMERA) {
+                PossibleIntersection::Hit(intersection) => intersection,
+                _ => panic!("path left the spheres early"),
+            };
+            let normal = intersection.normal;
+            let entering = intersection.front_face;
+            assert_eq!(entering, ray.direction.dot(normal) < 0.0);
+            let ior = intersection.sampled_material.ior;
+            let outside_ior = media.outside_ior(entering, ior);
+            if entering {
+                assert_eq!((outside_ior, ior), (eta_i, eta_t));
+            } else {
+                assert_eq!((ior, outside_ior), (eta_i, eta_t));
+            }
+
+            let bsdf = DisneyMaterial::compute_scattering(
+                &intersection,
+                &arena,
+                TransportMode::Importance,
+                true,
+                outside_ior,
+                None,
+            );
+            // The specular lobe also reflects, sample until it refracts
+            let wi = loop {
+                let mut wi = Vec3::zero();
+                let mut pdf = 0.0;
+                let mut sampled_kind = BxDFKind::ALL;
+                bsdf.sample_f(
+                    -ray.direction,
+                    &mut wi,
+                    &mut pdf,
+                    &mut sampled_kind,
+                    BxDFKind::ALL,
+                );
+                if sampled_kind.has(BxDFKind::TRANSMISSION) {
+                    break wi;
+                }
+            };
+
+            // Snell's law: eta_i sin(theta_i) = eta_t sin(theta_t)
+            let sin_i = ray.direction.cross(normal).magnitude();
+            let sin_t = wi.cross(normal).magnitude();
+            assert!(wi.dot(normal).signum() == ray.direction.dot(normal).signum());
+            assert_abs_diff_eq!(sin_t, eta_i / eta_t * sin_i, epsilon = 1e-4);
+
+            media.transmit(entering, ior);
+            let origin = offset_ray_origin(intersection.point, intersection.error, normal, wi);
+            ray = Ray::new(origin, wi, ray.time);
+        }
+        assert_eq!(media.current_ior(), 1.0);
+        assert!(matches!(
+            scene.intersect(&ray, Visibility::CAMERA),
+            PossibleIntersection::Miss
+        ));
+    }
+
+    #[test]
+    fn dispersive_glass_splits_white_light() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(16).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new()
+                    .base_color(WHITE)
+                    .transmission(1.0)
+                    .dispersion(20.0)
+                    .build(),
+            ))
+            .background(WHITE)
+            .build();
+
+        fastrand::seed(13);
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.15, 0.1, 1.0), 0.0);
+        let n = 20000;
+        let mut radiance = BLACK;
+        let mut monochromatic = 0;
+        for _ in 0..n {
+            let sample = ray_color(&ray, &scene, &arena, &stats);
+            // Refracted paths carry a single channel, reflected ones stay close to white
+            let channels = [sample.r, sample.g, sample.b];
+            if channels.iter().filter(|&&c| c > 0.0).count() == 1 {
+                monochromatic += 1;
+            } else {
+                assert!(
+                    sample.min_component() > 0.9 * sample.max_component(),
+                    "{sample:?}"
+                );
+            }
+            radiance += sample;
+        }
+        assert!(monochromatic > n / 2, "{monochromatic}");
+        // Without absorption every channel gets out to the background in the end
+        assert_abs_diff_eq!(radiance / n as Scalar, WHITE, epsilon = 0.05);
+    }
+
+    #[test]
+    fn prism_separates_colors() {
+        use crate::types::Quaternion;
+        use cgmath::{Deg, Rotation3};
+
+        // A 60 degree wedge between two planes through its apex at y = 1, the inside is below both
+        let face = |angle| {
+            Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                point3(0.0, 1.0, 0.0),
+                MaterialBuilder::new()
+                    .base_color(WHITE)
+                    .transmission(1.0)
+                    .dispersion(20.0)
+                    .build(),
+            )
+            .with_rotation(Quaternion::from_angle_z(Deg(angle)))
+        };
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(face(60.0))
+            .add_object(face(-60.0))
+            .build();
+
+        fastrand::seed(11);
+        let arena = Bump::new();
+        let exit_direction = |channel| {
+            let mut media = MediumStack::new();
+            let mut ray = Ray::new(point3(-5.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), 0.0);
+            for _ in 0..2 {
+                let intersection = match scene.intersect(&ray, Visibility::CAMERA) {
+                    PossibleIntersection::Hit(intersection) => intersection,
+                    _ => panic!("path missed the prism"),
+                };
+                let entering = intersection.front_face;
+                let ior = intersection.sampled_material.ior;
+                let bsdf = DisneyMaterial::compute_scattering(
+                    &intersection,
+                    &arena,
+                    TransportMode::Importance,
+                    true,
+                    media.outside_ior(entering, ior),
+                    Some(Wavelength::monochromatic(channel)),
+                );
+                let wi = loop {
+                    let mut wi = Vec3::zero();
+                    let mut pdf = 0.0;
+                    let mut sampled_kind = BxDFKind::ALL;
+                    bsdf.sample_f(
+                        -ray.direction,
+                        &mut wi,
+                        &mut pdf,
+                        &mut sampled_kind,
+                        BxDFKind::ALL,
+                    );
+                    if sampled_kind.has(BxDFKind::TRANSMISSION) {
+                        break wi;
+                    }
+                };
+                media.transmit(entering, ior);
+                let origin = offset_ray_origin(
+                    intersection.point,
+                    intersection.error,
+                    intersection.normal,
+                    wi,
+                );
+                ray = Ray::new(origin, wi, ray.time);
+            }
+            assert_eq!(media.current_ior(), 1.0);
+            ray.direction.normalize()
+        };
+
+        // Shorter wavelengths are bent further towards the base, fanning out into a rainbow
+        let [red, green, blue] = [0, 1, 2].map(exit_direction);
+        assert!(0.0 > red.y && red.y > green.y && green.y > blue.y);
+        assert!(red.angle(blue).0 > 0.01, "{red:?} {blue:?}");
+    }
+
+    #[test]
+    fn zero_dispersion_matches_plain_glass() {
+        let render = |material: MaterialBuilder| {
+            let scene = SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(8).build())
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 0.0, 4.0),
+                    material.base_color(WHITE).transmission(1.0).build(),
+                ))
+                .add_light(PointLight::new(point3(2.0, 2.0, 0.0), WHITE).with_power(20.0))
+                .background(color(0.2, 0.4, 0.6))
+                .build();
+            fastrand::seed(19);
+            let arena = Bump::new();
+            let stats = RayStats::new();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.15, 0.1, 1.0), 0.0);
+            (0..1000)
+                .map(|_| ray_color(&ray, &scene, &arena, &stats))
+                .collect::<Vec<_>>()
+        };
+        // Draws the same random numbers as before dispersion existed
+        assert_eq!(
+            render(MaterialBuilder::new().dispersion(0.0)),
+            render(MaterialBuilder::new())
+        );
+    }
+
+    #[test]
+    fn shadow_catcher_only_shows_shadows() {
+        let background = color(0.2, 0.4, 0.6);
+        let scene = |catcher: bool| {
+            let mut builder = SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(4).build())
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 2.0, 0.0),
+                    MaterialBuilder::new().build(),
+                ))
+                .add_light(DirectionLight::new(vec3(0.0, -1.0, 0.0), WHITE).with_power(3.0))
+                .background(background);
+            if catcher {
+                builder = builder.add_object(
+                    Object::new(
+                        Shape::Plane { uv_scale: 1.0 },
+                        Pt3::origin(),
+                        MaterialBuilder::new().build(),
+                    )
+                    .with_shadow_catcher(true),
+                );
+            }
+            builder.build()
+        };
+        let catcher = scene(true);
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        let towards = |target: Pt3| {
+            let origin = point3(0.0, 0.5, -3.0);
+            Ray::new(origin, (target - origin).normalize(), 0.0)
+        };
+
+        // The sphere's shadow is a disk of radius 1 under it, the rest of the plane is invisible
+        for x in [-0.9, 0.0, 0.5] {
+            let sample = trace_path(&towards(point3(x, 0.0, 0.3)), &catcher, &arena, &stats);
+            assert_eq!(sample.radiance, BLACK);
+            assert_abs_diff_eq!(sample.shadow, 1.0, epsilon = 1e-5);
+            assert_eq!(sample.alpha, sample.shadow);
+        }
+        for x in [-3.0, 1.2, 4.0] {
+            let sample = trace_path(&towards(point3(x, 0.0, 0.3)), &catcher, &arena, &stats);
+            assert_eq!(sample.radiance, background);
+            assert_abs_diff_eq!(sample.shadow, 0.0, epsilon = 1e-5);
+            assert_eq!(sample.alpha, sample.shadow);
+        }
+        // Rays that miss everything are transparent
+        let up = Ray::new(point3(0.0, 0.5, -3.0), vec3(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(trace_path(&up, &catcher, &arena, &stats).alpha, 0.0);
+
+        // Other rays pass through the catcher, so the sphere looks the same without it
+        let at_sphere = Ray::new(point3(0.0, 1.5, -4.0), vec3(0.0, 0.0, 1.0), 0.0);
+        for seed in 0..64 {
+            fastrand::seed(seed);
+            let with = trace_path(&at_sphere, &catcher, &arena, &stats);
+            fastrand::seed(seed);
+            let without = ray_color(&at_sphere, &scene(false), &arena, &stats);
+            assert_eq!(with.radiance, without);
+            assert_eq!(with.shadow, 0.0);
+            assert_eq!(with.alpha, 1.0);
+        }
+    }
+
+    #[test]
+    fn camera_sees_through_both_sides_of_catcher_sphere() {
+        let background = color(0.2, 0.4, 0.6);
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(4).build())
+            .add_object(
+                Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    Pt3::origin(),
+                    MaterialBuilder::new().build(),
+                )
+                .with_shadow_catcher(true),
+            )
+            .add_light(DirectionLight::new(vec3(0.0, -1.0, 0.0), WHITE).with_power(3.0))
+            .background(background)
+            .build();
+        let arena = Bump::new();
+        let stats = RayStats::new();
+
+        // Nothing shadows the sphere, so both its near and far side are invisible
+        for y in [-0.5, 0.0, 0.5] {
+            let ray = Ray::new(point3(0.0, y, -3.0), vec3(0.0, 0.0, 1.0), 0.0);
+            let sample = trace_path(&ray, &scene, &arena, &stats);
+            assert_abs_diff_eq!(sample.radiance, background, epsilon = 1e-5);
+            assert_abs_diff_eq!(sample.shadow, 0.0, epsilon = 1e-5);
+            assert_abs_diff_eq!(sample.alpha, 0.0, epsilon = 1e-5);
+        }
+    }
+
+    #[test]
+    fn camera_sees_through_shadow_catcher_with_camera_visibility() {
+        let scene = |catcher: bool| {
+            let mut builder = SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(2).build())
+                // Only reflections and lighting see this one
+                .add_object(
+                    Object::new(
+                        Shape::Sphere { radius: 1.0 },
+                        point3(0.0, -3.0, 0.0),
+                        MaterialBuilder::new()
+                            .base_color(BLACK)
+                            .emission(WHITE)
+                            .build(),
+                    )
+                    .with_visibility(Visibility::ALL.unset(Visibility::CAMERA)),
+                )
+                // And only the camera sees this one
+                .add_object(
+                    Object::new(
+                        Shape::Sphere { radius: 1.0 },
+                        point3(4.0, -3.0, 0.0),
+                        MaterialBuilder::new()
+                            .base_color(color(0.5, 0.5, 0.5))
+                            .specular(0.0)
+                            .emission(WHITE)
+                            .build(),
+                    )
+                    .with_visibility(Visibility::CAMERA),
+                );
+            if catcher {
+                builder = builder.add_object(
+                    Object::new(
+                        Shape::Plane { uv_scale: 1.0 },
+                        point3(0.0, -1.0, 0.0),
+                        MaterialBuilder::new().build(),
+                    )
+                    .with_shadow_catcher(true),
+                );
+            }
+            builder.build()
+        };
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        let down = |x: Scalar| Ray::new(point3(x, 0.0, 0.0), vec3(0.0, -1.0, 0.0), 0.0);
+
+        let hidden = trace_path(&down(0.0), &scene(true), &arena, &stats);
+        assert_eq!(hidden.radiance, BLACK);
+        assert_abs_diff_eq!(hidden.alpha, 0.0, epsilon = 1e-5);
+
+        let with = trace_path(&down(4.0), &scene(true), &arena, &stats);
+        let without = trace_path(&down(4.0), &scene(false), &arena, &stats);
+        assert_eq!(with.radiance, WHITE);
+        assert_eq!(with.alpha, 1.0);
+        assert_abs_diff_eq!(with.albedo, without.albedo, epsilon = 1e-3);
+        assert_abs_diff_eq!(with.albedo, color(0.5, 0.5, 0.5), epsilon = 1e-3);
+    }
+
+    #[test]
+    fn camera_inside_opaque_sphere_sees_lit_interior() {
+        let radius = 5.0;
+        let power = 100.0;
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(1).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius },
+                point3(0.0, 0.0, 0.0),
+                MaterialBuilder::new()
+                    .base_color(color(0.5, 0.5, 0.5))
+                    .specular(0.0)
+                    .build(),
+            ))
+            .add_light(PointLight::new(point3(0.0, 0.0, 0.0), WHITE).with_power(power))
+            .build();
+
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        // Light and camera at the center, so every wall point is lit and seen head on
+        let irradiance = power / (4.0 * PI * radius * radius);
+        for direction in [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(-0.3, 0.5, 0.2),
+        ] {
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), direction, 0.0);
+            let hit = match scene.intersect(&ray, Visibility::CAMERA) {
+                PossibleIntersection::Hit(hit) => hit,
+                _ => panic!("expected to hit the inside of the sphere"),
+            };
+            assert!(!hit.front_face);
+
+            let radiance = ray_color(&ray, &scene, &arena, &stats);
+            assert_abs_diff_eq!(radiance.r, 0.5 * irradiance / PI, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn path_components() {
+        // A lit room with a diffuse or a glossy metal wall, and an emissive ball in view
+        let scene = |wall: DisneyMaterial| {
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(6).build())
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 5.0 },
+                    point3(0.0, 0.0, 0.0),
+                    wall,
+                ))
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 0.5 },
+                    point3(0.0, 0.0, 2.0),
+                    MaterialBuilder::new()
+                        .base_color(BLACK)
+                        .specular(0.0)
+                        .emission(WHITE)
+                        .emission_strength(2.0)
+                        .build(),
+                ))
+                .add_light(PointLight::new(point3(0.0, 2.0, 0.0), WHITE).with_power(100.0))
+                .build()
+        };
+        let components = |scene: &Scene, direction| {
+            fastrand::seed(5);
+            let arena = Bump::new();
+            let stats = RayStats::new();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), direction, 0.0);
+            let mut total = PathComponents::default();
+            for _ in 0..200 {
+                let sample = trace_path(&ray, scene, &arena, &stats);
+                assert_eq!(sample.radiance, sample.components.total());
+                total.emission += sample.components.emission;
+                total.direct += sample.components.direct;
+                total.diffuse_indirect += sample.components.diffuse_indirect;
+                total.specular_indirect += sample.components.specular_indirect;
+            }
+            total
+        };
+
+        let diffuse = scene(
+            MaterialBuilder::new()
+                .base_color(color(0.5, 0.5, 0.5))
+                .specular(0.0)
+                .build(),
+        );
+        let ball = components(&diffuse, vec3(0.0, 0.0, 1.0));
+        // The black ball only reflects a little at grazing angles
+        let reflected = ball.direct + ball.diffuse_indirect + ball.specular_indirect;
+        assert!(ball.emission.r > 100.0 * reflected.r, "{ball:?}");
+
+        let wall = components(&diffuse, vec3(0.0, 0.0, -1.0));
+        assert_eq!(wall.emission, BLACK);
+        assert!(wall.direct.r > 0.0);
+        assert!(wall.diffuse_indirect.r > 0.0);
+
+        let metal = scene(
+            MaterialBuilder::new()
+                .base_color(WHITE)
+                .metallic(1.0)
+                .roughness(0.4)
+                .build(),
+        );
+        let wall = components(&metal, vec3(0.0, 0.0, -1.0));
+        assert!(wall.direct.r > 0.0);
+        assert!(wall.specular_indirect.r > 0.0);
+        assert_eq!(wall.diffuse_indirect, BLACK);
+    }
+
+    #[test]
+    fn background_is_not_a_light() {
+        let background = color(0.2, 0.4, 0.6);
+        let scene = |material: DisneyMaterial| {
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().build())
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 0.0, 4.0),
+                    material,
+                ))
+                .background(background)
+                .build()
+        };
+        let radiance = |scene: &Scene, direction| {
+            let arena = Bump::new();
+            let stats = RayStats::new();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), direction, 0.0);
+            ray_color(&ray, scene, &arena, &stats)
+        };
+
+        let mirror = scene(
+            MaterialBuilder::new()
+                .base_color(WHITE)
+                .metallic(1.0)
+                .roughness(0.0)
+                .build(),
+        );
+        assert_eq!(radiance(&mirror, vec3(0.0, 1.0, 0.0)), background);
+        assert_abs_diff_eq!(
+            radiance(&mirror, vec3(0.0, 0.0, 1.0)),
+            background,
+            epsilon = 1e-5
+        );
+
+        // Diffuse bounces only see sampled lights
+        let diffuse = scene(MaterialBuilder::new().specular(0.0).build());
+        assert_eq!(radiance(&diffuse, vec3(0.0, 0.0, 1.0)), BLACK);
+    }
+
+    #[test]
+    fn emissive_object_is_visible() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(1).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new()
+                    .base_color(BLACK)
+                    .specular(0.0)
+                    .emission(color(1.0, 0.5, 0.25))
+                    .emission_strength(2.0)
+                    .build(),
+            ))
+            .build();
+        assert_eq!(scene.emissive_objects().len(), 1);
+
+        let arena = Bump::new();
+        let ray = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 0.0);
+        let stats = RayStats::new();
+        let radiance = ray_color(&ray, &scene, &arena, &stats);
+        assert_abs_diff_eq!(radiance, color(2.0, 1.0, 0.5), epsilon = 1e-2);
+        assert_eq!(stats.primary_rays(), 1);
+        assert_eq!(stats.bounce_rays(), 0);
+    }
+
+    #[test]
+    fn camera_hidden_object_is_reflected_and_casts_shadows() {
+        let hidden =
+            |object: Object| object.with_visibility(Visibility::ALL.unset(Visibility::CAMERA));
+        let arena = Bump::new();
+        let stats = RayStats::new();
+
+        let mirrored = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(2).build())
+            .add_object(hidden(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new()
+                    .base_color(BLACK)
+                    .specular(0.0)
+                    .emission(color(1.0, 0.5, 0.25))
+                    .build(),
+            )))
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, -4.0),
+                MaterialBuilder::new()
+                    .base_color(WHITE)
+                    .metallic(1.0)
+                    .roughness(0.0)
+                    .build(),
+            ))
+            .build();
+        let direct = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(ray_color(&direct, &mirrored, &arena, &stats), BLACK);
+        let reflected = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 0.0, -1.0), 0.0);
+        assert_abs_diff_eq!(
+            ray_color(&reflected, &mirrored, &arena, &stats),
+            color(1.0, 0.5, 0.25),
+            epsilon = 1e-2
+        );
+
+        let shadowed = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(1).build())
+            .add_object(hidden(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 1.0, 0.0),
+                MaterialBuilder::new().build(),
+            )))
+            .add_object(Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                point3(0.0, -1.0, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_light(DirectionLight::new(vec3(0.0, -1.0, 0.0), WHITE))
+            .build();
+        let towards = |x: Scalar| {
+            let origin = point3(0.0, -0.5, -3.0);
+            Ray::new(origin, (point3(x, -1.0, 0.0) - origin).normalize(), 0.0)
+        };
+        assert_eq!(ray_color(&towards(0.0), &shadowed, &arena, &stats), BLACK);
+        assert!(ray_color(&towards(3.0), &shadowed, &arena, &stats).r > 0.0);
+    }
+
+    #[test]
+    fn rough_metal_furnace_matches_reflectance() {
+        // Under a constant white sky a lone convex object reflects its directional albedo, whether
+        // the light is found by light sampling or by the path escaping
+        let sky = Rgb32FImage::from_pixel(16, 8, Rgb([1.0; 3]));
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        // With a limit of one bounce the path's last ray is only traced towards the sky. Near
+        // mirrors are sampled as specular, they need a second ray to see anything.
+        for (roughness, bounce_limit) in [
+            (0.05, 2),
+            (0.25, 1),
+            (0.25, 2),
+            (0.5, 1),
+            (0.5, 2),
+            (1.0, 1),
+            (1.0, 2),
+        ] {
+            fastrand::seed(31);
+            let scene = SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(bounce_limit).build())
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 0.0, 4.0),
+                    MaterialBuilder::new()
+                        .base_color(WHITE)
+                        .metallic(1.0)
+                        .roughness(roughness)
+                        .build(),
+                ))
+                .add_light(Hdri::new(sky.clone(), 1.0))
+                .build();
+
+            let direction = vec3(0.1, 0.15, 1.0).normalize();
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), direction, 0.0);
+            const SAMPLES: usize = 20_000;
+            let rendered = (0..SAMPLES).fold(BLACK, |sum, _| {
+                sum + ray_color(&ray, &scene, &arena, &stats)
+            }) / SAMPLES as Scalar;
+
+            let PossibleIntersection::Hit(hit) = scene.intersect(&ray, Visibility::CAMERA) else {
+                panic!("camera ray missed the sphere");
+            };
+            let bsdf = DisneyMaterial::compute_scattering(
+                &hit,
+                &arena,
+                TransportMode::Importance,
+                true,
+                1.0,
+                None,
+            );
+            let samples = (0..200_000)
+                .map(|_| [scalar::rand(), scalar::rand()])
+                .collect::<Vec<_>>();
+            let reflectance = bsdf.rho(-direction, &samples, BxDFKind::ALL);
+
+            assert_abs_diff_eq!(
+                rendered,
+                reflectance,
+                epsilon = 0.02 * reflectance.max_component()
+            );
+        }
+    }
+
+    #[test]
+    fn hdri_lighting_matches_uniform_reference() {
+        fastrand::seed(23);
+        // Dim sky with a small bright sun, which light sampling has to find
+        let image = Rgb32FImage::from_fn(64, 32, |x, y| {
+            if (40..44).contains(&x) && (8..11).contains(&y) {
+                Rgb([40.0, 30.0, 20.0])
+            } else {
+                Rgb([0.3, 0.4, 0.6])
+            }
+        });
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().bounce_limit(1).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new()
+                    .base_color(color(0.8, 0.8, 0.8))
+                    .specular(0.0)
+                    .build(),
+            ))
+            .add_light(Hdri::new(image, 1.0))
+            .build();
+
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        for direction in [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.15, 0.2, 1.0).normalize(),
+            vec3(-0.2, -0.1, 1.0).normalize(),
+        ] {
+            let ray = Ray::new(point3(0.0, 0.0, 0.0), direction, 0.0);
+            const SAMPLES: usize = 50_000;
+            let rendered = (0..SAMPLES).fold(BLACK, |sum, _| {
+                sum + ray_color(&ray, &scene, &arena, &stats)
+            }) / SAMPLES as Scalar;
+
+            // The sphere is convex and alone, so the sky is visible above the whole surface
+            let PossibleIntersection::Hit(hit) = scene.intersect(&ray, Visibility::CAMERA) else {
+                panic!("camera ray missed the sphere");
+            };
+            let bsdf = DisneyMaterial::compute_scattering(
+                &hit,
+                &arena,
+                TransportMode::Importance,
+                true,
+                1.0,
+                None,
+            );
+            const REFERENCE_SAMPLES: usize = 1_000_000;
+            let reference = (0..REFERENCE_SAMPLES).fold(BLACK, |sum, _| {
+                let wi = random_unit_vec();
+                // Paths see the map filtered to the footprint of the BSDF's lobe
+                let footprint = lobe_footprint(bsdf.pdf(-direction, wi, BxDFKind::ALL));
+                let li = scene.lights[0].le_filtered(&Ray::new(hit.point, wi, 0.0), footprint);
+                let f = bsdf.f(-direction, wi, BxDFKind::ALL);
+                sum + li * f * wi.dot(hit.normal).max(0.0) * 4.0 * PI
+            }) / REFERENCE_SAMPLES as Scalar;
+
+            assert_abs_diff_eq!(
+                rendered,
+                reference,
+                epsilon = 0.03 * reference.max_component()
+            );
+        }
+    }
+
+    #[test]
+    fn hidden_hdri_lights_scene_but_not_camera() {
+        fastrand::seed(29);
+        let background = color(0.1, 0.2, 0.3);
+        let build = |visible_to_camera: bool| {
+            let sky = Rgb32FImage::from_pixel(16, 8, Rgb([0.5, 0.5, 0.5]));
+            SceneBuilder::new()
+                .camera(CameraBuilder::new().bounce_limit(2).build())
+                .background(background)
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(-1.5, 0.0, 4.0),
+                    MaterialBuilder::new()
+                        .base_color(color(0.8, 0.8, 0.8))
+                        .specular(0.0)
+                        .build(),
+                ))
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(1.5, 0.0, 4.0),
+                    MaterialBuilder::new()
+                        .base_color(WHITE)
+                        .metallic(1.0)
+                        .roughness(0.0)
+                        .build(),
+                ))
+                .add_light(Hdri::new(sky, 1.0).with_visible_to_camera(visible_to_camera))
+                .build()
+        };
+        let hidden = build(false);
+        let visible = build(true);
+
+        let arena = Bump::new();
+        let stats = RayStats::new();
+        let render = |scene: &Scene, ray: &Ray| {
+            const SAMPLES: usize = 20_000;
+            (0..SAMPLES).fold(BLACK, |sum, _| sum + ray_color(ray, scene, &arena, &stats))
+                / SAMPLES as Scalar
+        };
+
+        // Camera rays that miss see only the background
+        let miss = Ray::new(point3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), 0.0);
+        assert_eq!(ray_color(&miss, &hidden, &arena, &stats), background);
+        assert_abs_diff_eq!(
+            ray_color(&miss, &visible, &arena, &stats),
+            background + color(0.5, 0.5, 0.5),
+            epsilon = 1e-4
+        );
+
+        // The diffuse sphere is lit and the mirror reflects the map as if it were visible
+        for target in [point3(-1.5, 0.0, 3.0), point3(1.5, 0.0, 3.0)] {
+            let ray = Ray::new(
+                point3(0.0, 0.0, 0.0),
+                (target - point3(0.0, 0.0, 0.0)).normalize(),
+                0.0,
+            );
+            let expected = render(&visible, &ray);
+            assert!(expected.max_component() > 0.1);
+            assert_abs_diff_eq!(
+                render(&hidden, &ray),
+                expected,
+                epsilon = 0.03 * expected.max_component()
+            );
+        }
+    }
 }
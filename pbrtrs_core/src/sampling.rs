@@ -0,0 +1,153 @@
+use crate::types::{Pt2, Scalar};
+use cgmath::point2;
+
+fn binary_search_cdf(cdf: &[Scalar], value: Scalar) -> usize {
+    let mut low = 0;
+    let mut high = cdf.len() - 1;
+    while low < high {
+        let mid = (low + high) / 2;
+        if cdf[mid] <= value {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    (low as isize - 1).clamp(0, cdf.len() as isize - 2) as usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution1D {
+    cdf: Vec<Scalar>,
+    func: Vec<Scalar>,
+    integral: Scalar,
+}
+
+impl Distribution1D {
+    #[allow(clippy::needless_range_loop)]
+    pub fn new(func: Vec<Scalar>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+
+        for i in 1..(n + 1) {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as Scalar;
+        }
+
+        let integral = cdf[n];
+        if integral == 0.0 {
+            for i in 1..(n + 1) {
+                cdf[i] = i as Scalar / n as Scalar;
+            }
+        } else {
+            for i in 1..(n + 1) {
+                cdf[i] /= integral;
+            }
+        }
+
+        Self {
+            cdf,
+            integral,
+            func,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn sample_continuous(&self, u: Scalar, pdf: &mut Scalar) -> (usize, Scalar) {
+        let offset = binary_search_cdf(&self.cdf, u);
+        let mut du = u - self.cdf[offset];
+
+        if (self.cdf[offset + 1] - self.cdf[offset]) > 0.0 {
+            du /= self.cdf[offset + 1] - self.cdf[offset];
+        }
+
+        *pdf = self.func[offset] / self.integral;
+
+        (offset, (offset as Scalar + du) / self.count() as Scalar)
+    }
+
+    /// Probability of `sample_discrete` returning `index`
+    pub fn discrete_pdf(&self, index: usize) -> Scalar {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    pub fn sample_discrete(&self, u: Scalar) -> (usize, Scalar) {
+        let offset = binary_search_cdf(&self.cdf, u);
+        let u_prime = (u - self.cdf[offset]) / (self.cdf[offset + 1] - self.cdf[offset]);
+        (offset, u_prime)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution2D {
+    p_conditional_v: Vec<Distribution1D>,
+    p_marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(f: impl ExactSizeIterator<Item = Vec<Scalar>>) -> Self {
+        Self::from_conditionals(f.map(Distribution1D::new).collect())
+    }
+
+    /// Distribution from the already built distributions of its rows, in order of v
+    pub fn from_conditionals(p_conditional_v: Vec<Distribution1D>) -> Self {
+        let p_integral = p_conditional_v.iter().map(|p| p.integral).collect();
+        let p_marginal = Distribution1D::new(p_integral);
+
+        Self {
+            p_conditional_v,
+            p_marginal,
+        }
+    }
+
+    /// Average of the function over the unit square
+    pub fn integral(&self) -> Scalar {
+        self.p_marginal.integral
+    }
+
+    pub fn pdf(&self, u: Pt2) -> Scalar {
+        let iu = ((u[0] * self.p_conditional_v[0].count() as Scalar) as usize)
+            .clamp(0, self.p_conditional_v[0].count() - 1);
+        let iv = ((u[1] * self.p_marginal.count() as Scalar) as usize)
+            .clamp(0, self.p_marginal.count() - 1);
+        self.p_conditional_v[iv].func[iu] / self.p_marginal.integral
+    }
+
+    pub fn sample_continuous(&self, u: Pt2, pdf: &mut Scalar) -> Pt2 {
+        let (mut pdf_0, mut pdf_1) = (0.0, 0.0);
+        let (v, d1) = self.p_marginal.sample_continuous(u[1], &mut pdf_1);
+        let (_, d0) = self.p_conditional_v[v].sample_continuous(u[0], &mut pdf_0);
+
+        *pdf = pdf_0 * pdf_1;
+
+        point2(d0, d1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::assert_abs_diff_eq;
+
+    #[test]
+    fn sample_continuous_covers_unit_interval() {
+        let distribution = Distribution1D::new(vec![1.0, 3.0]);
+        let mut pdf = 0.0;
+        let (offset, x) = distribution.sample_continuous(0.999_999, &mut pdf);
+        assert_eq!(offset, 1);
+        assert_abs_diff_eq!(x, 1.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(pdf, 1.5);
+    }
+
+    #[test]
+    fn sample_discrete_skips_empty_buckets() {
+        let distribution = Distribution1D::new(vec![0.0, 2.0, 0.0, 6.0]);
+        assert_abs_diff_eq!(distribution.discrete_pdf(0), 0.0);
+        assert_abs_diff_eq!(distribution.discrete_pdf(1), 0.25);
+        assert_abs_diff_eq!(distribution.discrete_pdf(3), 0.75);
+        assert_eq!(distribution.sample_discrete(0.0).0, 1);
+        assert_eq!(distribution.sample_discrete(0.3).0, 3);
+        assert_eq!(distribution.sample_discrete(0.999_999).0, 3);
+    }
+}
@@ -1,19 +1,39 @@
+pub mod builder;
 
-use crate::types::{color, Color, Euler, Pt2, Pt3, Quaternion, Scalar, Vec3};
+pub use builder::*;
 
-use cgmath::{EuclideanSpace, InnerSpace, Rad, Zero};
-use image::{ImageBuffer, Luma, Pixel, Rgb};
+use crate::intersect::packet::PackedObjects;
+use crate::intersect::Transform;
+use crate::types::scalar::consts::PI;
+use crate::types::{color, Color, Euler, Mat3, Pt2, Pt3, Quaternion, Ray, Scalar, Vec2, Vec3};
+use crate::util::{bitfield_methods, random_polygon_aperture};
+
+use cgmath::{vec3, Basis2, Deg, EuclideanSpace, InnerSpace, One, Rad, Rotation, Rotation2, Zero};
+use image::{ImageBuffer, Luma, Pixel, Rgb, Rgb32FImage};
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 
 use std::cell::RefCell;
 use std::path::{Path, PathBuf};
 
-
+use crate::bxdf::FresnelConductor;
+use crate::io::gltf::load_gltf;
+use crate::light::cubemap::CubeMapLight;
 use crate::light::hdri::Hdri;
-use crate::light::{AmbientLight, AreaLight, DirectionLight, Light, PointLight, SpotLight};
+use crate::light::ies::IesProfile;
+use crate::light::{
+    AmbientLight, AreaLight, Attenuation, DirectionLight, Light, LightSampling, LightTrait,
+    PointLight, SpotFalloff, SpotLight, MAX_LIGHT_GROUPS, UNGROUPED,
+};
+use crate::medium::HomogeneousMedium;
+use crate::mesh::TriangleMesh;
+use crate::postprocess::{Bloom, DenoiseConfig, PostProcessChain, PostProcessStep};
+use crate::raytracer::RenderMode;
+use crate::sampling::Distribution1D;
+use crate::types::colorspace::ColorSpace;
 use crate::types::R8G8B8Color;
-use serde::de::{Error as SerdeError, SeqAccess, Visitor};
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{Error as SerdeError, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize as DeserializeTrait, Deserialize, Deserializer};
 
 pub trait PixelConverter<T> {
@@ -38,20 +58,68 @@ impl PixelConverter<Scalar> for Luma8ColorPixelConverter {
     type Pixel = Luma<u8>;
 
     fn from_pixel(v: &Self::Pixel) -> Scalar {
-        v.0[0] as f32 / 255.0
+        v.0[0] as Scalar / 255.0
+    }
+}
+
+/// A value that can be blended by procedural textures
+pub trait TextureValue: Copy {
+    fn lerp(self, other: Self, t: Scalar) -> Self;
+}
+
+impl TextureValue for Scalar {
+    fn lerp(self, other: Self, t: Scalar) -> Self {
+        self * (1.0 - t) + other * t
     }
 }
 
+impl TextureValue for Color {
+    fn lerp(self, other: Self, t: Scalar) -> Self {
+        self * (1.0 - t) + other * t
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureAxis {
+    U,
+    V,
+}
+
 pub enum Texture<T, P: PixelConverter<T>> {
     Value(T),
     Image(ImageBuffer<P::Pixel, Vec<<P::Pixel as Pixel>::Subpixel>>),
+    /// Alternates between `a` and `b` in `scale` by `scale` squares over the uv range
+    Checker {
+        a: T,
+        b: T,
+        scale: Scalar,
+    },
+    /// Blends from `a` to `b` along a uv axis
+    Gradient {
+        a: T,
+        b: T,
+        axis: TextureAxis,
+    },
 }
 
 impl<T: Debug, P: PixelConverter<T>> Debug for Texture<T, P> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Value(v) => v.fmt(f),
-            _ => write!(f, "[image]"),
+            Self::Image(_) => write!(f, "[image]"),
+            Self::Checker { a, b, scale } => f
+                .debug_struct("Checker")
+                .field("a", a)
+                .field("b", b)
+                .field("scale", scale)
+                .finish(),
+            Self::Gradient { a, b, axis } => f
+                .debug_struct("Gradient")
+                .field("a", a)
+                .field("b", b)
+                .field("axis", axis)
+                .finish(),
         }
     }
 }
@@ -62,7 +130,13 @@ impl<T: Default, P: PixelConverter<T>> Default for Texture<T, P> {
     }
 }
 
-impl<T: Copy, P: PixelConverter<T>> Texture<T, P> {
+impl<T, P: PixelConverter<T>> From<T> for Texture<T, P> {
+    fn from(value: T) -> Self {
+        Self::Value(value)
+    }
+}
+
+impl<T: TextureValue, P: PixelConverter<T>> Texture<T, P> {
     pub fn get(&self, uv: Pt2) -> T {
         match self {
             Self::Value(value) => *value,
@@ -74,6 +148,50 @@ impl<T: Copy, P: PixelConverter<T>> Texture<T, P> {
                 );
                 P::from_pixel(image.get_pixel(x, y))
             }
+            Self::Checker { a, b, scale } => {
+                let cell = (uv.x * scale).floor() as i64 + (uv.y * scale).floor() as i64;
+                if cell.rem_euclid(2) == 0 {
+                    *a
+                } else {
+                    *b
+                }
+            }
+            Self::Gradient { a, b, axis } => {
+                let t = match axis {
+                    TextureAxis::U => uv.x,
+                    TextureAxis::V => uv.y,
+                };
+                a.lerp(*b, t.clamp(0.0, 1.0))
+            }
+        }
+    }
+}
+
+fn default_checker_scale() -> Scalar {
+    8.0
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind")]
+enum ProceduralTextureRaw<T> {
+    Checker {
+        a: T,
+        b: T,
+        #[serde(default = "default_checker_scale")]
+        scale: Scalar,
+    },
+    Gradient {
+        a: T,
+        b: T,
+        axis: TextureAxis,
+    },
+}
+
+impl<T, P: PixelConverter<T>> From<ProceduralTextureRaw<T>> for Texture<T, P> {
+    fn from(raw: ProceduralTextureRaw<T>) -> Self {
+        match raw {
+            ProceduralTextureRaw::Checker { a, b, scale } => Texture::Checker { a, b, scale },
+            ProceduralTextureRaw::Gradient { a, b, axis } => Texture::Gradient { a, b, axis },
         }
     }
 }
@@ -84,7 +202,14 @@ impl<'de, P: PixelConverter<Scalar, Pixel = Luma<u8>>> Visitor<'de> for TextureS
     type Value = Texture<Scalar, P>;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        write!(formatter, "String path to texture or scalar")
+        write!(
+            formatter,
+            "String path to texture, procedural texture or scalar"
+        )
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        ProceduralTextureRaw::<Scalar>::deserialize(MapAccessDeserializer::new(map)).map(Into::into)
     }
 
     fn visit_f64<E: SerdeError>(self, v: f64) -> Result<Self::Value, E> {
@@ -114,7 +239,14 @@ impl<'de, P: PixelConverter<Color, Pixel = Rgb<u8>>> Visitor<'de> for TextureCol
     type Value = Texture<Color, P>;
 
     fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        write!(formatter, "String path to texture or 3 component rgb color")
+        write!(
+            formatter,
+            "String path to texture, procedural texture or 3 component rgb color"
+        )
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        ProceduralTextureRaw::<Color>::deserialize(MapAccessDeserializer::new(map)).map(Into::into)
     }
 
     fn visit_str<E: SerdeError>(self, v: &str) -> Result<Self::Value, E> {
@@ -122,7 +254,15 @@ impl<'de, P: PixelConverter<Color, Pixel = Rgb<u8>>> Visitor<'de> for TextureCol
             .unwrap()
             .decode()
             .unwrap();
-        Ok(Texture::Image(image.into_rgb8()))
+        let mut image = image.into_rgb8();
+        let transform = ColorSpace::LinearSrgb.transform_to(scene_color_space());
+        if !transform.is_identity() {
+            for pixel in image.pixels_mut() {
+                let texel = transform.apply(R8G8B8Color(pixel.0).into());
+                *pixel = R8G8B8Color::from(texel).into();
+            }
+        }
+        Ok(Texture::Image(image))
     }
 
     fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
@@ -157,12 +297,75 @@ pub struct DisneyMaterial {
     pub specular_tint: Texture<Scalar, Luma8ColorPixelConverter>,
     pub roughness: Texture<Scalar, Luma8ColorPixelConverter>,
     pub anisotropic: Texture<Scalar, Luma8ColorPixelConverter>,
+    /// Rotation of the anisotropy around the normal, 0 to 1 covers 0 to 180 degrees
+    #[serde(default)]
+    pub anisotropic_rotation: Texture<Scalar, Luma8ColorPixelConverter>,
     pub sheen: Texture<Scalar, Luma8ColorPixelConverter>,
     pub sheen_tint: Texture<Scalar, Luma8ColorPixelConverter>,
     pub clearcoat: Texture<Scalar, Luma8ColorPixelConverter>,
     pub clearcoat_gloss: Texture<Scalar, Luma8ColorPixelConverter>,
     pub transmission: Texture<Scalar, Luma8ColorPixelConverter>,
     pub ior: Texture<Scalar, Luma8ColorPixelConverter>,
+    /// Abbe number of transmissive materials, lower numbers split white light into wider
+    /// spectra. 0 disables dispersion.
+    #[serde(default)]
+    pub dispersion: Scalar,
+    #[serde(default = "no_emission")]
+    pub emission: Texture<Color, Rgb8ColorPixelConverter>,
+    #[serde(default = "default_emission_strength")]
+    pub emission_strength: Scalar,
+    /// Tangent space normals encoded as colors, the x and y axes follow the u and v texture axes
+    #[serde(skip)]
+    pub normal_map: Option<Texture<Color, Rgb8ColorPixelConverter>>,
+    /// Complex IOR used for the specular lobe of fully metallic surfaces, either a preset name
+    /// or a table with `eta` and `k`
+    #[serde(default, deserialize_with = "deserialize_conductor")]
+    pub conductor: Option<FresnelConductor>,
+    #[serde(default)]
+    pub diffuse_model: DiffuseModel,
+}
+
+/// Reflectance model of the diffuse lobe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffuseModel {
+    #[default]
+    Lambertian,
+    /// Rough diffuse surfaces like clay, with the roughness in radians as sigma
+    OrenNayar,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConductorRaw {
+    Preset(String),
+    Custom { eta: Color, k: Color },
+}
+
+fn deserialize_conductor<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<FresnelConductor>, D::Error> {
+    match ConductorRaw::deserialize(d)? {
+        ConductorRaw::Preset(name) => FresnelConductor::preset(&name)
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("Unknown conductor '{name}'"))),
+        ConductorRaw::Custom { eta, k } => Ok(Some(FresnelConductor::new(eta, k))),
+    }
+}
+
+fn no_emission() -> Texture<Color, Rgb8ColorPixelConverter> {
+    Texture::Value(color::BLACK)
+}
+
+fn default_emission_strength() -> Scalar {
+    1.0
+}
+
+impl DisneyMaterial {
+    pub fn is_emissive(&self) -> bool {
+        self.emission_strength != 0.0
+            && !matches!(self.emission, Texture::Value(emission) if emission == color::BLACK)
+    }
 }
 
 #[derive(Debug)]
@@ -174,45 +377,72 @@ pub struct SampledDisneyMaterial {
     pub specular_tint: Scalar,
     pub roughness: Scalar,
     pub anisotropic: Scalar,
+    pub anisotropic_rotation: Scalar,
     pub sheen: Scalar,
     pub sheen_tint: Scalar,
     pub clearcoat: Scalar,
     pub clearcoat_gloss: Scalar,
     pub transmission: Scalar,
     pub ior: Scalar,
+    pub dispersion: Scalar,
+    pub emission: Color,
+    /// Shading normal from the normal map in tangent space
+    pub normal: Option<Vec3>,
+    pub conductor: Option<FresnelConductor>,
+    pub diffuse_model: DiffuseModel,
+}
+
+impl SampledDisneyMaterial {
+    /// Whether the surface refracts each color channel in a different direction
+    pub fn is_dispersive(&self) -> bool {
+        self.transmission > 0.0 && self.dispersion > 0.0
+    }
 }
 
 impl Default for DisneyMaterial {
     fn default() -> Self {
         Self {
-            base_color: Texture::Value(Color::origin()),
+            base_color: Texture::Value(color::BLACK),
             subsurface: Default::default(),
             metallic: Default::default(),
             specular: Default::default(),
             specular_tint: Default::default(),
             roughness: Default::default(),
             anisotropic: Default::default(),
+            anisotropic_rotation: Default::default(),
             sheen: Default::default(),
             sheen_tint: Default::default(),
             clearcoat: Default::default(),
             clearcoat_gloss: Default::default(),
             transmission: Default::default(),
             ior: Default::default(),
+            dispersion: 0.0,
+            emission: no_emission(),
+            emission_strength: default_emission_strength(),
+            normal_map: None,
+            conductor: None,
+            diffuse_model: DiffuseModel::default(),
         }
     }
 }
 
-pub fn deserialize_rotation<'de, D: Deserializer<'de>>(d: D) -> Result<Quaternion, D::Error> {
-    let angles = Vec3::deserialize(d)?;
+/// Converts euler angles in degrees, as written in scene files, to a rotation
+pub fn rotation_from_degrees(angles: Vec3) -> Quaternion {
     let angles = angles.map(Scalar::to_radians).map(Rad);
     let angles = Euler::new(angles.x, angles.y, angles.z);
-    Ok(Quaternion::from(angles))
+    Quaternion::from(angles)
+}
+
+pub fn deserialize_rotation<'de, D: Deserializer<'de>>(d: D) -> Result<Quaternion, D::Error> {
+    let angles = Vec3::deserialize(d)?;
+    Ok(rotation_from_degrees(angles))
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Object {
     pub shape: Shape,
     pub position: Pt3,
+    /// Displacement over the exposure
     #[serde(default = "Vec3::zero")]
     pub motion: Vec3,
     #[serde(
@@ -220,79 +450,582 @@ pub struct Object {
         deserialize_with = "deserialize_rotation"
     )]
     pub rotation: Quaternion,
+    /// Rotation over the exposure, given in euler degrees
+    #[serde(
+        default = "Quaternion::zero",
+        deserialize_with = "deserialize_rotation"
+    )]
+    pub angular_motion: Quaternion,
     pub material: DisneyMaterial,
+    /// Kinds of rays that can hit the object
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Only hit by camera rays, which see what is behind it darkened by the shadows cast onto
+    /// it. Used to composite renders onto photographs.
+    #[serde(default)]
+    pub shadow_catcher: bool,
+}
+
+/// Kinds of rays, used to hide objects from some of them
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(from = "VisibilityRaw")]
+#[repr(transparent)]
+pub struct Visibility(u8);
+
+impl Visibility {
+    /// Rays traced from the camera
+    pub const CAMERA: Visibility = Visibility(1 << 0);
+    /// Rays testing whether a light is occluded
+    pub const SHADOW: Visibility = Visibility(1 << 1);
+    /// Rays scattered by diffuse or glossy surfaces and by media
+    pub const DIFFUSE: Visibility = Visibility(1 << 2);
+    /// Rays scattered by perfectly specular surfaces
+    pub const SPECULAR: Visibility = Visibility(1 << 3);
+    pub const ALL: Visibility = Self::CAMERA
+        .set(Self::SHADOW)
+        .set(Self::DIFFUSE)
+        .set(Self::SPECULAR);
+}
+
+bitfield_methods!(Visibility);
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+fn visible() -> bool {
+    true
+}
+
+/// Ray kinds an object is visible to, all default to true. `indirect` sets both `diffuse` and
+/// `specular` unless they are given themselves.
+#[derive(Deserialize)]
+struct VisibilityRaw {
+    #[serde(default = "visible")]
+    camera: bool,
+    #[serde(default = "visible")]
+    shadow: bool,
+    #[serde(default)]
+    indirect: Option<bool>,
+    #[serde(default)]
+    diffuse: Option<bool>,
+    #[serde(default)]
+    specular: Option<bool>,
+}
+
+impl From<VisibilityRaw> for Visibility {
+    fn from(raw: VisibilityRaw) -> Self {
+        [
+            (raw.camera, Visibility::CAMERA),
+            (raw.shadow, Visibility::SHADOW),
+            (
+                raw.diffuse.or(raw.indirect).unwrap_or(true),
+                Visibility::DIFFUSE,
+            ),
+            (
+                raw.specular.or(raw.indirect).unwrap_or(true),
+                Visibility::SPECULAR,
+            ),
+        ]
+        .into_iter()
+        .filter(|(visible, _)| *visible)
+        .fold(Visibility(0), |visibility, (_, kind)| visibility.set(kind))
+    }
+}
+
+impl Object {
+    pub fn new(shape: Shape, position: Pt3, material: DisneyMaterial) -> Self {
+        Self {
+            shape,
+            position,
+            motion: Vec3::zero(),
+            rotation: Quaternion::zero(),
+            angular_motion: Quaternion::zero(),
+            material,
+            visibility: Visibility::ALL,
+            shadow_catcher: false,
+        }
+    }
+
+    pub fn with_visibility(mut self, visibility: Visibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    pub fn with_shadow_catcher(mut self, shadow_catcher: bool) -> Self {
+        self.shadow_catcher = shadow_catcher;
+        self
+    }
+
+    pub fn with_motion(mut self, motion: Vec3) -> Self {
+        self.motion = motion;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: Quaternion) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_angular_motion(mut self, angular_motion: Quaternion) -> Self {
+        self.angular_motion = angular_motion;
+        self
+    }
+
+    /// Position of the object at `time`, a fraction of the exposure in [0, 1]
+    pub fn translation_at(&self, time: Scalar) -> Vec3 {
+        self.position.to_vec() + self.motion * time
+    }
+
+    /// Rotation of the object at `time`, a fraction of the exposure in [0, 1]
+    pub fn rotation_at(&self, time: Scalar) -> Quaternion {
+        // A zero quaternion stands for no rotation
+        if self.angular_motion.is_zero() || time == 0.0 {
+            return self.rotation;
+        }
+        let rotation = if self.rotation.is_zero() {
+            Quaternion::one()
+        } else {
+            self.rotation
+        };
+        rotation * Quaternion::one().slerp(self.angular_motion, time)
+    }
+
+    /// Object to world transform at `time`, a fraction of the exposure in [0, 1]
+    pub fn transform_at(&self, time: Scalar) -> Transform {
+        Transform::new(self.rotation_at(time), self.translation_at(time))
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "kind")]
 pub enum Shape {
-    Sphere { radius: Scalar },
+    Sphere {
+        radius: Scalar,
+    },
+    /// Unbounded plane through the origin with its normal along +y in object space. The uv
+    /// repeats every `1 / uv_scale` units along x and z.
+    Plane {
+        #[serde(default = "default_uv_scale")]
+        uv_scale: Scalar,
+    },
+    /// Loaded from model files rather than written in scenes
+    #[serde(skip)]
+    Mesh(TriangleMesh),
+}
+
+fn default_uv_scale() -> Scalar {
+    1.0
+}
+
+/// How the shutter opening is weighted over the exposure, determines the distribution of ray times
+#[derive(Debug, Clone, Default)]
+pub enum ShutterCurve {
+    /// Fully open for the whole exposure
+    #[default]
+    Box,
+    /// Opens linearly up to the middle of the exposure then closes
+    Triangle,
+    /// Piecewise linear openness between (time, weight) keyframes
+    Keyframes(Distribution1D),
 }
 
-impl Hdri {
-    fn from_path(path: impl AsRef<Path>, strength: Scalar) -> Self {
-        let image = image::io::Reader::open(path).unwrap().decode().unwrap();
-        Hdri::new(image.into_rgb32f(), strength)
+/// Resolution the keyframe curve is tabulated at for sampling
+const SHUTTER_CURVE_BINS: usize = 64;
+
+impl ShutterCurve {
+    /// Builds a curve from (time, weight) keyframes with increasing times in [0, 1]
+    pub fn keyframes(keyframes: &[(Scalar, Scalar)]) -> Result<Self, String> {
+        if keyframes.is_empty() {
+            return Err("Shutter curve needs at least one keyframe".to_owned());
+        }
+        if keyframes.windows(2).any(|w| w[0].0 > w[1].0) {
+            return Err("Shutter keyframe times must be increasing".to_owned());
+        }
+        if keyframes
+            .iter()
+            .any(|&(time, weight)| !(0.0..=1.0).contains(&time) || weight < 0.0)
+        {
+            return Err(
+                "Shutter keyframes need times in [0, 1] and non-negative weights".to_owned(),
+            );
+        }
+        if keyframes.iter().all(|&(_, weight)| weight == 0.0) {
+            return Err("Shutter curve must be open at some point".to_owned());
+        }
+
+        let weight_at = |time: Scalar| {
+            let next = keyframes.partition_point(|&(t, _)| t < time);
+            match (
+                next.checked_sub(1).map(|i| keyframes[i]),
+                keyframes.get(next),
+            ) {
+                (Some((t0, w0)), Some(&(t1, w1))) if t1 > t0 => {
+                    w0 + (w1 - w0) * (time - t0) / (t1 - t0)
+                }
+                (_, Some(&(_, w))) | (Some((_, w)), None) => w,
+                (None, None) => unreachable!(),
+            }
+        };
+        let weights = (0..SHUTTER_CURVE_BINS)
+            .map(|i| weight_at((i as Scalar + 0.5) / SHUTTER_CURVE_BINS as Scalar))
+            .collect();
+        Ok(ShutterCurve::Keyframes(Distribution1D::new(weights)))
     }
+
+    /// Maps a uniform random number to a time in [0, 1] distributed like the shutter opening
+    pub fn sample(&self, u: Scalar) -> Scalar {
+        match self {
+            ShutterCurve::Box => u,
+            ShutterCurve::Triangle => {
+                if u < 0.5 {
+                    (u / 2.0).sqrt()
+                } else {
+                    1.0 - ((1.0 - u) / 2.0).sqrt()
+                }
+            }
+            ShutterCurve::Keyframes(distribution) => {
+                let mut pdf = 0.0;
+                distribution.sample_continuous(u, &mut pdf).1
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ShutterCurveRaw {
+    Box,
+    Triangle,
+    Keyframes(Vec<(Scalar, Scalar)>),
+}
+
+impl<'de> DeserializeTrait<'de> for ShutterCurve {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match ShutterCurveRaw::deserialize(deserializer)? {
+            ShutterCurveRaw::Box => Ok(ShutterCurve::Box),
+            ShutterCurveRaw::Triangle => Ok(ShutterCurve::Triangle),
+            ShutterCurveRaw::Keyframes(keyframes) => {
+                ShutterCurve::keyframes(&keyframes).map_err(D::Error::custom)
+            }
+        }
+    }
+}
+
+/// Projection used to map pixels to camera rays
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CameraModel {
+    /// Perspective projection with depth of field
+    #[default]
+    ThinLens,
+    /// Equidistant fisheye, `fov` is the angle in degrees (at most 180) across the image width.
+    /// Corners outside the image circle continue the projection.
+    Fisheye { fov: Scalar },
+    /// Full 360x180 degree panorama, ignores the aspect ratio and aperture
+    Equirect,
+}
+
+fn default_roulette_depth() -> usize {
+    Camera::DEFAULT_ROULETTE_DEPTH
+}
+
+fn default_roulette_min_survival() -> Scalar {
+    Camera::DEFAULT_ROULETTE_MIN_SURVIVAL
+}
+
+fn default_variance_threshold() -> Scalar {
+    Camera::DEFAULT_VARIANCE_THRESHOLD
 }
 
 #[derive(Debug, Deserialize)]
 struct CameraRaw {
+    #[serde(default)]
+    pub model: CameraModel,
     pub position: Pt3,
     pub direction: Vec3,
     pub sensor_distance: Scalar,
     pub exposure_time: Scalar,
+    #[serde(default)]
+    pub shutter: ShutterCurve,
     pub aperture: Scalar,
+    #[serde(default)]
+    pub aperture_blades: u32,
+    #[serde(default)]
+    pub aperture_rotation: Scalar,
     pub focus_distance: Scalar,
     pub ldr_scale: Scalar,
+    #[serde(default = "Vec3::zero")]
+    pub motion: Vec3,
+    #[serde(
+        default = "Quaternion::zero",
+        deserialize_with = "deserialize_rotation"
+    )]
+    pub angular_motion: Quaternion,
+    #[serde(default)]
+    pub bloom: Option<Bloom>,
+    #[serde(default)]
+    pub denoise: Option<DenoiseConfig>,
+    #[serde(default)]
+    pub vignette: Scalar,
+    #[serde(default)]
+    pub chromatic_aberration: Scalar,
+    #[serde(default)]
+    pub mode: RenderMode,
 
     pub bounce_limit: usize,
+    #[serde(default = "default_roulette_depth")]
+    pub roulette_depth: usize,
+    #[serde(default = "default_roulette_min_survival")]
+    pub roulette_min_survival: Scalar,
+    #[serde(alias = "min_samples")]
     pub num_samples: usize,
+    #[serde(default)]
+    pub max_samples: Option<usize>,
+    #[serde(default = "default_variance_threshold")]
+    pub variance_threshold: Scalar,
     pub width: usize,
     pub height: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Camera {
+    pub model: CameraModel,
     pub position: Pt3,
     pub direction: Vec3,
     pub sensor_distance: Scalar,
     pub exposure_time: Scalar,
+    pub shutter: ShutterCurve,
     pub aperture: Scalar,
+    /// Number of diaphragm blades shaping out of focus highlights, 0 for a circular aperture
+    pub aperture_blades: u32,
+    /// Rotation of the aperture polygon in degrees
+    pub aperture_rotation: Scalar,
     pub focus_distance: Scalar,
     pub ldr_scale: Scalar,
+    /// Displacement over the exposure
+    pub motion: Vec3,
+    /// Rotation over the exposure
+    pub angular_motion: Quaternion,
+    /// Lens bloom, applied between denoising and tone mapping
+    pub bloom: Option<Bloom>,
+    /// Denoiser settings, which override those of the scene's denoise steps or add one before
+    /// every other step
+    pub denoise: Option<DenoiseConfig>,
+    /// Darkening at the image corners, applied after tone mapping
+    pub vignette: Scalar,
+    /// Radial offset of the red and blue channels, applied after tone mapping
+    pub chromatic_aberration: Scalar,
+    /// What is rendered, the radiance or a diagnostic value
+    pub mode: RenderMode,
 
     pub bounce_limit: usize,
+    /// Bounces before paths can be terminated by russian roulette
+    pub roulette_depth: usize,
+    /// Lower bound on the probability of a path surviving russian roulette, 1 disables it
+    pub roulette_min_survival: Scalar,
+    /// Samples per pixel, the minimum when sampling adaptively
     pub num_samples: usize,
+    /// Cap on the samples per pixel, pixels only take more than `num_samples` while noisy
+    pub max_samples: usize,
+    /// Pixels stop sampling once the 95% confidence interval of their luminance is within this
+    /// fraction of the mean
+    pub variance_threshold: Scalar,
     pub width: usize,
     pub height: usize,
 }
 
+impl Camera {
+    pub const DEFAULT_ROULETTE_DEPTH: usize = 3;
+    pub const DEFAULT_ROULETTE_MIN_SURVIVAL: Scalar = 0.7;
+    pub const DEFAULT_VARIANCE_THRESHOLD: Scalar = 0.05;
+
+    /// Whether pixels take a varying number of samples depending on their noise
+    pub fn is_adaptive(&self) -> bool {
+        self.max_samples > self.num_samples
+    }
+
+    /// Samples a ray time, as a fraction of the exposure, from the shutter curve. Always 0 when
+    /// the exposure time is 0.
+    pub fn sample_time(&self, u: Scalar) -> Scalar {
+        if self.exposure_time > 0.0 {
+            self.shutter.sample(u)
+        } else {
+            0.0
+        }
+    }
+
+    /// Position and camera to world basis at `time`, a fraction of the exposure in [0, 1]
+    pub fn frame_at(&self, time: Scalar) -> (Pt3, Mat3) {
+        let position = self.position + self.motion * time;
+        let camera_x = -self.direction.cross(vec3(0.0, 1.0, 0.0)).normalize();
+        let camera_y = camera_x.cross(self.direction).normalize();
+        let camera_z = self.direction.normalize();
+        let basis = Mat3::from([camera_x.into(), camera_y.into(), camera_z.into()]);
+        if self.angular_motion.is_zero() || time == 0.0 {
+            return (position, basis);
+        }
+        // The whole frame turns, so rolling about the view direction blurs too
+        let rotation = Quaternion::one().slerp(self.angular_motion, time);
+        (position, Mat3::from(rotation) * basis)
+    }
+
+    /// Random point on the unit aperture, shaped by the blade count and rotation
+    fn sample_aperture(&self) -> Vec2 {
+        let sample = random_polygon_aperture(self.aperture_blades).to_vec();
+        if self.aperture_blades == 0 || self.aperture_rotation == 0.0 {
+            sample
+        } else {
+            Basis2::from_angle(Deg(self.aperture_rotation)).rotate_vector(sample)
+        }
+    }
+
+    /// Generates a ray through the continuous pixel coordinate (`x`, `y`) at `time`, a fraction of
+    /// the exposure in [0, 1]. The lens is sampled randomly when the aperture is non-zero.
+    pub fn generate_ray(&self, x: Scalar, y: Scalar, time: Scalar) -> Ray {
+        let (position, basis) = self.frame_at(time);
+        let u = x / self.width as Scalar;
+        let v = y / self.height as Scalar;
+
+        match self.model {
+            CameraModel::ThinLens => {
+                let aspect_ratio = self.width as Scalar / self.height as Scalar;
+                let x = u * 2.0 - 1.0;
+                let y = (v * 2.0 - 1.0) / aspect_ratio;
+                let ray_dir = basis * vec3(x, y, self.sensor_distance);
+
+                // Depth of field, offset the origin on the lens and aim at the focal point
+                let lens_origin =
+                    position + basis * (self.aperture * self.sample_aperture()).extend(0.0);
+                let focal_point = position + self.focus_distance * ray_dir.normalize();
+
+                Ray::new(lens_origin, focal_point - lens_origin, time)
+            }
+            CameraModel::Fisheye { fov } => {
+                let aspect_ratio = self.width as Scalar / self.height as Scalar;
+                let x = u * 2.0 - 1.0;
+                let y = (v * 2.0 - 1.0) / aspect_ratio;
+
+                // Angle from the view direction is proportional to the distance from the center
+                let r = (x * x + y * y).sqrt();
+                let theta = r * fov.to_radians() / 2.0;
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                let ray_dir = if r == 0.0 {
+                    vec3(0.0, 0.0, 1.0)
+                } else {
+                    vec3(x / r * sin_theta, y / r * sin_theta, cos_theta)
+                };
+                Ray::new(position, basis * ray_dir, time)
+            }
+            CameraModel::Equirect => {
+                let phi = (u - 0.5) * 2.0 * PI;
+                let elevation = (v - 0.5) * PI;
+                let ray_dir = vec3(
+                    elevation.cos() * phi.sin(),
+                    elevation.sin(),
+                    elevation.cos() * phi.cos(),
+                );
+                Ray::new(position, basis * ray_dir, time)
+            }
+        }
+    }
+}
+
 impl<'de> DeserializeTrait<'de> for Camera {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let CameraRaw {
+            model,
             position,
             direction,
             sensor_distance,
             exposure_time,
+            shutter,
             aperture,
+            aperture_blades,
+            aperture_rotation,
             focus_distance,
             ldr_scale,
+            motion,
+            angular_motion,
+            bloom,
+            denoise,
+            vignette,
+            chromatic_aberration,
+            mode,
             bounce_limit,
+            roulette_depth,
+            roulette_min_survival,
             num_samples,
+            max_samples,
+            variance_threshold,
             width,
             height,
         } = CameraRaw::deserialize(deserializer)?;
+        let max_samples = max_samples.unwrap_or(num_samples);
+        if max_samples < num_samples {
+            return Err(D::Error::custom(format!(
+                "max_samples ({max_samples}) must be at least num_samples ({num_samples})"
+            )));
+        }
+        if variance_threshold.is_nan() || variance_threshold < 0.0 {
+            return Err(D::Error::custom(format!(
+                "variance_threshold must be non-negative, got {variance_threshold}"
+            )));
+        }
+        if !(roulette_min_survival > 0.0 && roulette_min_survival <= 1.0) {
+            return Err(D::Error::custom(format!(
+                "roulette_min_survival must be in (0, 1], got {roulette_min_survival}"
+            )));
+        }
+        if aperture_blades == 1 || aperture_blades == 2 {
+            return Err(D::Error::custom(format!(
+                "aperture_blades must be 0 (circular) or at least 3, got {aperture_blades}"
+            )));
+        }
+        match model {
+            CameraModel::Fisheye { fov } if !(fov > 0.0 && fov <= 180.0) => {
+                return Err(D::Error::custom(format!(
+                    "Fisheye fov must be in (0, 180] degrees, got {fov}"
+                )));
+            }
+            CameraModel::Fisheye { .. } | CameraModel::Equirect if aperture > 0.0 => {
+                eprintln!("Warning: camera aperture is ignored by the {model:?} camera model");
+            }
+            _ => {}
+        }
+        if model == CameraModel::Equirect && width != 2 * height {
+            eprintln!(
+                "Warning: equirect cameras ignore the aspect ratio, use a 2:1 resolution to avoid \
+                 stretching (got {width}x{height})"
+            );
+        }
         Ok(Camera {
+            model,
             position,
             direction: direction.normalize(),
             sensor_distance,
             exposure_time,
+            shutter,
             aperture,
+            aperture_blades,
+            aperture_rotation,
             focus_distance,
             ldr_scale,
+            motion,
+            angular_motion,
+            bloom,
+            denoise,
+            vignette,
+            chromatic_aberration,
+            mode,
             bounce_limit,
+            roulette_depth,
+            roulette_min_survival,
             num_samples,
+            max_samples,
+            variance_threshold,
             width,
             height,
         })
@@ -300,10 +1033,391 @@ impl<'de> DeserializeTrait<'de> for Camera {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct Scene {
+struct SceneRaw {
     pub camera: Camera,
+    #[serde(deserialize_with = "deserialize_objects")]
     pub objects: Vec<Object>,
+    pub lights: Vec<LightEntry>,
+    #[serde(default)]
+    pub light_sampling: LightSampling,
+    #[serde(default)]
+    pub postprocess: PostProcessChain,
+    #[serde(default)]
+    pub medium: Option<HomogeneousMedium>,
+    #[serde(default)]
+    pub background: Color,
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    #[serde(default)]
+    pub output_color_space: ColorSpace,
+}
+
+/// Entry of the scene's light list
+#[derive(Debug, Deserialize)]
+struct LightEntry {
+    #[serde(flatten)]
+    light: Light,
+    /// Light group the light's contribution is also written to, for rebalancing lights after
+    /// the render
+    #[serde(default)]
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind")]
+enum ImportRaw {
+    Gltf { path: String },
+}
+
+fn deserialize_objects<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Object>, D::Error> {
+    let mut objects = Vec::new();
+    // Entries with a kind are model files that expand into objects, everything else is an object.
+    // Telling them apart up front keeps the object's own errors, which name the bad field.
+    for entry in Vec::<toml::Value>::deserialize(d)? {
+        if entry.get("kind").is_some() {
+            let ImportRaw::Gltf { path } = entry.try_into().map_err(D::Error::custom)?;
+            objects.extend(
+                load_gltf(scene_relative_path(path), scene_color_space())
+                    .map_err(D::Error::custom)?,
+            );
+        } else {
+            let object: Object = entry.try_into().map_err(D::Error::custom)?;
+            if object.material.is_emissive() && !object.shape.is_bounded() {
+                return Err(D::Error::custom("Unbounded shapes can't be emissive"));
+            }
+            objects.push(object);
+        }
+    }
+    Ok(objects)
+}
+
+#[derive(Debug)]
+pub struct Scene {
+    pub camera: Camera,
+    /// Fixed once the scene is built, the emissive objects and packed spheres are found from them
+    objects: Vec<Object>,
     pub lights: Vec<Light>,
+    pub light_sampling: LightSampling,
+    /// Steps applied to the rendered image before it is saved
+    pub postprocess: PostProcessChain,
+    /// Participating medium filling the space between objects
+    pub medium: Option<HomogeneousMedium>,
+    /// Radiance of rays that miss everything, seen directly and in specular reflections but
+    /// never sampled as a light
+    pub background: Color,
+    /// Working space the scene's colors are given and rendered in. Image textures and
+    /// environment maps are read as linear sRGB and converted to it.
+    pub color_space: ColorSpace,
+    /// Space the rendered image is converted to before it is saved
+    pub output_color_space: ColorSpace,
+    /// File the scene was loaded from, if any
+    pub path: Option<PathBuf>,
+    /// Files the scene references, e.g. textures and environment maps, as resolved while loading
+    pub files: Vec<PathBuf>,
+    emissive_objects: Vec<usize>,
+    sampled_lights: Vec<usize>,
+    light_power: Distribution1D,
+    light_groups: Vec<String>,
+    /// Group slot of each light, see [`GroupedLight`](crate::light::GroupedLight)
+    light_group_slots: Vec<usize>,
+    /// Objects visible to each combination of ray kinds, indexed by its bits
+    packed_objects: Vec<PackedObjects>,
+}
+
+impl Scene {
+    pub fn new(camera: Camera, objects: Vec<Object>, lights: Vec<Light>) -> Self {
+        let emissive_objects = objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| object.material.is_emissive())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert!(
+            emissive_objects
+                .iter()
+                .all(|&i| objects[i].shape.is_bounded()),
+            "Unbounded shapes can't be emissive"
+        );
+        let sampled_lights = (0..lights.len()).collect();
+        let lights_len = lights.len();
+        let packed_objects = (0..=Visibility::ALL.0)
+            .map(|kind| PackedObjects::new(&objects, Visibility(kind)))
+            .collect();
+        let mut scene = Self {
+            camera,
+            objects,
+            lights,
+            light_sampling: LightSampling::default(),
+            postprocess: PostProcessChain::default(),
+            medium: None,
+            background: color::BLACK,
+            color_space: ColorSpace::LinearSrgb,
+            output_color_space: ColorSpace::LinearSrgb,
+            path: None,
+            files: Vec::new(),
+            emissive_objects,
+            sampled_lights,
+            light_power: Distribution1D::new(Vec::new()),
+            light_groups: Vec::new(),
+            light_group_slots: vec![UNGROUPED; lights_len],
+            packed_objects,
+        };
+
+        let world_radius = scene.world_radius();
+        let power = scene
+            .sampled_lights()
+            .map(|light| light.power(world_radius))
+            .chain(
+                scene
+                    .emissive_objects()
+                    .map(|object| object.power(world_radius)),
+            )
+            .collect();
+        scene.light_power = Distribution1D::new(power);
+        scene
+    }
+
+    pub fn with_light_sampling(mut self, light_sampling: LightSampling) -> Self {
+        self.light_sampling = light_sampling;
+        self
+    }
+
+    pub fn with_postprocess(mut self, postprocess: PostProcessChain) -> Self {
+        self.postprocess = postprocess;
+        self
+    }
+
+    pub fn with_medium(mut self, medium: Option<HomogeneousMedium>) -> Self {
+        self.medium = medium;
+        self
+    }
+
+    pub fn with_background(mut self, background: Color) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn with_color_spaces(mut self, working: ColorSpace, output: ColorSpace) -> Self {
+        self.color_space = working;
+        self.output_color_space = output;
+        self
+    }
+
+    /// Puts each light in the named group, or in none. Groups are numbered in the order they
+    /// first appear.
+    pub fn with_light_groups(mut self, groups: Vec<Option<String>>) -> Self {
+        assert_eq!(
+            groups.len(),
+            self.lights.len(),
+            "Every light needs a group entry"
+        );
+        self.light_groups.clear();
+        self.light_group_slots = groups
+            .into_iter()
+            .map(|group| match group {
+                None => UNGROUPED,
+                Some(group) => {
+                    let index = match self.light_groups.iter().position(|name| *name == group) {
+                        Some(index) => index,
+                        None => {
+                            self.light_groups.push(group);
+                            self.light_groups.len() - 1
+                        }
+                    };
+                    index + 1
+                }
+            })
+            .collect();
+        assert!(
+            self.light_groups.len() <= MAX_LIGHT_GROUPS,
+            "At most {MAX_LIGHT_GROUPS} light groups are supported"
+        );
+        self
+    }
+
+    /// Names of the light groups, the `i`th group's light is in slot `i + 1`
+    pub fn light_groups(&self) -> &[String] {
+        &self.light_groups
+    }
+
+    /// Group slot of the light at `index` in `lights`, lights added after the groups were set
+    /// aren't in a group
+    pub fn light_group(&self, index: usize) -> usize {
+        self.light_group_slots
+            .get(index)
+            .copied()
+            .unwrap_or(UNGROUPED)
+    }
+
+    /// Group slot of an area light of the scene
+    pub fn area_light_group(&self, area: &AreaLight) -> usize {
+        self.lights
+            .iter()
+            .position(|light| matches!(light, Light::Area(light) if std::ptr::eq(light, area)))
+            .map_or(UNGROUPED, |index| self.light_group(index))
+    }
+
+    /// Group slot of the `index`th of the sampled lights followed by the emissive objects,
+    /// emissive objects aren't in a group
+    pub fn candidate_group(&self, index: usize) -> usize {
+        self.sampled_lights
+            .get(index)
+            .map_or(UNGROUPED, |&light| self.light_group(light))
+    }
+
+    /// The scene's post-process chain with the camera's lens effects added
+    pub fn postprocess_chain(&self) -> PostProcessChain {
+        let mut chain = self.postprocess.clone();
+        if let Some(denoise) = self.camera.denoise {
+            chain = chain.with_denoise(denoise);
+        }
+        if let Some(bloom) = self.camera.bloom {
+            chain = chain.with_bloom(bloom);
+        }
+        if self.camera.vignette != 0.0 {
+            chain = chain.with_step(PostProcessStep::Vignette {
+                strength: self.camera.vignette,
+            });
+        }
+        if self.camera.chromatic_aberration != 0.0 {
+            chain = chain.with_step(PostProcessStep::ChromaticAberration {
+                amount: self.camera.chromatic_aberration,
+            });
+        }
+        chain
+    }
+
+    /// Objects with an emissive material, these are sampled as area lights
+    pub fn emissive_objects(&self) -> impl ExactSizeIterator<Item = &Object> + Clone {
+        self.emissive_objects.iter().map(|&i| &self.objects[i])
+    }
+
+    /// Lights that can be sampled for direct lighting
+    pub fn sampled_lights(&self) -> impl ExactSizeIterator<Item = &Light> + Clone {
+        self.sampled_lights.iter().map(|&i| &self.lights[i])
+    }
+
+    /// Distribution over the sampled lights followed by the emissive objects, proportional to
+    /// their approximate power
+    pub fn light_power_distribution(&self) -> &Distribution1D {
+        &self.light_power
+    }
+
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+
+    /// Objects visible to `ray_kind` with their static spheres packed
+    pub(crate) fn packed_objects(&self, ray_kind: Visibility) -> &PackedObjects {
+        &self.packed_objects[usize::from(ray_kind.0)]
+    }
+
+    /// Radius of a sphere around the origin containing the camera and every bounded object
+    pub fn world_radius(&self) -> Scalar {
+        self.objects
+            .iter()
+            .filter(|object| object.shape.is_bounded())
+            .map(|object| object.position.to_vec().magnitude() + object.shape.bounding_radius())
+            .fold(self.camera.position.to_vec().magnitude(), Scalar::max)
+            .max(1.0)
+    }
+}
+
+impl<'de> DeserializeTrait<'de> for Scene {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SceneRaw {
+            camera,
+            objects,
+            lights,
+            light_sampling,
+            postprocess,
+            medium,
+            background,
+            color_space,
+            output_color_space,
+        } = SceneRaw::deserialize(deserializer)?;
+        let (lights, groups): (Vec<_>, Vec<_>) = lights
+            .into_iter()
+            .map(|entry| (entry.light, entry.group))
+            .unzip();
+        let mut names = groups.iter().flatten().collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        if names.len() > MAX_LIGHT_GROUPS {
+            return Err(D::Error::custom(format!(
+                "{} light groups, at most {MAX_LIGHT_GROUPS} are supported",
+                names.len()
+            )));
+        }
+        Ok(Scene::new(camera, objects, lights)
+            .with_light_groups(groups)
+            .with_light_sampling(light_sampling)
+            .with_postprocess(postprocess)
+            .with_medium(medium)
+            .with_background(background)
+            .with_color_spaces(color_space, output_color_space))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AttenuationKind {
+    #[default]
+    InverseSquare,
+    Legacy,
+}
+
+fn default_min_distance() -> Scalar {
+    Attenuation::DEFAULT_MIN_DISTANCE
+}
+
+fn default_visible_to_camera() -> bool {
+    true
+}
+
+fn attenuation(kind: AttenuationKind, min_distance: Scalar) -> Attenuation {
+    match kind {
+        AttenuationKind::InverseSquare => Attenuation::InverseSquare { min_distance },
+        AttenuationKind::Legacy => Attenuation::Legacy,
+    }
+}
+
+/// Light color given either as rgb or as a color temperature in kelvin
+#[derive(Debug, Deserialize)]
+struct LightColor {
+    color: Option<Color>,
+    temperature: Option<Scalar>,
+    intensity: Option<Scalar>,
+}
+
+impl LightColor {
+    fn resolve(self) -> Result<Color, String> {
+        match (self.color, self.temperature, self.intensity) {
+            (Some(color), None, None) => Ok(color),
+            (None, Some(temperature), intensity) => {
+                if temperature <= 0.0 {
+                    return Err(format!("temperature must be positive, got {temperature}"));
+                }
+                Ok(color::from_temperature(temperature) * intensity.unwrap_or(1.0))
+            }
+            (Some(_), None, Some(_)) => Err("intensity requires temperature".to_owned()),
+            (Some(_), Some(_), _) => {
+                Err("light has both color and temperature, expected exactly one".to_owned())
+            }
+            (None, None, _) => Err("light requires either color or temperature".to_owned()),
+        }
+    }
+}
+
+/// Image paths of the six faces of a cube map light
+#[derive(Debug, Deserialize)]
+struct CubeMapFaces {
+    positive_x: String,
+    negative_x: String,
+    positive_y: String,
+    negative_y: String,
+    positive_z: String,
+    negative_z: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -311,22 +1425,50 @@ pub struct Scene {
 enum LightSerialStructure {
     Point {
         position: Pt3,
-        color: Color,
+        #[serde(flatten)]
+        color: LightColor,
+        power: Option<Scalar>,
+        #[serde(default)]
+        attenuation: AttenuationKind,
+        #[serde(default = "default_min_distance")]
+        min_distance: Scalar,
+        #[serde(default)]
+        ies_path: Option<String>,
     },
     Spot {
         position: Pt3,
         direction: Vec3,
         angle: Scalar,
         falloff: Scalar,
-        color: Color,
+        #[serde(default)]
+        falloff_mode: SpotFalloff,
+        #[serde(flatten)]
+        color: LightColor,
+        power: Option<Scalar>,
+        #[serde(default)]
+        attenuation: AttenuationKind,
+        #[serde(default = "default_min_distance")]
+        min_distance: Scalar,
+        #[serde(default)]
+        gobo: Option<Texture<Color, Rgb8ColorPixelConverter>>,
+        #[serde(default)]
+        ies_path: Option<String>,
     },
     Direction {
         direction: Vec3,
-        color: Color,
+        #[serde(flatten)]
+        color: LightColor,
+        power: Option<Scalar>,
     },
     Hdri {
         path: String,
         strength: Scalar,
+        #[serde(default = "default_visible_to_camera")]
+        visible_to_camera: bool,
+    },
+    CubeMap {
+        faces: CubeMapFaces,
+        strength: Scalar,
     },
     Area {
         #[serde(
@@ -336,10 +1478,14 @@ enum LightSerialStructure {
         rotation: Quaternion,
         position: Pt3,
         shape: Shape,
-        color: Color,
+        #[serde(flatten)]
+        color: LightColor,
+        #[serde(default)]
+        texture: Option<Texture<Color, Rgb8ColorPixelConverter>>,
     },
     Ambient {
-        color: Color,
+        #[serde(flatten)]
+        color: LightColor,
     },
 }
 
@@ -349,84 +1495,1106 @@ impl<'de> DeserializeTrait<'de> for Light {
         D: Deserializer<'de>,
     {
         let light = LightSerialStructure::deserialize(deserializer)?;
+        let resolve = |color: LightColor| color.resolve().map_err(D::Error::custom);
+        let load_ies = |path: Option<String>| {
+            path.map(|path| IesProfile::from_path(scene_relative_path(path)))
+                .transpose()
+                .map_err(D::Error::custom)
+        };
         match light {
             LightSerialStructure::Point {
                 position,
-                color: radiance,
-            } => Ok(Light::Point(PointLight { position, radiance })),
+                color,
+                power,
+                attenuation: attenuation_kind,
+                min_distance,
+                ies_path,
+            } => {
+                let mut light = PointLight::new(position, resolve(color)?)
+                    .with_attenuation(attenuation(attenuation_kind, min_distance));
+                if let Some(ies) = load_ies(ies_path)? {
+                    light = light.with_ies(ies);
+                }
+                if let Some(power) = power {
+                    light = light.with_power(power);
+                }
+                Ok(light.into())
+            }
             LightSerialStructure::Spot {
                 position,
                 direction,
                 angle,
                 falloff,
-                color: radiance,
-            } => Ok(Light::Spot(SpotLight {
-                position,
-                radiance,
-                cos_angle: angle.to_radians().cos(),
-                cos_falloff: falloff.to_radians().cos(),
-                direction: direction.normalize(),
-            })),
+                falloff_mode,
+                color,
+                power,
+                attenuation: attenuation_kind,
+                min_distance,
+                gobo,
+                ies_path,
+            } => {
+                let mut light =
+                    SpotLight::new(position, direction, angle, falloff, resolve(color)?)
+                        .with_attenuation(attenuation(attenuation_kind, min_distance))
+                        .with_falloff_mode(falloff_mode);
+                if let Some(ies) = load_ies(ies_path)? {
+                    light = light.with_ies(ies);
+                }
+                if let Some(power) = power {
+                    light = light.with_power(power);
+                }
+                if let Some(gobo) = gobo {
+                    light = light.with_gobo(gobo);
+                }
+                Ok(light.into())
+            }
             LightSerialStructure::Direction {
                 direction,
-                color: radiance,
-            } => Ok(Light::Direction(DirectionLight {
-                direction: direction.normalize(),
-                radiance,
-            })),
-            LightSerialStructure::Hdri { path, strength } => Ok(Light::Hdri(Hdri::from_path(
-                scene_relative_path(path),
+                color,
+                power,
+            } => {
+                let mut light = DirectionLight::new(direction, resolve(color)?);
+                if let Some(power) = power {
+                    light = light.with_power(power);
+                }
+                Ok(light.into())
+            }
+            LightSerialStructure::Hdri {
+                path,
                 strength,
-            ))),
+                visible_to_camera,
+            } => Ok(
+                Hdri::new(load_environment_image(scene_relative_path(path)), strength)
+                    .with_visible_to_camera(visible_to_camera)
+                    .into(),
+            ),
+            LightSerialStructure::CubeMap { faces, strength } => {
+                let paths = [
+                    faces.positive_x,
+                    faces.negative_x,
+                    faces.positive_y,
+                    faces.negative_y,
+                    faces.positive_z,
+                    faces.negative_z,
+                ]
+                .map(|path| load_environment_image(scene_relative_path(path)));
+                Ok(CubeMapLight::new(paths, strength).into())
+            }
             LightSerialStructure::Area {
                 position,
                 shape,
                 rotation,
-                color: radiance,
-            } => Ok(Light::Area(AreaLight {
-                rotation,
-                position,
-                shape,
-                radiance,
-            })),
-            LightSerialStructure::Ambient { color: radiance } => {
-                Ok(Light::Ambient(AmbientLight { radiance }))
+                color,
+                texture,
+            } => {
+                if !shape.is_bounded() {
+                    return Err(D::Error::custom("Area lights need a bounded shape"));
+                }
+                let mut light = AreaLight::new(position, rotation, shape, resolve(color)?);
+                if let Some(texture) = texture {
+                    light = light.with_texture(texture);
+                }
+                Ok(light.into())
+            }
+            LightSerialStructure::Ambient { color } => {
+                Ok(AmbientLight::new(resolve(color)?).into())
             }
         }
     }
 }
 
+/// Loads an HDR environment image, converting it from linear sRGB to the working space
+fn load_environment_image(path: PathBuf) -> Rgb32FImage {
+    let mut image = image::io::Reader::open(path)
+        .unwrap()
+        .decode()
+        .unwrap()
+        .into_rgb32f();
+    ColorSpace::LinearSrgb
+        .transform_to(scene_color_space())
+        .apply_image(&mut image);
+    image
+}
+
+/// Settings of a scene being loaded that its textures and lights need while deserializing
+struct SceneLoadContext {
+    base_dir: Option<PathBuf>,
+    color_space: ColorSpace,
+    /// Paths resolved by [`scene_relative_path`] so far
+    files: Vec<PathBuf>,
+}
+
 thread_local! {
-    static SCENE_FILE_PATH: RefCell<Option<PathBuf>> = RefCell::new(None);
+    /// Scenes currently being loaded, innermost last.
+    static SCENE_FILE_PATH: RefCell<Vec<SceneLoadContext>> = const { RefCell::new(Vec::new()) };
 }
 
+/// Pops the context pushed by a scene load, even if loading panics.
+struct SceneLoadGuard;
+
+impl SceneLoadGuard {
+    fn push(base_dir: Option<&Path>, color_space: ColorSpace) -> Self {
+        SCENE_FILE_PATH.with(|f| {
+            f.borrow_mut().push(SceneLoadContext {
+                base_dir: base_dir.map(Path::to_path_buf),
+                color_space,
+                files: Vec::new(),
+            })
+        });
+        SceneLoadGuard
+    }
+
+    /// Files resolved while loading the innermost scene
+    fn take_files(&self) -> Vec<PathBuf> {
+        SCENE_FILE_PATH.with(|f| {
+            f.borrow_mut()
+                .last_mut()
+                .map(|context| std::mem::take(&mut context.files))
+                .unwrap_or_default()
+        })
+    }
+}
+
+/// Working space of the innermost scene being loaded, linear sRGB outside of a load
+fn scene_color_space() -> ColorSpace {
+    SCENE_FILE_PATH.with(|f| {
+        f.borrow()
+            .last()
+            .map_or(ColorSpace::LinearSrgb, |context| context.color_space)
+    })
+}
+
+impl Drop for SceneLoadGuard {
+    fn drop(&mut self) {
+        SCENE_FILE_PATH.with(|f| f.borrow_mut().pop());
+    }
+}
+
+/// Resolves `rel` against the directory of the innermost scene being loaded, and records it in
+/// the scene's [`Scene::files`].
+///
+/// Absolute paths are returned as is. Relative paths panic when the scene is
+/// loaded without a base directory.
 pub fn scene_relative_path<P: AsRef<Path>>(rel: P) -> PathBuf {
+    let rel = rel.as_ref();
     SCENE_FILE_PATH.with(|f| {
-        let mut path = f
-            .borrow()
-            .as_ref()
-            .expect("Not currently loading a scene")
-            .clone();
-        path.push(rel);
+        let mut stack = f.borrow_mut();
+        let Some(context) = stack.last_mut() else {
+            assert!(rel.is_absolute(), "Not currently loading a scene");
+            return rel.to_path_buf();
+        };
+        let path = if rel.is_absolute() {
+            rel.to_path_buf()
+        } else {
+            context
+                .base_dir
+                .as_ref()
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Cannot resolve relative path {:?} without a scene base directory",
+                        rel
+                    )
+                })
+                .join(rel)
+        };
+        if !context.files.contains(&path) {
+            context.files.push(path.clone());
+        }
         path
     })
 }
 
+/// Serialization format of a scene file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneFormat {
+    Toml,
+    Json,
+}
+
+impl SceneFormat {
+    /// Detects the format from the file extension, `None` if it is not a known scene format
+    pub fn from_path(path: &Path) -> Option<SceneFormat> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(SceneFormat::Toml),
+            "json" => Some(SceneFormat::Json),
+            _ => None,
+        }
+    }
+}
+
 pub fn load_scene<P: AsRef<Path>>(path: P) -> Scene {
-    assert!(path.as_ref().is_file());
+    let path = path.as_ref();
+    assert!(path.is_file());
 
-    SCENE_FILE_PATH.with(|f| {
-        assert!(f.borrow().is_none());
-        *f.borrow_mut() = Some(path.as_ref().parent().unwrap().to_path_buf());
+    let format = SceneFormat::from_path(path).unwrap_or_else(|| {
+        panic!(
+            "Unknown scene format for {}, expected a .toml or .json file",
+            path.display()
+        )
     });
-
     let source = std::fs::read_to_string(path).unwrap();
-    let mut scene: Scene = toml::from_str(&source).unwrap();
-    scene.camera.direction = scene.camera.direction.normalize();
+    let mut scene = load_scene_from_str(&source, format, path.parent());
+    scene.path = Some(path.canonicalize().unwrap_or_else(|_| path.to_owned()));
+    scene
+}
 
-    SCENE_FILE_PATH.with(|f| {
-        *f.borrow_mut() = None;
-    });
+/// Parses a scene from TOML or JSON source.
+///
+/// Relative texture and environment paths are resolved against `base_dir`. When
+/// `base_dir` is `None` only absolute paths can be loaded. Loads may nest.
+pub fn load_scene_from_str(source: &str, format: SceneFormat, base_dir: Option<&Path>) -> Scene {
+    // Textures are converted to the working space as they load, so it is read first
+    #[derive(Deserialize)]
+    struct ColorSpaceRaw {
+        #[serde(default)]
+        color_space: ColorSpace,
+    }
+    let color_space = match format {
+        SceneFormat::Toml => toml::from_str::<ColorSpaceRaw>(source).map_err(|e| e.to_string()),
+        SceneFormat::Json => {
+            serde_json::from_str::<ColorSpaceRaw>(source).map_err(|e| e.to_string())
+        }
+    }
+    .map_or(ColorSpace::LinearSrgb, |raw| raw.color_space);
 
+    let (scene, files) = {
+        let guard = SceneLoadGuard::push(base_dir, color_space);
+        let scene = match format {
+            SceneFormat::Toml => toml::from_str::<Scene>(source).map_err(|e| e.to_string()),
+            SceneFormat::Json => serde_json::from_str::<Scene>(source).map_err(|e| e.to_string()),
+        };
+        (scene, guard.take_files())
+    };
+
+    let mut scene = scene.unwrap_or_else(|e| panic!("Failed to parse {format:?} scene: {e}"));
+    scene.camera.direction = scene.camera.direction.normalize();
+    scene.files = files;
     scene
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intersect::Intersection;
+    use crate::postprocess::ToneMapOperator;
+    use crate::types::scalar::consts::FRAC_PI_4;
+    use cgmath::{assert_abs_diff_eq, point2, point3};
+
+    fn scene_source(base_color: &str) -> String {
+        format!(
+            r#"
+[camera]
+position = [0.0, 0.0, 0.0]
+direction = [0.0, 0.0, 2.0]
+sensor_distance = 1.0
+exposure_time = 0.0
+aperture = 0.0
+focus_distance = 1.0
+ldr_scale = 1.0
+bounce_limit = 4
+num_samples = 1
+width = 8
+height = 8
+
+[[lights]]
+kind = "Point"
+position = [0.0, 1.0, 0.0]
+color = [1.0, 1.0, 1.0]
+
+[[objects]]
+shape = {{ kind = "Sphere", radius = 0.5 }}
+position = [0.0, 0.0, 3.0]
+
+[objects.material]
+base_color = {}
+subsurface = 0.0
+metallic = 0.0
+specular = 0.5
+specular_tint = 0.0
+roughness = 0.5
+anisotropic = 0.0
+sheen = 0.0
+sheen_tint = 0.0
+clearcoat = 0.0
+clearcoat_gloss = 0.0
+transmission = 0.0
+ior = 1.5
+"#,
+            base_color
+        )
+    }
+
+    #[test]
+    fn builder_matches_deserialized_scene() {
+        let built = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .direction(vec3(0.0, 0.0, 2.0))
+                    .bounce_limit(4)
+                    .num_samples(1)
+                    .resolution(8, 8)
+                    .build(),
+            )
+            .add_light(PointLight::new(point3(0.0, 1.0, 0.0), color::WHITE))
+            .add_object(Object::new(
+                Shape::Sphere { radius: 0.5 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new().build(),
+            ))
+            .build();
+        let loaded = load_scene_from_str(&scene_source("[0.8, 0.8, 0.8]"), SceneFormat::Toml, None);
+        assert_eq!(built.camera.direction, vec3(0.0, 0.0, 1.0));
+        assert_eq!(format!("{built:?}"), format!("{loaded:?}"));
+    }
+
+    #[test]
+    fn color_spaces() {
+        let source = scene_source("[0.8, 0.8, 0.8]");
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(scene.color_space, ColorSpace::LinearSrgb);
+        assert_eq!(scene.output_color_space, ColorSpace::LinearSrgb);
+
+        // Image textures are converted to the working space as they load
+        let dir = std::env::temp_dir();
+        let name = format!("pbrtrs_color_space_{}.png", std::process::id());
+        image::RgbImage::from_pixel(1, 1, Rgb([255, 0, 0]))
+            .save(dir.join(&name))
+            .unwrap();
+        let source = format!(
+            "color_space = \"acescg\"\noutput_color_space = \"aces2065_1\"\n{}",
+            scene_source(&format!("{name:?}"))
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, Some(&dir));
+        std::fs::remove_file(dir.join(&name)).unwrap();
+        assert_eq!(scene.color_space, ColorSpace::AcesCg);
+        assert_eq!(scene.output_color_space, ColorSpace::Aces2065_1);
+        let expected = ColorSpace::LinearSrgb.convert(ColorSpace::AcesCg, color::RED);
+        match &scene.objects[0].material.base_color {
+            Texture::Image(image) => {
+                let texel = Color::from(R8G8B8Color(image.get_pixel(0, 0).0));
+                assert_abs_diff_eq!(texel, expected, epsilon = 1.0 / 255.0);
+            }
+            _ => panic!("Expected an image texture"),
+        }
+    }
+
+    #[test]
+    fn gltf_objects() {
+        // One triangle, next to the sphere of the scene
+        let gltf = r#"{
+  "asset": { "version": "2.0" },
+  "scenes": [{ "nodes": [0] }],
+  "nodes": [{ "mesh": 0 }],
+  "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+  "accessors": [{
+    "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+    "min": [0.0, 0.0, 0.0], "max": [1.0, 1.0, 0.0]
+  }],
+  "bufferViews": [{ "buffer": 0, "byteLength": 36 }],
+  "buffers": [{
+    "byteLength": 36,
+    "uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA"
+  }]
+}"#;
+        let dir = std::env::temp_dir();
+        let name = format!("pbrtrs_scene_{}.gltf", std::process::id());
+        std::fs::write(dir.join(&name), gltf).unwrap();
+        let source = format!(
+            "{}\n[[objects]]\nkind = \"Gltf\"\npath = {name:?}\n",
+            scene_source("[0.8, 0.8, 0.8]")
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, Some(&dir));
+        std::fs::remove_file(dir.join(&name)).unwrap();
+
+        assert_eq!(scene.objects.len(), 2);
+        assert_eq!(scene.files, [dir.join(&name)]);
+        assert!(matches!(scene.objects[0].shape, Shape::Sphere { .. }));
+        match &scene.objects[1].shape {
+            Shape::Mesh(mesh) => assert_abs_diff_eq!(mesh.area(), 0.5, epsilon = 1e-6),
+            _ => panic!("Expected a mesh"),
+        }
+    }
+
+    #[test]
+    fn plane_objects() {
+        let plane = |shape: &str, material: &str| {
+            let source = scene_source("[0.8, 0.8, 0.8]")
+                .replace("{ kind = \"Sphere\", radius = 0.5 }", shape)
+                + material;
+            toml::from_str::<SceneRaw>(&source)
+        };
+
+        let scene = plane("{ kind = \"Plane\" }", "").unwrap();
+        assert!(matches!(scene.objects[0].shape, Shape::Plane { uv_scale } if uv_scale == 1.0));
+        let scene = plane("{ kind = \"Plane\", uv_scale = 0.25 }", "").unwrap();
+        assert!(matches!(scene.objects[0].shape, Shape::Plane { uv_scale } if uv_scale == 0.25));
+        // Unbounded shapes can't be sampled as lights
+        assert!(plane("{ kind = \"Plane\" }", "emission = [1.0, 1.0, 1.0]\n").is_err());
+        assert!(load_light(
+            "kind = \"Area\"\nposition = [0.0, 0.0, 0.0]\nshape = { kind = \"Plane\" }\n\
+             color = [1.0, 1.0, 1.0]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn load_scene_from_str_without_base_dir() {
+        let source = scene_source("[0.8, 0.8, 0.8]");
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(scene.camera.direction, vec3(0.0, 0.0, 1.0));
+        assert!(scene.files.is_empty());
+        assert_eq!(scene.objects.len(), 1);
+        assert_eq!(scene.lights.len(), 1);
+
+        // The loading state is reset so another scene can be parsed
+        load_scene_from_str(&source, SceneFormat::Toml, None);
+    }
+
+    #[test]
+    fn nested_scene_loads() {
+        let outer = SceneLoadGuard::push(Some(Path::new("outer")), ColorSpace::LinearSrgb);
+        assert_eq!(scene_relative_path("a.png"), Path::new("outer/a.png"));
+
+        let source = scene_source("[0.8, 0.8, 0.8]");
+        load_scene_from_str(&source, SceneFormat::Toml, Some(Path::new("inner")));
+        assert_eq!(scene_relative_path("a.png"), Path::new("outer/a.png"));
+
+        {
+            let _inner = SceneLoadGuard::push(Some(Path::new("inner")), ColorSpace::AcesCg);
+            assert_eq!(scene_relative_path("a.png"), Path::new("inner/a.png"));
+            assert_eq!(scene_color_space(), ColorSpace::AcesCg);
+        }
+        assert_eq!(scene_relative_path("a.png"), Path::new("outer/a.png"));
+
+        drop(outer);
+        SCENE_FILE_PATH.with(|f| assert!(f.borrow().is_empty()));
+    }
+
+    #[test]
+    fn failed_load_pops_base_dir() {
+        let source = scene_source("\"texture.png\"");
+        let result =
+            std::panic::catch_unwind(|| load_scene_from_str(&source, SceneFormat::Toml, None));
+        assert!(result.is_err());
+        SCENE_FILE_PATH.with(|f| assert!(f.borrow().is_empty()));
+    }
+
+    #[test]
+    fn json_and_toml_scenes_match() {
+        let source = format!(
+            "{}\n[[lights]]\nkind = \"Ambient\"\ntemperature = 2700\nintensity = 3.0",
+            scene_source(
+                r#"{ kind = "Checker", a = [1.0, 1.0, 1.0], b = [0.0, 0.0, 0.0], scale = 4.0 }"#
+            )
+        );
+        let value: toml::Value = toml::from_str(&source).unwrap();
+        let json = serde_json::to_string(&value).unwrap();
+
+        let from_toml = load_scene_from_str(&source, SceneFormat::Toml, None);
+        let from_json = load_scene_from_str(&json, SceneFormat::Json, None);
+        assert_eq!(from_json.lights.len(), 2);
+        assert_eq!(format!("{from_toml:?}"), format!("{from_json:?}"));
+    }
+
+    #[test]
+    fn postprocess_chain_from_toml() {
+        let source = format!(
+            r#"{}
+[[postprocess]]
+kind = "Bloom"
+threshold = 1.0
+radius = 4.0
+intensity = 0.2
+
+[[postprocess]]
+kind = "ToneMap"
+operator = "aces"
+"#,
+            scene_source("[0.5, 0.5, 0.5]")
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(
+            scene.postprocess.steps(),
+            [
+                PostProcessStep::Bloom(Bloom {
+                    threshold: 1.0,
+                    radius: 4.0,
+                    intensity: 0.2
+                }),
+                PostProcessStep::ToneMap {
+                    operator: ToneMapOperator::Aces,
+                    exposure: 1.0
+                },
+            ]
+        );
+        let scene = load_scene_from_str(&scene_source("[0.5, 0.5, 0.5]"), SceneFormat::Toml, None);
+        assert!(scene.postprocess.is_empty());
+        assert_eq!(scene.camera.bloom, None);
+
+        let source = format!(
+            "{}\n[[postprocess]]\nkind = \"ToneMap\"\n",
+            scene_source("[0.5, 0.5, 0.5]").replace(
+                "ldr_scale = 1.0",
+                "ldr_scale = 1.0\nbloom = { threshold = 2.0, radius = 8.0, intensity = 0.1 }\n\
+                 vignette = 0.3\nchromatic_aberration = 0.01",
+            )
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        let bloom = Bloom {
+            threshold: 2.0,
+            radius: 8.0,
+            intensity: 0.1,
+        };
+        assert_eq!(scene.camera.bloom, Some(bloom));
+        // Bloom runs on the linear image, the lens effects after tone mapping
+        assert_eq!(
+            scene.postprocess_chain().steps(),
+            [
+                PostProcessStep::Bloom(bloom),
+                PostProcessStep::ToneMap {
+                    operator: ToneMapOperator::Reinhard,
+                    exposure: 1.0
+                },
+                PostProcessStep::Vignette { strength: 0.3 },
+                PostProcessStep::ChromaticAberration { amount: 0.01 },
+            ]
+        );
+    }
+
+    #[test]
+    fn camera_denoise_config() {
+        let source = format!(
+            "{}\n[[postprocess]]\nkind = \"Denoise\"\n[[postprocess]]\nkind = \"ToneMap\"\n",
+            scene_source("[0.5, 0.5, 0.5]")
+        );
+        let tone_map = PostProcessStep::ToneMap {
+            operator: ToneMapOperator::Reinhard,
+            exposure: 1.0,
+        };
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(scene.camera.denoise, None);
+        assert_eq!(
+            scene.postprocess_chain().steps(),
+            [
+                PostProcessStep::Denoise(DenoiseConfig::default()),
+                tone_map.clone()
+            ]
+        );
+
+        let with_denoise = |denoise: &str| {
+            load_scene_from_str(
+                &source.replace(
+                    "ldr_scale = 1.0",
+                    &format!("ldr_scale = 1.0\ndenoise = {denoise}"),
+                ),
+                SceneFormat::Toml,
+                None,
+            )
+        };
+        let scene = with_denoise("{ prefilter = true, hdr = false, clean_aux = true }");
+        let config = DenoiseConfig {
+            enabled: true,
+            prefilter: true,
+            hdr: false,
+            clean_aux: true,
+        };
+        assert_eq!(scene.camera.denoise, Some(config));
+        assert_eq!(
+            scene.postprocess_chain().steps(),
+            [PostProcessStep::Denoise(config), tone_map.clone()]
+        );
+
+        let scene = with_denoise("{ enabled = false }");
+        assert_eq!(scene.postprocess_chain().steps(), [tone_map]);
+    }
+
+    #[test]
+    fn scene_format_from_extension() {
+        let format = |path| SceneFormat::from_path(Path::new(path));
+        assert_eq!(format("examples/spot.toml"), Some(SceneFormat::Toml));
+        assert_eq!(format("generated.json"), Some(SceneFormat::Json));
+        assert_eq!(format("scene.yaml"), None);
+        assert_eq!(format("scene"), None);
+    }
+
+    #[test]
+    fn shutter_curves() {
+        let mean = |shutter: &ShutterCurve| {
+            (0..1000)
+                .map(|i| shutter.sample((i as Scalar + 0.5) / 1000.0))
+                .sum::<Scalar>()
+                / 1000.0
+        };
+        assert_abs_diff_eq!(mean(&ShutterCurve::Box), 0.5, epsilon = 1e-3);
+        assert_abs_diff_eq!(mean(&ShutterCurve::Triangle), 0.5, epsilon = 1e-3);
+        // Half the triangle's samples land in the middle half of the exposure, more than a box
+        let middle = (0..1000)
+            .map(|i| ShutterCurve::Triangle.sample((i as Scalar + 0.5) / 1000.0))
+            .filter(|t| (0.25..0.75).contains(t))
+            .count();
+        assert_eq!(middle, 750);
+
+        // A linearly closing shutter has mean 1/3
+        let closing = ShutterCurve::keyframes(&[(0.0, 1.0), (1.0, 0.0)]).unwrap();
+        assert_abs_diff_eq!(mean(&closing), 1.0 / 3.0, epsilon = 1e-2);
+        assert!(ShutterCurve::keyframes(&[(0.5, 1.0), (0.2, 1.0)]).is_err());
+        assert!(ShutterCurve::keyframes(&[(0.0, 0.0), (1.0, 0.0)]).is_err());
+
+        let camera = |shutter: &str| {
+            let source = scene_source("[0.5, 0.5, 0.5]").replace(
+                "exposure_time = 0.0",
+                &format!("exposure_time = 1.0\nshutter = {shutter}"),
+            );
+            toml::from_str::<SceneRaw>(&source).map(|scene| scene.camera)
+        };
+        assert!(matches!(
+            camera("\"triangle\"").unwrap().shutter,
+            ShutterCurve::Triangle
+        ));
+        let bursts = camera("{ keyframes = [[0.0, 1.0], [0.5, 0.0], [1.0, 1.0]] }").unwrap();
+        assert_abs_diff_eq!(mean(&bursts.shutter), 0.5, epsilon = 1e-2);
+        assert!(camera("{ keyframes = [] }").is_err());
+    }
+
+    #[test]
+    fn spinning_sphere_blurs_tangentially() {
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .exposure_time(1.0)
+                    .shutter(ShutterCurve::Triangle)
+                    .build(),
+            )
+            .add_object(
+                Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 0.0, 3.0),
+                    MaterialBuilder::new().build(),
+                )
+                .with_angular_motion(rotation_from_degrees(vec3(0.0, 60.0, 0.0))),
+            )
+            .build();
+
+        // Along a single camera ray only the longitude of the hit surface point changes
+        let uvs: Vec<Pt2> = (0..16)
+            .map(|i| {
+                let time = scene.camera.sample_time((i as Scalar + 0.5) / 16.0);
+                let ray = Ray::new(Pt3::origin(), vec3(0.1, 0.2, 1.0), time);
+                scene.intersect(&ray, Visibility::CAMERA).unwrap().uv
+            })
+            .collect();
+        let spread = |axis: fn(&Pt2) -> Scalar| {
+            let values = uvs.iter().map(axis);
+            values.clone().fold(Scalar::MIN, Scalar::max) - values.fold(Scalar::MAX, Scalar::min)
+        };
+        assert!(spread(|uv| uv.y) > 0.05);
+        assert!(spread(|uv| uv.x) < 1e-4);
+    }
+
+    #[test]
+    fn equirect_camera_directions() {
+        let direction = vec3(1.0, 0.0, 1.0).normalize();
+        let camera = CameraBuilder::new()
+            .model(CameraModel::Equirect)
+            .direction(direction)
+            .resolution(64, 32)
+            .build();
+
+        let center = camera.generate_ray(32.0, 16.0, 0.0);
+        assert_abs_diff_eq!(center.direction, direction, epsilon = 1e-5);
+        for x in [0.0, 64.0] {
+            let edge = camera.generate_ray(x, 16.0, 0.0);
+            assert_abs_diff_eq!(edge.direction, -direction, epsilon = 1e-5);
+        }
+        // Top and bottom rows look along the camera's vertical axis
+        let top = camera.generate_ray(10.0, 0.0, 0.0);
+        let bottom = camera.generate_ray(10.0, 32.0, 0.0);
+        assert_abs_diff_eq!(top.direction.dot(direction), 0.0, epsilon = 1e-5);
+        assert_abs_diff_eq!(top.direction, -bottom.direction, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn fisheye_camera_fov() {
+        let camera = CameraBuilder::new()
+            .model(CameraModel::Fisheye { fov: 180.0 })
+            .resolution(32, 32)
+            .build();
+
+        let center = camera.generate_ray(16.0, 16.0, 0.0);
+        assert_abs_diff_eq!(center.direction, vec3(0.0, 0.0, 1.0), epsilon = 1e-5);
+        // The image circle touches the left and right edges at half the field of view
+        for x in [0.0, 32.0] {
+            let edge = camera.generate_ray(x, 16.0, 0.0);
+            assert_abs_diff_eq!(edge.direction.z, 0.0, epsilon = 1e-5);
+        }
+        let quarter = camera.generate_ray(8.0, 16.0, 0.0);
+        assert_abs_diff_eq!(quarter.direction.z, FRAC_PI_4.cos(), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn camera_motion_blur_streak() {
+        const SIZE: usize = 64;
+        const DISTANCE: Scalar = 10.0;
+        const RADIUS: Scalar = 0.2;
+        const MOTION: Scalar = 1.25;
+        const ROLL: Scalar = 15.0;
+        const HEIGHT: Scalar = 5.0;
+
+        let scene = |motion, angular_motion, center| {
+            SceneBuilder::new()
+                .camera(
+                    CameraBuilder::new()
+                        .resolution(SIZE, SIZE)
+                        .motion(motion)
+                        .angular_motion(angular_motion)
+                        .build(),
+                )
+                .add_object(Object::new(
+                    Shape::Sphere { radius: RADIUS },
+                    center,
+                    MaterialBuilder::new().build(),
+                ))
+                .build()
+        };
+        let still = Quaternion::zero();
+        let centered = point3(0.0, 0.0, DISTANCE);
+        // Number of columns crossing the sphere at some point in the exposure, sampled along the
+        // rows between pixels so the center row is one of them
+        let streak_length = |scene: &Scene| {
+            (0..SIZE)
+                .filter(|&x| {
+                    (0..=SIZE).any(|y| {
+                        (0..=32).any(|t| {
+                            let time = t as Scalar / 32.0;
+                            let ray =
+                                scene
+                                    .camera
+                                    .generate_ray(x as Scalar + 0.5, y as Scalar, time);
+                            scene.intersect(&ray, Visibility::CAMERA).is_hit()
+                        })
+                    })
+                })
+                .count()
+        };
+
+        // The image plane is 2 units wide at unit distance
+        let pixel_size = 2.0 * DISTANCE / SIZE as Scalar;
+
+        let still_length = streak_length(&scene(Vec3::zero(), still, centered));
+        assert!(still_length as Scalar <= 2.0 * RADIUS / pixel_size + 1.0);
+
+        let moving = streak_length(&scene(vec3(MOTION, 0.0, 0.0), still, centered));
+        let expected = (MOTION + 2.0 * RADIUS) / pixel_size;
+        assert!(
+            (moving as Scalar - expected).abs() <= 1.0,
+            "streak of {moving} pixels, expected {expected}"
+        );
+
+        // Rolling about the view direction sweeps a sphere above the center along an arc
+        let roll = rotation_from_degrees(vec3(0.0, 0.0, ROLL));
+        let rolling = streak_length(&scene(Vec3::zero(), roll, point3(0.0, HEIGHT, DISTANCE)));
+        let expected = (HEIGHT * ROLL.to_radians().sin() + 2.0 * RADIUS) / pixel_size;
+        assert!(
+            (rolling as Scalar - expected).abs() <= 1.0,
+            "streak of {rolling} pixels, expected {expected}"
+        );
+    }
+
+    #[test]
+    fn procedural_textures() {
+        let checker: Texture<Scalar, Luma8ColorPixelConverter> = Texture::Checker {
+            a: 0.0,
+            b: 1.0,
+            scale: 2.0,
+        };
+        assert_eq!(checker.get(point2(0.25, 0.25)), 0.0);
+        assert_eq!(checker.get(point2(0.75, 0.25)), 1.0);
+        assert_eq!(checker.get(point2(0.75, 0.75)), 0.0);
+
+        let gradient: Texture<Color, Rgb8ColorPixelConverter> = Texture::Gradient {
+            a: color::BLACK,
+            b: color::WHITE,
+            axis: TextureAxis::V,
+        };
+        assert_eq!(gradient.get(point2(0.9, 0.25)), color(0.25, 0.25, 0.25));
+        assert_eq!(gradient.get(point2(0.1, 2.0)), color::WHITE);
+
+        let source = scene_source(
+            r#"{ kind = "Checker", a = [1.0, 1.0, 1.0], b = [0.0, 0.0, 0.0], scale = 4.0 }"#,
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        let base_color = &scene.objects[0].material.base_color;
+        assert!(matches!(base_color, Texture::Checker { scale, .. } if *scale == 4.0));
+        assert_eq!(base_color.get(point2(0.1, 0.3)), color::BLACK);
+    }
+
+    fn load_light(light: &str) -> Result<Light, toml::de::Error> {
+        toml::from_str::<SceneRaw>(&format!(
+            "{}\n[[lights]]\n{}",
+            scene_source("[0.5, 0.5, 0.5]"),
+            light
+        ))
+        .map(|mut scene| scene.lights.pop().unwrap().light)
+    }
+
+    #[test]
+    fn light_groups_from_toml() {
+        let light = |group: &str| {
+            format!("[[lights]]\nkind = \"Ambient\"\ncolor = [0.1, 0.1, 0.1]\n{group}\n")
+        };
+        let source = format!(
+            "{}\n{}{}{}",
+            scene_source("[0.5, 0.5, 0.5]"),
+            light("group = \"fill\""),
+            light(""),
+            light("group = \"fill\"")
+        );
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        // The scene source's own light comes first and has no group
+        assert_eq!(scene.light_groups(), ["fill"]);
+        assert_eq!(
+            (0..4).map(|i| scene.light_group(i)).collect::<Vec<_>>(),
+            [0, 1, 0, 1]
+        );
+        assert_eq!(scene.candidate_group(1), 1);
+
+        let too_many = (0..=MAX_LIGHT_GROUPS)
+            .map(|i| light(&format!("group = \"{i}\"")))
+            .collect::<String>();
+        let source = format!("{}\n{too_many}", scene_source("[0.5, 0.5, 0.5]"));
+        assert!(toml::from_str::<Scene>(&source).is_err());
+    }
+
+    #[test]
+    fn conductor_materials() {
+        let material = |conductor: &str| {
+            let source = scene_source("[0.5, 0.5, 0.5]") + &format!("conductor = {conductor}\n");
+            toml::from_str::<SceneRaw>(&source).map(|mut scene| scene.objects.remove(0).material)
+        };
+
+        assert_eq!(
+            material("\"copper\"").unwrap().conductor,
+            Some(FresnelConductor::COPPER)
+        );
+        assert_eq!(
+            material("{ eta = [1.0, 2.0, 3.0], k = [4.0, 5.0, 6.0] }")
+                .unwrap()
+                .conductor,
+            Some(FresnelConductor::new(
+                color(1.0, 2.0, 3.0),
+                color(4.0, 5.0, 6.0)
+            ))
+        );
+        assert!(material("\"unobtainium\"").is_err());
+        let scene = load_scene_from_str(&scene_source("[0.5, 0.5, 0.5]"), SceneFormat::Toml, None);
+        assert_eq!(scene.objects[0].material.conductor, None);
+    }
+
+    #[test]
+    fn object_visibility() {
+        let source = scene_source("[0.5, 0.5, 0.5]");
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(scene.objects[0].visibility, Visibility::ALL);
+
+        let hidden = source.clone() + "[objects.visibility]\ncamera = false\nshadow = false\n";
+        let scene = load_scene_from_str(&hidden, SceneFormat::Toml, None);
+        assert_eq!(
+            scene.objects[0].visibility,
+            Visibility::DIFFUSE.set(Visibility::SPECULAR)
+        );
+
+        // `indirect` covers both bounce kinds, either can still be set on its own
+        let indirect = source + "[objects.visibility]\nindirect = false\nspecular = true\n";
+        let scene = load_scene_from_str(&indirect, SceneFormat::Toml, None);
+        assert_eq!(
+            scene.objects[0].visibility,
+            Visibility::ALL.unset(Visibility::DIFFUSE)
+        );
+    }
+
+    #[test]
+    fn adaptive_sampling_config() {
+        let source = scene_source("[0.5, 0.5, 0.5]");
+        let camera = load_scene_from_str(&source, SceneFormat::Toml, None).camera;
+        assert_eq!((camera.num_samples, camera.max_samples), (1, 1));
+        assert!(!camera.is_adaptive());
+
+        let source = source.replace(
+            "num_samples = 1",
+            "min_samples = 2\nmax_samples = 16\nvariance_threshold = 0.1",
+        );
+        let camera = load_scene_from_str(&source, SceneFormat::Toml, None).camera;
+        assert_eq!((camera.num_samples, camera.max_samples), (2, 16));
+        assert_eq!(camera.variance_threshold, 0.1);
+        assert!(camera.is_adaptive());
+    }
+
+    #[test]
+    #[should_panic(expected = "metallic")]
+    fn bad_object_field_is_named() {
+        let source = scene_source("[0.5, 0.5, 0.5]").replace("metallic = 0.0", "metallic = true");
+        load_scene_from_str(&source, SceneFormat::Toml, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_samples (2) must be at least num_samples (4)")]
+    fn max_samples_below_min() {
+        let source = scene_source("[0.5, 0.5, 0.5]")
+            .replace("num_samples = 1", "num_samples = 4\nmax_samples = 2");
+        load_scene_from_str(&source, SceneFormat::Toml, None);
+    }
+
+    #[test]
+    fn diffuse_model() {
+        let source = scene_source("[0.5, 0.5, 0.5]");
+        let scene = load_scene_from_str(&source, SceneFormat::Toml, None);
+        assert_eq!(
+            scene.objects[0].material.diffuse_model,
+            DiffuseModel::Lambertian
+        );
+        let scene = load_scene_from_str(
+            &(source + "diffuse_model = \"oren_nayar\"\n"),
+            SceneFormat::Toml,
+            None,
+        );
+        assert_eq!(
+            scene.objects[0].material.diffuse_model,
+            DiffuseModel::OrenNayar
+        );
+    }
+
+    #[test]
+    fn light_color_temperature() {
+        let light = load_light("kind = \"Ambient\"\ntemperature = 2700\nintensity = 3.0").unwrap();
+        match light {
+            Light::Ambient(ambient) => {
+                assert!((color::luminance(ambient.radiance) - 3.0).abs() < 1e-4);
+                assert!(ambient.radiance.r > ambient.radiance.b);
+            }
+            _ => panic!("Expected an ambient light"),
+        }
+
+        let light = load_light("kind = \"Ambient\"\ncolor = [0.1, 0.2, 0.3]").unwrap();
+        assert!(
+            matches!(light, Light::Ambient(ambient) if ambient.radiance == color(0.1, 0.2, 0.3))
+        );
+
+        assert!(load_light("kind = \"Ambient\"").is_err());
+        assert!(
+            load_light("kind = \"Ambient\"\ncolor = [1.0, 1.0, 1.0]\ntemperature = 5500").is_err()
+        );
+        assert!(
+            load_light("kind = \"Ambient\"\ncolor = [1.0, 1.0, 1.0]\nintensity = 2.0").is_err()
+        );
+        assert!(
+            load_light("kind = \"Point\"\nposition = [0.0, 1.0, 0.0]\ntemperature = 5500").is_ok()
+        );
+    }
+
+    #[test]
+    fn spot_light_gobo() {
+        let spot = |gobo: &str| {
+            let light = load_light(&format!(
+                "kind = \"Spot\"\nposition = [0.0, 2.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\n\
+                 angle = 45.0\nfalloff = 40.0\ncolor = [1.0, 1.0, 1.0]\n{gobo}"
+            ))
+            .unwrap();
+            match light {
+                Light::Spot(spot) => spot,
+                _ => panic!("Expected a spot light"),
+            }
+        };
+        let lit_floor = |spot: &SpotLight| {
+            [-1.2, -0.4, 0.4, 1.2].map(|z| {
+                let si = Intersection {
+                    point: point3(0.0, 0.0, z),
+                    error: 0.0,
+                    front_face: true,
+                    ..Intersection::dummy()
+                };
+                let (mut wi, mut pdf) = (Vec3::zero(), 0.0);
+                spot.sample_li(&si, &mut wi, &mut pdf) != color::BLACK
+            })
+        };
+
+        assert_eq!(lit_floor(&spot("")), [true; 4]);
+        assert_eq!(spot("").falloff_mode, SpotFalloff::Smoothstep);
+        assert_eq!(
+            spot("falloff_mode = \"quartic\"").falloff_mode,
+            SpotFalloff::Quartic
+        );
+        // The light's frame puts the gobo u axis along z, so a 4x4 checker covers the cone with
+        // stripes 45 / 2 degrees wide
+        let checker = spot(
+            "gobo = { kind = \"Checker\", a = [1.0, 1.0, 1.0], b = [0.0, 0.0, 0.0], scale = 4.0 }",
+        );
+        assert_eq!(lit_floor(&checker), [true, false, true, false]);
+    }
+
+    #[test]
+    fn area_light_texture() {
+        let area = |texture: &str| {
+            let light = load_light(&format!(
+                "kind = \"Area\"\nposition = [0.0, 2.0, 0.0]\nshape = {{ kind = \"Sphere\", \
+                 radius = 1.0 }}\ncolor = [2.0, 2.0, 2.0]\n{texture}"
+            ))
+            .unwrap();
+            match light {
+                Light::Area(area) => area,
+                _ => panic!("Expected an area light"),
+            }
+        };
+
+        let uv = point2(0.1, 0.1);
+        assert_eq!(area("").emission(uv), Color::new(2.0, 2.0, 2.0));
+        let gradient = area(
+            "texture = { kind = \"Gradient\", a = [1.0, 0.0, 0.0], b = [0.0, 0.0, 1.0], \
+             axis = \"u\" }",
+        );
+        assert_abs_diff_eq!(
+            gradient.emission(uv),
+            Color::new(1.8, 0.0, 0.2),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    fn light_ies_profile() {
+        let path = std::env::temp_dir().join(format!("pbrtrs_{}.ies", std::process::id()));
+        std::fs::write(
+            &path,
+            "IESNA:LM-63-2002\nTILT=NONE\n1 -1 1 2 1 1 1 0 0 0\n1 1 0\n0 90\n0\n10 0\n",
+        )
+        .unwrap();
+        let light = |kind: &str| {
+            load_light(&format!(
+                "kind = \"{kind}\"\nposition = [0.0, 2.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\n\
+                 angle = 45.0\nfalloff = 40.0\ncolor = [1.0, 1.0, 1.0]\nies_path = {:?}",
+                path.to_str().unwrap()
+            ))
+        };
+        let point = light("Point");
+        let spot = light("Spot");
+        std::fs::remove_file(&path).unwrap();
+
+        match point.unwrap() {
+            Light::Point(point) => assert!(point.ies.is_some()),
+            _ => panic!("Expected a point light"),
+        }
+        match spot.unwrap() {
+            Light::Spot(spot) => assert!(spot.ies.is_some()),
+            _ => panic!("Expected a spot light"),
+        }
+        let missing = load_light(
+            "kind = \"Point\"\nposition = [0.0, 1.0, 0.0]\ncolor = [1.0, 1.0, 1.0]\n\
+             ies_path = \"/nonexistent/lamp.ies\"",
+        );
+        assert!(missing.unwrap_err().to_string().contains("lamp.ies"));
+    }
+
+    #[test]
+    #[should_panic(expected = "without a scene base directory")]
+    fn relative_texture_requires_base_dir() {
+        load_scene_from_str(&scene_source("\"texture.png\""), SceneFormat::Toml, None);
+    }
+}
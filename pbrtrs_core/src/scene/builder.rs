@@ -0,0 +1,441 @@
+use crate::bxdf::FresnelConductor;
+use crate::light::{Light, LightSampling};
+use crate::medium::HomogeneousMedium;
+use crate::postprocess::{Bloom, DenoiseConfig, PostProcessChain};
+use crate::raytracer::RenderMode;
+use crate::scene::{
+    Camera, CameraModel, DiffuseModel, DisneyMaterial, Luma8ColorPixelConverter, Object,
+    Rgb8ColorPixelConverter, Scene, ShutterCurve, Texture,
+};
+use crate::types::colorspace::ColorSpace;
+use crate::types::{color, Color, Pt3, Quaternion, Scalar, Vec3};
+use cgmath::{vec3, EuclideanSpace, InnerSpace, Zero};
+
+#[derive(Debug)]
+pub struct CameraBuilder {
+    model: CameraModel,
+    position: Pt3,
+    direction: Vec3,
+    sensor_distance: Scalar,
+    exposure_time: Scalar,
+    shutter: ShutterCurve,
+    aperture: Scalar,
+    aperture_blades: u32,
+    aperture_rotation: Scalar,
+    focus_distance: Scalar,
+    ldr_scale: Scalar,
+    motion: Vec3,
+    angular_motion: Quaternion,
+    bloom: Option<Bloom>,
+    denoise: Option<DenoiseConfig>,
+    vignette: Scalar,
+    chromatic_aberration: Scalar,
+    mode: RenderMode,
+    bounce_limit: usize,
+    roulette_depth: usize,
+    roulette_min_survival: Scalar,
+    num_samples: usize,
+    max_samples: Option<usize>,
+    variance_threshold: Scalar,
+    width: usize,
+    height: usize,
+}
+
+impl Default for CameraBuilder {
+    fn default() -> Self {
+        Self {
+            model: CameraModel::ThinLens,
+            position: Pt3::origin(),
+            direction: vec3(0.0, 0.0, 1.0),
+            sensor_distance: 1.0,
+            exposure_time: 0.0,
+            shutter: ShutterCurve::Box,
+            aperture: 0.0,
+            aperture_blades: 0,
+            aperture_rotation: 0.0,
+            focus_distance: 1.0,
+            ldr_scale: 1.0,
+            motion: Vec3::zero(),
+            angular_motion: Quaternion::zero(),
+            bloom: None,
+            denoise: None,
+            vignette: 0.0,
+            chromatic_aberration: 0.0,
+            mode: RenderMode::Beauty,
+            bounce_limit: 10,
+            roulette_depth: Camera::DEFAULT_ROULETTE_DEPTH,
+            roulette_min_survival: Camera::DEFAULT_ROULETTE_MIN_SURVIVAL,
+            num_samples: 100,
+            max_samples: None,
+            variance_threshold: Camera::DEFAULT_VARIANCE_THRESHOLD,
+            width: 512,
+            height: 400,
+        }
+    }
+}
+
+macro_rules! builder_setters {
+    ($($field: ident: $ty: ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: $ty) -> Self {
+                self.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+impl CameraBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    builder_setters! {
+        model: CameraModel,
+        position: Pt3,
+        direction: Vec3,
+        sensor_distance: Scalar,
+        exposure_time: Scalar,
+        shutter: ShutterCurve,
+        aperture: Scalar,
+        aperture_blades: u32,
+        aperture_rotation: Scalar,
+        focus_distance: Scalar,
+        ldr_scale: Scalar,
+        motion: Vec3,
+        angular_motion: Quaternion,
+        bloom: Option<Bloom>,
+        denoise: Option<DenoiseConfig>,
+        vignette: Scalar,
+        chromatic_aberration: Scalar,
+        mode: RenderMode,
+        bounce_limit: usize,
+        roulette_depth: usize,
+        roulette_min_survival: Scalar,
+        num_samples: usize,
+        variance_threshold: Scalar,
+    }
+
+    /// Samples noisy pixels adaptively up to `max_samples`, defaults to `num_samples`
+    pub fn max_samples(mut self, max_samples: usize) -> Self {
+        self.max_samples = Some(max_samples);
+        self
+    }
+
+    /// Points the camera at `target` from its current position
+    pub fn look_at(mut self, target: Pt3) -> Self {
+        self.direction = target - self.position;
+        self
+    }
+
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn build(self) -> Camera {
+        assert!(
+            self.width > 0 && self.height > 0,
+            "Camera resolution must be non-zero, got {}x{}",
+            self.width,
+            self.height
+        );
+        assert!(self.num_samples > 0, "Camera must take at least one sample");
+        let max_samples = self.max_samples.unwrap_or(self.num_samples);
+        assert!(
+            max_samples >= self.num_samples,
+            "Camera max samples ({max_samples}) must be at least num samples ({})",
+            self.num_samples
+        );
+        assert!(
+            self.variance_threshold >= 0.0,
+            "Camera variance threshold must be non-negative, got {}",
+            self.variance_threshold
+        );
+        assert!(
+            self.direction.magnitude2() > 0.0,
+            "Camera direction must be non-zero"
+        );
+        assert!(
+            self.aperture_blades == 0 || self.aperture_blades >= 3,
+            "Camera aperture needs 0 (circular) or at least 3 blades, got {}",
+            self.aperture_blades
+        );
+        if let CameraModel::Fisheye { fov } = self.model {
+            assert!(
+                fov > 0.0 && fov <= 180.0,
+                "Fisheye fov must be in (0, 180] degrees, got {fov}"
+            );
+        }
+        assert!(
+            self.sensor_distance > 0.0,
+            "Camera sensor distance must be positive"
+        );
+        assert!(
+            self.roulette_min_survival > 0.0 && self.roulette_min_survival <= 1.0,
+            "Russian roulette survival probability must be in (0, 1], got {}",
+            self.roulette_min_survival
+        );
+
+        Camera {
+            model: self.model,
+            position: self.position,
+            direction: self.direction.normalize(),
+            sensor_distance: self.sensor_distance,
+            exposure_time: self.exposure_time,
+            shutter: self.shutter,
+            aperture: self.aperture,
+            aperture_blades: self.aperture_blades,
+            aperture_rotation: self.aperture_rotation,
+            focus_distance: self.focus_distance,
+            ldr_scale: self.ldr_scale,
+            motion: self.motion,
+            angular_motion: self.angular_motion,
+            bloom: self.bloom,
+            denoise: self.denoise,
+            vignette: self.vignette,
+            chromatic_aberration: self.chromatic_aberration,
+            mode: self.mode,
+            bounce_limit: self.bounce_limit,
+            roulette_depth: self.roulette_depth,
+            roulette_min_survival: self.roulette_min_survival,
+            num_samples: self.num_samples,
+            max_samples,
+            variance_threshold: self.variance_threshold,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+type ColorTexture = Texture<Color, Rgb8ColorPixelConverter>;
+type ScalarTexture = Texture<Scalar, Luma8ColorPixelConverter>;
+
+#[derive(Debug)]
+pub struct MaterialBuilder {
+    material: DisneyMaterial,
+}
+
+impl Default for MaterialBuilder {
+    fn default() -> Self {
+        Self {
+            material: DisneyMaterial {
+                base_color: Texture::Value(color(0.8, 0.8, 0.8)),
+                specular: Texture::Value(0.5),
+                roughness: Texture::Value(0.5),
+                ior: Texture::Value(1.5),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+macro_rules! material_setters {
+    ($($field: ident: $ty: ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: impl Into<$ty>) -> Self {
+                self.material.$field = $field.into();
+                self
+            }
+        )*
+    };
+}
+
+impl MaterialBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    material_setters! {
+        base_color: ColorTexture,
+        subsurface: ScalarTexture,
+        metallic: ScalarTexture,
+        specular: ScalarTexture,
+        specular_tint: ScalarTexture,
+        roughness: ScalarTexture,
+        anisotropic: ScalarTexture,
+        anisotropic_rotation: ScalarTexture,
+        sheen: ScalarTexture,
+        sheen_tint: ScalarTexture,
+        clearcoat: ScalarTexture,
+        clearcoat_gloss: ScalarTexture,
+        transmission: ScalarTexture,
+        ior: ScalarTexture,
+        emission: ColorTexture,
+        normal_map: Option<ColorTexture>,
+    }
+
+    /// Uses the conductor Fresnel equations for the specular lobe when metallic is 1
+    pub fn conductor(mut self, conductor: FresnelConductor) -> Self {
+        self.material.conductor = Some(conductor);
+        self
+    }
+
+    pub fn diffuse_model(mut self, diffuse_model: DiffuseModel) -> Self {
+        self.material.diffuse_model = diffuse_model;
+        self
+    }
+
+    /// Abbe number of transmissive materials, 0 disables dispersion
+    pub fn dispersion(mut self, dispersion: Scalar) -> Self {
+        self.material.dispersion = dispersion;
+        self
+    }
+
+    pub fn emission_strength(mut self, emission_strength: Scalar) -> Self {
+        self.material.emission_strength = emission_strength;
+        self
+    }
+
+    pub fn build(self) -> DisneyMaterial {
+        self.material
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SceneBuilder {
+    camera: Option<Camera>,
+    objects: Vec<Object>,
+    lights: Vec<Light>,
+    light_groups: Vec<Option<String>>,
+    light_sampling: LightSampling,
+    postprocess: PostProcessChain,
+    medium: Option<HomogeneousMedium>,
+    background: Color,
+    color_space: ColorSpace,
+    output_color_space: ColorSpace,
+}
+
+impl SceneBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn camera(mut self, camera: Camera) -> Self {
+        self.camera = Some(camera);
+        self
+    }
+
+    pub fn add_object(mut self, object: Object) -> Self {
+        self.objects.push(object);
+        self
+    }
+
+    pub fn add_light(mut self, light: impl Into<Light>) -> Self {
+        self.lights.push(light.into());
+        self.light_groups.push(None);
+        self
+    }
+
+    /// Adds a light whose contribution is also accumulated in the light group `group`
+    pub fn add_light_to_group(mut self, light: impl Into<Light>, group: &str) -> Self {
+        self.lights.push(light.into());
+        self.light_groups.push(Some(group.to_owned()));
+        self
+    }
+
+    pub fn light_sampling(mut self, light_sampling: LightSampling) -> Self {
+        self.light_sampling = light_sampling;
+        self
+    }
+
+    pub fn postprocess(mut self, postprocess: PostProcessChain) -> Self {
+        self.postprocess = postprocess;
+        self
+    }
+
+    pub fn medium(mut self, medium: HomogeneousMedium) -> Self {
+        self.medium = Some(medium);
+        self
+    }
+
+    pub fn background(mut self, background: Color) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Working space the scene's colors are given in
+    pub fn color_space(mut self, color_space: ColorSpace) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    pub fn output_color_space(mut self, output_color_space: ColorSpace) -> Self {
+        self.output_color_space = output_color_space;
+        self
+    }
+
+    pub fn build(self) -> Scene {
+        Scene::new(
+            self.camera.expect("Scene requires a camera"),
+            self.objects,
+            self.lights,
+        )
+        .with_light_sampling(self.light_sampling)
+        .with_postprocess(self.postprocess)
+        .with_medium(self.medium)
+        .with_background(self.background)
+        .with_color_spaces(self.color_space, self.output_color_space)
+        .with_light_groups(self.light_groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{PointLight, SpotLight};
+    use crate::scene::Shape;
+    use cgmath::{assert_abs_diff_eq, point3};
+
+    #[test]
+    fn camera_builder_normalizes_direction() {
+        let camera = CameraBuilder::new()
+            .position(point3(0.0, 2.0, -6.0))
+            .look_at(point3(0.0, 1.0, 0.0))
+            .build();
+        assert_abs_diff_eq!(camera.direction.magnitude(), 1.0, epsilon = 1e-6);
+        assert_abs_diff_eq!(
+            camera.direction,
+            vec3(0.0, -1.0, 6.0).normalize(),
+            epsilon = 1e-6
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn camera_builder_rejects_empty_image() {
+        CameraBuilder::new().resolution(0, 400).build();
+    }
+
+    #[test]
+    fn scene_builder() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 5.0),
+                MaterialBuilder::new().roughness(0.2).build(),
+            ))
+            .add_light(PointLight::new(point3(0.0, 4.0, 0.0), color(1.0, 1.0, 1.0)))
+            .add_light(SpotLight::new(
+                point3(0.0, 4.0, 0.0),
+                vec3(0.0, -2.0, 0.0),
+                45.0,
+                40.0,
+                color(1.0, 1.0, 1.0),
+            ))
+            .build();
+
+        assert_eq!(scene.objects.len(), 1);
+        assert_eq!(scene.lights.len(), 2);
+        match &scene.lights[1] {
+            Light::Spot(spot) => {
+                assert_abs_diff_eq!(spot.direction, vec3(0.0, -1.0, 0.0));
+                assert_abs_diff_eq!(spot.cos_angle, (45.0 as Scalar).to_radians().cos());
+                assert_abs_diff_eq!(spot.cos_falloff, (40.0 as Scalar).to_radians().cos());
+            }
+            _ => panic!("expected a spot light"),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Ray counters incremented by the integrator while rendering.
+///
+/// Counters are relaxed atomics so a single instance can be shared between render threads,
+/// but contention is lower if each thread keeps its own and [`RayStats::merge`]s it at the end.
+#[derive(Debug, Default)]
+pub struct RayStats {
+    primary_rays: AtomicU64,
+    bounce_rays: AtomicU64,
+    shadow_rays: AtomicU64,
+}
+
+impl RayStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_primary_ray(&self) {
+        self.primary_rays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bounce_ray(&self) {
+        self.bounce_rays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_shadow_ray(&self) {
+        self.shadow_rays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Rays traced from the camera
+    pub fn primary_rays(&self) -> u64 {
+        self.primary_rays.load(Ordering::Relaxed)
+    }
+
+    /// Rays traced after a scattering event, not including shadow rays
+    pub fn bounce_rays(&self) -> u64 {
+        self.bounce_rays.load(Ordering::Relaxed)
+    }
+
+    /// Visibility rays traced towards lights
+    pub fn shadow_rays(&self) -> u64 {
+        self.shadow_rays.load(Ordering::Relaxed)
+    }
+
+    pub fn total_rays(&self) -> u64 {
+        self.primary_rays() + self.bounce_rays() + self.shadow_rays()
+    }
+
+    /// Average number of bounces per camera path
+    pub fn average_bounce_depth(&self) -> f64 {
+        let primary_rays = self.primary_rays();
+        if primary_rays == 0 {
+            0.0
+        } else {
+            self.bounce_rays() as f64 / primary_rays as f64
+        }
+    }
+
+    /// Adds the counts of `other` to these counters
+    pub fn merge(&self, other: &RayStats) {
+        self.primary_rays
+            .fetch_add(other.primary_rays(), Ordering::Relaxed);
+        self.bounce_rays
+            .fetch_add(other.bounce_rays(), Ordering::Relaxed);
+        self.shadow_rays
+            .fetch_add(other.shadow_rays(), Ordering::Relaxed);
+    }
+}
+
+/// Work done by the integrator, broken down by stage. Recorded by the `stats_count!` and
+/// `stats_time!` macros when built with the `enable_stats` feature.
+///
+/// Stages nest, light sampling includes the shadow rays it traces.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatsReport {
+    /// Camera and bounce rays
+    pub rays: u64,
+    pub shadow_rays: u64,
+    pub bsdf_samples: u64,
+    pub hdri_samples: u64,
+    /// Nanoseconds spent finding the closest hit of camera and bounce rays, and tracing shadow
+    /// rays
+    pub intersection_ns: u64,
+    /// Nanoseconds spent building and sampling BSDFs
+    pub bsdf_ns: u64,
+    /// Nanoseconds spent estimating direct lighting
+    pub light_sampling_ns: u64,
+}
+
+impl StatsReport {
+    /// Adds the counts of `other` to these
+    pub fn merge(&mut self, other: &StatsReport) {
+        self.rays += other.rays;
+        self.shadow_rays += other.shadow_rays;
+        self.bsdf_samples += other.bsdf_samples;
+        self.hdri_samples += other.hdri_samples;
+        self.intersection_ns += other.intersection_ns;
+        self.bsdf_ns += other.bsdf_ns;
+        self.light_sampling_ns += other.light_sampling_ns;
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl Display for StatsReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = |ns: u64| ns as f64 / 1e6;
+        writeln!(
+            f,
+            "Rays: {}, shadow rays: {}, BSDF samples: {}, HDRI samples: {}",
+            self.rays, self.shadow_rays, self.bsdf_samples, self.hdri_samples
+        )?;
+        write!(
+            f,
+            "Thread time: intersection {:.1}ms, BSDF {:.1}ms, light sampling {:.1}ms",
+            ms(self.intersection_ns),
+            ms(self.bsdf_ns),
+            ms(self.light_sampling_ns)
+        )
+    }
+}
+
+#[cfg(feature = "enable_stats")]
+pub mod inner {
+    use super::StatsReport;
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+
+    static REPORT: Mutex<StatsReport> = Mutex::new(StatsReport {
+        rays: 0,
+        shadow_rays: 0,
+        bsdf_samples: 0,
+        hdri_samples: 0,
+        intersection_ns: 0,
+        bsdf_ns: 0,
+        light_sampling_ns: 0,
+    });
+
+    thread_local! {
+        /// Counts of this thread since it was last flushed, so render threads don't contend on
+        /// `REPORT`
+        static THREAD_REPORT: RefCell<StatsReport> = RefCell::new(StatsReport::default());
+    }
+
+    #[inline]
+    pub fn record(f: impl FnOnce(&mut StatsReport)) {
+        THREAD_REPORT.with(|report| f(&mut report.borrow_mut()));
+    }
+
+    /// Adds the counts of this thread to the report and resets them
+    pub fn flush_thread() {
+        let thread_report = THREAD_REPORT.with(|report| report.take());
+        REPORT.lock().unwrap().merge(&thread_report);
+    }
+
+    /// Counts flushed since the last [`reset`]
+    pub fn report() -> StatsReport {
+        *REPORT.lock().unwrap()
+    }
+
+    pub fn reset() {
+        *REPORT.lock().unwrap() = StatsReport::default();
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::raytracer::trace_path;
+        use crate::scene::{CameraBuilder, MaterialBuilder, Object, SceneBuilder, Shape};
+        use crate::stats::RayStats;
+        use bumpalo::Bump;
+        use cgmath::point3;
+
+        #[test]
+        fn counts_rays_of_paths() {
+            let (width, height, spp) = (4, 3, 5);
+            let camera = CameraBuilder::new()
+                .resolution(width, height)
+                .num_samples(spp)
+                .bounce_limit(4)
+                .build();
+            let scene = SceneBuilder::new()
+                .camera(camera)
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 1.0 },
+                    point3(0.0, 0.0, 2.0),
+                    MaterialBuilder::new().build(),
+                ))
+                .build();
+
+            // Traced on a thread of its own, so its counts are the only ones
+            let (report, stats) = std::thread::spawn(move || {
+                let arena = Bump::new();
+                let stats = RayStats::new();
+                fastrand::seed(7);
+                for y in 0..height {
+                    for x in 0..width {
+                        for _ in 0..spp {
+                            let ray = scene.camera.generate_ray(x as _, y as _, 0.0);
+                            trace_path(&ray, &scene, &arena, &stats);
+                        }
+                    }
+                }
+                (THREAD_REPORT.with(|report| report.take()), stats)
+            })
+            .join()
+            .unwrap();
+
+            assert_eq!(stats.primary_rays(), (width * height * spp) as u64);
+            assert!(stats.bounce_rays() > 0);
+            assert_eq!(report.rays, stats.primary_rays() + stats.bounce_rays());
+            assert_eq!(report.shadow_rays, stats.shadow_rays());
+            assert!(report.bsdf_samples > 0);
+            assert!(report.intersection_ns > 0);
+        }
+    }
+}
+
+#[cfg(feature = "enable_stats")]
+pub use inner::{flush_thread, report, reset};
+
+/// Increments a [`StatsReport`] counter of this thread with the `enable_stats` feature
+#[macro_export]
+macro_rules! stats_count {
+    ($counter: ident) => {
+        #[cfg(feature = "enable_stats")]
+        $crate::stats::inner::record(|report| report.$counter += 1);
+    };
+}
+
+#[allow(unused)]
+pub use stats_count;
+
+/// Evaluates `$e`, adding the time it took to a [`StatsReport`] stage of this thread with the
+/// `enable_stats` feature
+#[macro_export]
+macro_rules! stats_time {
+    ($stage: ident, $e: expr) => {{
+        #[cfg(feature = "enable_stats")]
+        let start = std::time::Instant::now();
+        let value = $e;
+        #[cfg(feature = "enable_stats")]
+        $crate::stats::inner::record(|report| report.$stage += start.elapsed().as_nanos() as u64);
+        value
+    }};
+}
+
+#[allow(unused)]
+pub use stats_time;
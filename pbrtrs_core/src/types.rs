@@ -1,20 +1,42 @@
 #![allow(unused)]
 
 use cgmath::{
-    InnerSpace, Matrix2, Matrix3, Matrix4, Point2, Point3, Rad, Vector2, Vector3, Vector4,
+    AbsDiffEq, InnerSpace, Matrix2, Matrix3, Matrix4, Point2, Point3, Rad, Vector2, Vector3,
+    Vector4,
 };
 use image::{Luma, LumaA, Pixel, Rgb, Rgba};
-use std::ops::Index;
+use serde::Deserialize;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Sub, SubAssign};
 
+#[cfg(not(feature = "f64"))]
 pub type Scalar = f32;
+#[cfg(feature = "f64")]
+pub type Scalar = f64;
 
 pub mod scalar {
     use super::Scalar;
+    #[cfg(not(feature = "f64"))]
     pub use std::f32::consts;
+    #[cfg(feature = "f64")]
+    pub use std::f64::consts;
 
+    #[cfg(not(feature = "f64"))]
     pub fn rand() -> Scalar {
         fastrand::f32()
     }
+
+    #[cfg(feature = "f64")]
+    pub fn rand() -> Scalar {
+        fastrand::f64()
+    }
+
+    /// Converts to the `f32` images are stored in, which is a no-op without the `f64` feature
+    #[allow(clippy::unnecessary_cast)]
+    #[inline]
+    pub fn to_f32(value: Scalar) -> f32 {
+        value as f32
+    }
 }
 
 pub type Basis2 = cgmath::Basis2<Scalar>;
@@ -34,16 +56,210 @@ pub type Pt3 = Point3<Scalar>;
 pub type Quaternion = cgmath::Quaternion<Scalar>;
 pub type Euler = cgmath::Euler<Rad<Scalar>>;
 
-pub type Color = Pt3;
+/// Linear RGB color, written as `[r, g, b]` in scene files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(from = "[Scalar; 3]")]
+pub struct Color {
+    pub r: Scalar,
+    pub g: Scalar,
+    pub b: Scalar,
+}
 
 #[inline]
 pub const fn color(r: Scalar, g: Scalar, b: Scalar) -> Color {
     Color::new(r, g, b)
 }
 
+impl Color {
+    #[inline]
+    pub const fn new(r: Scalar, g: Scalar, b: Scalar) -> Color {
+        Color { r, g, b }
+    }
+
+    /// Grey with every channel set to `value`
+    #[inline]
+    pub const fn from_value(value: Scalar) -> Color {
+        Color::new(value, value, value)
+    }
+
+    #[inline]
+    pub fn map(self, mut f: impl FnMut(Scalar) -> Scalar) -> Color {
+        Color::new(f(self.r), f(self.g), f(self.b))
+    }
+
+    /// Perceived brightness of the color
+    #[inline]
+    pub fn luminance(self) -> Scalar {
+        0.299 * self.r + 0.587 * self.g + 0.114 * self.b
+    }
+
+    #[inline]
+    pub fn average(self) -> Scalar {
+        (self.r + self.g + self.b) / 3.0
+    }
+
+    #[inline]
+    pub fn max_component(self) -> Scalar {
+        self.r.max(self.g).max(self.b)
+    }
+
+    #[inline]
+    pub fn min_component(self) -> Scalar {
+        self.r.min(self.g).min(self.b)
+    }
+
+    #[inline]
+    pub fn is_finite(self) -> bool {
+        self.r.is_finite() && self.g.is_finite() && self.b.is_finite()
+    }
+
+    #[inline]
+    pub fn is_black(self) -> bool {
+        self == color::BLACK
+    }
+}
+
+impl Index<usize> for Color {
+    type Output = Scalar;
+
+    fn index(&self, index: usize) -> &Scalar {
+        match index {
+            0 => &self.r,
+            1 => &self.g,
+            2 => &self.b,
+            _ => panic!("Color channel index out of range: {index}"),
+        }
+    }
+}
+
+macro_rules! color_ops {
+    ($($op: ident::$method: ident, $assign_op: ident::$assign_method: ident);* $(;)?) => {
+        $(
+            impl $op for Color {
+                type Output = Color;
+
+                #[inline]
+                fn $method(self, rhs: Color) -> Color {
+                    Color::new(self.r.$method(rhs.r), self.g.$method(rhs.g), self.b.$method(rhs.b))
+                }
+            }
+
+            impl $op<Scalar> for Color {
+                type Output = Color;
+
+                #[inline]
+                fn $method(self, rhs: Scalar) -> Color {
+                    Color::new(self.r.$method(rhs), self.g.$method(rhs), self.b.$method(rhs))
+                }
+            }
+
+            impl $assign_op for Color {
+                #[inline]
+                fn $assign_method(&mut self, rhs: Color) {
+                    *self = $op::$method(*self, rhs);
+                }
+            }
+
+            impl $assign_op<Scalar> for Color {
+                #[inline]
+                fn $assign_method(&mut self, rhs: Scalar) {
+                    *self = $op::$method(*self, rhs);
+                }
+            }
+        )*
+    };
+}
+
+color_ops! {
+    Add::add, AddAssign::add_assign;
+    Sub::sub, SubAssign::sub_assign;
+    Mul::mul, MulAssign::mul_assign;
+    Div::div, DivAssign::div_assign;
+}
+
+impl Mul<Color> for Scalar {
+    type Output = Color;
+
+    #[inline]
+    fn mul(self, rhs: Color) -> Color {
+        rhs * self
+    }
+}
+
+impl Sum for Color {
+    fn sum<I: Iterator<Item = Color>>(iter: I) -> Color {
+        iter.fold(color::BLACK, Add::add)
+    }
+}
+
+impl AbsDiffEq for Color {
+    type Epsilon = Scalar;
+
+    fn default_epsilon() -> Scalar {
+        Scalar::default_epsilon()
+    }
+
+    fn abs_diff_eq(&self, other: &Color, epsilon: Scalar) -> bool {
+        self.r.abs_diff_eq(&other.r, epsilon)
+            && self.g.abs_diff_eq(&other.g, epsilon)
+            && self.b.abs_diff_eq(&other.b, epsilon)
+    }
+}
+
+impl From<[Scalar; 3]> for Color {
+    fn from([r, g, b]: [Scalar; 3]) -> Color {
+        Color::new(r, g, b)
+    }
+}
+
+impl From<Color> for [Scalar; 3] {
+    fn from(c: Color) -> [Scalar; 3] {
+        [c.r, c.g, c.b]
+    }
+}
+
+impl From<Pt3> for Color {
+    fn from(p: Pt3) -> Color {
+        Color::new(p.x, p.y, p.z)
+    }
+}
+
+impl From<Color> for Pt3 {
+    fn from(c: Color) -> Pt3 {
+        Pt3::new(c.r, c.g, c.b)
+    }
+}
+
+impl From<Vec3> for Color {
+    fn from(v: Vec3) -> Color {
+        Color::new(v.x, v.y, v.z)
+    }
+}
+
+impl From<Color> for Vec3 {
+    fn from(c: Color) -> Vec3 {
+        Vec3::new(c.r, c.g, c.b)
+    }
+}
+
+impl From<Rgb<f32>> for Color {
+    fn from(Rgb([r, g, b]): Rgb<f32>) -> Color {
+        Color::new(r as Scalar, g as Scalar, b as Scalar)
+    }
+}
+
+impl From<Color> for Rgb<f32> {
+    fn from(c: Color) -> Rgb<f32> {
+        Rgb([
+            scalar::to_f32(c.r),
+            scalar::to_f32(c.g),
+            scalar::to_f32(c.b),
+        ])
+    }
+}
+
 pub mod color {
     use super::{color, Color, Scalar};
-    use cgmath::ElementWise;
 
     pub const WHITE: Color = color(1.0, 1.0, 1.0);
     pub const BLACK: Color = color(0.0, 0.0, 0.0);
@@ -54,9 +270,66 @@ pub mod color {
     pub const MAGENTA: Color = color(1.0, 0.0, 1.0);
     pub const YELLOW: Color = color(1.0, 1.0, 0.0);
 
+    /// Perceived brightness of a linear rgb color
+    pub fn luminance(c: Color) -> Scalar {
+        c.luminance()
+    }
+
     pub fn mix(a: Color, b: Color, value: Scalar) -> Color {
         let value = value.clamp(0.0, 1.0);
-        (a * (1.0 - value)).add_element_wise(b * value)
+        a * (1.0 - value) + b * value
+    }
+
+    /// Piecewise gaussian used by the CIE color matching function fit
+    fn cmf_gaussian(x: f64, mu: f64, sigma_low: f64, sigma_high: f64) -> f64 {
+        let sigma = if x < mu { sigma_low } else { sigma_high };
+        let t = (x - mu) / sigma;
+        (-0.5 * t * t).exp()
+    }
+
+    /// CIE 1931 color matching functions, using the multi-lobe fit from Wyman et al. 2013
+    fn cie_xyz(lambda_nm: f64) -> [f64; 3] {
+        let x = 1.056 * cmf_gaussian(lambda_nm, 599.8, 37.9, 31.0)
+            + 0.362 * cmf_gaussian(lambda_nm, 442.0, 16.0, 26.7)
+            - 0.065 * cmf_gaussian(lambda_nm, 501.1, 20.4, 26.2);
+        let y = 0.821 * cmf_gaussian(lambda_nm, 568.8, 46.9, 40.5)
+            + 0.286 * cmf_gaussian(lambda_nm, 530.9, 16.3, 31.1);
+        let z = 1.217 * cmf_gaussian(lambda_nm, 437.0, 11.8, 36.0)
+            + 0.681 * cmf_gaussian(lambda_nm, 459.0, 26.0, 13.8);
+        [x, y, z]
+    }
+
+    /// Spectral radiance of a blackbody, up to a constant factor
+    fn planck(lambda_nm: f64, kelvin: f64) -> f64 {
+        // Second radiation constant hc/k in nm K
+        const C2: f64 = 1.438_777e7;
+        1.0 / (lambda_nm.powi(5) * ((C2 / (lambda_nm * kelvin)).exp() - 1.0))
+    }
+
+    /// Linear sRGB color of a blackbody at the given temperature, with unit luminance
+    pub fn from_temperature(kelvin: Scalar) -> Color {
+        assert!(kelvin > 0.0, "Color temperature must be positive");
+
+        let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+        for lambda in (380..=780).step_by(5) {
+            let lambda = lambda as f64;
+            #[allow(clippy::unnecessary_cast)]
+            let radiance = planck(lambda, kelvin as f64);
+            let [cx, cy, cz] = cie_xyz(lambda);
+            x += radiance * cx;
+            y += radiance * cy;
+            z += radiance * cz;
+        }
+
+        let r = 3.240_454_2 * x - 1.537_138_5 * y - 0.498_531_4 * z;
+        let g = -0.969_266_0 * x + 1.876_010_8 * y + 0.041_556_0 * z;
+        let b = 0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z;
+        let rgb = color(
+            r.max(0.0) as Scalar,
+            g.max(0.0) as Scalar,
+            b.max(0.0) as Scalar,
+        );
+        rgb / luminance(rgb)
     }
 }
 
@@ -64,7 +337,10 @@ pub mod color {
 pub struct Ray {
     pub origin: Pt3,
     pub direction: Vec3,
+    /// Fraction of the camera exposure the ray is traced at, in [0, 1]
     pub time: Scalar,
+    /// Hits further along the ray than this distance are ignored
+    pub t_max: Scalar,
 }
 
 impl Ray {
@@ -73,6 +349,7 @@ impl Ray {
             origin,
             direction: direction.normalize(),
             time,
+            t_max: Scalar::INFINITY,
         }
     }
 
@@ -82,14 +359,150 @@ impl Ray {
             origin,
             direction,
             time,
+            t_max: Scalar::INFINITY,
         }
     }
 
+    /// The same ray, only hitting surfaces up to `t_max` along it
+    pub fn with_t_max(self, t_max: Scalar) -> Ray {
+        Ray { t_max, ..self }
+    }
+
     pub fn at(&self, t: Scalar) -> Pt3 {
         self.origin + self.direction * t
     }
 }
 
+/// RGB color spaces the renderer can work and output in. Colors are linear in all of them.
+pub mod colorspace {
+    use super::{color, Color, Scalar};
+    use image::Rgb32FImage;
+    use serde::Deserialize;
+
+    type Matrix = [[Scalar; 3]; 3];
+
+    /// Linear sRGB (D65) to ACEScg (AP1, D60) with a Bradford adaptation
+    const SRGB_TO_ACESCG: Matrix = [
+        [0.613_097_4, 0.339_523_1, 0.047_379_3],
+        [0.070_194_2, 0.916_355_6, 0.013_452_6],
+        [0.020_615_6, 0.109_569_8, 0.869_815_1],
+    ];
+    /// ACES2065-1 (AP0) to ACEScg (AP1), both D60
+    const ACES2065_TO_ACESCG: Matrix = [
+        [1.451_439_3, -0.236_510_7, -0.214_928_6],
+        [-0.076_553_8, 1.176_229_7, -0.099_675_9],
+        [0.008_316_1, -0.006_032_4, 0.997_716_3],
+    ];
+    const IDENTITY: Matrix = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+    pub enum ColorSpace {
+        #[default]
+        #[serde(rename = "linear_srgb")]
+        LinearSrgb,
+        #[serde(rename = "acescg")]
+        AcesCg,
+        #[serde(rename = "aces2065_1")]
+        Aces2065_1,
+    }
+
+    impl ColorSpace {
+        fn to_acescg(self) -> Matrix {
+            match self {
+                ColorSpace::LinearSrgb => SRGB_TO_ACESCG,
+                ColorSpace::AcesCg => IDENTITY,
+                ColorSpace::Aces2065_1 => ACES2065_TO_ACESCG,
+            }
+        }
+
+        /// Matrix taking colors in this space to `to`
+        pub fn transform_to(self, to: ColorSpace) -> ColorTransform {
+            if self == to {
+                ColorTransform(IDENTITY)
+            } else {
+                // The inverse is computed so conversions round trip exactly
+                ColorTransform(mul(invert(to.to_acescg()), self.to_acescg()))
+            }
+        }
+
+        /// `c`, in this space, in the space `to`
+        pub fn convert(self, to: ColorSpace, c: Color) -> Color {
+            self.transform_to(to).apply(c)
+        }
+    }
+
+    /// Linear map between two color spaces
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct ColorTransform(Matrix);
+
+    impl ColorTransform {
+        pub fn is_identity(&self) -> bool {
+            self.0 == IDENTITY
+        }
+
+        pub fn apply(&self, c: Color) -> Color {
+            let [r, g, b] = self.0.map(|row| row[0] * c.r + row[1] * c.g + row[2] * c.b);
+            color(r, g, b)
+        }
+
+        pub fn apply_image(&self, image: &mut Rgb32FImage) {
+            if self.is_identity() {
+                return;
+            }
+            for pixel in image.pixels_mut() {
+                *pixel = self.apply(Color::from(*pixel)).into();
+            }
+        }
+    }
+
+    fn mul(a: Matrix, b: Matrix) -> Matrix {
+        std::array::from_fn(|i| std::array::from_fn(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+    }
+
+    fn invert(m: Matrix) -> Matrix {
+        let cofactor = |i: usize, j: usize| {
+            let (i0, i1) = ((i + 1) % 3, (i + 2) % 3);
+            let (j0, j1) = ((j + 1) % 3, (j + 2) % 3);
+            m[i0][j0] * m[i1][j1] - m[i0][j1] * m[i1][j0]
+        };
+        let det = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum::<Scalar>();
+        std::array::from_fn(|i| std::array::from_fn(|j| cofactor(j, i) / det))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::types::color::WHITE;
+        use cgmath::assert_abs_diff_eq;
+
+        const SPACES: [ColorSpace; 3] = [
+            ColorSpace::LinearSrgb,
+            ColorSpace::AcesCg,
+            ColorSpace::Aces2065_1,
+        ];
+
+        #[test]
+        fn conversions_round_trip_and_keep_white() {
+            let c = color(0.8, 0.3, 0.05);
+            for from in SPACES {
+                for to in SPACES {
+                    let there = from.convert(to, c);
+                    assert_abs_diff_eq!(to.convert(from, there), c, epsilon = 1e-5);
+                    assert_abs_diff_eq!(from.convert(to, WHITE), WHITE, epsilon = 1e-4);
+                }
+            }
+        }
+
+        #[test]
+        fn srgb_primaries_in_aces() {
+            let red = ColorSpace::LinearSrgb.convert(ColorSpace::AcesCg, color(1.0, 0.0, 0.0));
+            assert_abs_diff_eq!(red, color(0.6131, 0.0702, 0.0206), epsilon = 1e-4);
+            let red = ColorSpace::LinearSrgb.convert(ColorSpace::Aces2065_1, color(1.0, 0.0, 0.0));
+            assert_abs_diff_eq!(red, color(0.4397, 0.0898, 0.0175), epsilon = 1e-3);
+        }
+    }
+}
+
 #[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct R8G8B8Color(pub [u8; 3]);
 
@@ -119,11 +532,11 @@ impl From<R8G8B8Color> for Rgb<u8> {
 
 impl From<Color> for R8G8B8Color {
     fn from(value: Color) -> R8G8B8Color {
-        let value = value.map(|el| {
+        let channel = |el: Scalar| {
             let el = el.clamp(0.0, 1.0);
             (el * 256.0).floor().clamp(0.0, 255.0) as u8
-        });
-        R8G8B8Color(value.into())
+        };
+        R8G8B8Color([channel(value.r), channel(value.g), channel(value.b)])
     }
 }
 
@@ -155,4 +568,64 @@ mod tests {
             R8G8B8Color([128, 128, 128])
         );
     }
+
+    #[test]
+    fn color_arithmetic() {
+        let a = color(0.5, 1.0, 2.0);
+        let b = color(2.0, 0.5, 0.25);
+        assert_eq!(a * b, color(1.0, 0.5, 0.5));
+        assert_eq!(a + b, color(2.5, 1.5, 2.25));
+        assert_eq!(a - b, color(-1.5, 0.5, 1.75));
+        assert_eq!(a / b, color(0.25, 2.0, 8.0));
+        assert_eq!(2.0 * a, a * 2.0);
+        assert_eq!(a / 2.0, color(0.25, 0.5, 1.0));
+        assert_eq!([a, b].into_iter().sum::<Color>(), a + b);
+
+        let mut c = a;
+        c *= b;
+        c += color::WHITE;
+        assert_eq!(c, color(2.0, 1.5, 1.5));
+
+        assert_eq!(a.max_component(), 2.0);
+        assert_eq!(a[1], 1.0);
+        assert!(a.is_finite() && !color(Scalar::NAN, 0.0, 0.0).is_finite());
+        assert!(color::BLACK.is_black() && !a.is_black());
+        assert_eq!(Color::from(Rgb([0.5, 1.0, 2.0])), a);
+        assert_eq!(<[Scalar; 3]>::from(a), [0.5, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn deserialize_color() {
+        #[derive(Deserialize)]
+        struct Table {
+            c: Color,
+        }
+        let table: Table = toml::from_str("c = [0.1, 0.2, 0.3]").unwrap();
+        assert_eq!(table.c, color(0.1, 0.2, 0.3));
+        assert!(toml::from_str::<Table>("c = [0.1, 0.2]").is_err());
+    }
+
+    #[test]
+    fn color_temperature() {
+        use cgmath::assert_abs_diff_eq;
+
+        for kelvin in [1500.0, 2700.0, 6500.0, 10000.0] {
+            assert_abs_diff_eq!(
+                color::luminance(color::from_temperature(kelvin)),
+                1.0,
+                epsilon = 1e-4
+            );
+        }
+
+        // Close to the D65 white point
+        let daylight = color::from_temperature(6500.0);
+        assert_abs_diff_eq!(daylight, color::WHITE, epsilon = 0.1);
+
+        let warm = color::from_temperature(2700.0);
+        assert!(warm.r > warm.g && warm.g > warm.b);
+        assert!(warm.b < 0.5 * warm.r);
+
+        let cool = color::from_temperature(10000.0);
+        assert!(cool.b > cool.g && cool.g > cool.r);
+    }
 }
@@ -1,6 +1,6 @@
-use crate::types::scalar::consts::{FRAC_PI_2, FRAC_PI_4};
-use crate::types::{scalar, Pt2, Pt3, Scalar, Vec3};
-use cgmath::{point2, vec3, EuclideanSpace, InnerSpace};
+use crate::types::scalar::consts::{FRAC_1_PI, FRAC_PI_2, FRAC_PI_4, TAU};
+use crate::types::{scalar, Pt2, Pt3, Scalar, Vec2, Vec3};
+use cgmath::{point2, vec2, vec3, EuclideanSpace, InnerSpace};
 
 pub fn max_value3(v: Pt3) -> Scalar {
     if v[0] > v[1] && v[0] > v[2] {
@@ -14,9 +14,9 @@ pub fn max_value3(v: Pt3) -> Scalar {
 
 pub fn random_vec() -> Vec3 {
     vec3(
-        fastrand::f32() * 2.0 - 1.0,
-        fastrand::f32() * 2.0 - 1.0,
-        fastrand::f32() * 2.0 - 1.0,
+        scalar::rand() * 2.0 - 1.0,
+        scalar::rand() * 2.0 - 1.0,
+        scalar::rand() * 2.0 - 1.0,
     )
 }
 
@@ -46,23 +46,83 @@ pub fn random_concentric_disk() -> Pt2 {
     }
 }
 
+/// Uniformly samples a regular polygon with `num_blades` vertices on the unit circle, the first
+/// on the +x axis. Zero blades samples the unit disk.
+pub fn random_polygon_aperture(num_blades: u32) -> Pt2 {
+    if num_blades == 0 {
+        return random_concentric_disk();
+    }
+    assert!(
+        num_blades >= 3,
+        "An aperture needs at least 3 blades, got {num_blades}"
+    );
+
+    // Pick one triangle of the fan around the center, then sample it uniformly
+    let blade = fastrand::u32(..num_blades);
+    let angle = TAU / num_blades as Scalar;
+    let a = vec2_at_angle(blade as Scalar * angle);
+    let b = vec2_at_angle((blade + 1) as Scalar * angle);
+
+    let r = scalar::rand().sqrt();
+    let t = scalar::rand();
+    Pt2::from_vec((a * (1.0 - t) + b * t) * r)
+}
+
+fn vec2_at_angle(angle: Scalar) -> Vec2 {
+    vec2(angle.cos(), angle.sin())
+}
+
 pub fn random_cos_sample_hemisphere() -> Vec3 {
     let d = random_concentric_disk();
     let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
     vec3(d.x, d.y, z)
 }
 
+/// Cosine weighted direction in the hemisphere around the world space unit `normal`, with its
+/// pdf over solid angle
+pub fn cos_sample_hemisphere_around(normal: Vec3) -> (Vec3, Scalar) {
+    let local = random_cos_sample_hemisphere();
+    let (tangent, bitangent) = coordinate_system(normal);
+    let direction = tangent * local.x + bitangent * local.y + normal * local.z;
+    (direction, local.z * FRAC_1_PI)
+}
+
 pub fn reflect(vec: Vec3, reflector: Vec3) -> Vec3 {
     -vec + 2.0 * reflector * vec.dot(reflector)
 }
 
+/// Builds two vectors that form an orthonormal basis with the unit vector `v`
+pub fn coordinate_system(v: Vec3) -> (Vec3, Vec3) {
+    let v2 = if v.x.abs() > v.y.abs() {
+        vec3(-v.z, 0.0, v.x) / (v.x * v.x + v.z * v.z).sqrt()
+    } else {
+        vec3(0.0, v.z, -v.y) / (v.y * v.y + v.z * v.z).sqrt()
+    };
+    (v2, v.cross(v2))
+}
+
+/// Absolute floor of the offset applied by [`offset_ray_origin`]
+const RAY_OFFSET_MIN: Scalar = 1e-5;
+/// Safety factor applied to the error bound of the hit point
+const RAY_OFFSET_ERROR_SCALE: Scalar = 64.0;
+
+/// Moves a secondary ray origin off the surface along the geometric normal, to
+/// the side `direction` leaves from. The offset grows with `error`, the bound on
+/// the floating point error of `point`, so the new ray can't hit the surface it
+/// starts on.
+pub fn offset_ray_origin(point: Pt3, error: Scalar, normal: Vec3, direction: Vec3) -> Pt3 {
+    let distance = (error * RAY_OFFSET_ERROR_SCALE).max(RAY_OFFSET_MIN);
+    if normal.dot(direction) < 0.0 {
+        point - normal * distance
+    } else {
+        point + normal * distance
+    }
+}
+
 pub fn spherical_direction(sin_theta: Scalar, cos_theta: Scalar, phi: Scalar) -> Vec3 {
     vec3(sin_theta * phi.cos(), sin_theta * phi.cos(), cos_theta)
 }
 
-#[cfg(test)]
-mod tests {}
-
 pub trait NormalBasisVector<S> {
     fn cos_theta(self) -> S;
     fn cos2_theta(self) -> S;
@@ -101,7 +161,8 @@ impl NormalBasisVector<Scalar> for Vec3 {
 
     #[inline]
     fn sin2_theta(self) -> Scalar {
-        (1.0 - self.cos2_theta()).max(0.0)
+        // 1 - cos^2 cancels badly near the normal, where narrow lobes need it most
+        self.x * self.x + self.y * self.y
     }
 
     #[inline]
@@ -117,8 +178,9 @@ impl NormalBasisVector<Scalar> for Vec3 {
     #[inline]
     fn cos_phi(self) -> Scalar {
         let sin_theta = self.sin_theta();
+        // Along the normal phi is arbitrary, pick 0 so cos and sin stay a rotation
         if sin_theta == 0.0 {
-            0.0
+            1.0
         } else {
             (self.x / sin_theta).clamp(-1.0, 1.0)
         }
@@ -193,3 +255,113 @@ macro_rules! bitfield_methods {
 }
 
 pub(crate) use bitfield_methods;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::scalar::consts::PI;
+    use cgmath::point3;
+
+    #[test]
+    fn offset_ray_origin_follows_direction() {
+        let normal = vec3(0.0, 1.0, 0.0);
+        let p = point3(1.0, 0.0, 0.0);
+        assert!(offset_ray_origin(p, 0.0, normal, vec3(0.3, 1.0, 0.0)).y > 0.0);
+        assert!(offset_ray_origin(p, 0.0, normal, vec3(0.3, -1.0, 0.0)).y < 0.0);
+    }
+
+    #[test]
+    fn offset_ray_origin_scales_with_error() {
+        let normal = vec3(0.0, 0.0, 1.0);
+        let p = point3(1.0, 0.0, 0.0);
+        let error = 10.0 * RAY_OFFSET_MIN;
+        let exact = offset_ray_origin(p, 0.0, normal, normal);
+        let rounded = offset_ray_origin(p, error, normal, normal);
+        assert!(rounded.z > exact.z);
+        // The offset must exceed the error of the hit point
+        assert!(rounded.z > error);
+    }
+
+    #[test]
+    fn cos_sample_around_normal() {
+        fastrand::seed(11);
+        for normal in [
+            vec3(0.0, 0.0, 1.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(1.0, 2.0, -3.0).normalize(),
+        ] {
+            const SAMPLES: usize = 50_000;
+            let mut mean_cos = 0.0;
+            for _ in 0..SAMPLES {
+                let (direction, pdf) = cos_sample_hemisphere_around(normal);
+                let cos = direction.dot(normal);
+                assert!((direction.magnitude() - 1.0).abs() < 1e-4);
+                assert!(cos >= -1e-6);
+                assert!((pdf - cos / PI).abs() < 1e-5);
+                mean_cos += cos / SAMPLES as Scalar;
+            }
+            // E[cos] = integral of cos^2 / pi over the hemisphere
+            assert!((mean_cos - 2.0 / 3.0).abs() < 0.01, "{mean_cos}");
+        }
+    }
+
+    #[test]
+    fn polygon_aperture_inside_hexagon() {
+        // Edge normals of a hexagon with a vertex on +x are at odd multiples of 30 degrees
+        let apothem = (TAU / 12.0).cos();
+        for _ in 0..10_000 {
+            let p = random_polygon_aperture(6).to_vec();
+            for edge in 0..6 {
+                let normal = vec2_at_angle((2 * edge + 1) as Scalar * TAU / 12.0);
+                assert!(p.dot(normal) <= apothem + 1e-5, "{p:?} outside the hexagon");
+            }
+        }
+    }
+
+    #[test]
+    fn polygon_aperture_is_uniform() {
+        const SAMPLES: usize = 100_000;
+        const CELLS: usize = 4;
+        // A 4x4 grid over [-0.5, 0.5]^2, which lies inside the hexagon, plus one bin for the rest
+        fastrand::seed(7);
+        let mut counts = [0usize; CELLS * CELLS + 1];
+        for _ in 0..SAMPLES {
+            let p = random_polygon_aperture(6);
+            let cell = if p.x.abs() < 0.5 && p.y.abs() < 0.5 {
+                let x = ((p.x + 0.5) * CELLS as Scalar) as usize;
+                let y = ((p.y + 0.5) * CELLS as Scalar) as usize;
+                x.min(CELLS - 1) + y.min(CELLS - 1) * CELLS
+            } else {
+                CELLS * CELLS
+            };
+            counts[cell] += 1;
+        }
+
+        let hexagon_area = 1.5 * (3.0 as Scalar).sqrt();
+        let cell_fraction = 1.0 / (CELLS * CELLS) as Scalar / hexagon_area;
+        let chi_squared: Scalar = counts
+            .iter()
+            .enumerate()
+            .map(|(cell, &count)| {
+                let fraction = if cell < CELLS * CELLS {
+                    cell_fraction
+                } else {
+                    1.0 - 1.0 / hexagon_area
+                };
+                let expected = fraction * SAMPLES as Scalar;
+                (count as Scalar - expected).powi(2) / expected
+            })
+            .sum();
+        // 99.9th percentile of chi-squared with 16 degrees of freedom is 39.25
+        assert!(chi_squared < 39.25, "chi-squared {chi_squared}");
+    }
+
+    #[test]
+    fn zero_blades_samples_disk() {
+        fastrand::seed(3);
+        let polygon: Vec<_> = (0..16).map(|_| random_polygon_aperture(0)).collect();
+        fastrand::seed(3);
+        let disk: Vec<_> = (0..16).map(|_| random_concentric_disk()).collect();
+        assert_eq!(polygon, disk);
+    }
+}
@@ -0,0 +1,96 @@
+//! White furnace tests: a non-emissive object that reflects or transmits all light, inside a
+//! uniform environment of radiance 1, must look exactly like the environment.
+
+use bumpalo::Bump;
+use cgmath::{assert_abs_diff_eq, point3};
+use image::{Rgb, Rgb32FImage};
+use pbrtrs_core::light::hdri::Hdri;
+use pbrtrs_core::raytracer::ray_color;
+use pbrtrs_core::scene::{
+    CameraBuilder, DisneyMaterial, MaterialBuilder, Object, SceneBuilder, Shape,
+};
+use pbrtrs_core::stats::RayStats;
+use pbrtrs_core::types::color::{BLACK, WHITE};
+use pbrtrs_core::types::Scalar;
+
+const SAMPLES: usize = 20_000;
+const SIZE: usize = 16;
+
+/// Mean radiance of the center pixel, which sees `material` on a sphere filling the view
+fn furnace(material: DisneyMaterial) -> Scalar {
+    let environment = Rgb32FImage::from_pixel(16, 8, Rgb([1.0, 1.0, 1.0]));
+    let scene = SceneBuilder::new()
+        .camera(
+            CameraBuilder::new()
+                .resolution(SIZE, SIZE)
+                .bounce_limit(64)
+                .build(),
+        )
+        .add_object(Object::new(
+            Shape::Sphere { radius: 1.0 },
+            point3(0.0, 0.0, 3.0),
+            material,
+        ))
+        .add_light(Hdri::new(environment, 1.0))
+        .build();
+
+    fastrand::seed(17);
+    let arena = Bump::new();
+    let stats = RayStats::new();
+    let mut radiance = BLACK;
+    for _ in 0..SAMPLES {
+        // Jittered over the center pixel, which covers a range of incident angles
+        let x = (SIZE / 2) as Scalar + fastrand::f32() as Scalar;
+        let y = (SIZE / 2) as Scalar + fastrand::f32() as Scalar;
+        let ray = scene.camera.generate_ray(x, y, 0.0);
+        radiance += ray_color(&ray, &scene, &arena, &stats);
+    }
+    let radiance = radiance / SAMPLES as Scalar;
+    assert_abs_diff_eq!(radiance.r, radiance.g, epsilon = 1e-4);
+    assert_abs_diff_eq!(radiance.r, radiance.b, epsilon = 1e-4);
+    radiance.r
+}
+
+#[test]
+fn lambertian() {
+    let material = MaterialBuilder::new()
+        .base_color(WHITE)
+        .specular(0.0)
+        .roughness(0.0)
+        .build();
+    assert_abs_diff_eq!(furnace(material), 1.0, epsilon = 0.02);
+}
+
+#[test]
+fn rough_conductor() {
+    // Single scattering microfacets lose the light that bounces between microfacets, the
+    // expected values are the directional albedo of GGX near normal incidence
+    for (roughness, albedo) in [(0.3, 0.99), (0.6, 0.83), (1.0, 0.31)] {
+        let material = MaterialBuilder::new()
+            .base_color(WHITE)
+            .metallic(1.0)
+            .roughness(roughness)
+            .build();
+        assert_abs_diff_eq!(furnace(material), albedo, epsilon = 0.02);
+    }
+}
+
+#[test]
+fn smooth_conductor() {
+    let material = MaterialBuilder::new()
+        .base_color(WHITE)
+        .metallic(1.0)
+        .roughness(0.0)
+        .build();
+    assert_abs_diff_eq!(furnace(material), 1.0, epsilon = 0.02);
+}
+
+#[test]
+fn glass() {
+    let material = MaterialBuilder::new()
+        .base_color(WHITE)
+        .transmission(1.0)
+        .ior(1.5)
+        .build();
+    assert_abs_diff_eq!(furnace(material), 1.0, epsilon = 0.02);
+}
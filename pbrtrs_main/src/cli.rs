@@ -0,0 +1,416 @@
+use crate::image_tiler::TileOrder;
+use crate::output::OutputFormat;
+use crate::render::RenderOverrides;
+use pbrtrs_core::raytracer::RenderMode;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: pbrtrs [OPTIONS] <scene_path>
+
+Options:
+  -o, --output <path>        Output image path, the extension picks the format: .exr
+                             for linear float with alpha, .png or .tiff for 16-bit
+                             sRGB [default: out.exr]
+      --albedo <path>        Also write the first-hit albedo to <path>
+      --layers <path>        Also write the emission, direct and indirect light layers,
+                             one layer per light group and the shadow catchers'
+                             shadows, to a multi-layer EXR at <path>
+      --sample-heatmap <path>
+                             Also write the number of samples taken per pixel as a
+                             false color image, blue for few and red for many
+  -s, --samples <n>          Override the number of samples per pixel, disables
+                             adaptive sampling
+  -b, --bounces <n>          Override the maximum path depth
+      --mode <mode>          Render a diagnostic instead of the image: normal, depth,
+                             uv, bounces or time [default: beauty]
+  -r, --resolution <WxH>     Override the image resolution, e.g. 1280x720
+      --width <n>            Override the image width
+      --height <n>           Override the image height
+      --seed <n>             Seed of the render's random numbers
+  -j, --threads <n>          Number of render threads [default: all cores]
+      --tile-size <n>        Width and height of a render tile [default: 16]
+      --tile-order <order>   Order the tiles are rendered in: random, scanline, spiral
+                             or hilbert [default: random]
+      --no-preview           Don't connect to tev, only write the final image
+      --watch                Re-render whenever the scene file or a file it references
+                             changes, until interrupted
+      --navigate             Move the camera from the terminal with a quick preview
+                             after every move, Enter renders and saves the image
+      --debug-pixel <X,Y>    Pixel to record with the enable_debugger feature, can be
+                             given more than once
+      --stats <path>         Write the enable_stats feature's report as JSON to <path>
+  -h, --help                 Print this message";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+    pub scene_path: PathBuf,
+    pub output: PathBuf,
+    pub albedo: Option<PathBuf>,
+    pub layers: Option<PathBuf>,
+    pub sample_heatmap: Option<PathBuf>,
+    pub samples: Option<usize>,
+    pub bounce_limit: Option<usize>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub mode: Option<RenderMode>,
+    pub seed: Option<u64>,
+    pub threads: Option<usize>,
+    pub tile_size: Option<usize>,
+    pub tile_order: TileOrder,
+    pub preview: bool,
+    pub watch: bool,
+    pub navigate: bool,
+    pub debug_pixels: Vec<(usize, usize)>,
+    pub stats: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Help,
+    Invalid(String),
+}
+
+fn parse_count(flag: &str, value: &str) -> Result<usize, ParseError> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(ParseError::Invalid(format!(
+            "{flag} expects a positive integer, got '{value}'"
+        ))),
+        Ok(n) => Ok(n),
+    }
+}
+
+fn parse_seed(value: &str) -> Result<u64, ParseError> {
+    value
+        .parse()
+        .map_err(|_| ParseError::Invalid(format!("--seed expects an integer, got '{value}'")))
+}
+
+fn parse_pixel(value: &str) -> Result<(usize, usize), ParseError> {
+    let invalid = || ParseError::Invalid(format!("--debug-pixel expects X,Y, got '{value}'"));
+    let (x, y) = value.split_once(',').ok_or_else(invalid)?;
+    match (x.trim().parse(), y.trim().parse()) {
+        (Ok(x), Ok(y)) => Ok((x, y)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_tile_order(value: &str) -> Result<TileOrder, ParseError> {
+    match value {
+        "random" => Ok(TileOrder::Random),
+        "scanline" => Ok(TileOrder::Scanline),
+        "spiral" => Ok(TileOrder::Spiral),
+        "hilbert" => Ok(TileOrder::Hilbert),
+        _ => Err(ParseError::Invalid(format!(
+            "--tile-order expects random, scanline, spiral or hilbert, got '{value}'"
+        ))),
+    }
+}
+
+fn parse_mode(value: &str) -> Result<RenderMode, ParseError> {
+    match value {
+        "beauty" => Ok(RenderMode::Beauty),
+        "normal" => Ok(RenderMode::Normal),
+        "depth" => Ok(RenderMode::Depth),
+        "uv" => Ok(RenderMode::Uv),
+        "bounces" => Ok(RenderMode::Bounces),
+        "time" => Ok(RenderMode::Time),
+        _ => Err(ParseError::Invalid(format!(
+            "--mode expects beauty, normal, depth, uv, bounces or time, got '{value}'"
+        ))),
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<(usize, usize), ParseError> {
+    let invalid = || ParseError::Invalid(format!("--resolution expects WxH, got '{value}'"));
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => Err(invalid()),
+    }
+}
+
+impl Args {
+    /// Parses command line arguments, not including the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, ParseError> {
+        let mut scene_path = None;
+        let mut output = PathBuf::from("out.exr");
+        let mut albedo = None;
+        let mut layers = None;
+        let mut sample_heatmap = None;
+        let mut samples = None;
+        let mut bounce_limit = None;
+        let mut width = None;
+        let mut height = None;
+        let mut mode = None;
+        let mut seed = None;
+        let mut threads = None;
+        let mut tile_size = None;
+        let mut tile_order = TileOrder::default();
+        let mut preview = true;
+        let mut watch = false;
+        let mut navigate = false;
+        let mut debug_pixels = Vec::new();
+        let mut stats = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .ok_or_else(|| ParseError::Invalid(format!("{flag} expects a value")))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Err(ParseError::Help),
+                "-o" | "--output" => output = PathBuf::from(value(&arg)?),
+                "--albedo" => albedo = Some(PathBuf::from(value(&arg)?)),
+                "--layers" => layers = Some(PathBuf::from(value(&arg)?)),
+                "--sample-heatmap" => sample_heatmap = Some(PathBuf::from(value(&arg)?)),
+                "-s" | "--samples" => samples = Some(parse_count(&arg, &value(&arg)?)?),
+                "-b" | "--bounces" | "--bounce-limit" => {
+                    bounce_limit = Some(parse_count(&arg, &value(&arg)?)?)
+                }
+                "-r" | "--resolution" => {
+                    let (w, h) = parse_resolution(&value(&arg)?)?;
+                    width = Some(w);
+                    height = Some(h);
+                }
+                "--width" => width = Some(parse_count(&arg, &value(&arg)?)?),
+                "--height" => height = Some(parse_count(&arg, &value(&arg)?)?),
+                "--mode" => mode = Some(parse_mode(&value(&arg)?)?),
+                "--seed" => seed = Some(parse_seed(&value(&arg)?)?),
+                "-j" | "--threads" => threads = Some(parse_count(&arg, &value(&arg)?)?),
+                "--tile-size" => tile_size = Some(parse_count(&arg, &value(&arg)?)?),
+                "--tile-order" => tile_order = parse_tile_order(&value(&arg)?)?,
+                "--no-preview" => preview = false,
+                "--watch" => watch = true,
+                "--navigate" => navigate = true,
+                "--debug-pixel" => debug_pixels.push(parse_pixel(&value(&arg)?)?),
+                "--stats" => stats = Some(PathBuf::from(value(&arg)?)),
+                flag if flag.starts_with('-') => {
+                    return Err(ParseError::Invalid(format!("Unknown option '{flag}'")))
+                }
+                path if scene_path.is_none() => scene_path = Some(PathBuf::from(path)),
+                extra => {
+                    return Err(ParseError::Invalid(format!(
+                        "Unexpected argument '{extra}'"
+                    )))
+                }
+            }
+        }
+
+        for path in [Some(&output), albedo.as_ref(), sample_heatmap.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            OutputFormat::from_path(path).map_err(ParseError::Invalid)?;
+        }
+
+        if watch && navigate {
+            return Err(ParseError::Invalid(
+                "--watch and --navigate can't be used together".to_owned(),
+            ));
+        }
+
+        Ok(Args {
+            scene_path: scene_path
+                .ok_or_else(|| ParseError::Invalid("Missing scene path".to_owned()))?,
+            output,
+            albedo,
+            layers,
+            sample_heatmap,
+            samples,
+            bounce_limit,
+            width,
+            height,
+            mode,
+            seed,
+            threads,
+            tile_size,
+            tile_order,
+            preview,
+            watch,
+            navigate,
+            debug_pixels,
+            stats,
+        })
+    }
+
+    /// Camera settings given on the command line, these win over the scene file
+    pub fn overrides(&self) -> RenderOverrides {
+        RenderOverrides {
+            num_samples: self.samples,
+            bounce_limit: self.bounce_limit,
+            width: self.width,
+            height: self.height,
+            mode: self.mode,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pbrtrs_core::scene::{load_scene_from_str, SceneFormat};
+
+    fn parse(args: &[&str]) -> Result<Args, ParseError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parse(&["scene.toml"]).unwrap();
+        assert_eq!(
+            args,
+            Args {
+                scene_path: PathBuf::from("scene.toml"),
+                output: PathBuf::from("out.exr"),
+                albedo: None,
+                layers: None,
+                sample_heatmap: None,
+                samples: None,
+                bounce_limit: None,
+                width: None,
+                height: None,
+                mode: None,
+                seed: None,
+                threads: None,
+                tile_size: None,
+                tile_order: TileOrder::Random,
+                preview: true,
+                watch: false,
+                navigate: false,
+                debug_pixels: vec![],
+                stats: None,
+            }
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let args = parse(&[
+            "--no-preview",
+            "--watch",
+            "-o",
+            "render.exr",
+            "--albedo",
+            "albedo.exr",
+            "--layers",
+            "layers.exr",
+            "--sample-heatmap",
+            "heatmap.exr",
+            "examples/spot.toml",
+            "--samples",
+            "16",
+            "-r",
+            "320x200",
+            "-j",
+            "2",
+            "--bounce-limit",
+            "3",
+            "--mode",
+            "depth",
+            "--seed",
+            "0",
+            "--tile-size",
+            "32",
+            "--tile-order",
+            "hilbert",
+            "--debug-pixel",
+            "70,206",
+            "--debug-pixel",
+            "1,2",
+            "--stats",
+            "stats.json",
+        ])
+        .unwrap();
+        assert_eq!(args.scene_path, PathBuf::from("examples/spot.toml"));
+        assert_eq!(args.output, PathBuf::from("render.exr"));
+        assert_eq!(args.albedo, Some(PathBuf::from("albedo.exr")));
+        assert_eq!(args.layers, Some(PathBuf::from("layers.exr")));
+        assert_eq!(args.sample_heatmap, Some(PathBuf::from("heatmap.exr")));
+        assert_eq!(args.samples, Some(16));
+        assert_eq!(args.bounce_limit, Some(3));
+        assert_eq!(args.mode, Some(RenderMode::Depth));
+        assert_eq!((args.width, args.height), (Some(320), Some(200)));
+        assert_eq!(args.seed, Some(0));
+        assert_eq!(args.threads, Some(2));
+        assert_eq!(args.tile_size, Some(32));
+        assert_eq!(args.tile_order, TileOrder::Hilbert);
+        assert!(!args.preview);
+        assert!(args.watch);
+        assert_eq!(args.debug_pixels, [(70, 206), (1, 2)]);
+        assert_eq!(args.stats, Some(PathBuf::from("stats.json")));
+
+        // Later options win, so a single dimension can replace part of a resolution
+        let args = parse(&[
+            "a.toml",
+            "-r",
+            "320x200",
+            "--height",
+            "100",
+            "--bounces",
+            "4",
+        ])
+        .unwrap();
+        assert_eq!((args.width, args.height), (Some(320), Some(100)));
+        assert_eq!(args.bounce_limit, Some(4));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert_eq!(parse(&["-h"]), Err(ParseError::Help));
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.toml", "b.toml"]).is_err());
+        assert!(parse(&["a.toml", "--samples", "0"]).is_err());
+        assert!(parse(&["a.toml", "--resolution", "320"]).is_err());
+        assert!(parse(&["a.toml", "--threads"]).is_err());
+        assert!(parse(&["a.toml", "--bogus"]).is_err());
+        assert!(parse(&["a.toml", "--watch", "--navigate"]).is_err());
+        assert!(parse(&["a.toml", "-o", "out.jpg"]).is_err());
+        assert!(parse(&["a.toml", "--albedo", "albedo"]).is_err());
+        assert!(parse(&["a.toml", "--seed", "-1"]).is_err());
+        assert!(parse(&["a.toml", "--tile-size", "0"]).is_err());
+        assert!(parse(&["a.toml", "--tile-order", "diagonal"]).is_err());
+        assert!(parse(&["a.toml", "--mode", "albedo"]).is_err());
+        assert!(parse(&["a.toml", "--debug-pixel", "70"]).is_err());
+        assert!(parse(&["a.toml", "--debug-pixel", "x,1"]).is_err());
+    }
+
+    #[test]
+    fn command_line_beats_scene_file() {
+        let mut scene = load_scene_from_str(
+            "
+            objects = []
+            lights = []
+
+            [camera]
+            position = [0.0, 0.0, 0.0]
+            direction = [0.0, 0.0, 1.0]
+            sensor_distance = 1.0
+            exposure_time = 0.0
+            aperture = 0.0
+            focus_distance = 1.0
+            ldr_scale = 1.0
+            bounce_limit = 10
+            num_samples = 64
+            width = 512
+            height = 400
+            ",
+            SceneFormat::Toml,
+            None,
+        );
+        parse(&["a.toml", "--width", "64", "-s", "4"])
+            .unwrap()
+            .overrides()
+            .apply(&mut scene.camera);
+        let camera = &scene.camera;
+        assert_eq!(
+            (
+                camera.width,
+                camera.height,
+                camera.num_samples,
+                camera.bounce_limit
+            ),
+            (64, 400, 4, 10)
+        );
+    }
+}
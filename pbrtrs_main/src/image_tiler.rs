@@ -1,26 +1,108 @@
+/// Default width and height of a tile
 pub const TILE_SIZE: usize = 16;
 
+/// Order the tiles of an image are rendered in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TileOrder {
+    /// Shuffled by the render seed, the preview fills in evenly
+    #[default]
+    Random,
+    /// Rows from the top, each left to right
+    Scanline,
+    /// Rings around the tile with the center pixel, outwards
+    Spiral,
+    /// Along a Hilbert curve, neighbouring tiles are rendered close in time
+    Hilbert,
+}
+
+impl TileOrder {
+    /// Sorts `tiles`, laid out in scanline order on a grid `columns` tiles wide. `center` is the
+    /// cell of the image's center pixel.
+    fn apply(
+        self,
+        tiles: &mut [(usize, usize, usize, usize)],
+        columns: usize,
+        center: (usize, usize),
+        seed: u64,
+    ) {
+        let rows = tiles.len().div_ceil(columns);
+        let cell = |i: usize| (i % columns, i / columns);
+        let mut order = (0..tiles.len()).collect::<Vec<_>>();
+        match self {
+            // A local generator leaves the thread's random numbers to the render
+            TileOrder::Random => fastrand::Rng::with_seed(seed).shuffle(&mut order),
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => {
+                let key = |i: usize| {
+                    let (x, y) = cell(i);
+                    let (dx, dy) = (x as f64 - center.0 as f64, y as f64 - center.1 as f64);
+                    (dx.abs().max(dy.abs()), dy.atan2(dx))
+                };
+                order.sort_by(|&a, &b| key(a).partial_cmp(&key(b)).unwrap());
+            }
+            TileOrder::Hilbert => {
+                let n = columns.max(rows).next_power_of_two();
+                order.sort_by_key(|&i| hilbert_index(n, cell(i)));
+            }
+        }
+        let sorted = order.iter().map(|&i| tiles[i]).collect::<Vec<_>>();
+        tiles.copy_from_slice(&sorted);
+    }
+}
+
+/// Distance along the Hilbert curve filling an `n` by `n` grid, `n` a power of two
+fn hilbert_index(n: usize, (mut x, mut y): (usize, usize)) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve inside it starts and ends at the right corners
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
 pub struct ImageTileGenerator {
+    /// Tiles left to render, the next one last
     tiles: Vec<(usize, usize, usize, usize)>,
 }
 
 impl ImageTileGenerator {
-    pub fn new(width: usize, height: usize) -> ImageTileGenerator {
+    /// `seed` shuffles the tiles in [`TileOrder::Random`]
+    pub fn new(
+        width: usize,
+        height: usize,
+        tile_size: usize,
+        order: TileOrder,
+        seed: u64,
+    ) -> ImageTileGenerator {
+        assert!(tile_size > 0, "Tile size must be non-zero");
         let mut tiles = Vec::new();
         let (mut next_tile_x, mut next_tile_y) = (0, 0);
         while next_tile_y < height && next_tile_x < width {
             let tile_x = next_tile_x;
             let tile_y = next_tile_y;
-            let tile_width = (width - tile_x).min(TILE_SIZE);
-            let tile_height = (height - tile_y).min(TILE_SIZE);
-            next_tile_x += TILE_SIZE;
+            let tile_width = (width - tile_x).min(tile_size);
+            let tile_height = (height - tile_y).min(tile_size);
+            next_tile_x += tile_size;
             if next_tile_x >= width {
                 next_tile_x = 0;
-                next_tile_y += TILE_SIZE;
+                next_tile_y += tile_size;
             }
             tiles.push((tile_x, tile_y, tile_width, tile_height));
         }
-        fastrand::shuffle(&mut tiles);
+        let center = (width / 2 / tile_size, height / 2 / tile_size);
+        order.apply(&mut tiles, width.div_ceil(tile_size), center, seed);
+        tiles.reverse();
         ImageTileGenerator { tiles }
     }
 
@@ -65,7 +147,6 @@ impl<T> ImageTile<T> {
         &self.tile[idx]
     }
 
-    #[allow(unused)]
     pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
         self.tile.get_mut(idx)
     }
@@ -88,3 +169,104 @@ impl<T> ImageTile<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile_locations(width: usize, height: usize, order: TileOrder) -> Vec<(usize, usize)> {
+        let mut generator = ImageTileGenerator::new(width, height, 16, order, 0);
+        std::iter::from_fn(|| generator.get_tile(0u8))
+            .map(|tile| tile.location())
+            .collect()
+    }
+
+    #[test]
+    fn every_order_renders_every_tile_once() {
+        let scanline = tile_locations(100, 70, TileOrder::Scanline);
+        assert_eq!(scanline.len(), 7 * 5);
+        assert_eq!(&scanline[..3], [(0, 0), (16, 0), (32, 0)]);
+        for order in [TileOrder::Random, TileOrder::Spiral, TileOrder::Hilbert] {
+            let mut tiles = tile_locations(100, 70, order);
+            tiles.sort_by_key(|&(x, y)| (y, x));
+            assert_eq!(tiles, scanline, "{order:?}");
+        }
+    }
+
+    #[test]
+    fn every_order_renders_every_pixel_once() {
+        let orders = [
+            TileOrder::Random,
+            TileOrder::Scanline,
+            TileOrder::Spiral,
+            TileOrder::Hilbert,
+        ];
+        for order in orders {
+            for (width, height, tile_size) in [(100, 70, 16), (64, 64, 16), (33, 7, 5), (1, 1, 8)] {
+                let mut generator = ImageTileGenerator::new(width, height, tile_size, order, 3);
+                let mut count = vec![0; width * height];
+                while let Some(mut tile) = generator.get_tile(0u8) {
+                    while let Some((_, x, y)) = tile.next_tile() {
+                        count[x + y * width] += 1;
+                    }
+                }
+                assert!(count.iter().all(|&c| c == 1), "{order:?} {width}x{height}");
+            }
+        }
+    }
+
+    #[test]
+    fn random_order_is_seeded() {
+        fastrand::seed(1);
+        let expected = fastrand::u64(..);
+        fastrand::seed(1);
+        let tiles = tile_locations(100, 70, TileOrder::Random);
+        // The shuffle doesn't draw from the thread's generator
+        assert_eq!(fastrand::u64(..), expected);
+        assert_eq!(tiles, tile_locations(100, 70, TileOrder::Random));
+        let mut other_seed = ImageTileGenerator::new(100, 70, 16, TileOrder::Random, 1);
+        let other = std::iter::from_fn(|| other_seed.get_tile(0u8))
+            .map(|tile| tile.location())
+            .collect::<Vec<_>>();
+        assert_ne!(tiles, other);
+    }
+
+    #[test]
+    fn edge_tiles_are_clipped() {
+        let mut generator = ImageTileGenerator::new(100, 70, 32, TileOrder::Scanline, 0);
+        let dimensions = std::iter::from_fn(|| generator.get_tile(0u8))
+            .map(|tile| tile.dimensions())
+            .collect::<Vec<_>>();
+        assert_eq!(dimensions.len(), 4 * 3);
+        assert_eq!(dimensions[0], (32, 32));
+        assert_eq!(dimensions[3], (4, 32));
+        assert_eq!(dimensions[11], (4, 6));
+    }
+
+    #[test]
+    fn spiral_starts_at_the_center() {
+        // The first tile has the center pixel, also when the grid has no middle tile
+        for (width, height) in [(80, 48), (64, 64), (100, 70), (40, 17)] {
+            let (x, y) = tile_locations(width, height, TileOrder::Spiral)[0];
+            assert!((x..x + 16).contains(&(width / 2)), "{width}x{height}");
+            assert!((y..y + 16).contains(&(height / 2)), "{width}x{height}");
+        }
+
+        let tiles = tile_locations(80, 48, TileOrder::Spiral);
+        assert_eq!(tiles[0], (32, 16));
+        // The eight tiles around the center come next
+        for &(x, y) in &tiles[1..9] {
+            assert!(x.abs_diff(32) <= 16 && y.abs_diff(16) <= 16);
+        }
+    }
+
+    #[test]
+    fn hilbert_steps_between_neighbours() {
+        let tiles = tile_locations(128, 128, TileOrder::Hilbert);
+        assert_eq!(tiles[0], (0, 0));
+        for pair in tiles.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            assert_eq!(x0.abs_diff(x1) + y0.abs_diff(y1), 16);
+        }
+    }
+}
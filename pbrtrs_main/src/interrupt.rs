@@ -0,0 +1,63 @@
+use crate::render::CancelToken;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Set by the signal handler, which can't touch the token itself
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Cancels `cancel` on the first Ctrl-C so the partial image is saved, a second one exits
+/// right away. Only supported on unix, elsewhere Ctrl-C still kills the process.
+pub fn cancel_on_interrupt(cancel: CancelToken) {
+    if !install_handler() {
+        return;
+    }
+    thread::Builder::new()
+        .name("interrupt".to_owned())
+        .spawn(move || {
+            while !INTERRUPTED.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(50));
+            }
+            println!("Interrupted, finishing the render in flight (Ctrl-C again to quit)");
+            cancel.cancel();
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+fn install_handler() -> bool {
+    extern "C" fn on_interrupt(_signal: libc::c_int) {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            // Only async-signal-safe calls are allowed here
+            unsafe { libc::_exit(130) };
+        }
+    }
+
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) != libc::SIG_ERR }
+}
+
+#[cfg(not(unix))]
+fn install_handler() -> bool {
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn interrupt_cancels() {
+        let cancel = CancelToken::new();
+        cancel_on_interrupt(cancel.clone());
+        assert!(!cancel.is_cancelled());
+
+        unsafe { libc::raise(libc::SIGINT) };
+        let start = Instant::now();
+        while !cancel.is_cancelled() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -1,258 +1,201 @@
 extern crate bumpalo;
 extern crate cgmath;
 extern crate core;
+extern crate exr;
 extern crate fastrand;
 extern crate image;
+#[cfg(unix)]
+extern crate libc;
 extern crate pbrtrs_core;
 extern crate rayon;
 extern crate tev_client;
 extern crate threadpool;
 
+mod cli;
 mod image_tiler;
+mod interrupt;
+mod navigate;
+mod output;
+mod render;
+mod watch;
 
-use pbrtrs_core::debugger;
-use pbrtrs_core::types::{scalar, Color, Mat3, Ray, Scalar};
 use std::fmt::{Display, Formatter};
 
-use bumpalo::Bump;
-use cgmath::{vec3, EuclideanSpace, InnerSpace};
-use image::{Rgb, Rgb32FImage};
-use image_tiler::{ImageTile, ImageTileGenerator};
-use pbrtrs_core::raytracer::ray_color;
+use cli::{Args, ParseError};
+use output::{save_image, save_render};
+use pbrtrs_core::postprocess;
 use pbrtrs_core::scene::load_scene;
-use pbrtrs_core::util::random_concentric_disk;
-use std::num::NonZeroUsize;
+use render::{render, CancelToken, PreviewSink, PrintProgress, RenderOptions, RenderOutput};
+use std::net::TcpStream;
+use std::panic;
 use std::process::Command;
-use std::sync::{mpsc, Arc};
-use std::thread;
-use std::time::{Duration, Instant};
-use tev_client::{PacketCreateImage, PacketUpdateImage, TevClient};
+use std::time::Duration;
+use tev_client::TevClient;
+use watch::{render_watched, FileWatcher};
 
-#[cfg(feature = "enable_debugger")]
-use pbrtrs_core::debugger::debug_info;
-
-#[cfg(feature = "enable_debugger")]
-const DEBUG_PIXEL: (usize, usize) = (70, 206);
+/// Renders are deterministic, the same scene and seed always give the same image
+const RENDER_SEED: u64 = 0x8815_6e97_8ca3_1877;
 
 fn main() {
-    // Deterministic rendering
-    fastrand::seed(0x8815_6e97_8ca3_1877);
-
-    let tev_path = std::env::var("TEV_PATH").ok();
-
-    let mut tev_client = if let Some(tev_path) = tev_path {
-        println!("{tev_path}");
-        if tev_path.is_empty() {
-            None
-        } else {
-            Some(TevClient::spawn(Command::new(tev_path)).unwrap())
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(ParseError::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(ParseError::Invalid(message)) => {
+            eprintln!("{message}\n\n{}", cli::USAGE);
+            std::process::exit(2);
         }
+    };
+
+    let preview = if args.preview {
+        connect_tev().map(|client| Box::new(client) as Box<dyn PreviewSink>)
     } else {
         None
     };
 
-    println!("Loading scene...");
-    let scene_path = std::env::args().nth(1).expect("Usage: pbrtrs <scene_path>");
-    let scene = Arc::new(load_scene(scene_path));
-    println!("Rendering...");
-
-    let image_width = scene.camera.width;
-    let image_height = scene.camera.height;
-
-    if let Some(tev_client) = &mut tev_client {
-        tev_client
-            .send(PacketCreateImage {
-                image_name: "out",
-                grab_focus: false,
-                width: image_width as u32,
-                height: image_height as u32,
-                channel_names: &["R", "G", "B"],
-            })
-            .unwrap();
+    if !args.debug_pixels.is_empty() && !cfg!(feature = "enable_debugger") {
+        eprintln!("--debug-pixel needs the enable_debugger feature, ignoring it");
     }
-
-    let aspect_ratio = image_width as Scalar / image_height as Scalar;
-    let mut image_tile_generator = ImageTileGenerator::new(image_width, image_height);
-
-    let total_num_tiles = image_tile_generator.get_num_tiles();
-
-    let pool = threadpool::Builder::new()
-        .thread_name("render_thread".to_owned())
-        .num_threads(
-            thread::available_parallelism()
-                .map(NonZeroUsize::get)
-                .unwrap_or(4),
-        )
-        .build();
-
-    // Camera space direction basis
-    let camera_x = -scene
-        .camera
-        .direction
-        .cross(vec3(0.0, 1.0, 0.0))
-        .normalize();
-    let camera_y = camera_x.cross(scene.camera.direction).normalize();
-    let camera_z = scene.camera.direction.normalize();
-    let camera_basis = Mat3::from([camera_x.into(), camera_y.into(), camera_z.into()]);
-
-    let (image_writer_tx, image_writer_rx) = mpsc::channel();
-
-    // start of rt
-    let rt_start = Instant::now();
-
-    while let Some(tile) = image_tile_generator.get_tile(Rgb([0.0, 0.0, 0.0])) {
-        let scene = scene.clone();
-        let image_writer_tx = image_writer_tx.clone();
-        let seed = fastrand::u64(..);
-        pool.execute(move || {
-            fastrand::seed(seed);
-            // Render tile
-            let mut tile: ImageTile<Rgb<f32>> = tile;
-            while let Some((pixel, x, y)) = tile.next_tile() {
-                #[cfg(feature = "enable_debugger")]
-                debugger::set_should_debug_pixel((x, y) == DEBUG_PIXEL);
-
-                let arena = Bump::new();
-
-                let mut color = Color::origin();
-                for _ in 0..scene.camera.num_samples {
-                    debugger::begin_sample!();
-                    let time = scalar::rand() * scene.camera.exposure_time;
-
-                    let x = x as Scalar + scalar::rand();
-                    let y = y as Scalar + scalar::rand();
-                    let x = (x / image_width as Scalar) * 2.0 - 1.0;
-                    let y = ((y / image_height as Scalar) * 2.0 - 1.0) / aspect_ratio;
-                    let ray_dir = camera_basis * vec3(x, y, scene.camera.sensor_distance);
-
-                    let pc = scene.camera.position;
-                    let pr = scene.camera.position
-                        + camera_basis
-                            * (scene.camera.aperture * random_concentric_disk())
-                                .to_vec()
-                                .extend(0.0);
-                    let wp = ray_dir.normalize();
-                    let pl = pc + scene.camera.focus_distance * wp;
-                    let wr = pl - pr;
-
-                    let ray = Ray::new(pr, wr, time);
-
-                    let sample_color = ray_color(&ray, &scene, &arena);
-                    debugger::end_sample!(sample_color);
-                    if sample_color.x.is_finite()
-                        && sample_color.y.is_finite()
-                        && sample_color.z.is_finite()
-                    {
-                        color += sample_color.to_vec();
-                    }
-                }
-                color /= scene.camera.num_samples as Scalar;
-                debugger::end_pixel!(color);
-                *pixel = Rgb([color.x, color.y, color.z]);
-            }
-
-            #[cfg(feature = "enable_axis")]
-            if tile.location() == (0, 0) {
-                draw_axis(&mut tile, &scene);
-            }
-
-            image_writer_tx.send(Some(tile)).unwrap();
-        });
+    if args.stats.is_some() && !cfg!(feature = "enable_stats") {
+        eprintln!("--stats needs the enable_stats feature, ignoring it");
     }
 
-    // Draw tiles to image preview
-
-    let pool_ender_thread = thread::Builder::new()
-        .name("pool_ender".to_owned())
-        .spawn(move || {
-            pool.join();
-            let end = rt_start.elapsed();
-            println!("Time required: {}", HMSDuration(end));
-            image_writer_tx.send(None).unwrap();
-        })
-        .unwrap();
-
-    let mut output_image = Rgb32FImage::from_pixel(
-        image_width as u32,
-        image_height as u32,
-        Rgb([0.3, 0.3, 0.3]),
-    );
-
-    let mut time = Instant::now();
-
-    let mut num_tiles: usize = 0;
-
-    macro_rules! update_image {
-        () => {
-            if let Some(tev_client) = &mut tev_client {
-                tev_client
-                    .send(PacketUpdateImage {
-                        image_name: "out",
-                        grab_focus: false,
-                        channel_names: &["R", "G", "B"],
-                        channel_offsets: &[0, 1, 2],
-                        channel_strides: &[3, 3, 3],
-                        x: 0,
-                        y: 0,
-                        width: image_width as u32,
-                        height: image_height as u32,
-                        data: &output_image,
-                    })
-                    .unwrap()
-            }
-        };
+    if args.watch {
+        watch(&args, preview);
     }
 
-    while let Some(tile) = image_writer_rx.recv().unwrap() {
-        num_tiles += 1;
-        let (tile_x, tile_y) = tile.location();
-        let (width, height) = tile.dimensions();
-        for x in 0..width {
-            for y in 0..height {
-                let (image_x, image_y) = (x + tile_x, y + tile_y);
-
-                let pixel = *tile.get(x + y * width);
-
-                output_image.put_pixel(image_x as u32, image_y as u32, pixel);
-            }
+    println!("Loading scene...");
+    let scene = load_scene(&args.scene_path);
+    println!("Rendering...");
+    let options = render_options(&args, preview);
+    interrupt::cancel_on_interrupt(options.cancel.clone());
+    let output = if args.navigate {
+        match navigate::navigate(scene, options) {
+            Some(output) => output,
+            None => return,
         }
-        if time.elapsed() > Duration::from_millis(250) {
-            let elapsed_time = rt_start.elapsed();
-            let time_per_tile = elapsed_time / num_tiles as u32;
-            let remaining_tiles = total_num_tiles - num_tiles;
-            let remaining_time = time_per_tile * remaining_tiles as u32;
-
-            println!(
-                "{num_tiles}/{total_num_tiles}; Elapsed: {}, Remaining Time: {}, Time Per Tile: {:?}",
-                HMSDuration(elapsed_time), HMSDuration(remaining_time), time_per_tile,
-            );
+    } else {
+        render(scene, options)
+    };
+    if save_output(&args, output) > 0 {
+        std::process::exit(1);
+    }
+}
 
-            update_image!();
+fn render_options(args: &Args, preview: Option<Box<dyn PreviewSink>>) -> RenderOptions {
+    RenderOptions {
+        threads: args.threads,
+        seed: args.seed.unwrap_or(RENDER_SEED),
+        tile_size: args.tile_size,
+        tile_order: args.tile_order,
+        debug_pixels: args.debug_pixels.clone(),
+        preview,
+        progress: Some(Box::new(PrintProgress::default())),
+        cancel: CancelToken::new(),
+        overrides: args.overrides(),
+    }
+}
 
-            time = Instant::now();
+/// Prints the render's stats and writes its images, returns the number of failed tiles
+fn save_output(args: &Args, output: RenderOutput) -> usize {
+    let RenderOutput {
+        image: output_image,
+        albedo,
+        alpha,
+        sample_counts,
+        layers,
+        stats,
+        failed_tiles,
+        ..
+    } = output;
+    if stats.cancelled {
+        println!("Render cancelled, saving the partial image");
+    }
+    println!("Time required: {}", HMSDuration(stats.wall_time));
+    println!(
+        "Rays: {} primary, {} shadow, {} total ({:.2} Mrays/s); Average bounce depth: {:.2}",
+        stats.primary_rays,
+        stats.shadow_rays,
+        stats.total_rays,
+        stats.rays_per_second() / 1e6,
+        stats.average_bounce_depth,
+    );
+    #[cfg(feature = "enable_stats")]
+    {
+        let report = pbrtrs_core::stats::report();
+        println!("{report}");
+        if let Some(stats_path) = &args.stats {
+            std::fs::write(stats_path, report.to_json()).unwrap();
         }
     }
 
-    pool_ender_thread.join().unwrap();
-
-    #[cfg(feature = "enable_oidn")]
-    {
-        use pbrtrs_core::postprocess;
-        println!("Denoising");
-        let time = Instant::now();
-        postprocess::denoise(&mut output_image);
-        println!("Time to denoise: {}", HMSDuration(time.elapsed()));
+    save_render(&output_image, &alpha, &args.output).unwrap();
+    if let Some(albedo_path) = &args.albedo {
+        save_image(&albedo, albedo_path).unwrap();
+    }
+    if let Some(layers_path) = &args.layers {
+        layers.save_exr(layers_path).unwrap();
+    }
+    if let Some(heatmap_path) = &args.sample_heatmap {
+        save_image(&postprocess::sample_heatmap(&sample_counts), heatmap_path).unwrap();
     }
 
-    update_image!();
+    if !failed_tiles.is_empty() {
+        eprintln!(
+            "{} of the tiles failed to render and are magenta in the output",
+            failed_tiles.len()
+        );
+    }
+    failed_tiles.len()
+}
 
-    #[cfg(feature = "enable_debugger")]
-    {
-        let debug = debug_info().lock().unwrap();
-        debug.save(&scene, "debug_out.xml", DEBUG_PIXEL);
+/// Renders the scene again every time one of its files changes, cancelling the render in flight.
+/// The preview is kept, so tev updates the same image.
+fn watch(args: &Args, mut preview: Option<Box<dyn PreviewSink>>) -> ! {
+    loop {
+        println!("Loading scene...");
+        // A broken edit shouldn't end the session, the panic hook has already printed why
+        let scene = match panic::catch_unwind(|| load_scene(&args.scene_path)) {
+            Ok(scene) => scene,
+            Err(_) => {
+                println!("Waiting for the scene to change...");
+                FileWatcher::new([args.scene_path.clone()]).wait();
+                continue;
+            }
+        };
+        let watcher = FileWatcher::for_scene(&scene);
+        println!("Rendering...");
+        let (mut output, changed) = render_watched(scene, render_options(args, preview), &watcher);
+        preview = output.preview.take();
+        if changed {
+            println!("Scene changed, restarting the render");
+            continue;
+        }
+        save_output(args, output);
+        println!("Waiting for the scene to change...");
+        watcher.wait();
     }
+}
 
-    output_image.save("./out.exr").unwrap();
+/// Connects to a running tev at `TEV_HOST`, or spawns the one at `TEV_PATH`. The preview is
+/// optional, so failures only print a warning.
+fn connect_tev() -> Option<TevClient> {
+    let client = if let Ok(host) = std::env::var("TEV_HOST") {
+        TcpStream::connect(&host)
+            .map(TevClient::wrap)
+            .map_err(|err| format!("Couldn't connect to tev at {host}: {err}"))
+    } else {
+        match std::env::var("TEV_PATH") {
+            Ok(path) if !path.is_empty() => TevClient::spawn(Command::new(&path))
+                .map_err(|err| format!("Couldn't start tev at {path}: {err:?}")),
+            _ => Err("Neither TEV_HOST nor TEV_PATH is set".to_owned()),
+        }
+    };
+    client
+        .map_err(|message| eprintln!("{message}, rendering without a preview"))
+        .ok()
 }
 
 #[repr(transparent)]
@@ -274,49 +217,3 @@ impl Display for HMSDuration {
         }
     }
 }
-
-#[cfg(feature = "enable_axis")]
-fn draw_axis(tile: &mut ImageTile<R8G8B8Color>, scene: &pbrtrs_core::scene::Scene) {
-    use crate::image_tiler::TILE_SIZE;
-    use cgmath::{point3, vec2, SquareMatrix, Transform};
-    use pbrtrs_core::types::color;
-
-    let root_pt = point3(0.0, 0.0, 0.0);
-    let x_pt = point3(1.0, 0.0, 0.0);
-    let y_pt = point3(0.0, 1.0, 0.0);
-    let z_pt = point3(0.0, 0.0, 1.0);
-
-    let camera_x = -scene
-        .camera
-        .direction
-        .cross(vec3(0.0, 1.0, 0.0))
-        .normalize();
-    let camera_y = camera_x.cross(scene.camera.direction).normalize();
-    let camera_z = scene.camera.direction.normalize();
-    // Ax = b, A: camera_basis, x: camera_space_coords, b: world_space_coords
-    let camera_basis = Mat3::from([camera_x.into(), camera_y.into(), camera_z.into()]);
-    let world_basis = camera_basis.invert().unwrap();
-
-    let root_pt = world_basis.transform_point(root_pt).xy();
-    let x_pt = world_basis.transform_point(x_pt).xy();
-    let y_pt = world_basis.transform_point(y_pt).xy();
-    let z_pt = world_basis.transform_point(z_pt).xy();
-
-    let lines = [
-        (x_pt - root_pt, color::RED),
-        (y_pt - root_pt, color::GREEN),
-        (z_pt - root_pt, color::BLUE),
-    ];
-
-    for t in 0..20 {
-        let t = t as Scalar / 20.0;
-        for (line, color) in lines {
-            let pt = root_pt + line * t;
-            let pt = (pt + vec2(1.0, 1.0) / 2.0) * TILE_SIZE as Scalar;
-            let pt = pt.map(|v| v as usize);
-            if pt.x < TILE_SIZE && pt.y < TILE_SIZE {
-                *tile.get_mut(pt.x + pt.y * TILE_SIZE).unwrap() = R8G8B8Color::from(color);
-            }
-        }
-    }
-}
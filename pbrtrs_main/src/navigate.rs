@@ -0,0 +1,295 @@
+use crate::render::{render, RenderOptions, RenderOutput, RenderOverrides};
+use cgmath::{vec3, InnerSpace};
+use pbrtrs_core::scene::{Camera, Scene};
+use pbrtrs_core::types::{Scalar, Vec3};
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Samples per pixel of the previews rendered while moving
+const PREVIEW_SAMPLES: usize = 4;
+/// Previews are rendered at this fraction of the width and height
+const PREVIEW_SCALE: usize = 4;
+/// Distance moved per key press, as a fraction of the camera's focus distance
+const STEP_FRACTION: Scalar = 0.1;
+
+pub const KEYS: &str = "\
+W/S or Up/Down: forward/back, A/D or Left/Right: left/right, E/Q: up/down
+Enter: render at full quality and save, Ctrl-C or Ctrl-D: quit without rendering";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Forward,
+    Back,
+    Left,
+    Right,
+    Up,
+    Down,
+    Render,
+    Quit,
+}
+
+impl Key {
+    /// Keys in a chunk read from the terminal, unknown bytes are skipped
+    pub fn parse(mut bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        while let Some((&byte, rest)) = bytes.split_first() {
+            bytes = rest;
+            let key = match byte {
+                b'w' | b'W' => Key::Forward,
+                b's' | b'S' => Key::Back,
+                b'a' | b'A' => Key::Left,
+                b'd' | b'D' => Key::Right,
+                b'e' | b'E' => Key::Up,
+                b'q' | b'Q' => Key::Down,
+                b'\r' | b'\n' => Key::Render,
+                // Ctrl-C and Ctrl-D
+                0x03 | 0x04 => Key::Quit,
+                // Arrow keys are sent as `ESC [ A` to `ESC [ D`
+                0x1b => match bytes {
+                    [b'[', arrow @ b'A'..=b'D', rest @ ..] => {
+                        bytes = rest;
+                        match arrow {
+                            b'A' => Key::Forward,
+                            b'B' => Key::Back,
+                            b'C' => Key::Right,
+                            _ => Key::Left,
+                        }
+                    }
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            keys.push(key);
+        }
+        keys
+    }
+
+    /// Displacement of the camera in units of the step size, `None` for keys that don't move
+    fn movement(self, camera: &Camera) -> Option<Vec3> {
+        // The basis' x axis points to the right of the image
+        let (_, basis) = camera.frame_at(0.0);
+        let movement = match self {
+            Key::Forward => camera.direction.normalize(),
+            Key::Back => -camera.direction.normalize(),
+            Key::Right => basis.x,
+            Key::Left => -basis.x,
+            Key::Up => vec3(0.0, 1.0, 0.0),
+            Key::Down => vec3(0.0, -1.0, 0.0),
+            Key::Render | Key::Quit => return None,
+        };
+        Some(movement)
+    }
+}
+
+/// Moves the camera for every movement key and returns the first key that ends moving, if any
+pub fn apply_keys(camera: &mut Camera, keys: impl IntoIterator<Item = Key>) -> Option<Key> {
+    let step = camera.focus_distance * STEP_FRACTION;
+    for key in keys {
+        match key.movement(camera) {
+            Some(movement) => camera.position += movement * step,
+            None => return Some(key),
+        }
+    }
+    None
+}
+
+/// Quick, low resolution settings for a preview of `overrides`
+fn preview_overrides(overrides: RenderOverrides, camera: &Camera) -> RenderOverrides {
+    let width = overrides.width.unwrap_or(camera.width);
+    let height = overrides.height.unwrap_or(camera.height);
+    RenderOverrides {
+        num_samples: Some(PREVIEW_SAMPLES),
+        width: Some((width / PREVIEW_SCALE).max(1)),
+        height: Some((height / PREVIEW_SCALE).max(1)),
+        ..overrides
+    }
+}
+
+/// Renders `scene` as seen from `camera`, the scene's own camera is replaced
+pub fn render_view(mut scene: Scene, camera: &Camera, options: RenderOptions) -> RenderOutput {
+    scene.camera = camera.clone();
+    render(scene, options)
+}
+
+/// Lets the camera be moved from the terminal, re-rendering a low sample preview after every
+/// move, until a full quality render is requested. Returns that render, or `None` when the
+/// session is quit. `options` are used for the full render, previews keep its preview sink.
+pub fn navigate(scene: Scene, mut options: RenderOptions) -> Option<RenderOutput> {
+    let raw_mode = RawMode::enable();
+    let keys = read_keys();
+    println!("{KEYS}");
+
+    let mut camera = scene.camera.clone();
+    let mut scene = scene;
+    loop {
+        let preview = render_view(
+            scene,
+            &camera,
+            RenderOptions {
+                threads: options.threads,
+                seed: options.seed,
+                tile_size: options.tile_size,
+                tile_order: options.tile_order,
+                preview: options.preview.take(),
+                overrides: preview_overrides(options.overrides, &camera),
+                ..Default::default()
+            },
+        );
+        options.preview = preview.preview;
+        scene = preview.scene;
+        // As it's written in a scene file, to keep the framing
+        let p = camera.position;
+        println!("position = [{}, {}, {}]", p.x, p.y, p.z);
+
+        // Moves made while the preview rendered are applied together
+        let key = keys.recv().ok()?;
+        let pending = std::iter::once(key).chain(keys.try_iter());
+        match apply_keys(&mut camera, pending) {
+            Some(Key::Render) => break,
+            Some(_) => return None,
+            None => {}
+        }
+    }
+    // Ctrl-C cancels the full render as usual
+    drop(raw_mode);
+    println!("Rendering...");
+    Some(render_view(scene, &camera, options))
+}
+
+/// Reads keys from stdin on a thread of its own. The channel closes when stdin does.
+fn read_keys() -> Receiver<Key> {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("keys".to_owned())
+        .spawn(move || {
+            let mut buf = [0; 64];
+            while let Ok(read @ 1..) = io::stdin().read(&mut buf) {
+                for key in Key::parse(&buf[..read]) {
+                    if tx.send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+        })
+        .unwrap();
+    rx
+}
+
+/// Puts the terminal in non-canonical mode without echo while alive, so keys arrive as they are
+/// pressed. Only supported on unix, elsewhere keys only arrive with Enter, which also starts the
+/// full render.
+struct RawMode {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> Self {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            // Not a terminal
+            return RawMode { original: None };
+        }
+        let original = termios;
+        // Ctrl-C arrives as a key instead of a signal, so the terminal is always restored
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        RawMode {
+            original: Some(original),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(original) = &self.original {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+        }
+    }
+}
+
+#[cfg(not(unix))]
+impl RawMode {
+    fn enable() -> Self {
+        RawMode {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{assert_abs_diff_eq, point3};
+    use pbrtrs_core::scene::{CameraBuilder, MaterialBuilder, Object, SceneBuilder, Shape};
+    use pbrtrs_core::types::color::WHITE;
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(
+            Key::parse(b"wA\x1b[Dx\x1b[C\x1b\re\x03"),
+            [
+                Key::Forward,
+                Key::Left,
+                Key::Left,
+                Key::Right,
+                Key::Render,
+                Key::Up,
+                Key::Quit
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_move_camera() {
+        let mut camera = CameraBuilder::new()
+            .position(point3(0.0, 0.0, 0.0))
+            .direction(vec3(0.0, 0.0, 2.0))
+            .focus_distance(10.0)
+            .build();
+
+        let keys = [Key::Forward, Key::Forward, Key::Up, Key::Right];
+        assert_eq!(apply_keys(&mut camera, keys), None);
+        assert_abs_diff_eq!(camera.position, point3(1.0, 1.0, 2.0), epsilon = 1e-5);
+
+        // Keys after a render are left alone
+        let keys = [Key::Back, Key::Render, Key::Forward];
+        assert_eq!(apply_keys(&mut camera, keys), Some(Key::Render));
+        assert_abs_diff_eq!(camera.position, point3(1.0, 1.0, 1.0), epsilon = 1e-5);
+    }
+
+    #[test]
+    fn view_renders_from_camera() {
+        let scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().resolution(8, 8).num_samples(1).build())
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new().emission(WHITE).build(),
+            ))
+            .build();
+        let mut camera = scene.camera.clone();
+        camera.direction = vec3(0.0, 0.0, -1.0);
+
+        let overrides = preview_overrides(RenderOverrides::default(), &camera);
+        assert_eq!(
+            (overrides.width, overrides.height, overrides.num_samples),
+            (Some(2), Some(2), Some(PREVIEW_SAMPLES))
+        );
+
+        let output = render_view(
+            scene,
+            &camera,
+            RenderOptions {
+                threads: Some(1),
+                ..Default::default()
+            },
+        );
+        // Facing away from the sphere, the returned scene keeps the moved camera
+        assert!(output.image.pixels().all(|pixel| pixel.0 == [0.0; 3]));
+        assert_eq!(output.scene.camera.direction, camera.direction);
+    }
+}
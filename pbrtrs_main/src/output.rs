@@ -0,0 +1,177 @@
+use image::{ImageBuffer, Luma, Rgb, Rgb32FImage, Rgba, Rgba32FImage};
+use std::path::Path;
+
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+pub type AlphaImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Seed of the dither noise, so the same render always gives the same file
+const DITHER_SEED: u64 = 0x2d35_8dcc_aa6c_78a5;
+
+/// File format of a saved image, chosen by the extension of its path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Linear 32-bit float
+    Exr,
+    /// sRGB encoded 16-bit
+    Png,
+    /// sRGB encoded 16-bit
+    Tiff,
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("exr") => Ok(OutputFormat::Exr),
+            Some("png") => Ok(OutputFormat::Png),
+            Some("tif" | "tiff") => Ok(OutputFormat::Tiff),
+            _ => Err(format!(
+                "Can't save '{}', the extension must be .exr, .png, .tif or .tiff",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Writes `image` in the format given by the extension of `path`. PNG and TIFF are clamped to
+/// [0, 1], so tone mapping should have been applied before.
+pub fn save_image(image: &Rgb32FImage, path: &Path) -> Result<(), String> {
+    let result = match OutputFormat::from_path(path)? {
+        OutputFormat::Exr => image.save_with_format(path, image::ImageFormat::OpenExr),
+        OutputFormat::Png => to_rgb16(image).save_with_format(path, image::ImageFormat::Png),
+        OutputFormat::Tiff => to_rgb16(image).save_with_format(path, image::ImageFormat::Tiff),
+    };
+    result.map_err(|err| format!("Couldn't save '{}': {err}", path.display()))
+}
+
+/// Writes a render like [`save_image`], with `alpha` as its alpha channel in EXR. PNG and TIFF
+/// are written opaque, so the background still shows in image viewers.
+pub fn save_render(image: &Rgb32FImage, alpha: &AlphaImage, path: &Path) -> Result<(), String> {
+    if OutputFormat::from_path(path)? != OutputFormat::Exr {
+        return save_image(image, path);
+    }
+    with_alpha(image, alpha)
+        .save_with_format(path, image::ImageFormat::OpenExr)
+        .map_err(|err| format!("Couldn't save '{}': {err}", path.display()))
+}
+
+pub fn with_alpha(image: &Rgb32FImage, alpha: &AlphaImage) -> Rgba32FImage {
+    Rgba32FImage::from_fn(image.width(), image.height(), |x, y| {
+        let Rgb([r, g, b]) = *image.get_pixel(x, y);
+        Rgba([r, g, b, alpha.get_pixel(x, y)[0]])
+    })
+}
+
+/// Encodes a linear image with the sRGB transfer curve at 16 bits. Triangular dither noise of up
+/// to one step is added before rounding, which turns banding in smooth gradients into fine noise.
+pub fn to_rgb16(image: &Rgb32FImage) -> Rgb16Image {
+    let rng = fastrand::Rng::with_seed(DITHER_SEED);
+    let max = u16::MAX as f32;
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        Rgb(image.get_pixel(x, y).0.map(|c| {
+            let dither = rng.f32() + rng.f32() - 1.0;
+            (srgb_encode(c) * max + dither).round().clamp(0.0, max) as u16
+        }))
+    })
+}
+
+/// sRGB transfer function from linear light to the encoded value, clamped to [0, 1]
+fn srgb_encode(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn format_from_extension() {
+        let format = |path: &str| OutputFormat::from_path(&PathBuf::from(path));
+        assert_eq!(format("out.exr"), Ok(OutputFormat::Exr));
+        assert_eq!(format("renders/out.PNG"), Ok(OutputFormat::Png));
+        assert_eq!(format("out.tif"), Ok(OutputFormat::Tiff));
+        assert_eq!(format("out.tiff"), Ok(OutputFormat::Tiff));
+        assert!(format("out.jpg").is_err());
+        assert!(format("out").is_err());
+    }
+
+    #[test]
+    fn dithering_keeps_gradients_on_average() {
+        // A gradient much finer than a 16-bit step, each value repeated over a row
+        let step = 1.0 / u16::MAX as f32;
+        let image = Rgb32FImage::from_fn(1000, 16, |x, _| {
+            let encoded = 0.5 + x as f32 * step / 100.0;
+            // Inverse of the sRGB curve, so the encoded values are the gradient
+            let linear = ((encoded + 0.055) / 1.055).powf(2.4);
+            Rgb([linear; 3])
+        });
+        let encoded = to_rgb16(&image);
+        for x in (0..1000).step_by(100) {
+            let mean = (0..16)
+                .map(|y| encoded.get_pixel(x, y)[0] as f32)
+                .sum::<f32>()
+                / 16.0;
+            let expected = (0.5 + x as f32 * step / 100.0) * u16::MAX as f32;
+            assert!((mean - expected).abs() < 0.5, "{mean} {expected}");
+        }
+        // Rows differ by the noise, rather than all rounding the same way
+        assert!((0..16).any(|y| encoded.get_pixel(0, y) != encoded.get_pixel(0, 0)));
+
+        let extremes = to_rgb16(&Rgb32FImage::from_fn(64, 1, |x, _| {
+            Rgb([0.0, 1.0, if x % 2 == 0 { -1.0 } else { 4.0 }])
+        }));
+        for pixel in extremes.pixels() {
+            assert!(pixel[0] <= 1 && pixel[1] >= u16::MAX - 1);
+            assert!(pixel[2] <= 1 || pixel[2] >= u16::MAX - 1);
+        }
+    }
+
+    #[test]
+    fn saves_16_bit_images() {
+        let image = Rgb32FImage::from_fn(4, 3, |x, y| Rgb([x as f32 / 3.0, y as f32 / 2.0, 0.25]));
+        for extension in ["png", "tiff"] {
+            let path = std::env::temp_dir()
+                .join(format!("pbrtrs_output_{}.{extension}", std::process::id()));
+            save_image(&image, &path).unwrap();
+            let saved = image::open(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(saved.color(), image::ColorType::Rgb16);
+            let saved = saved.into_rgb16();
+            assert_eq!(saved.dimensions(), (4, 3));
+            let [r, g, _] = saved.get_pixel(3, 2).0;
+            assert!(r >= u16::MAX - 1 && g >= u16::MAX - 1);
+        }
+        assert!(save_image(&image, Path::new("out.jpg")).is_err());
+    }
+
+    #[test]
+    fn saves_alpha_in_exr() {
+        let image = Rgb32FImage::from_fn(4, 3, |x, y| Rgb([x as f32, y as f32, 2.5]));
+        let alpha = AlphaImage::from_fn(4, 3, |x, _| Luma([x as f32 / 4.0]));
+        let path = |extension: &str| {
+            std::env::temp_dir().join(format!("pbrtrs_alpha_{}.{extension}", std::process::id()))
+        };
+
+        let exr = path("exr");
+        save_render(&image, &alpha, &exr).unwrap();
+        let saved = image::open(&exr).unwrap();
+        std::fs::remove_file(&exr).unwrap();
+        assert_eq!(saved.into_rgba32f(), with_alpha(&image, &alpha));
+
+        let png = path("png");
+        save_render(&image, &alpha, &png).unwrap();
+        let saved = image::open(&png).unwrap();
+        std::fs::remove_file(&png).unwrap();
+        assert_eq!(saved.color(), image::ColorType::Rgb16);
+    }
+}
@@ -0,0 +1,1781 @@
+use crate::image_tiler::{ImageTile, ImageTileGenerator, TileOrder, TILE_SIZE};
+use crate::output::AlphaImage;
+use crate::HMSDuration;
+use bumpalo::Bump;
+use image::{ImageBuffer, Luma, Rgb, Rgb32FImage};
+use pbrtrs_core::debugger;
+use pbrtrs_core::intersect::RAY_PACKET;
+use pbrtrs_core::postprocess::PostProcessChain;
+use pbrtrs_core::raytracer::{
+    trace_camera_ray, trace_camera_ray_from, PathComponents, PathSample, RenderMode,
+};
+use pbrtrs_core::scene::{Camera, Scene, Visibility};
+use pbrtrs_core::stats::{self, RayStats};
+use pbrtrs_core::types::color::{BLACK, WHITE};
+use pbrtrs_core::types::{scalar, Color, Ray, Scalar};
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::iter;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tev_client::{PacketCreateImage, PacketUpdateImage, TevClient};
+
+/// Camera settings that replace the scene's values for a single render.
+///
+/// Overrides always win over the values in the scene file, which are used for anything left
+/// as `None`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOverrides {
+    pub num_samples: Option<usize>,
+    pub bounce_limit: Option<usize>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub mode: Option<RenderMode>,
+}
+
+impl RenderOverrides {
+    pub fn apply(&self, camera: &mut Camera) {
+        // A fixed sample count, adaptive sampling is turned off
+        if let Some(num_samples) = self.num_samples {
+            camera.num_samples = num_samples;
+            camera.max_samples = num_samples;
+        }
+        if let Some(bounce_limit) = self.bounce_limit {
+            camera.bounce_limit = bounce_limit;
+        }
+        if let Some(width) = self.width {
+            camera.width = width;
+        }
+        if let Some(height) = self.height {
+            camera.height = height;
+        }
+        if let Some(mode) = self.mode {
+            camera.mode = mode;
+        }
+    }
+}
+
+/// Progress of a render, reported after every finished tile
+#[derive(Clone, Copy)]
+pub struct Progress<'a> {
+    pub tiles_completed: usize,
+    pub total_tiles: usize,
+    pub elapsed: Duration,
+    /// Estimated time until the render finishes
+    pub remaining: Duration,
+    /// The image so far, before post processing. Tiles that haven't finished are gray.
+    pub image: &'a Rgb32FImage,
+}
+
+impl<'a> Progress<'a> {
+    fn new(
+        tiles_completed: usize,
+        total_tiles: usize,
+        elapsed: Duration,
+        image: &'a Rgb32FImage,
+    ) -> Self {
+        let time_per_tile = elapsed / tiles_completed as u32;
+        Progress {
+            tiles_completed,
+            total_tiles,
+            elapsed,
+            remaining: time_per_tile * (total_tiles - tiles_completed) as u32,
+            image,
+        }
+    }
+}
+
+impl Debug for Progress<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("tiles_completed", &self.tiles_completed)
+            .field("total_tiles", &self.total_tiles)
+            .field("elapsed", &self.elapsed)
+            .field("remaining", &self.remaining)
+            // Only the size, the pixels would drown out everything else
+            .field("image", &self.image.dimensions())
+            .finish()
+    }
+}
+
+/// Receives the progress of a render, called on the thread that started it
+pub trait ProgressReporter: Send {
+    fn report(&mut self, progress: &Progress);
+}
+
+impl<F: FnMut(&Progress) + Send> ProgressReporter for F {
+    fn report(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Prints progress to stdout at most every 250ms
+pub struct PrintProgress {
+    last_print: Instant,
+}
+
+impl Default for PrintProgress {
+    fn default() -> Self {
+        PrintProgress {
+            last_print: Instant::now(),
+        }
+    }
+}
+
+impl ProgressReporter for PrintProgress {
+    fn report(&mut self, progress: &Progress) {
+        if self.last_print.elapsed() > Duration::from_millis(250) {
+            println!(
+                "{}/{}; Elapsed: {}, Remaining Time: {}, Time Per Tile: {:?}",
+                progress.tiles_completed,
+                progress.total_tiles,
+                HMSDuration(progress.elapsed),
+                HMSDuration(progress.remaining),
+                progress.elapsed / progress.tiles_completed as u32,
+            );
+            self.last_print = Instant::now();
+        }
+    }
+}
+
+/// Receives the image as it renders, e.g. to display it. The render stops sending to a sink after
+/// it returns an error.
+pub trait PreviewSink {
+    /// Called once before any update
+    fn create(&mut self, width: u32, height: u32) -> io::Result<()>;
+    /// Called with the whole image as tiles complete, and with the final image
+    fn update(&mut self, image: &Rgb32FImage) -> io::Result<()>;
+}
+
+impl PreviewSink for TevClient {
+    fn create(&mut self, width: u32, height: u32) -> io::Result<()> {
+        self.send(PacketCreateImage {
+            image_name: "out",
+            grab_focus: false,
+            width,
+            height,
+            channel_names: &["R", "G", "B"],
+        })
+    }
+
+    fn update(&mut self, image: &Rgb32FImage) -> io::Result<()> {
+        self.send(PacketUpdateImage {
+            image_name: "out",
+            grab_focus: false,
+            channel_names: &["R", "G", "B"],
+            channel_offsets: &[0, 1, 2],
+            channel_strides: &[3, 3, 3],
+            x: 0,
+            y: 0,
+            width: image.width(),
+            height: image.height(),
+            data: image,
+        })
+    }
+}
+
+/// Sends to the preview, dropping it the first time it fails
+fn send_preview(
+    preview: &mut Option<Box<dyn PreviewSink>>,
+    send: impl FnOnce(&mut dyn PreviewSink) -> io::Result<()>,
+) {
+    if let Some(sink) = preview {
+        if let Err(err) = send(sink.as_mut()) {
+            eprintln!("Lost the preview, rendering without it: {err}");
+            *preview = None;
+        }
+    }
+}
+
+/// Stops a render from another thread. Render threads check it between pixels, the render then
+/// returns the partial image.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct RenderOptions {
+    /// Number of render threads, defaults to the available parallelism
+    pub threads: Option<usize>,
+    /// Seed of the random numbers used for every sample, renders with the same seed are
+    /// identical whatever the thread count and tile order
+    pub seed: u64,
+    /// Width and height of the tiles the image is split into, defaults to [`TILE_SIZE`]
+    pub tile_size: Option<usize>,
+    pub tile_order: TileOrder,
+    /// Pixels whose paths are recorded to `debug_out.xml` when built with the `enable_debugger`
+    /// feature
+    pub debug_pixels: Vec<(usize, usize)>,
+    /// Progressively displays the image while rendering
+    pub preview: Option<Box<dyn PreviewSink>>,
+    pub progress: Option<Box<dyn ProgressReporter>>,
+    pub cancel: CancelToken,
+    pub overrides: RenderOverrides,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderStats {
+    pub primary_rays: u64,
+    pub total_rays: u64,
+    pub shadow_rays: u64,
+    /// Average number of bounces per camera path
+    pub average_bounce_depth: f64,
+    pub wall_time: Duration,
+    /// Whether the render was cancelled, pixels that weren't rendered are black
+    pub cancelled: bool,
+}
+
+impl RenderStats {
+    fn new(rays: &RayStats, wall_time: Duration, cancelled: bool) -> Self {
+        RenderStats {
+            primary_rays: rays.primary_rays(),
+            total_rays: rays.total_rays(),
+            shadow_rays: rays.shadow_rays(),
+            average_bounce_depth: rays.average_bounce_depth(),
+            wall_time,
+            cancelled,
+        }
+    }
+
+    pub fn rays_per_second(&self) -> f64 {
+        self.total_rays as f64 / self.wall_time.as_secs_f64()
+    }
+}
+
+/// The render split by light path, the layers sum to `beauty`
+pub struct RenderLayers {
+    /// The render before post processing
+    pub beauty: Rgb32FImage,
+    pub emission: Rgb32FImage,
+    pub direct: Rgb32FImage,
+    pub diffuse_indirect: Rgb32FImage,
+    pub specular_indirect: Rgb32FImage,
+    /// The render split by light group, indexed by group slot and named after the group. Empty
+    /// if the scene has no light groups, otherwise these also sum to `beauty`.
+    pub groups: Vec<(String, Rgb32FImage)>,
+    /// Opacity of the shadows on shadow catchers in every channel, to composite them over a
+    /// photo as alpha. Not part of the sum.
+    pub shadow: Rgb32FImage,
+}
+
+impl RenderLayers {
+    fn new(width: u32, height: u32, light_groups: &[String]) -> Self {
+        let groups = if light_groups.is_empty() {
+            Vec::new()
+        } else {
+            iter::once("ungrouped")
+                .chain(light_groups.iter().map(String::as_str))
+                .map(|name| (name.to_owned(), Rgb32FImage::new(width, height)))
+                .collect()
+        };
+        RenderLayers {
+            beauty: Rgb32FImage::new(width, height),
+            emission: Rgb32FImage::new(width, height),
+            direct: Rgb32FImage::new(width, height),
+            diffuse_indirect: Rgb32FImage::new(width, height),
+            specular_indirect: Rgb32FImage::new(width, height),
+            groups,
+            shadow: Rgb32FImage::new(width, height),
+        }
+    }
+
+    /// Layers by the name they are saved under
+    pub fn named(&self) -> [(&'static str, &Rgb32FImage); 5] {
+        [
+            ("beauty", &self.beauty),
+            ("emission", &self.emission),
+            ("direct", &self.direct),
+            ("diffuse_indirect", &self.diffuse_indirect),
+            ("specular_indirect", &self.specular_indirect),
+        ]
+    }
+
+    /// Writes every layer to a single multi-part EXR, light groups are written as `light_<group>`
+    /// followed by `shadow`
+    pub fn save_exr(&self, path: impl AsRef<Path>) -> exr::error::UnitResult {
+        use exr::prelude::*;
+
+        let (width, height) = self.beauty.dimensions();
+        let size = Vec2(width as usize, height as usize);
+        let layers = self
+            .named()
+            .into_iter()
+            .map(|(name, image)| (name.to_owned(), image))
+            .chain(
+                self.groups
+                    .iter()
+                    .map(|(name, image)| (format!("light_{name}"), image)),
+            )
+            .chain(iter::once(("shadow".to_owned(), &self.shadow)))
+            .map(|(name, image)| {
+                Layer::new(
+                    size,
+                    LayerAttributes::named(name.as_str()),
+                    Encoding::FAST_LOSSLESS,
+                    SpecificChannels::rgb(move |position: Vec2<usize>| {
+                        let Rgb([r, g, b]) =
+                            *image.get_pixel(position.x() as u32, position.y() as u32);
+                        (r, g, b)
+                    }),
+                )
+            })
+            .collect::<Vec<_>>();
+        Image::from_layers(
+            ImageAttributes::new(IntegerBounds::from_dimensions(size)),
+            layers,
+        )
+        .write()
+        .to_file(path)
+    }
+}
+
+pub struct RenderOutput {
+    /// The post processed render
+    pub image: Rgb32FImage,
+    /// Reflectance of the first surface seen through each pixel, for the denoiser
+    pub albedo: Rgb32FImage,
+    /// Fraction of each pixel covered by the scene, see [`PathSample::alpha`]. Unaffected by
+    /// post processing.
+    pub alpha: AlphaImage,
+    /// Number of samples taken for each pixel, which adaptive sampling varies
+    pub sample_counts: ImageBuffer<Luma<u32>, Vec<u32>>,
+    pub layers: RenderLayers,
+    pub stats: RenderStats,
+    /// Tiles whose render thread panicked, they are magenta in `image`
+    pub failed_tiles: Vec<FailedTile>,
+    /// The preview the render was given, unless it failed, so the next render can reuse it
+    pub preview: Option<Box<dyn PreviewSink>>,
+    /// The rendered scene with the overrides applied, so it can be rendered again without
+    /// reloading
+    pub scene: Scene,
+}
+
+/// Region of the image whose render thread panicked
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedTile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    /// The panic message
+    pub message: String,
+}
+
+enum TileResult {
+    Rendered(ImageTile<TilePixel>),
+    Failed(FailedTile),
+}
+
+type TileRenderer = fn(&mut ImageTile<TilePixel>, &Scene, &RayStats, u64, &CancelToken);
+
+/// Everything accumulated for a rendered pixel
+#[derive(Debug, Clone, Copy)]
+struct TilePixel {
+    color: Rgb<f32>,
+    albedo: Rgb<f32>,
+    components: PathComponents,
+    shadow: Scalar,
+    alpha: Scalar,
+    samples: u32,
+}
+
+/// Combines a seed with a value into a new well mixed seed, using the splitmix64 finalizer
+fn mix_seed(seed: u64, value: u64) -> u64 {
+    let mut z = seed.wrapping_add(value.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Running mean and variance of the luminance of a pixel's samples
+#[derive(Debug, Default)]
+struct LuminanceEstimate {
+    count: usize,
+    sum: Scalar,
+    sum_squares: Scalar,
+}
+
+impl LuminanceEstimate {
+    fn add(&mut self, luminance: Scalar) {
+        self.count += 1;
+        self.sum += luminance;
+        self.sum_squares += luminance * luminance;
+    }
+
+    /// Whether the 95% confidence interval of the mean is within `threshold` times the mean
+    fn converged(&self, threshold: Scalar) -> bool {
+        if self.count < 2 {
+            return false;
+        }
+        let n = self.count as Scalar;
+        let mean = self.sum / n;
+        let variance = ((self.sum_squares - self.sum * mean) / (n - 1.0)).max(0.0);
+        1.96 * (variance / n).sqrt() <= threshold * mean
+    }
+}
+
+thread_local! {
+    /// Arena of the render thread, kept between the tiles it renders so it only grows once
+    static ARENA: RefCell<Bump> = RefCell::new(Bump::new());
+}
+
+fn render_tile(
+    tile: &mut ImageTile<TilePixel>,
+    scene: &Scene,
+    stats: &RayStats,
+    seed: u64,
+    cancel: &CancelToken,
+) {
+    let (tile_x, tile_y) = tile.location();
+    let tile_seed = mix_seed(mix_seed(seed, tile_x as u64), tile_y as u64);
+    // BxDFs only live for one sample, so the thread's arena is reset and reused for every sample
+    ARENA.with(|arena| {
+        let mut arena = arena.borrow_mut();
+        // A sample of the thread's last tile may have panicked before resetting it
+        arena.reset();
+        if traces_packets(&scene.camera) {
+            render_blocks(tile, tile_seed, scene, stats, &mut arena, cancel);
+            return;
+        }
+        let mut pixel_index = 0;
+        while let Some((pixel, x, y)) = tile.next_tile() {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let pixel_seed = mix_seed(tile_seed, pixel_index);
+            pixel_index += 1;
+            *pixel = render_pixel(x, y, pixel_seed, scene, stats, &mut arena);
+        }
+    });
+
+    #[cfg(feature = "enable_axis")]
+    if tile.location() == (0, 0) {
+        draw_axis(tile, scene);
+    }
+}
+
+/// Whether camera rays are traced in packets over blocks of pixels. `Time` measures each pixel
+/// alone, and the debugger follows one pixel's samples at a time.
+fn traces_packets(camera: &Camera) -> bool {
+    camera.mode != RenderMode::Time && !cfg!(feature = "enable_debugger")
+}
+
+/// Renders the tile in 2x2 blocks of pixels, whose camera rays are coherent enough to intersect
+/// the scene as one packet. Pixels get the same seeds as in [`render_tile`], so the image is the
+/// same as rendering them one at a time.
+fn render_blocks(
+    tile: &mut ImageTile<TilePixel>,
+    tile_seed: u64,
+    scene: &Scene,
+    stats: &RayStats,
+    arena: &mut Bump,
+    cancel: &CancelToken,
+) {
+    let (tile_x, tile_y) = tile.location();
+    let (width, height) = tile.dimensions();
+    for block_y in (0..height).step_by(2) {
+        for block_x in (0..width).step_by(2) {
+            if cancel.is_cancelled() {
+                return;
+            }
+            let indices = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                .into_iter()
+                .map(|(dx, dy)| (block_x + dx, block_y + dy))
+                .filter(|&(x, y)| x < width && y < height)
+                .map(|(x, y)| x + y * width)
+                .collect::<Vec<_>>();
+            let samplers = indices
+                .iter()
+                .map(|&index| {
+                    PixelSampler::new(
+                        tile_x + index % width,
+                        tile_y + index / width,
+                        mix_seed(tile_seed, index as u64),
+                    )
+                })
+                .collect();
+            let pixels = render_block(samplers, scene, stats, arena);
+            for (index, pixel) in indices.into_iter().zip(pixels) {
+                *tile.get_mut(index).unwrap() = pixel;
+            }
+        }
+    }
+}
+
+/// Samples a block of pixels. While all pixels of a full block need more samples their camera
+/// rays are intersected as one packet, the remaining samples are traced one ray at a time.
+fn render_block(
+    mut samplers: Vec<PixelSampler>,
+    scene: &Scene,
+    stats: &RayStats,
+    arena: &mut Bump,
+) -> Vec<TilePixel> {
+    let camera = &scene.camera;
+    if let Ok(block) = <&mut [PixelSampler; RAY_PACKET]>::try_from(&mut samplers[..]) {
+        while block.iter().all(|sampler| sampler.wants_sample(camera)) {
+            // Each path continues the random stream its camera ray was generated from
+            let mut streams = [0; RAY_PACKET];
+            let rays: [Ray; RAY_PACKET] = std::array::from_fn(|lane| {
+                let ray = block[lane].next_ray(camera);
+                streams[lane] = fastrand::get_seed();
+                ray
+            });
+            let hits = stats::stats_time!(
+                intersection_ns,
+                scene.intersect_packet(&rays, Visibility::CAMERA)
+            );
+            for (lane, hit) in hits.into_iter().enumerate() {
+                fastrand::seed(streams[lane]);
+                let sample = trace_camera_ray_from(&rays[lane], hit, scene, arena, stats);
+                arena.reset();
+                block[lane].add(sample);
+            }
+        }
+    }
+    samplers
+        .into_iter()
+        .map(|mut sampler| {
+            while sampler.wants_sample(camera) {
+                sampler.sample(scene, stats, arena);
+            }
+            sampler.finish(camera)
+        })
+        .collect()
+}
+
+/// Samples the pixel at `x`, `y`, allocating each sample's BxDFs in `arena` and resetting it after
+fn render_pixel(
+    x: usize,
+    y: usize,
+    pixel_seed: u64,
+    scene: &Scene,
+    stats: &RayStats,
+    arena: &mut Bump,
+) -> TilePixel {
+    let mut sampler = PixelSampler::new(x, y, pixel_seed);
+    while sampler.wants_sample(&scene.camera) {
+        sampler.sample(scene, stats, arena);
+    }
+    sampler.finish(&scene.camera)
+}
+
+/// Samples of one pixel added up so far
+struct PixelSampler {
+    x: usize,
+    y: usize,
+    pixel_seed: u64,
+    start: Instant,
+    components: PathComponents,
+    albedo: Color,
+    shadow: Scalar,
+    alpha: Scalar,
+    estimate: LuminanceEstimate,
+    num_samples: usize,
+}
+
+impl PixelSampler {
+    fn new(x: usize, y: usize, pixel_seed: u64) -> Self {
+        #[cfg(feature = "enable_debugger")]
+        debugger::begin_pixel((x, y));
+
+        Self {
+            x,
+            y,
+            pixel_seed,
+            start: Instant::now(),
+            components: PathComponents::default(),
+            albedo: BLACK,
+            shadow: 0.0,
+            alpha: 0.0,
+            estimate: LuminanceEstimate::default(),
+            num_samples: 0,
+        }
+    }
+
+    /// Noisy pixels keep sampling past the minimum, up to the cap
+    fn wants_sample(&self, camera: &Camera) -> bool {
+        self.num_samples < camera.num_samples
+            || (self.num_samples < camera.max_samples
+                && !self.estimate.converged(camera.variance_threshold))
+    }
+
+    /// Starts the next sample and generates its camera ray
+    fn next_ray(&self, camera: &Camera) -> Ray {
+        // Every sample has its own stream, so it doesn't depend on how many samples came
+        // before it or which thread renders it
+        fastrand::seed(mix_seed(self.pixel_seed, self.num_samples as u64));
+
+        debugger::begin_sample!();
+        // Fraction of the exposure the sample is taken at
+        let time = camera.sample_time(scalar::rand());
+
+        let x = self.x as Scalar + scalar::rand();
+        let y = self.y as Scalar + scalar::rand();
+        camera.generate_ray(x, y, time)
+    }
+
+    /// Traces the next sample on its own, allocating its BxDFs in `arena` and resetting it after
+    fn sample(&mut self, scene: &Scene, stats: &RayStats, arena: &mut Bump) {
+        let ray = self.next_ray(&scene.camera);
+        let sample = trace_camera_ray(&ray, scene, arena, stats);
+        // The sample's BxDFs are no longer used
+        arena.reset();
+        self.add(sample);
+    }
+
+    fn add(&mut self, sample: PathSample) {
+        let sample_color = sample.radiance;
+        debugger::end_sample!(sample_color);
+        if sample_color.is_finite() {
+            let components = sample.components;
+            self.components.emission += components.emission;
+            self.components.direct += components.direct;
+            self.components.diffuse_indirect += components.diffuse_indirect;
+            self.components.specular_indirect += components.specular_indirect;
+            self.components.groups += components.groups;
+            self.estimate.add(sample_color.luminance());
+        }
+        self.albedo += sample.albedo;
+        self.shadow += sample.shadow;
+        self.alpha += sample.alpha;
+        self.num_samples += 1;
+    }
+
+    fn finish(self, camera: &Camera) -> TilePixel {
+        let scale = 1.0 / self.num_samples as Scalar;
+        let components = if camera.mode == RenderMode::Time {
+            let time = WHITE * self.start.elapsed().as_secs_f64() as Scalar * 1e6;
+            PathSample::diagnostic(time).components
+        } else {
+            PathComponents {
+                emission: self.components.emission * scale,
+                direct: self.components.direct * scale,
+                diffuse_indirect: self.components.diffuse_indirect * scale,
+                specular_indirect: self.components.specular_indirect * scale,
+                groups: self.components.groups * scale,
+            }
+        };
+        let color = components.total();
+        debugger::end_pixel!(color);
+        TilePixel {
+            color: color.into(),
+            albedo: (self.albedo * scale).into(),
+            components,
+            shadow: self.shadow * scale,
+            alpha: self.alpha * scale,
+            samples: self.num_samples as u32,
+        }
+    }
+}
+
+/// Renders `scene` on a thread pool, printing progress as tiles complete, then applies the
+/// scene's post-process chain. A panic while rendering a tile fails only that tile.
+pub fn render(scene: Scene, options: RenderOptions) -> RenderOutput {
+    render_with(scene, options, render_tile)
+}
+
+#[cfg_attr(not(feature = "enable_debugger"), allow(unused_variables))]
+fn render_with(
+    mut scene: Scene,
+    options: RenderOptions,
+    render_tile: TileRenderer,
+) -> RenderOutput {
+    let RenderOptions {
+        threads,
+        seed,
+        tile_size,
+        tile_order,
+        debug_pixels,
+        mut preview,
+        mut progress,
+        cancel,
+        overrides,
+    } = options;
+
+    overrides.apply(&mut scene.camera);
+    let scene = Arc::new(scene);
+
+    #[cfg(feature = "enable_debugger")]
+    debugger::set_debug_pixels(&debug_pixels);
+    #[cfg(feature = "enable_stats")]
+    pbrtrs_core::stats::reset();
+
+    let image_width = scene.camera.width;
+    let image_height = scene.camera.height;
+
+    send_preview(&mut preview, |sink| {
+        sink.create(image_width as u32, image_height as u32)
+    });
+
+    let mut image_tile_generator = ImageTileGenerator::new(
+        image_width,
+        image_height,
+        tile_size.unwrap_or(TILE_SIZE),
+        tile_order,
+        seed,
+    );
+
+    let total_num_tiles = image_tile_generator.get_num_tiles();
+
+    let pool = threadpool::Builder::new()
+        .thread_name("render_thread".to_owned())
+        .num_threads(threads.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(4)
+        }))
+        .build();
+
+    let (image_writer_tx, image_writer_rx) = mpsc::channel();
+
+    let stats = Arc::new(RayStats::new());
+
+    // start of rt
+    let rt_start = Instant::now();
+
+    let black = TilePixel {
+        color: Rgb([0.0, 0.0, 0.0]),
+        albedo: Rgb([0.0, 0.0, 0.0]),
+        components: PathComponents::default(),
+        shadow: 0.0,
+        alpha: 0.0,
+        samples: 0,
+    };
+    while let Some(tile) = image_tile_generator.get_tile(black) {
+        let scene = scene.clone();
+        let stats = stats.clone();
+        let image_writer_tx = image_writer_tx.clone();
+        let cancel = cancel.clone();
+        pool.execute(move || {
+            let mut tile: ImageTile<TilePixel> = tile;
+            // Count locally so render threads don't contend on the shared counters
+            let tile_stats = RayStats::new();
+            // The writer waits for every tile, so a panic must still send one
+            let rendered = panic::catch_unwind(AssertUnwindSafe(|| {
+                render_tile(&mut tile, &scene, &tile_stats, seed, &cancel)
+            }));
+            stats.merge(&tile_stats);
+            #[cfg(feature = "enable_stats")]
+            pbrtrs_core::stats::flush_thread();
+
+            let result = match rendered {
+                Ok(()) => TileResult::Rendered(tile),
+                Err(payload) => {
+                    let (x, y) = tile.location();
+                    let (width, height) = tile.dimensions();
+                    TileResult::Failed(FailedTile {
+                        x,
+                        y,
+                        width,
+                        height,
+                        message: panic_message(payload.as_ref()),
+                    })
+                }
+            };
+            image_writer_tx.send(Some(result)).unwrap();
+        });
+    }
+
+    // Draw tiles to image preview
+
+    let pool_ender_thread = thread::Builder::new()
+        .name("pool_ender".to_owned())
+        .spawn(move || {
+            pool.join();
+            let end = rt_start.elapsed();
+            image_writer_tx.send(None).unwrap();
+            end
+        })
+        .unwrap();
+
+    let mut output_image = Rgb32FImage::from_pixel(
+        image_width as u32,
+        image_height as u32,
+        Rgb([0.3, 0.3, 0.3]),
+    );
+    let mut albedo_image = Rgb32FImage::new(image_width as u32, image_height as u32);
+    let mut alpha_image = AlphaImage::new(image_width as u32, image_height as u32);
+    let mut sample_counts = ImageBuffer::new(image_width as u32, image_height as u32);
+    let mut layers = RenderLayers::new(
+        image_width as u32,
+        image_height as u32,
+        scene.light_groups(),
+    );
+
+    let mut time = Instant::now();
+
+    let mut num_tiles: usize = 0;
+
+    let mut failed_tiles = Vec::new();
+
+    while let Some(result) = image_writer_rx.recv().unwrap() {
+        num_tiles += 1;
+        match result {
+            TileResult::Rendered(tile) => {
+                let (tile_x, tile_y) = tile.location();
+                let (width, height) = tile.dimensions();
+                for x in 0..width {
+                    for y in 0..height {
+                        let (image_x, image_y) = (x + tile_x, y + tile_y);
+
+                        let (image_x, image_y) = (image_x as u32, image_y as u32);
+                        let pixel = tile.get(x + y * width);
+
+                        output_image.put_pixel(image_x, image_y, pixel.color);
+                        albedo_image.put_pixel(image_x, image_y, pixel.albedo);
+                        alpha_image.put_pixel(
+                            image_x,
+                            image_y,
+                            Luma([scalar::to_f32(pixel.alpha)]),
+                        );
+                        sample_counts.put_pixel(image_x, image_y, Luma([pixel.samples]));
+                        let components = pixel.components;
+                        layers
+                            .emission
+                            .put_pixel(image_x, image_y, components.emission.into());
+                        layers
+                            .direct
+                            .put_pixel(image_x, image_y, components.direct.into());
+                        layers.diffuse_indirect.put_pixel(
+                            image_x,
+                            image_y,
+                            components.diffuse_indirect.into(),
+                        );
+                        layers.specular_indirect.put_pixel(
+                            image_x,
+                            image_y,
+                            components.specular_indirect.into(),
+                        );
+                        for (slot, (_, group)) in layers.groups.iter_mut().enumerate() {
+                            group.put_pixel(image_x, image_y, components.groups.group(slot).into());
+                        }
+                        layers
+                            .shadow
+                            .put_pixel(image_x, image_y, (WHITE * pixel.shadow).into());
+                    }
+                }
+            }
+            TileResult::Failed(failed) => {
+                eprintln!(
+                    "Tile at ({}, {}) failed: {}",
+                    failed.x, failed.y, failed.message
+                );
+                for y in failed.y..failed.y + failed.height {
+                    for x in failed.x..failed.x + failed.width {
+                        output_image.put_pixel(x as u32, y as u32, Rgb([1.0, 0.0, 1.0]));
+                        alpha_image.put_pixel(x as u32, y as u32, Luma([1.0]));
+                    }
+                }
+                failed_tiles.push(failed);
+            }
+        }
+        // Tiles finished after a cancel are partial
+        if let (Some(progress), false) = (&mut progress, cancel.is_cancelled()) {
+            progress.report(&Progress::new(
+                num_tiles,
+                total_num_tiles,
+                rt_start.elapsed(),
+                &output_image,
+            ));
+        }
+        if time.elapsed() > Duration::from_millis(250) {
+            send_preview(&mut preview, |sink| sink.update(&output_image));
+            time = Instant::now();
+        }
+    }
+
+    let wall_time = pool_ender_thread.join().unwrap();
+    layers.beauty = output_image.clone();
+
+    // Diagnostic values are saved as they are, without denoising or tone mapping
+    let postprocess = if scene.camera.mode == RenderMode::Beauty {
+        scene.postprocess_chain()
+    } else {
+        PostProcessChain::default()
+    };
+    if !postprocess.is_empty() {
+        println!("Post processing...");
+        let time = Instant::now();
+        postprocess.run_with_albedo(&mut output_image, Some(&albedo_image));
+        println!("Time to post process: {}", HMSDuration(time.elapsed()));
+    }
+
+    let output_transform = scene.color_space.transform_to(scene.output_color_space);
+    if !output_transform.is_identity() {
+        for image in [
+            &mut output_image,
+            &mut albedo_image,
+            &mut layers.beauty,
+            &mut layers.emission,
+            &mut layers.direct,
+            &mut layers.diffuse_indirect,
+            &mut layers.specular_indirect,
+        ]
+        .into_iter()
+        .chain(layers.groups.iter_mut().map(|(_, group)| group))
+        {
+            output_transform.apply_image(image);
+        }
+    }
+
+    send_preview(&mut preview, |sink| sink.update(&output_image));
+
+    #[cfg(feature = "enable_debugger")]
+    if !debug_pixels.is_empty() {
+        let debug = debugger::debug_info().lock().unwrap();
+        debug.save(&scene, "debug_out.xml");
+    }
+
+    RenderOutput {
+        image: output_image,
+        albedo: albedo_image,
+        alpha: alpha_image,
+        sample_counts,
+        layers,
+        stats: RenderStats::new(&stats, wall_time, cancel.is_cancelled()),
+        failed_tiles,
+        preview,
+        // The pool has joined, so the render threads no longer hold the scene
+        scene: Arc::into_inner(scene).unwrap(),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(feature = "enable_axis")]
+fn draw_axis(tile: &mut ImageTile<TilePixel>, scene: &Scene) {
+    use cgmath::{point3, vec2, SquareMatrix, Transform};
+    use pbrtrs_core::types::color;
+
+    let root_pt = point3(0.0, 0.0, 0.0);
+    let x_pt = point3(1.0, 0.0, 0.0);
+    let y_pt = point3(0.0, 1.0, 0.0);
+    let z_pt = point3(0.0, 0.0, 1.0);
+
+    // Ax = b, A: camera_basis, x: camera_space_coords, b: world_space_coords
+    let (_, camera_basis) = scene.camera.frame_at(0.0);
+    let world_basis = camera_basis.invert().unwrap();
+
+    let root_pt = world_basis.transform_point(root_pt).xy();
+    let x_pt = world_basis.transform_point(x_pt).xy();
+    let y_pt = world_basis.transform_point(y_pt).xy();
+    let z_pt = world_basis.transform_point(z_pt).xy();
+
+    // Edge tiles can be smaller than the tile size
+    let (width, height) = tile.dimensions();
+    let lines = [
+        (x_pt - root_pt, color::RED),
+        (y_pt - root_pt, color::GREEN),
+        (z_pt - root_pt, color::BLUE),
+    ];
+
+    for t in 0..20 {
+        let t = t as Scalar / 20.0;
+        for (line, color) in lines {
+            let pt = root_pt + line * t;
+            let pt = pt + vec2(1.0, 1.0) / 2.0;
+            let (x, y) = (
+                (pt.x * width as Scalar) as usize,
+                (pt.y * height as Scalar) as usize,
+            );
+            if x < width && y < height {
+                tile.get_mut(x + y * width).unwrap().color = color.into();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, vec3};
+    use pbrtrs_core::light::hdri::Hdri;
+    use pbrtrs_core::light::{DirectionLight, PointLight};
+    use pbrtrs_core::mesh::TriangleMesh;
+    use pbrtrs_core::scene::{CameraBuilder, MaterialBuilder, Object, SceneBuilder, Shape};
+    use pbrtrs_core::types::Color;
+    use std::sync::Mutex;
+
+    #[test]
+    fn counts_primary_rays() {
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(40, 24)
+                    .num_samples(3)
+                    .build(),
+            )
+            .build();
+
+        let RenderOutput {
+            image,
+            albedo,
+            stats,
+            ..
+        } = render(
+            scene,
+            RenderOptions {
+                threads: Some(2),
+                overrides: RenderOverrides {
+                    num_samples: Some(2),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert_eq!(image.dimensions(), (40, 24));
+        assert_eq!(stats.primary_rays, 40 * 24 * 2);
+        // Every camera ray misses the empty scene
+        assert_eq!(stats.total_rays, stats.primary_rays);
+        assert_eq!(stats.average_bounce_depth, 0.0);
+        assert_eq!(albedo.dimensions(), (40, 24));
+        assert!(albedo.pixels().all(|pixel| pixel.0 == [0.0; 3]));
+    }
+
+    #[test]
+    fn luminance_estimate_convergence() {
+        let mut constant = LuminanceEstimate::default();
+        constant.add(0.5);
+        assert!(!constant.converged(0.05));
+        constant.add(0.5);
+        assert!(constant.converged(0.0));
+
+        let mut noisy = LuminanceEstimate::default();
+        for i in 0..100 {
+            noisy.add((i % 2) as Scalar);
+        }
+        // Mean 0.5, standard error 0.05
+        assert!(!noisy.converged(0.1));
+        assert!(noisy.converged(0.25));
+    }
+
+    /// Sphere lit by a bright sliver of environment, so its pixels are noisy
+    fn noisy_scene(max_samples: usize) -> Scene {
+        let environment = Rgb32FImage::from_fn(16, 8, |x, _| {
+            if x == 3 {
+                Rgb([50.0, 50.0, 50.0])
+            } else {
+                Rgb([0.0, 0.0, 0.0])
+            }
+        });
+        SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(20, 20)
+                    .num_samples(4)
+                    .max_samples(max_samples)
+                    .variance_threshold(0.05)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 0.5 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_light(Hdri::new(environment, 1.0))
+            .build()
+    }
+
+    /// Like `render_tile` before the arena was reused and camera rays were traced in packets, one
+    /// pixel at a time with a new arena for each
+    fn arena_per_pixel(
+        tile: &mut ImageTile<TilePixel>,
+        scene: &Scene,
+        stats: &RayStats,
+        seed: u64,
+        _cancel: &CancelToken,
+    ) {
+        let (tile_x, tile_y) = tile.location();
+        let tile_seed = mix_seed(mix_seed(seed, tile_x as u64), tile_y as u64);
+        let mut pixel_index = 0;
+        while let Some((pixel, x, y)) = tile.next_tile() {
+            let pixel_seed = mix_seed(tile_seed, pixel_index);
+            pixel_index += 1;
+            *pixel = render_pixel(x, y, pixel_seed, scene, stats, &mut Bump::new());
+        }
+    }
+
+    #[test]
+    fn reused_arena_renders_the_same() {
+        let options = || RenderOptions {
+            threads: Some(2),
+            seed: 11,
+            ..Default::default()
+        };
+        let reused = render(noisy_scene(16), options());
+        let fresh = render_with(noisy_scene(16), options(), arena_per_pixel);
+        assert_eq!(reused.image, fresh.image);
+        assert_eq!(reused.albedo, fresh.albedo);
+        assert_eq!(reused.alpha, fresh.alpha);
+        assert_eq!(reused.sample_counts, fresh.sample_counts);
+    }
+
+    #[test]
+    fn ray_packets_render_the_same() {
+        let scene = || {
+            // A grid of quads in front of the camera, partly hidden by a sphere
+            let size = 8;
+            let positions = (0..=size)
+                .flat_map(|y| {
+                    (0..=size)
+                        .map(move |x| point3(x as Scalar / 4.0 - 1.0, y as Scalar / 4.0 - 1.0, 0.0))
+                })
+                .collect();
+            let triangles = (0..size)
+                .flat_map(|y| {
+                    (0..size).flat_map(move |x| {
+                        let i = y * (size + 1) + x;
+                        [[i, i + 1, i + size + 2], [i, i + size + 2, i + size + 1]]
+                    })
+                })
+                .collect();
+            SceneBuilder::new()
+                .camera(
+                    CameraBuilder::new()
+                        .resolution(13, 9)
+                        .num_samples(2)
+                        .max_samples(6)
+                        .variance_threshold(0.05)
+                        .build(),
+                )
+                .add_object(Object::new(
+                    Shape::Mesh(TriangleMesh::new(positions, None, None, triangles)),
+                    point3(0.0, 0.0, 3.0),
+                    MaterialBuilder::new().roughness(0.4).build(),
+                ))
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 0.3 },
+                    point3(0.3, 0.2, 2.5),
+                    MaterialBuilder::new().build(),
+                ))
+                .add_light(PointLight::new(
+                    point3(1.0, 2.0, 0.0),
+                    Color::new(10.0, 10.0, 10.0),
+                ))
+                .build()
+        };
+        let options = || RenderOptions {
+            threads: Some(2),
+            seed: 5,
+            ..Default::default()
+        };
+        // The odd resolution leaves blocks at the tile edges with fewer than four pixels
+        let packets = render(scene(), options());
+        let single = render_with(scene(), options(), arena_per_pixel);
+        assert_ne!(packets.image, Rgb32FImage::new(13, 9));
+        assert_eq!(packets.image, single.image);
+        assert_eq!(packets.albedo, single.albedo);
+        assert_eq!(packets.sample_counts, single.sample_counts);
+    }
+
+    #[test]
+    fn diagnostic_modes() {
+        let render_mode = |mode| {
+            // Zoomed in so the sphere covers many pixels, but not the corners
+            let scene = SceneBuilder::new()
+                .camera(
+                    CameraBuilder::new()
+                        .resolution(21, 21)
+                        .sensor_distance(4.0)
+                        .num_samples(16)
+                        .build(),
+                )
+                .add_object(Object::new(
+                    Shape::Sphere { radius: 0.5 },
+                    point3(0.0, 0.0, 3.0),
+                    MaterialBuilder::new().build(),
+                ))
+                .build();
+            render(
+                scene,
+                RenderOptions {
+                    threads: Some(2),
+                    overrides: RenderOverrides {
+                        mode: Some(mode),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+        };
+        let assert_center = |output: &RenderOutput, expected: [f32; 3]| {
+            let center = output.image.get_pixel(10, 10).0;
+            for (value, expected) in center.into_iter().zip(expected) {
+                assert!(
+                    (value - expected).abs() < 0.01,
+                    "{center:?} != {expected:?}"
+                );
+            }
+        };
+
+        // The center of the sphere faces the camera, its normal is -z
+        let normal = render_mode(RenderMode::Normal);
+        assert_center(&normal, [0.5, 0.5, 0.0]);
+        assert_eq!(normal.image.get_pixel(0, 0).0, [0.0; 3]);
+        // Only the camera rays are traced
+        assert_eq!(normal.stats.total_rays, normal.stats.primary_rays);
+
+        // The camera is at the origin, the sphere's front at z = 2.5
+        let depth = render_mode(RenderMode::Depth);
+        assert_center(&depth, [2.5; 3]);
+        assert_eq!(depth.image.get_pixel(0, 0).0, [0.0; 3]);
+
+        // Rays that miss stop after the camera ray
+        let bounces = render_mode(RenderMode::Bounces);
+        assert_eq!(bounces.image.get_pixel(0, 0).0, [1.0; 3]);
+        assert!(bounces.image.get_pixel(10, 10).0[0] > 1.0);
+    }
+
+    #[test]
+    fn adaptive_sampling_spends_samples_on_noise() {
+        let render = |max_samples| {
+            render(
+                noisy_scene(max_samples),
+                RenderOptions {
+                    threads: Some(2),
+                    ..Default::default()
+                },
+            )
+        };
+        let pixels: u64 = 20 * 20;
+
+        // Without adaptive sampling every pixel takes the same number of samples
+        let fixed = render(4);
+        assert_eq!(fixed.stats.primary_rays, pixels * 4);
+        assert!(fixed.sample_counts.pixels().all(|count| count[0] == 4));
+
+        // Pixels seeing only the black background converge right after the minimum, the sphere
+        // takes more
+        let output = render(64);
+        let adaptive = output.stats.primary_rays;
+        assert!(adaptive > pixels * 4, "{adaptive}");
+        assert!(adaptive < pixels * 64, "{adaptive}");
+        let counts = &output.sample_counts;
+        assert_eq!(
+            counts.pixels().map(|count| count[0] as u64).sum::<u64>(),
+            adaptive
+        );
+        assert_eq!(counts.get_pixel(0, 0)[0], 4);
+        assert!(counts.get_pixel(10, 10)[0] > 4);
+    }
+
+    #[test]
+    fn identical_across_thread_counts() {
+        let render = |threads, seed| {
+            render(
+                noisy_scene(16),
+                RenderOptions {
+                    threads: Some(threads),
+                    seed,
+                    ..Default::default()
+                },
+            )
+            .image
+        };
+        let single = render(1, 7);
+        // Tiles are scheduled in a random order on every render
+        assert_eq!(single, render(8, 7));
+        assert_eq!(single, render(3, 7));
+        assert_ne!(single, render(8, 8));
+    }
+
+    #[test]
+    fn layers_sum_to_beauty() {
+        let RenderOutput { image, layers, .. } = render(
+            noisy_scene(4),
+            RenderOptions {
+                threads: Some(2),
+                ..Default::default()
+            },
+        );
+        // Without post processing the beauty layer is the output image
+        assert_eq!(layers.beauty, image);
+        for (x, y, beauty) in layers.beauty.enumerate_pixels() {
+            for c in 0..3 {
+                let sum = layers.named()[1..]
+                    .iter()
+                    .map(|(_, layer)| layer.get_pixel(x, y)[c])
+                    .sum::<f32>();
+                assert!((sum - beauty[c]).abs() <= 1e-5 * beauty[c].max(1.0));
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("pbrtrs_layers_{}.exr", std::process::id()));
+        layers.save_exr(&path).unwrap();
+        let saved = exr::prelude::read_all_rgba_layers_from_file(
+            &path,
+            |size, _| Rgb32FImage::new(size.width() as u32, size.height() as u32),
+            |image: &mut Rgb32FImage, position, (r, g, b, _): (f32, f32, f32, f32)| {
+                image.put_pixel(position.x() as u32, position.y() as u32, Rgb([r, g, b]))
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let names = saved
+            .layer_data
+            .iter()
+            .map(|layer| layer.attributes.layer_name.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "beauty",
+                "emission",
+                "direct",
+                "diffuse_indirect",
+                "specular_indirect",
+                "shadow"
+            ]
+        );
+        for (layer, (_, original)) in saved.layer_data.iter().zip(layers.named()) {
+            assert_eq!(&layer.channel_data.pixels, original);
+        }
+    }
+
+    #[test]
+    fn alpha_is_coverage() {
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(16, 16)
+                    .num_samples(16)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new().build(),
+            ))
+            .background(Color::new(0.5, 0.5, 0.5))
+            .build();
+        let RenderOutput { alpha, .. } = render(
+            scene,
+            RenderOptions {
+                threads: Some(1),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(alpha.get_pixel(8, 8)[0], 1.0);
+        assert_eq!(alpha.get_pixel(0, 0)[0], 0.0);
+        // Pixels on the silhouette are partly covered
+        assert!(alpha.pixels().any(|pixel| pixel[0] > 0.0 && pixel[0] < 1.0));
+    }
+
+    #[test]
+    fn shadow_layer_holds_catcher_shadows() {
+        // Looking down at a catcher, with the sphere's shadow to its side. Looking straight down
+        // would leave the camera without an up direction.
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .position(point3(0.0, 4.0, -1.0))
+                    .direction(vec3(0.0, -4.0, 1.0))
+                    .resolution(16, 16)
+                    .num_samples(4)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 1.5, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_object(
+                Object::new(
+                    Shape::Plane { uv_scale: 1.0 },
+                    point3(0.0, 0.0, 0.0),
+                    MaterialBuilder::new().build(),
+                )
+                .with_shadow_catcher(true),
+            )
+            .add_light(DirectionLight::new(vec3(1.0, -1.0, 0.0), WHITE))
+            .build();
+        let RenderOutput { layers, .. } = render(
+            scene,
+            RenderOptions {
+                threads: Some(1),
+                ..Default::default()
+            },
+        );
+
+        // Grey, fully opaque in the umbra and clear where the catcher is lit
+        assert!(layers
+            .shadow
+            .pixels()
+            .all(|&Rgb([r, g, b])| r == g && g == b && (0.0..=1.0).contains(&r)));
+        assert!(layers.shadow.pixels().any(|pixel| pixel[0] > 0.99));
+        assert!(layers.shadow.pixels().any(|pixel| pixel[0] == 0.0));
+    }
+
+    #[test]
+    fn light_groups_sum_to_beauty() {
+        // Red and blue lights on a white scene, so light from each only has its own channel
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(16, 16)
+                    .num_samples(8)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 4.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_object(Object::new(
+                Shape::Plane { uv_scale: 1.0 },
+                point3(0.0, -1.0, 0.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_light_to_group(
+                PointLight::new(point3(-2.0, 2.0, 2.0), Color::new(1.0, 0.0, 0.0)),
+                "key",
+            )
+            .add_light_to_group(
+                PointLight::new(point3(2.0, 1.0, 3.0), Color::new(0.0, 0.0, 1.0)),
+                "fill",
+            )
+            .background(Color::new(0.0, 0.1, 0.0))
+            .build();
+        let RenderOutput { image, layers, .. } = render(
+            scene,
+            RenderOptions {
+                threads: Some(2),
+                ..Default::default()
+            },
+        );
+
+        let names = layers.groups.iter().map(|(name, _)| name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["ungrouped", "key", "fill"]);
+        for (x, y, beauty) in image.enumerate_pixels() {
+            for c in 0..3 {
+                let sum = layers
+                    .groups
+                    .iter()
+                    .map(|(_, layer)| layer.get_pixel(x, y)[c])
+                    .sum::<f32>();
+                assert!((sum - beauty[c]).abs() <= 1e-5 * beauty[c].max(1.0));
+            }
+        }
+        // Each light's bounces stay in its group, the background is in no group
+        let channel_sum = |(_, layer): &(String, Rgb32FImage), c: usize| {
+            layer.pixels().map(|pixel| pixel[c]).sum::<f32>()
+        };
+        let [ungrouped, key, fill] = [0, 1, 2].map(|group| &layers.groups[group]);
+        assert!(channel_sum(key, 0) > 0.0 && channel_sum(key, 1) + channel_sum(key, 2) == 0.0);
+        assert!(channel_sum(fill, 2) > 0.0 && channel_sum(fill, 0) + channel_sum(fill, 1) == 0.0);
+        assert!(channel_sum(ungrouped, 1) > 0.0);
+        assert_eq!(channel_sum(ungrouped, 0) + channel_sum(ungrouped, 2), 0.0);
+
+        let path = std::env::temp_dir().join(format!("pbrtrs_groups_{}.exr", std::process::id()));
+        layers.save_exr(&path).unwrap();
+        let saved = exr::prelude::read_all_rgba_layers_from_file(
+            &path,
+            |_, _| (),
+            |_: &mut (), _, _: (f32, f32, f32, f32)| (),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let names = saved
+            .layer_data
+            .iter()
+            .map(|layer| layer.attributes.layer_name.as_ref().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names[5..],
+            ["light_ungrouped", "light_key", "light_fill", "shadow"]
+        );
+    }
+
+    #[test]
+    fn cancel_returns_partial_image() {
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(160, 160)
+                    .num_samples(64)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_light(Hdri::new(Rgb32FImage::from_pixel(4, 2, Rgb([1.0; 3])), 1.0))
+            .build();
+
+        let cancel = CancelToken::new();
+        let token = cancel.clone();
+        let mut reports = 0;
+        let start = Instant::now();
+        let RenderOutput { image, stats, .. } = render(
+            scene,
+            RenderOptions {
+                threads: Some(2),
+                progress: Some(Box::new(move |progress: &Progress| {
+                    assert_eq!(progress.total_tiles, 100);
+                    reports += 1;
+                    assert_eq!(progress.tiles_completed, reports);
+                    // The report comes with the finished tile already in the image
+                    let unfinished = Rgb([0.3, 0.3, 0.3]);
+                    let drawn = progress
+                        .image
+                        .pixels()
+                        .filter(|&&p| p != unfinished)
+                        .count();
+                    assert_eq!(drawn, 16 * 16);
+                    token.cancel();
+                })),
+                cancel,
+                ..Default::default()
+            },
+        );
+        assert!(stats.cancelled);
+        assert!(start.elapsed() < Duration::from_secs(30));
+        // The threads stop at the next pixel, far short of the 100 tiles
+        assert!(
+            stats.primary_rays < 160 * 160 * 64 / 4,
+            "{}",
+            stats.primary_rays
+        );
+        let rendered = image.pixels().filter(|pixel| pixel.0 != [0.0; 3]).count();
+        assert!(rendered >= 16 * 16, "{rendered}");
+        assert!(rendered < 160 * 160);
+    }
+
+    #[test]
+    fn progress_image_shows_finished_tiles() {
+        let background = Color::new(1.0, 0.5, 0.25);
+        let rendered = Rgb::from(background).0;
+        let scene = SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(40, 24)
+                    .num_samples(1)
+                    .build(),
+            )
+            .background(background)
+            .build();
+        let reports = Arc::new(Mutex::new(0));
+        let counted = reports.clone();
+        let RenderOutput { image, .. } = render(
+            scene,
+            RenderOptions {
+                threads: Some(2),
+                tile_size: Some(8),
+                progress: Some(Box::new(move |progress: &Progress| {
+                    *counted.lock().unwrap() += 1;
+                    assert_eq!(progress.total_tiles, 15);
+                    let mut finished = 0;
+                    for tile_y in (0..24).step_by(8) {
+                        for tile_x in (0..40).step_by(8) {
+                            let pixel =
+                                |x: u32, y: u32| progress.image.get_pixel(tile_x + x, tile_y + y).0;
+                            let first = pixel(0, 0);
+                            // Tiles are drawn whole, never part way through
+                            for (x, y) in (0..8).flat_map(|y| (0..8).map(move |x| (x, y))) {
+                                assert_eq!(pixel(x, y), first);
+                            }
+                            if first == [0.3; 3] {
+                                continue;
+                            }
+                            assert_eq!(first, rendered);
+                            finished += 1;
+                        }
+                    }
+                    assert_eq!(finished, progress.tiles_completed);
+                })),
+                ..Default::default()
+            },
+        );
+        assert_eq!(*reports.lock().unwrap(), 15);
+        assert!(image.pixels().all(|pixel| pixel.0 == rendered));
+    }
+
+    #[test]
+    fn panicking_tile_fails_alone() {
+        fn panic_on_second_tile(
+            tile: &mut ImageTile<TilePixel>,
+            scene: &Scene,
+            stats: &RayStats,
+            seed: u64,
+            cancel: &CancelToken,
+        ) {
+            render_tile(tile, scene, stats, seed, cancel);
+            assert!(tile.location() != (16, 0), "NaN sample");
+        }
+
+        let RenderOutput {
+            image,
+            failed_tiles,
+            ..
+        } = render_with(
+            noisy_scene(4),
+            RenderOptions {
+                threads: Some(2),
+                ..Default::default()
+            },
+            panic_on_second_tile,
+        );
+        assert_eq!(
+            failed_tiles,
+            [FailedTile {
+                x: 16,
+                y: 0,
+                width: 4,
+                height: 16,
+                message: "NaN sample".to_owned(),
+            }]
+        );
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let failed = x >= 16 && y < 16;
+            assert_eq!(pixel.0 == [1.0, 0.0, 1.0], failed, "({x}, {y})");
+        }
+    }
+
+    /// Records the calls it gets, failing every call after `fail_after` of them
+    struct RecordingPreview {
+        calls: Arc<Mutex<Vec<(u32, u32)>>>,
+        fail_after: usize,
+    }
+
+    impl RecordingPreview {
+        fn record(&mut self, dimensions: (u32, u32)) -> io::Result<()> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push(dimensions);
+            if calls.len() > self.fail_after {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "viewer closed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl PreviewSink for RecordingPreview {
+        fn create(&mut self, width: u32, height: u32) -> io::Result<()> {
+            self.record((width, height))
+        }
+
+        fn update(&mut self, image: &Rgb32FImage) -> io::Result<()> {
+            self.record(image.dimensions())
+        }
+    }
+
+    #[test]
+    fn preview_gets_final_image() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let RenderOutput { image, preview, .. } = render(
+            noisy_scene(4),
+            RenderOptions {
+                preview: Some(Box::new(RecordingPreview {
+                    calls: calls.clone(),
+                    fail_after: usize::MAX,
+                })),
+                ..Default::default()
+            },
+        );
+        assert!(preview.is_some());
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() >= 2);
+        assert!(calls.iter().all(|&call| call == image.dimensions()));
+    }
+
+    #[test]
+    fn failing_preview_is_dropped() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let RenderOutput { image, preview, .. } = render(
+            noisy_scene(4),
+            RenderOptions {
+                threads: Some(2),
+                seed: 7,
+                preview: Some(Box::new(RecordingPreview {
+                    calls: calls.clone(),
+                    fail_after: 0,
+                })),
+                ..Default::default()
+            },
+        );
+        // Only the failed create is sent, the render carries on without the preview
+        assert_eq!(calls.lock().unwrap().len(), 1);
+        assert!(preview.is_none());
+        let without_preview = render(
+            noisy_scene(4),
+            RenderOptions {
+                threads: Some(2),
+                seed: 7,
+                ..Default::default()
+            },
+        );
+        assert_eq!(image, without_preview.image);
+    }
+
+    #[test]
+    fn overrides_win_over_scene() {
+        let mut camera = CameraBuilder::new()
+            .resolution(40, 24)
+            .num_samples(3)
+            .max_samples(30)
+            .bounce_limit(5)
+            .build();
+
+        RenderOverrides::default().apply(&mut camera);
+        assert_eq!(
+            (
+                camera.width,
+                camera.height,
+                camera.num_samples,
+                camera.bounce_limit
+            ),
+            (40, 24, 3, 5)
+        );
+
+        RenderOverrides {
+            num_samples: Some(1),
+            bounce_limit: Some(2),
+            width: Some(8),
+            height: Some(6),
+            mode: Some(RenderMode::Depth),
+        }
+        .apply(&mut camera);
+        assert_eq!(camera.mode, RenderMode::Depth);
+        assert_eq!(
+            (
+                camera.width,
+                camera.height,
+                camera.num_samples,
+                camera.bounce_limit
+            ),
+            (8, 6, 1, 2)
+        );
+        assert!(!camera.is_adaptive());
+    }
+}
@@ -0,0 +1,195 @@
+use crate::render::{render, RenderOptions, RenderOutput};
+use pbrtrs_core::scene::Scene;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often watched files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Polls the modification times of a set of files
+#[derive(Debug)]
+pub struct FileWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl FileWatcher {
+    pub fn new(paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect();
+        FileWatcher { files }
+    }
+
+    /// Watches the file `scene` was loaded from and every file it references
+    pub fn for_scene(scene: &Scene) -> Self {
+        Self::new(scene.path.iter().chain(&scene.files).cloned())
+    }
+
+    /// Whether a file was modified, created or removed since the watcher was made
+    pub fn changed(&self) -> bool {
+        self.files
+            .iter()
+            .any(|(path, last_modified)| modified(path) != *last_modified)
+    }
+
+    /// Blocks until a file changes
+    pub fn wait(&self) {
+        while !self.changed() {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// `None` if the file doesn't exist
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Renders `scene`, cancelling the render through `options.cancel` as soon as a file of `watcher`
+/// changes. Also returns whether a file changed, the output is then out of date.
+pub fn render_watched(
+    scene: Scene,
+    options: RenderOptions,
+    watcher: &FileWatcher,
+) -> (RenderOutput, bool) {
+    let cancel = options.cancel.clone();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    thread::scope(|scope| {
+        let poller = scope.spawn(move || loop {
+            if watcher.changed() {
+                cancel.cancel();
+                return true;
+            }
+            // Dropping the sender ends the wait early once the render is done
+            if let Err(RecvTimeoutError::Disconnected) = done_rx.recv_timeout(POLL_INTERVAL) {
+                return false;
+            }
+        });
+        let output = render(scene, options);
+        drop(done_tx);
+        (output, poller.join().unwrap())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::PreviewSink;
+    use cgmath::point3;
+    use image::{Rgb, Rgb32FImage};
+    use pbrtrs_core::light::hdri::Hdri;
+    use pbrtrs_core::scene::{CameraBuilder, MaterialBuilder, Object, SceneBuilder, Shape};
+    use std::fs::File;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    /// Counts the images it is asked to create
+    struct CountingPreview(Arc<Mutex<usize>>);
+
+    impl PreviewSink for CountingPreview {
+        fn create(&mut self, _width: u32, _height: u32) -> io::Result<()> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn update(&mut self, _image: &Rgb32FImage) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn scene(num_samples: usize) -> Scene {
+        SceneBuilder::new()
+            .camera(
+                CameraBuilder::new()
+                    .resolution(64, 64)
+                    .num_samples(num_samples)
+                    .build(),
+            )
+            .add_object(Object::new(
+                Shape::Sphere { radius: 1.0 },
+                point3(0.0, 0.0, 3.0),
+                MaterialBuilder::new().build(),
+            ))
+            .add_light(Hdri::new(Rgb32FImage::from_pixel(4, 2, Rgb([1.0; 3])), 1.0))
+            .build()
+    }
+
+    fn touch(path: &Path, seconds_later: u64) {
+        let modified = modified(path).unwrap() + Duration::from_secs(seconds_later);
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn detects_changes() {
+        let path = std::env::temp_dir().join(format!("pbrtrs_watch_{}.toml", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let missing = path.with_extension("png");
+
+        let watcher = FileWatcher::new([path.clone(), missing.clone()]);
+        assert!(!watcher.changed());
+        touch(&path, 1);
+        assert!(watcher.changed());
+
+        // Files that appear count as changes
+        let watcher = FileWatcher::new([path.clone(), missing.clone()]);
+        std::fs::write(&missing, "").unwrap();
+        assert!(watcher.changed());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&missing).unwrap();
+    }
+
+    #[test]
+    fn change_restarts_render() {
+        let path = std::env::temp_dir().join(format!("pbrtrs_reload_{}.toml", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        let creates = Arc::new(Mutex::new(0));
+
+        // A change cancels the in-flight render, which would otherwise take far longer
+        let watcher = FileWatcher::new([path.clone()]);
+        touch(&path, 1);
+        let start = std::time::Instant::now();
+        let (output, changed) = render_watched(
+            scene(100_000),
+            RenderOptions {
+                threads: Some(2),
+                preview: Some(Box::new(CountingPreview(creates.clone()))),
+                ..Default::default()
+            },
+            &watcher,
+        );
+        assert!(changed);
+        assert!(output.stats.cancelled);
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // The reloaded render runs to completion on the same preview
+        let watcher = FileWatcher::new([path.clone()]);
+        let (output, changed) = render_watched(
+            scene(1),
+            RenderOptions {
+                threads: Some(2),
+                preview: output.preview,
+                ..Default::default()
+            },
+            &watcher,
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(!changed);
+        assert!(!output.stats.cancelled);
+        assert!(output.preview.is_some());
+        assert_eq!(*creates.lock().unwrap(), 2);
+    }
+}
@@ -1,14 +1,27 @@
 extern crate kiss3d;
 extern crate xml;
 
-use cgmath::{point3, vec3, EuclideanSpace, Zero};
+use cgmath::{point2, EuclideanSpace, InnerSpace, Zero};
 use kiss3d::light::Light;
 use kiss3d::nalgebra::{Point3, Translation3, Vector3};
+use kiss3d::resource::Mesh as KissMesh;
+use kiss3d::scene::SceneNode;
 use kiss3d::window::Window;
-use pbrtrs_core::scene::{load_scene, Camera, Shape, Texture};
-use pbrtrs_core::types::{scalar, Color, Pt3, Vec3};
+use pbrtrs_core::debugger::DebugValue;
+use pbrtrs_core::light::Light as SceneLight;
+use pbrtrs_core::raytracer::RenderMode;
+use pbrtrs_core::scene::{
+    load_scene, Camera, CameraModel, Shape, ShutterCurve, Texture, TextureValue,
+};
+use pbrtrs_core::types::scalar::consts::TAU;
+use pbrtrs_core::types::scalar::{self, to_f32};
+use pbrtrs_core::types::{Color, Pt3, Quaternion, Scalar, Vec3};
+use pbrtrs_core::util::coordinate_system;
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -16,9 +29,69 @@ use xml::attribute::OwnedAttribute;
 use xml::reader::{Events, XmlEvent};
 use xml::EventReader;
 
+const USAGE: &str = "\
+Usage: pbrtrs_visual_debug [OPTIONS] [DEBUG_XML]
+
+Shows the rays recorded by a render with --debug-pixel, read from DEBUG_XML (default
+debug_out.xml).
+
+Options:
+  --scene <PATH>  Scene to draw, defaults to the scene recorded in DEBUG_XML or
+                  examples/hdr.toml
+  -h, --help      Print this help
+
+Commands:
+  p               Print the camera
+  px [N]          List the pixels, or select pixel N
+  s N             Select sample N
+  r N             Highlight ray N and print its values
+  v X Y Z         Draw a vector from the current ray
+  vr N            Draw the vector value N printed by r
+  clear           Remove the drawn vectors
+  q               Quit";
+
+struct Args {
+    debug_path: PathBuf,
+    scene_path: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parses the arguments, `Err` holds the message to print before exiting
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut debug_path = None;
+        let mut scene_path = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--scene" => {
+                    let path = args.next().ok_or("--scene needs a path")?;
+                    scene_path = Some(PathBuf::from(path));
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ if debug_path.is_some() => return Err(format!("unexpected argument {arg}")),
+                _ => debug_path = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(Args {
+            debug_path: debug_path.unwrap_or_else(|| "debug_out.xml".into()),
+            scene_path,
+        })
+    }
+}
+
+/// Contents of the debugger's XML output
+struct Document {
+    pixels: Vec<Pixel>,
+    camera: Camera,
+    /// Scene the render was made from, if it was loaded from a file
+    scene_path: Option<PathBuf>,
+}
+
 #[allow(unused)]
 #[derive(Debug)]
 struct Pixel {
+    x: usize,
+    y: usize,
     color: Color,
     samples: Vec<Sample>,
 }
@@ -38,6 +111,15 @@ struct Ray {
     origin: Pt3,
     direction: Vec3,
     debug: String,
+    values: Vec<Value>,
+}
+
+#[allow(unused)]
+#[derive(Debug)]
+struct Value {
+    name: String,
+    location: String,
+    value: DebugValue,
 }
 
 struct VisualDebuggerSharedData {
@@ -47,18 +129,21 @@ struct VisualDebuggerSharedData {
 
 struct VisualDebugger {
     shared_data: Arc<Mutex<VisualDebuggerSharedData>>,
-    pixel: Pixel,
+    pixels: Vec<Pixel>,
+    pixel: usize,
     sample: usize,
 }
 
 impl VisualDebugger {
-    pub fn new(pixel: Pixel) -> VisualDebugger {
+    pub fn new(pixels: Vec<Pixel>) -> VisualDebugger {
+        assert!(!pixels.is_empty(), "no pixels were recorded");
         let vd = VisualDebugger {
             shared_data: Arc::new(Mutex::new(VisualDebuggerSharedData {
                 ray_lines: vec![],
                 debug_vectors: vec![],
             })),
-            pixel,
+            pixels,
+            pixel: 0,
             sample: 0,
         };
         vd.update_ray_lines();
@@ -83,8 +168,8 @@ impl VisualDebugger {
         let mut last: Option<Pt3> = None;
         for ray in &self.current_sample().bounces {
             if let Some(l) = last {
-                let l = Point3::new(l.x, l.y, l.z);
-                let o = Point3::new(ray.origin.x, ray.origin.y, ray.origin.z);
+                let l = cgm_to_kiss3d_pt3(l);
+                let o = cgm_to_kiss3d_pt3(ray.origin);
                 d.ray_lines.push((l, o, Point3::new(1.0, 0.0, 0.0)));
                 last = Some(ray.origin);
             } else {
@@ -92,9 +177,8 @@ impl VisualDebugger {
             }
         }
         if let Some(ray) = self.current_sample().bounces.last() {
-            let o0 = Point3::new(ray.origin.x, ray.origin.y, ray.origin.z);
-            let o1 = ray.origin + ray.direction;
-            let o1 = Point3::new(o1.x, o1.y, o1.z);
+            let o0 = cgm_to_kiss3d_pt3(ray.origin);
+            let o1 = cgm_to_kiss3d_pt3(ray.origin + ray.direction);
             d.ray_lines.push((o0, o1, Point3::new(1.0, 0.0, 1.0)));
         }
     }
@@ -111,55 +195,201 @@ impl VisualDebugger {
         }
     }
 
+    fn select_pixel(&mut self, pixel: usize) {
+        self.pixel = pixel;
+        self.sample = 0;
+        self.update_ray_lines();
+    }
+
+    fn print_pixels(&self) {
+        for (i, pixel) in self.pixels.iter().enumerate() {
+            let current = if i == self.pixel { "*" } else { " " };
+            println!("{current}{i}: ({}, {})", pixel.x, pixel.y);
+        }
+    }
+
     fn current_sample(&self) -> &Sample {
-        &self.pixel.samples[self.sample]
+        &self.pixels[self.pixel].samples[self.sample]
     }
 }
 
+fn print_camera(camera: &Camera) {
+    println!("position: {:?}", camera.position);
+    println!("direction: {:?}", camera.direction);
+    println!("sensor_distance: {}", camera.sensor_distance);
+    println!("exposure_time: {}", camera.exposure_time);
+    println!("aperture: {}", camera.aperture);
+    println!("focus_distance: {}", camera.focus_distance);
+    println!("ldr_scale: {}", camera.ldr_scale);
+    println!("bounce_limit: {}", camera.bounce_limit);
+    println!("num_samples: {}", camera.num_samples);
+    println!("resolution: {}x{}", camera.width, camera.height);
+}
+
+/// Adds a node with the geometry of `shape` centered at `position`
+fn add_shape(window: &mut Window, shape: &Shape, position: Pt3) -> SceneNode {
+    let mut node = match shape {
+        Shape::Sphere { radius } => window.add_sphere(to_f32(*radius)),
+        Shape::Plane { .. } => {
+            // A large quad stands in for the unbounded plane
+            const HALF_SIZE: f32 = 50.0;
+            let corners = [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)]
+                .map(|(x, z)| Point3::new(x * HALF_SIZE, 0.0, z * HALF_SIZE));
+            window.add_quad_with_vertices(&corners, 2, 2)
+        }
+        Shape::Mesh(mesh) => {
+            // kiss3d indexes vertices with u16, so large meshes are split into several
+            const CHUNK_TRIANGLES: usize = u16::MAX as usize / 3;
+            let triangles = mesh.triangles().collect::<Vec<_>>();
+            let mut group = window.add_group();
+            for chunk in triangles.chunks(CHUNK_TRIANGLES) {
+                let coords = chunk
+                    .iter()
+                    .flatten()
+                    .map(|&p| cgm_to_kiss3d_pt3(p))
+                    .collect::<Vec<_>>();
+                let faces = (0..chunk.len() as u16)
+                    .map(|i| Point3::new(3 * i, 3 * i + 1, 3 * i + 2))
+                    .collect();
+                let chunk_mesh = KissMesh::new(coords, faces, None, None, false);
+                group.add_mesh(
+                    Rc::new(RefCell::new(chunk_mesh)),
+                    Vector3::new(1.0, 1.0, 1.0),
+                );
+            }
+            group
+        }
+    };
+    let p = cgm_to_kiss3d_pt3(position);
+    node.set_local_translation(Translation3::new(p.x, p.y, p.z));
+    node
+}
+
+/// `color` scaled so its largest component is 1, for lights brighter than the display
+fn set_light_color(node: &mut SceneNode, color: Color) {
+    let max = color.r.max(color.g).max(color.b);
+    let c = if max > 0.0 { color / max } else { color };
+    node.set_color(to_f32(c.r), to_f32(c.g), to_f32(c.b));
+}
+
+/// Adds a small sphere marking the position of a point or spot light
+fn add_light_marker(window: &mut Window, position: Pt3, color: Color) {
+    let mut node = add_shape(window, &Shape::Sphere { radius: 0.05 }, position);
+    set_light_color(&mut node, color);
+}
+
+/// Lines outlining the cone of a spot light, one unit long
+fn spot_cone_lines(
+    position: Pt3,
+    direction: Vec3,
+    cos_angle: Scalar,
+) -> Vec<(Point3<f32>, Point3<f32>, Point3<f32>)> {
+    const SEGMENTS: usize = 16;
+    let color = Point3::new(1.0, 1.0, 0.0);
+    let direction = direction.normalize();
+    let (tangent, bitangent) = coordinate_system(direction);
+    let sin_angle = (1.0 - cos_angle * cos_angle).max(0.0).sqrt();
+    let rim = (0..SEGMENTS)
+        .map(|i| {
+            let phi = TAU * i as Scalar / SEGMENTS as Scalar;
+            let w =
+                direction * cos_angle + (tangent * phi.cos() + bitangent * phi.sin()) * sin_angle;
+            cgm_to_kiss3d_pt3(position + w)
+        })
+        .collect::<Vec<_>>();
+    let apex = cgm_to_kiss3d_pt3(position);
+    let mut lines = (0..SEGMENTS)
+        .map(|i| (rim[i], rim[(i + 1) % SEGMENTS], color))
+        .collect::<Vec<_>>();
+    lines.extend(rim.iter().step_by(SEGMENTS / 4).map(|&p| (apex, p, color)));
+    lines
+}
+
 fn cgm_to_kiss3d_vec3(v: Vec3) -> Vector3<f32> {
-    Vector3::new(v.x, v.y, v.z)
+    Vector3::new(to_f32(v.x), to_f32(v.y), to_f32(v.z))
 }
 
 fn cgm_to_kiss3d_pt3(v: Pt3) -> Point3<f32> {
-    Point3::new(v.x, v.y, v.z)
+    Point3::new(to_f32(v.x), to_f32(v.y), to_f32(v.z))
 }
 
 fn main() {
-    let file = File::open("debug_out.xml").unwrap();
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            println!("{message}");
+            return;
+        }
+    };
+
+    let file = File::open(&args.debug_path)
+        .unwrap_or_else(|err| panic!("failed to open {}: {err}", args.debug_path.display()));
     let file = BufReader::new(file);
     let parser = EventReader::new(file);
 
     let mut parser = parser.into_iter();
-    let (pixel, _camera) = parse_document(&mut parser);
+    let document = parse_document(&mut parser);
     drop(parser);
 
-    let scene = load_scene("examples/hdr.toml");
+    let scene_path = args
+        .scene_path
+        .or(document.scene_path)
+        .unwrap_or_else(|| "examples/hdr.toml".into());
+    let scene = load_scene(scene_path);
+    let camera = document.camera;
 
-    let mut vd = VisualDebugger::new(pixel);
+    let mut vd = VisualDebugger::new(document.pixels);
+    vd.print_pixels();
 
     let mut window = Window::new("Debug");
     window.set_light(Light::StickToCamera);
 
-    for object in &scene.objects {
-        let mut node = match &object.shape {
-            Shape::Sphere { radius } => {
-                let mut sphere = window.add_sphere(*radius);
-                sphere.set_local_translation(Translation3::new(
-                    object.position.x,
-                    object.position.y,
-                    object.position.z,
-                ));
-                sphere
-            }
-        };
+    for object in scene.objects() {
+        let mut node = add_shape(&mut window, &object.shape, object.position);
 
         match &object.material.base_color {
             Texture::Value(c) => {
-                node.set_color(c.x, c.y, c.z);
+                node.set_color(to_f32(c.r), to_f32(c.g), to_f32(c.b));
             }
             Texture::Image(_) => {
-                node.set_color(scalar::rand(), scalar::rand(), scalar::rand());
+                node.set_color(
+                    to_f32(scalar::rand()),
+                    to_f32(scalar::rand()),
+                    to_f32(scalar::rand()),
+                );
+            }
+            Texture::Checker { a, b, .. } | Texture::Gradient { a, b, .. } => {
+                let c = a.lerp(*b, 0.5);
+                node.set_color(to_f32(c.r), to_f32(c.g), to_f32(c.b));
+            }
+        }
+    }
+
+    let mut light_lines = vec![];
+    for light in &scene.lights {
+        match light {
+            SceneLight::Area(area) => {
+                let mut node = add_shape(&mut window, &area.shape, area.position);
+                set_light_color(&mut node, area.emission(point2(0.5, 0.5)));
+                node.set_surface_rendering_activation(false);
+                node.set_lines_width(1.0);
+            }
+            SceneLight::Point(point) => {
+                add_light_marker(&mut window, point.position, point.radiance);
             }
+            SceneLight::Spot(spot) => {
+                add_light_marker(&mut window, spot.position, spot.radiance);
+                light_lines.extend(spot_cone_lines(
+                    spot.position,
+                    spot.direction,
+                    spot.cos_angle,
+                ));
+            }
+            // Lights at infinity have nothing to draw
+            SceneLight::Direction(_)
+            | SceneLight::Hdri(_)
+            | SceneLight::CubeMap(_)
+            | SceneLight::Ambient(_) => {}
         }
     }
 
@@ -210,6 +440,16 @@ fn main() {
                     "q" => {
                         window_is_open.store(false, Ordering::Relaxed);
                     }
+                    "p" => print_camera(&camera),
+                    "px" if input.len() == 1 => vd.print_pixels(),
+                    "px" => {
+                        let pixel = prompt_try!(arg!(1).parse::<usize>());
+                        if pixel >= vd.pixels.len() {
+                            println!("Invalid input");
+                            continue;
+                        }
+                        vd.select_pixel(pixel);
+                    }
                     "s" => {
                         let sample = prompt_try!(arg!(1).parse::<usize>());
                         vd.sample = sample;
@@ -226,17 +466,17 @@ fn main() {
                         }
 
                         for line in ray.debug.trim().lines() {
-                            let line = line.trim();
-                            if line.starts_with("pbrtrs_core") {
-                                println!("@{line}");
-                            } else {
-                                let (name, value) = prompt_try_opt!(line.split_once(':'));
-                                let name = name.trim();
-                                let value = value.trim();
-                                let idx = current_debug_refs.len();
-                                current_debug_refs.push(value.to_string());
-                                println!("    {idx}: {name}: {value}");
+                            println!("{}", line.trim());
+                        }
+                        let mut location = None;
+                        for value in &ray.values {
+                            if location != Some(&value.location) {
+                                println!("@{}", value.location);
+                                location = Some(&value.location);
                             }
+                            let idx = current_debug_refs.len();
+                            current_debug_refs.push(value.value.clone());
+                            println!("    {idx}: {}: {}", value.name, value.value);
                         }
                     }
                     "clear" => {
@@ -255,8 +495,13 @@ fn main() {
                     }
                     "vr" => {
                         let r = prompt_try!(arg!(1).parse::<usize>());
-                        let val = &current_debug_refs[r];
-                        let v = cgm_to_kiss3d_vec3(parse_vec3(val));
+                        let v = match prompt_try_opt!(current_debug_refs.get(r)) {
+                            DebugValue::Vec3(v) => cgm_to_kiss3d_vec3(*v),
+                            value => {
+                                println!("{} is a {}, not a vec3", r, value.kind());
+                                continue;
+                            }
+                        };
 
                         vd.add_debug_vector((
                             current_origin,
@@ -273,6 +518,9 @@ fn main() {
     };
 
     while window.render() {
+        for line in &light_lines {
+            window.draw_line(&line.0, &line.1, &line.2);
+        }
         let vd = vd_shared_data.lock().unwrap();
         for ray in vd.ray_lines.iter().chain(vd.debug_vectors.iter()) {
             window.draw_line(&ray.0, &ray.1, &ray.2);
@@ -283,36 +531,57 @@ fn main() {
     window_is_open.store(false, Ordering::Relaxed);
 }
 
-fn parse_document(parser: &mut Events<impl Read>) -> (Pixel, Camera) {
-    let mut pixel = None;
+fn parse_document(parser: &mut Events<impl Read>) -> Document {
+    let mut pixels = vec![];
     let mut camera = None;
+    let mut scene_path = None;
     while let Some(e) = parser.next() {
         match e {
             Ok(XmlEvent::StartElement {
                 name, attributes, ..
             }) => match name.local_name.as_str() {
-                "pixel" => pixel = Some(parse_pixel(parser, &attributes)),
+                "pixel" => pixels.push(parse_pixel(parser, &attributes)),
                 "camera" => camera = Some(parse_camera(parser, &attributes)),
+                "scene" => scene_path = get_attr(&attributes, "path").map(PathBuf::from),
                 _ => {}
             },
             Err(e) => println!("Error: {}", e),
             _ => {}
         }
     }
-    (pixel.unwrap(), camera.unwrap())
+    Document {
+        pixels,
+        camera: camera.unwrap(),
+        scene_path,
+    }
 }
 
 fn parse_camera(parser: &mut Events<impl Read>, _attr: &[OwnedAttribute]) -> Camera {
     let mut out = Camera {
+        model: CameraModel::ThinLens,
         position: Pt3::origin(),
         direction: Vec3::zero(),
         sensor_distance: 0.0,
         exposure_time: 0.0,
+        shutter: ShutterCurve::Box,
         aperture: 0.0,
+        aperture_blades: 0,
+        aperture_rotation: 0.0,
         focus_distance: 0.0,
         ldr_scale: 0.0,
+        motion: Vec3::zero(),
+        angular_motion: Quaternion::zero(),
+        bloom: None,
+        denoise: None,
+        vignette: 0.0,
+        chromatic_aberration: 0.0,
+        mode: RenderMode::Beauty,
         bounce_limit: 0,
+        roulette_depth: 0,
+        roulette_min_survival: 1.0,
         num_samples: 0,
+        max_samples: 0,
+        variance_threshold: 0.0,
         width: 0,
         height: 0,
     };
@@ -323,8 +592,8 @@ fn parse_camera(parser: &mut Events<impl Read>, _attr: &[OwnedAttribute]) -> Cam
             }) => {
                 let v = get_attr(&attributes, "value");
                 match name.local_name.as_str() {
-                    "position" => out.position = parse_pt3(v.unwrap()),
-                    "direction" => out.direction = parse_vec3(v.unwrap()),
+                    "position" => out.position = parse_xyz(v.unwrap()).into(),
+                    "direction" => out.direction = parse_xyz(v.unwrap()).into(),
                     "sensor_distance" => out.sensor_distance = v.unwrap().parse().unwrap(),
                     "exposure_time" => out.exposure_time = v.unwrap().parse().unwrap(),
                     "aperture" => out.aperture = v.unwrap().parse().unwrap(),
@@ -334,6 +603,8 @@ fn parse_camera(parser: &mut Events<impl Read>, _attr: &[OwnedAttribute]) -> Cam
                     "num_samples" => out.num_samples = v.unwrap().parse().unwrap(),
                     "width" => out.width = v.unwrap().parse().unwrap(),
                     "height" => out.height = v.unwrap().parse().unwrap(),
+                    // Only the thin lens model is visualized
+                    "model" => {}
                     _ => {}
                 }
             }
@@ -352,36 +623,25 @@ fn get_attr<'a>(attributes: &'a [OwnedAttribute], name: &str) -> Option<&'a str>
         .map(|a| a.value.as_str())
 }
 
-fn parse_pt3(s: &str) -> Pt3 {
-    let brackets = s
-        .trim_start_matches("Point3 [")
-        .trim_end_matches(']')
-        .split(',');
-    let el = brackets
-        .map(|s| s.trim().parse::<f32>().unwrap())
-        .collect::<Vec<_>>();
-    assert_eq!(el.len(), 3);
-    point3(el[0], el[1], el[2])
-}
-
-fn parse_vec3(s: &str) -> Vec3 {
-    let brackets = s
-        .trim_start_matches("Vector3 [")
-        .trim_end_matches(']')
-        .split(',');
-    let el = brackets
-        .map(|s| s.trim().parse::<f32>().unwrap())
+/// Parses a vector or color attribute written as "x y z"
+fn parse_xyz(s: &str) -> [Scalar; 3] {
+    let el = s
+        .split_whitespace()
+        .map(|s| s.parse::<Scalar>().unwrap())
         .collect::<Vec<_>>();
     assert_eq!(el.len(), 3);
-    vec3(el[0], el[1], el[2])
+    [el[0], el[1], el[2]]
 }
 
 fn parse_color(s: &str) -> Color {
-    parse_pt3(s)
+    let [r, g, b] = parse_xyz(s);
+    Color::new(r, g, b)
 }
 
 fn parse_pixel(parser: &mut Events<impl Read>, attr: &[OwnedAttribute]) -> Pixel {
     let mut out = Pixel {
+        x: get_attr(attr, "x").unwrap().parse().unwrap(),
+        y: get_attr(attr, "y").unwrap().parse().unwrap(),
         color: parse_color(get_attr(attr, "color").unwrap()),
         samples: vec![],
     };
@@ -427,13 +687,34 @@ fn parse_sample(parser: &mut Events<impl Read>, attr: &[OwnedAttribute]) -> Samp
 fn parse_ray(parser: &mut Events<impl Read>, attr: &[OwnedAttribute]) -> Ray {
     let mut out = Ray {
         idx: get_attr(attr, "idx").unwrap().parse().unwrap(),
-        origin: parse_pt3(get_attr(attr, "origin").unwrap()),
-        direction: parse_vec3(get_attr(attr, "direction").unwrap()),
+        origin: parse_xyz(get_attr(attr, "origin").unwrap()).into(),
+        direction: parse_xyz(get_attr(attr, "direction").unwrap()).into(),
         debug: String::new(),
+        values: vec![],
     };
+    let mut in_text = false;
     for e in parser.by_ref() {
         match e {
-            Ok(XmlEvent::Whitespace(s)) | Ok(XmlEvent::Characters(s)) => out.debug.push_str(&s),
+            Ok(XmlEvent::StartElement {
+                name, attributes, ..
+            }) => match name.local_name.as_str() {
+                "text" => in_text = true,
+                "value" => {
+                    let attr = |name| get_attr(&attributes, name).unwrap();
+                    out.values.push(Value {
+                        name: attr("name").to_owned(),
+                        location: attr("location").to_owned(),
+                        value: DebugValue::parse(attr("kind"), attr("value")).unwrap(),
+                    });
+                }
+                _ => {}
+            },
+            Ok(XmlEvent::Whitespace(s)) | Ok(XmlEvent::Characters(s)) if in_text => {
+                out.debug.push_str(&s)
+            }
+            Ok(XmlEvent::EndElement { name }) if name.local_name.as_str() == "text" => {
+                in_text = false;
+            }
             Ok(XmlEvent::EndElement { name }) if name.local_name.as_str() == "ray" => {
                 break;
             }
@@ -443,3 +724,112 @@ fn parse_ray(parser: &mut Events<impl Read>, attr: &[OwnedAttribute]) -> Ray {
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{point3, vec3};
+    use pbrtrs_core::debugger::inner::{BounceInfo, DebugEntry, DebugInfo, PixelInfo, SampleInfo};
+    use pbrtrs_core::scene::{CameraBuilder, SceneBuilder};
+    use pbrtrs_core::types::color::BLACK;
+
+    #[test]
+    fn debug_output_round_trip() {
+        let mut scene = SceneBuilder::new()
+            .camera(CameraBuilder::new().resolution(32, 24).build())
+            .build();
+        scene.path = Some(PathBuf::from("/scenes/a <b>&c.toml"));
+        let ray = pbrtrs_core::types::Ray::new(point3(1.0, -2.5, 3.0), vec3(0.0, 0.6, 0.8), 0.0);
+        let debug_info = "value: \"a<b&c\\\"\"\n\t'quoted' <tag/> ]]>";
+        let values = [
+            ("wi", DebugValue::Vec3(vec3(0.1, -1e-7, 3.3333333))),
+            ("-ray.direction", DebugValue::Vec3(-ray.direction)),
+            ("point", DebugValue::Pt3(point3(1e20, 0.3, -0.0))),
+            ("pdf", DebugValue::Scalar(1.0 / 3.0)),
+            ("f", DebugValue::Color(Color::new(0.2, 0.7, 1e-3))),
+            (
+                "object",
+                DebugValue::Str("Object { name: \"a<b&c\\\"\" }".to_owned()),
+            ),
+        ];
+        let debug = DebugInfo {
+            pixels: [
+                (
+                    (3, 4),
+                    PixelInfo {
+                        samples: vec![SampleInfo {
+                            bounces: vec![BounceInfo {
+                                ray,
+                                debug_info: debug_info.to_owned(),
+                                entries: values
+                                    .iter()
+                                    .map(|(name, value)| DebugEntry {
+                                        name,
+                                        location: "pbrtrs_core/src/raytracer.rs:191",
+                                        value: value.clone(),
+                                    })
+                                    .collect(),
+                            }],
+                            final_color: Color::new(0.5, 1.0, 2.0),
+                        }],
+                        final_color: Color::new(0.25, 0.5, 1.0),
+                    },
+                ),
+                (
+                    (7, 1),
+                    PixelInfo {
+                        samples: vec![],
+                        final_color: BLACK,
+                    },
+                ),
+            ]
+            .into(),
+        };
+        let mut xml = vec![];
+        debug.write(&scene, &mut xml).unwrap();
+
+        let Document {
+            pixels,
+            camera,
+            scene_path,
+        } = parse_document(&mut EventReader::new(xml.as_slice()).into_iter());
+        assert_eq!(scene_path, scene.path);
+        assert_eq!((camera.width, camera.height), (32, 24));
+        assert_eq!(camera.position, scene.camera.position);
+        assert_eq!(camera.direction, scene.camera.direction);
+        assert_eq!(pixels.len(), 2);
+        assert_eq!((pixels[0].x, pixels[0].y), (3, 4));
+        assert_eq!((pixels[1].x, pixels[1].y), (7, 1));
+        assert_eq!(pixels[0].color, Color::new(0.25, 0.5, 1.0));
+
+        let sample = &pixels[0].samples[0];
+        assert_eq!(sample.color, Color::new(0.5, 1.0, 2.0));
+        assert_eq!(sample.bounces[0].origin, ray.origin);
+        assert_eq!(sample.bounces[0].direction, ray.direction);
+        assert_eq!(sample.bounces[0].debug, debug_info);
+        let parsed = &sample.bounces[0].values;
+        assert_eq!(parsed.len(), values.len());
+        for (parsed, (name, value)) in parsed.iter().zip(values) {
+            assert_eq!(parsed.name, name);
+            assert_eq!(parsed.location, "pbrtrs_core/src/raytracer.rs:191");
+            // Bit for bit, the values are written with the shortest round-tripping format
+            assert_eq!(parsed.value, value);
+        }
+    }
+
+    #[test]
+    fn parse_args() {
+        let parse = |args: &[&str]| Args::parse(args.iter().map(|arg| arg.to_string()));
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.debug_path, PathBuf::from("debug_out.xml"));
+        assert_eq!(args.scene_path, None);
+
+        let args = parse(&["--scene", "scene.toml", "out.xml"]).unwrap();
+        assert_eq!(args.debug_path, PathBuf::from("out.xml"));
+        assert_eq!(args.scene_path, Some(PathBuf::from("scene.toml")));
+
+        assert!(parse(&["--scene"]).is_err());
+        assert!(parse(&["a.xml", "b.xml"]).is_err());
+        assert!(parse(&["--help"]).is_err());
+    }
+}